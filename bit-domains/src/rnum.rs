@@ -1,6 +1,12 @@
-use core::ops::{Add,Sub,Mul,Rem,Div,Shl,Shr,BitXor,BitOr,BitAnd,Not};
+use core::ops::{Add,Sub,Mul,Shl,Shr,BitXor,BitOr,BitAnd};
+use core::cmp;
+use tnum::Tnum;
 
 /// Range number
+///
+/// Tracks the smallest and largest value, both when interpreted as unsigned (`min`, `max`) and as
+/// signed (`smin`, `smax`). The contained values are those within both ranges.
+#[derive(Copy,Clone,PartialEq,Eq,Debug)]
 pub struct Rnum {
     max: u64,
     min: u64,
@@ -9,11 +15,159 @@ pub struct Rnum {
     smin: i64,
 }
 
+impl Rnum {
+    pub fn from_value(v: u64) -> Self {
+        Self {
+            max: v,
+            min: v,
+            smax: v as i64,
+            smin: v as i64,
+        }
+    }
+
+    /// From both ranges. `None` if they contain no common values.
+    pub fn from_parts(min: u64, max: u64, smin: i64, smax: i64) -> Option<Self> {
+        Self { max, min, smax, smin }.deduce()
+    }
+
+    /// From an unsigned range. `None` if `min > max`.
+    pub fn from_range(min: u64, max: u64) -> Option<Self> {
+        Self::from_parts(min, max, i64::MIN, i64::MAX)
+    }
+
+    /// From a signed range. `None` if `smin > smax`.
+    pub fn from_srange(smin: i64, smax: i64) -> Option<Self> {
+        Self::from_parts(0, u64::MAX, smin, smax)
+    }
+
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn smin(&self) -> i64 {
+        self.smin
+    }
+
+    pub fn smax(&self) -> i64 {
+        self.smax
+    }
+
+    /// If this is a constant (only a single contained value), return that value. Otherwise, return
+    /// None.
+    pub fn value(&self) -> Option<u64> {
+        if self.min == self.max {
+            Some(self.min)
+        } else {
+            None
+        }
+    }
+
+    /// Is a specific value contained in this?
+    pub fn contains_value(&self, v: u64) -> bool {
+        self.min <= v && v <= self.max && self.smin <= v as i64 && v as i64 <= self.smax
+    }
+
+    /// `self` includes all possible elements in `other`
+    pub fn contains(&self, other: Self) -> bool {
+        self.min <= other.min && other.max <= self.max
+            && self.smin <= other.smin && other.smax <= self.smax
+    }
+
+    /// Return a domain containing the values in `self` and the values in `other`
+    pub fn union(&self, other: Self) -> Self {
+        Self {
+            max: cmp::max(self.max, other.max),
+            min: cmp::min(self.min, other.min),
+            smax: cmp::max(self.smax, other.smax),
+            smin: cmp::min(self.smin, other.smin),
+        }.deduce().unwrap_or_default()
+    }
+
+    /// Return a domain containing only the values that exist in both `self` and `other`, or
+    /// `None` if there are none.
+    pub fn intersection(&self, other: Self) -> Option<Self> {
+        Self::from_parts(
+            cmp::max(self.min, other.min),
+            cmp::min(self.max, other.max),
+            cmp::max(self.smin, other.smin),
+            cmp::min(self.smax, other.smax),
+        )
+    }
+
+    /// Narrow each range using the other, as values on one side of the sign bit are ordered the
+    /// same way in both. `None` if no values remain.
+    fn deduce(mut self) -> Option<Self> {
+        if self.min > self.max || self.smin > self.smax {
+            return None;
+        }
+
+        if (self.min ^ self.max) >> 63 == 0 {
+            // the unsigned range doesn't cross the sign bit, so it is also a signed range
+            self.smin = cmp::max(self.smin, self.min as i64);
+            self.smax = cmp::min(self.smax, self.max as i64);
+            self.min = self.smin as u64;
+            self.max = self.smax as u64;
+        } else if (self.smin ^ self.smax) >= 0 {
+            // the signed range doesn't cross zero, so it is also an unsigned range
+            self.min = cmp::max(self.min, self.smin as u64);
+            self.max = cmp::min(self.max, self.smax as u64);
+            self.smin = self.min as i64;
+            self.smax = self.max as i64;
+        }
+
+        if self.min > self.max || self.smin > self.smax {
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    /// With the unsigned range replaced by `[min, max]`, or entirely unknown if `None`. The signed
+    /// range is derived from it.
+    fn unsigned(r: Option<(u64, u64)>) -> Self {
+        r.and_then(|(min, max)| Self::from_range(min, max)).unwrap_or_default()
+    }
+}
+
+impl Default for Rnum {
+    /// Default is a completely unknown value
+    fn default() -> Self {
+        Self {
+            max: u64::MAX,
+            min: 0,
+            smax: i64::MAX,
+            smin: i64::MIN,
+        }
+    }
+}
+
+impl From<Tnum> for Rnum {
+    /// The ranges spanned by the values of a `Tnum`
+    fn from(t: Tnum) -> Self {
+        let sign = 1 << 63;
+        Self {
+            max: t.value() | t.mask(),
+            min: t.value(),
+            smax: (t.value() | (t.mask() & !sign)) as i64,
+            smin: (t.value() | (t.mask() & sign)) as i64,
+        }.deduce().unwrap_or_default()
+    }
+}
+
+/// The smallest all-ones value at least as large as `v`
+fn fill(v: u64) -> u64 {
+    if v == 0 { 0 } else { u64::MAX >> v.leading_zeros() }
+}
+
 impl BitOr for Rnum {
     type Output = Rnum;
     fn bitor(self, other: Self) -> Self
     {
-        unimplemented!()
+        Self::unsigned(Some((cmp::max(self.min, other.min), fill(self.max | other.max))))
     }
 }
 
@@ -21,7 +175,7 @@ impl BitAnd for Rnum {
     type Output = Rnum;
     fn bitand(self, other: Self) -> Self
     {
-        unimplemented!()
+        Self::unsigned(Some((0, cmp::min(self.max, other.max))))
     }
 }
 
@@ -29,60 +183,64 @@ impl BitXor for Rnum {
     type Output = Rnum;
     fn bitxor(self, other: Self) -> Self
     {
-        unimplemented!()
+        Self::unsigned(Some((0, fill(cmp::max(self.max, other.max)))))
     }
 }
 
-/*
-impl Add for Rnum {
+impl Shl<u8> for Rnum {
     type Output = Rnum;
-    fn add(self, other: Self) -> Self {
-        Self {
-            max: self.max + other.max,
-            min: self.min + other.min,
+    fn shl(self, shift: u8) -> Self {
+        if self.max.leading_zeros() < u32::from(shift) {
+            return Self::default();
         }
+        Self::unsigned(Some((self.min << shift, self.max << shift)))
     }
 }
 
-impl Sub for Rnum {
+impl Shr<u8> for Rnum {
     type Output = Rnum;
-    fn sub(self, other: Self) -> Self {
-        unimplemented!()
+    fn shr(self, shift: u8) -> Self {
+        Self::unsigned(Some((self.min >> shift, self.max >> shift)))
     }
 }
 
-impl Neg for Rnum {
+impl Add for Rnum {
     type Output = Rnum;
-    fn neg(self) -> Self {
-        unimplemented!()
+    fn add(self, other: Self) -> Self {
+        let u = self.min.checked_add(other.min).and_then(|min| {
+            self.max.checked_add(other.max).map(|max| (min, max))
+        });
+        let s = self.smin.checked_add(other.smin).and_then(|smin| {
+            self.smax.checked_add(other.smax).map(|smax| (smin, smax))
+        });
+        combine(u, s)
     }
 }
 
-impl Not for Rnum {
+impl Sub for Rnum {
     type Output = Rnum;
-    fn not(self) -> Self {
-        unimplemented!()
+    fn sub(self, other: Self) -> Self {
+        let u = self.min.checked_sub(other.max).and_then(|min| {
+            self.max.checked_sub(other.min).map(|max| (min, max))
+        });
+        let s = self.smin.checked_sub(other.smax).and_then(|smin| {
+            self.smax.checked_sub(other.smin).map(|smax| (smin, smax))
+        });
+        combine(u, s)
     }
 }
 
 impl Mul for Rnum {
     type Output = Rnum;
     fn mul(self, other: Self) -> Self {
-        unimplemented!()
-    }
-}
-
-impl Div for Rnum {
-    type Output = Rnum;
-    fn div(self, other: Self) -> Self {
-        unimplemented!()
+        let u = self.max.checked_mul(other.max).map(|max| (self.min * other.min, max));
+        Self::unsigned(u)
     }
 }
 
-impl Rem for Rnum {
-    type Output = Rnum;
-    fn rem(self, other: Self) -> Self {
-        unimplemented!()
-    }
+/// From unsigned and signed ranges, either of which may be entirely unknown (`None`)
+fn combine(u: Option<(u64, u64)>, s: Option<(i64, i64)>) -> Rnum {
+    let (min, max) = u.unwrap_or((0, u64::MAX));
+    let (smin, smax) = s.unwrap_or((i64::MIN, i64::MAX));
+    Rnum::from_parts(min, max, smin, smax).unwrap_or_default()
 }
-*/
@@ -1,4 +1,4 @@
-use core::ops::{Add,Sub,Mul,Rem,Div,Shl,Shr,BitXor,BitOr,BitAnd,Not};
+use core::ops::{Add,Sub,Mul,Shl,Shr,BitXor,BitOr,BitAnd,Not};
 
 /// Tracking number
 ///
@@ -25,22 +25,103 @@ impl Tnum {
     pub fn from_value(value: u64) -> Self 
     {
         Self {
-            value: value,
+            value,
             mask: 0,
         }
     }
 
+    /// From the known bit values in `value` and the unknown bits in `mask`. Bits of `value` that
+    /// are set in `mask` are ignored.
+    pub fn from_parts(value: u64, mask: u64) -> Self
+    {
+        Self {
+            value: value & !mask,
+            mask,
+        }
+    }
+
     // right now, we lose some information here as we are forced to expand the range to power of 2
     // might be reasonable to have direct range tracking or have a `Rnum` which extends `Tnum` with
     // range tracking.
     pub fn from_range(min: u64, max: u64) -> Self
     {
-        unimplemented!()
+        // the bits above the highest that differs are shared by every value in the range
+        let bits = 64 - (min ^ max).leading_zeros();
+        if bits > 63 {
+            return Self::default();
+        }
+        let delta = (1 << bits) - 1;
+        Self {
+            value: min & !delta,
+            mask: delta,
+        }
+    }
+
+    /// The known bits. Unknown bits are zero.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// The unknown bits
+    pub fn mask(&self) -> u64 {
+        self.mask
     }
 
     pub fn is_const(&self) -> bool {
         self.mask == 0
     }
+
+    /// Is a specific value contained in this?
+    pub fn contains_value(&self, v: u64) -> bool {
+        v & !self.mask == self.value
+    }
+
+    /// `self` includes all possible elements in `other`
+    pub fn contains(&self, other: Self) -> bool {
+        other.mask & !self.mask == 0 && other.value & !self.mask == self.value
+    }
+
+    /// Return a domain containing the values in `self` and the values in `other`
+    pub fn union(&self, other: Self) -> Self {
+        let mu = self.mask | other.mask | (self.value ^ other.value);
+        Self {
+            value: self.value & !mu,
+            mask: mu,
+        }
+    }
+
+    /// Return a domain containing only the values that exist in both `self` and `other`, or
+    /// `None` if a bit is known to be different in each.
+    pub fn intersection(&self, other: Self) -> Option<Self> {
+        if (self.value ^ other.value) & !self.mask & !other.mask != 0 {
+            return None;
+        }
+        let mu = self.mask & other.mask;
+        Some(Self {
+            value: (self.value | other.value) & !mu,
+            mask: mu,
+        })
+    }
+
+    /// Arithmetic (sign extending) right shift
+    pub fn arshift(self, shift: u8) -> Self {
+        Self {
+            value: ((self.value as i64) >> shift) as u64,
+            mask: ((self.mask as i64) >> shift) as u64,
+        }
+    }
+
+    /// Truncate to the lower `size` bytes, zero extending
+    pub fn cast(self, size: u8) -> Self {
+        if size >= 8 {
+            return self;
+        }
+        let keep = (1u64 << (size * 8)) - 1;
+        Self {
+            value: self.value & keep,
+            mask: self.mask & keep,
+        }
+    }
 }
 
 impl Default for Tnum {
@@ -83,7 +164,14 @@ impl BitAnd for Tnum {
     type Output = Tnum;
     fn bitand(self, other: Self) -> Self
     {
-        unimplemented!()
+        let alpha = self.value | self.mask;
+        let beta = other.value | other.mask;
+        let v = self.value & other.value;
+
+        Self {
+            value: v,
+            mask: alpha & beta & !v,
+        }
     }
 }
 
@@ -91,7 +179,13 @@ impl BitXor for Tnum {
     type Output = Tnum;
     fn bitxor(self, other: Self) -> Self
     {
-        unimplemented!()
+        let v = self.value ^ other.value;
+        let mu = self.mask | other.mask;
+
+        Self {
+            value: v & !mu,
+            mask: mu,
+        }
     }
 }
 
@@ -115,76 +209,56 @@ impl Shr<u8> for Tnum {
     }
 }
 
-/*
- *
-
 impl Add for Tnum {
     type Output = Tnum;
     fn add(self, other: Self) -> Self
     {
-        // we need to think about how addition is handled at the bit level, but then be clever
-        // about using our avaliable addition operator to reduce how much work we do.
-
-        let mo = self.mask | other.mask;
-        let ma = self.mask + other.mask;
-    
-        // 3 * 3 * 3 = 27 variants
-        //
-        // 2*2*2 = 8 are everything known
-        //
-        // carry.known(0) + s.known(1) + o.unknown => self unknown & carry unknown 
-        // carry.known(1) + s.known(0) + o.unknown => self unknown & carry unknown
-        //  really, one or the other, but unclear if we can effectively model that
-        //
-        // carry.known(0) + s.known(0) + o.unknown => self unknown (carry known 0)
-        // carry.known(1) + s.known(1) + o.unknown => self unknown (carry known 1)
-        // c.unk + s.k(0) + o.unk => s.u || c.u
-        // c.unk + s.k(1) + o.unk => s.u, c.k(1)
-        //
-        let m1 = ma | mo; 
-
-        let v1 = self.value + other.value;
+        // the sums with every unknown bit clear and with every unknown bit set differ in every bit
+        // a carry may have changed
+        let sm = self.mask.wrapping_add(other.mask);
+        let sv = self.value.wrapping_add(other.value);
+        let chi = sm.wrapping_add(sv) ^ sv;
+        let mu = chi | self.mask | other.mask;
 
         Self {
-            mask: m1,
-            value: v1
+            value: sv & !mu,
+            mask: mu,
         }
     }
 }
- *
+
 impl Sub for Tnum {
     type Output = Tnum;
     fn sub(self, other: Self) -> Self {
-        unimplemented!()
+        let dv = self.value.wrapping_sub(other.value);
+        let alpha = dv.wrapping_add(self.mask);
+        let beta = dv.wrapping_sub(other.mask);
+        let mu = (alpha ^ beta) | self.mask | other.mask;
+
+        Self {
+            value: dv & !mu,
+            mask: mu,
+        }
     }
 }
 
 impl Mul for Tnum {
     type Output = Tnum;
     fn mul(self, other: Self) -> Self {
-        unimplemented!()
-    }
-}
-
-impl Div for Tnum {
-    type Output = Tnum;
-    fn div(self, other: Self) -> Self {
-        unimplemented!()
-    }
-}
-
-impl Rem for Tnum {
-    type Output = Tnum;
-    fn rem(self, other: Self) -> Self {
-        unimplemented!()
-    }
-}
-
-impl Neg for Tnum {
-    type Output = Tnum;
-    fn neg(self) -> Self {
-        unimplemented!()
+        // long multiplication: known products are summed exactly, and each partial product that
+        // depends on an unknown bit is added as entirely unknown bits
+        let acc_v = self.value.wrapping_mul(other.value);
+        let mut acc_m = Tnum::from_value(0);
+        let (mut a, mut b) = (self, other);
+        while a.value != 0 || a.mask != 0 {
+            if a.value & 1 != 0 {
+                acc_m = acc_m + Tnum::from_parts(0, b.mask);
+            } else if a.mask & 1 != 0 {
+                acc_m = acc_m + Tnum::from_parts(0, b.value | b.mask);
+            }
+            a = a >> 1;
+            b = b << 1;
+        }
+        Tnum::from_value(acc_v) + acc_m
     }
 }
-
-*/
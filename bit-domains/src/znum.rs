@@ -1,4 +1,4 @@
-use core::ops::{Add,Sub,Mul,Rem,Div,Shl,Shr,BitXor,BitOr,BitAnd,Not};
+use core::ops::{Shl,Shr,BitXor,BitOr,BitAnd,Not};
 
 /// Tracks which bits "may be 1s" (o) and "may be 0s" (z)
 ///
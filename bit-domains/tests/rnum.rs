@@ -0,0 +1,128 @@
+extern crate bit_domains;
+extern crate quickcheck;
+use bit_domains::{Rnum, Tnum};
+use quickcheck::{quickcheck, QuickCheck, StdThreadGen, Testable};
+
+/// Check `f` with small values, and with values spread across the whole range of each type
+fn check<A: Testable + Copy>(f: A) {
+    quickcheck(f);
+    QuickCheck::with_gen(StdThreadGen::new(usize::MAX)).quickcheck(f);
+}
+
+/// A range containing both `a` and `b`, and a value in it picked by `pick`
+fn member(a: u64, b: u64, pick: u64) -> (Rnum, u64) {
+    let (min, max) = if a < b { (a, b) } else { (b, a) };
+    let r = Rnum::from_range(min, max).unwrap();
+    let span = max - min;
+    let x = if span == u64::MAX { pick } else { min + pick % (span + 1) };
+    (r, x)
+}
+
+/// A signed range containing both `a` and `b`, and a value in it picked by `pick`
+fn smember(a: i64, b: i64, pick: u64) -> (Rnum, u64) {
+    let (smin, smax) = if a < b { (a, b) } else { (b, a) };
+    let r = Rnum::from_srange(smin, smax).unwrap();
+    let span = smax.wrapping_sub(smin) as u64;
+    let x = if span == u64::MAX { pick } else { (smin as u64).wrapping_add(pick % (span + 1)) };
+    (r, x)
+}
+
+#[test]
+fn ranges_contain_members() {
+    fn prop(a: u64, b: u64, c: i64, d: i64, pick: u64) -> bool {
+        let (r, x) = member(a, b, pick);
+        let (s, y) = smember(c, d, pick);
+        r.contains_value(x) && s.contains_value(y)
+    }
+    check(prop as fn(u64, u64, i64, i64, u64) -> bool);
+}
+
+#[test]
+fn from_value() {
+    fn prop(x: u64) -> bool {
+        let r = Rnum::from_value(x);
+        r.value() == Some(x) && r.contains_value(x) && !r.contains_value(x.wrapping_add(1))
+    }
+    check(prop as fn(u64) -> bool);
+}
+
+#[test]
+fn deduce_across_signs() {
+    // [-5, 5] signed intersected with values below the sign bit is [0, 5]
+    let r = Rnum::from_parts(0, i64::MAX as u64, -5, 5).unwrap();
+    assert_eq!((r.min(), r.max(), r.smin(), r.smax()), (0, 5, 0, 5));
+
+    // [-11, -1] unsigned intersected with [-5, 5] signed is [-5, -1]
+    let r = Rnum::from_parts(!0 - 10, !0, -5, 5).unwrap();
+    assert_eq!((r.smin(), r.smax()), (-5, -1));
+
+    assert_eq!(Rnum::from_parts(0, 10, 20, 30), None);
+    assert_eq!(Rnum::from_range(3, 2), None);
+}
+
+macro_rules! binop_sound {
+    ($name:ident, $op:expr, $rnum_op:expr) => {
+        #[test]
+        fn $name() {
+            fn prop(a: u64, b: u64, p: u64, c: i64, d: i64, q: u64) -> bool {
+                let (r, x) = member(a, b, p);
+                let (s, y) = smember(c, d, q);
+                $rnum_op(r, s).contains_value($op(x, y)) && $rnum_op(s, r).contains_value($op(y, x))
+                    && $rnum_op(r, r).contains_value($op(x, x))
+            }
+            check(prop as fn(u64, u64, u64, i64, i64, u64) -> bool);
+        }
+    }
+}
+
+binop_sound!(add_sound, u64::wrapping_add, |a, b| a + b);
+binop_sound!(sub_sound, u64::wrapping_sub, |a, b| a - b);
+binop_sound!(mul_sound, u64::wrapping_mul, |a, b| a * b);
+binop_sound!(and_sound, |x, y| x & y, |a, b| a & b);
+binop_sound!(or_sound, |x, y| x | y, |a, b| a | b);
+binop_sound!(xor_sound, |x, y| x ^ y, |a, b| a ^ b);
+binop_sound!(union_sound, |x, _| x, |a: Rnum, b| a.union(b));
+
+#[test]
+fn small_ranges_are_precise() {
+    let a = Rnum::from_range(1, 3).unwrap();
+    let b = Rnum::from_range(10, 20).unwrap();
+    assert_eq!(a + b, Rnum::from_range(11, 23).unwrap());
+    assert_eq!(b - a, Rnum::from_range(7, 19).unwrap());
+    assert_eq!(a - b, Rnum::from_srange(-19, -7).unwrap());
+    assert_eq!(a * b, Rnum::from_range(10, 60).unwrap());
+    assert_eq!(b << 2, Rnum::from_range(40, 80).unwrap());
+}
+
+#[test]
+fn shifts_sound() {
+    fn prop(a: u64, b: u64, p: u64, s: u8) -> bool {
+        let (r, x) = member(a, b, p);
+        let s = s & 63;
+        (r << s).contains_value(x << s) && (r >> s).contains_value(x >> s)
+    }
+    check(prop as fn(u64, u64, u64, u8) -> bool);
+}
+
+#[test]
+fn from_tnum_sound() {
+    fn prop(v: u64, m: u64, p: u64) -> bool {
+        let t = Tnum::from_parts(v, m);
+        let x = t.value() | (p & t.mask());
+        Rnum::from(t).contains_value(x)
+    }
+    check(prop as fn(u64, u64, u64) -> bool);
+}
+
+#[test]
+fn intersection() {
+    fn prop(a: u64, b: u64, p: u64, c: i64, d: i64) -> bool {
+        let (r, x) = member(a, b, p);
+        let (s, _) = smember(c, d, p);
+        match r.intersection(s) {
+            Some(i) => r.contains(i) && s.contains(i) && (!s.contains_value(x) || i.contains_value(x)),
+            None => !s.contains_value(x),
+        }
+    }
+    check(prop as fn(u64, u64, u64, i64, i64) -> bool);
+}
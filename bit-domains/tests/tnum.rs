@@ -0,0 +1,110 @@
+extern crate bit_domains;
+extern crate quickcheck;
+use bit_domains::Tnum;
+use quickcheck::{quickcheck, QuickCheck, StdThreadGen, TestResult, Testable};
+
+/// Check `f` with small values, and with values spread across the whole range of each type
+fn check<A: Testable + Copy>(f: A) {
+    quickcheck(f);
+    QuickCheck::with_gen(StdThreadGen::new(usize::MAX)).quickcheck(f);
+}
+
+/// A `Tnum` and a value contained in it, chosen by `pick`
+fn member(value: u64, mask: u64, pick: u64) -> (Tnum, u64) {
+    let t = Tnum::from_parts(value, mask);
+    (t, t.value() | (pick & t.mask()))
+}
+
+#[test]
+fn const_value_roundtrip() {
+    fn prop(x: u64) -> bool {
+        let t = Tnum::from_value(x);
+        t.is_const() && t.value() == x && t.contains_value(x)
+    }
+    check(prop as fn(u64) -> bool);
+}
+
+#[test]
+fn from_range_contains() {
+    fn prop(a: u64, b: u64, pick: u64) -> bool {
+        let (min, max) = if a < b { (a, b) } else { (b, a) };
+        let t = Tnum::from_range(min, max);
+        let x = min + pick % (max - min).saturating_add(1).max(1);
+        t.contains_value(min) && t.contains_value(max) && (max - min == u64::MAX || t.contains_value(x))
+    }
+    check(prop as fn(u64, u64, u64) -> bool);
+}
+
+macro_rules! binop_sound {
+    ($name:ident, $op:expr, $tnum_op:expr) => {
+        #[test]
+        fn $name() {
+            fn prop(av: u64, am: u64, ap: u64, bv: u64, bm: u64, bp: u64) -> bool {
+                let (a, x) = member(av, am, ap);
+                let (b, y) = member(bv, bm, bp);
+                $tnum_op(a, b).contains_value($op(x, y))
+            }
+            check(prop as fn(u64, u64, u64, u64, u64, u64) -> bool);
+        }
+    }
+}
+
+binop_sound!(add_sound, u64::wrapping_add, |a, b| a + b);
+binop_sound!(sub_sound, u64::wrapping_sub, |a, b| a - b);
+binop_sound!(mul_sound, u64::wrapping_mul, |a, b| a * b);
+binop_sound!(and_sound, |x, y| x & y, |a, b| a & b);
+binop_sound!(or_sound, |x, y| x | y, |a, b| a | b);
+binop_sound!(xor_sound, |x, y| x ^ y, |a, b| a ^ b);
+binop_sound!(union_sound_a, |x, _| x, |a: Tnum, b| a.union(b));
+binop_sound!(union_sound_b, |_, y| y, |a: Tnum, b| a.union(b));
+
+#[test]
+fn const_ops_are_exact() {
+    fn prop(x: u64, y: u64) -> bool {
+        let (a, b) = (Tnum::from_value(x), Tnum::from_value(y));
+        a + b == Tnum::from_value(x.wrapping_add(y))
+            && a - b == Tnum::from_value(x.wrapping_sub(y))
+            && a * b == Tnum::from_value(x.wrapping_mul(y))
+            && (a & b) == Tnum::from_value(x & y)
+            && (a ^ b) == Tnum::from_value(x ^ y)
+    }
+    check(prop as fn(u64, u64) -> bool);
+}
+
+#[test]
+fn shifts_sound() {
+    fn prop(v: u64, m: u64, p: u64, s: u8) -> bool {
+        let (t, x) = member(v, m, p);
+        let s = s & 63;
+        (t << s).contains_value(x << s)
+            && (t >> s).contains_value(x >> s)
+            && t.arshift(s).contains_value(((x as i64) >> s) as u64)
+    }
+    check(prop as fn(u64, u64, u64, u8) -> bool);
+}
+
+#[test]
+fn cast_sound() {
+    fn prop(v: u64, m: u64, p: u64, size: u8) -> bool {
+        let (t, x) = member(v, m, p);
+        let size = [1, 2, 4, 8][(size & 3) as usize];
+        let keep = if size == 8 { !0 } else { (1u64 << (size * 8)) - 1 };
+        t.cast(size).contains_value(x & keep)
+    }
+    check(prop as fn(u64, u64, u64, u8) -> bool);
+}
+
+#[test]
+fn intersection() {
+    fn prop(av: u64, am: u64, bv: u64, bm: u64, pick: u64) -> TestResult {
+        let (a, b) = (Tnum::from_parts(av, am), Tnum::from_parts(bv, bm));
+        let (_, x) = member(av, am, pick);
+        match a.intersection(b) {
+            Some(c) => TestResult::from_bool(a.contains(c) && b.contains(c)
+                && (!b.contains_value(x) || c.contains_value(x))),
+            // only disjoint when a known bit differs
+            None => TestResult::from_bool(!b.contains_value(x)),
+        }
+    }
+    check(prop as fn(u64, u64, u64, u64, u64) -> TestResult);
+}
@@ -72,14 +72,14 @@ fn const_is_defined() {
 
 #[test]
 fn instance_is_defined() {
-    assert_eq!(Znum::from_parts(0,0).is_defined(), false);
-    assert_eq!(Znum::from_parts(0,1).is_defined(), false);
-    assert_eq!(Znum::from_parts(1,1).is_defined(), false);
-    assert_eq!(Znum::from_parts(1,0).is_defined(), false);
-    assert_eq!(Znum::from_parts(0xfffffffffffffffe,0xfffffffffffffffe).is_defined(), false);
-    assert_eq!(Znum::from_parts(0xfffffffffffffffe,0xffffffffffffffff).is_defined(), true);
-    assert_eq!(Znum::from_parts(0xffffffffffffffff,0xfffffffffffffffe).is_defined(), true);
-    assert_eq!(Znum::from_parts(0xffffffffffffffff,0xffffffffffffffff).is_defined(), true);
+    assert!(!Znum::from_parts(0,0).is_defined());
+    assert!(!Znum::from_parts(0,1).is_defined());
+    assert!(!Znum::from_parts(1,1).is_defined());
+    assert!(!Znum::from_parts(1,0).is_defined());
+    assert!(!Znum::from_parts(0xfffffffffffffffe,0xfffffffffffffffe).is_defined());
+    assert!(Znum::from_parts(0xfffffffffffffffe,0xffffffffffffffff).is_defined());
+    assert!(Znum::from_parts(0xffffffffffffffff,0xfffffffffffffffe).is_defined());
+    assert!(Znum::from_parts(0xffffffffffffffff,0xffffffffffffffff).is_defined());
 }
 
 #[test]
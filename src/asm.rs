@@ -0,0 +1,963 @@
+//! An assembler for eBPF programs
+//!
+//! Two syntaxes are accepted, and may be mixed freely within a program:
+//!
+//!  - The C-like syntax used by llvm and the linux kernel's verifier log:
+//!
+//!    ```text
+//!    r0 = 1
+//!    r1 += r2
+//!    r2 = *(u32 *)(r1 + 4)
+//!    *(u32 *)(r10 - 4) = r1
+//!    if r1 > 0x10 goto +2
+//!    call 1
+//!    exit
+//!    ```
+//!
+//!  - A mnemonic syntax (as used by uBPF and others):
+//!
+//!    ```text
+//!    mov r0, 1
+//!    add r1, r2
+//!    ldxw r2, [r1+4]
+//!    stxw [r10-4], r1
+//!    jgt r1, 0x10, +2
+//!    ```
+//!
+//! Jump targets may be given as a relative offset (`+2`, `-1`) or as the name of a label, as may
+//! the target of a call to another BPF function (`call +4`), which `call N` of a helper is told
+//! apart from by the sign. Comparing 32-bit registers (`if w1 > w2 goto +1`, `jgt32 r1, r2, +1`)
+//! uses `Class::Jmp32`, and `gotol` is the unconditional jump with a 32-bit offset. Labels are
+//! declared by an identifier followed by `:`. Comments start with `//`, `#` or `;` and run to the
+//! end of the line.
+//!
+//! `r1 = map[0] ll` loads the map with index 0, and `r1 = map_value[0] + 8 ll` a pointer 8 bytes
+//! into its value, as `disasm` shows them.
+
+use super::*;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::{cmp, fmt};
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum AsmErrorKind {
+    /// The input did not match the expected syntax
+    Syntax(&'static str),
+    UnknownMnemonic,
+    /// An immediate or offset does not fit in its field
+    OutOfRange,
+    DuplicateLabel,
+    UndefinedLabel,
+    /// A jump's target is too far away to be encoded in its offset field
+    JumpOutOfRange,
+}
+
+/// An error from `assemble()`, with the (1-based) line and column at which it occurred
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct AsmError {
+    line: usize,
+    col: usize,
+    kind: AsmErrorKind,
+}
+
+impl AsmError {
+    pub(crate) fn new(line: usize, col: usize, kind: AsmErrorKind) -> Self {
+        AsmError { line, col, kind }
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn col(&self) -> usize {
+        self.col
+    }
+
+    pub fn kind(&self) -> &AsmErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsmErrorKind::Syntax(s) => write!(f, "syntax error: {}", s),
+            AsmErrorKind::UnknownMnemonic => write!(f, "unknown instruction"),
+            AsmErrorKind::OutOfRange => write!(f, "value out of range"),
+            AsmErrorKind::DuplicateLabel => write!(f, "label defined more than once"),
+            AsmErrorKind::UndefinedLabel => write!(f, "undefined label"),
+            AsmErrorKind::JumpOutOfRange => write!(f, "jump target out of range"),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.kind)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum TokKind<'a> {
+    Ident(&'a str),
+    Num(u64),
+    Punct(&'static str),
+}
+
+#[derive(Debug,Clone,Copy)]
+struct Tok<'a> {
+    kind: TokKind<'a>,
+    col: usize,
+}
+
+// longest first, so that prefixes don't match early
+const PUNCTS: &[&str] = &[
+    "<<=", ">>=",
+    "==", "!=", ">=", "<=", "+=", "-=", "*=", "/=", "|=", "&=", "%=", "^=",
+    ">", "<", "=", "&", "(", ")", "[", "]", "*", ",", ":", "+", "-",
+];
+
+const SIGNED_PUNCTS: &[&str] = &["s>>=", "s>=", "s<=", "s>", "s<"];
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'.'
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut end = line.len();
+    for pat in &["//", "#", ";"] {
+        if let Some(i) = line.find(pat) {
+            end = cmp::min(end, i);
+        }
+    }
+    &line[..end]
+}
+
+fn parse_num(s: &str) -> Option<u64> {
+    let (digits, radix) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None => (s, 10),
+    };
+
+    let mut v: u64 = 0;
+    let mut any = false;
+    for c in digits.chars() {
+        if c == '_' {
+            continue;
+        }
+        let d = c.to_digit(radix)?;
+        v = v.checked_mul(u64::from(radix))?.checked_add(u64::from(d))?;
+        any = true;
+    }
+
+    if any { Some(v) } else { None }
+}
+
+fn lex(line: &str, line_no: usize) -> Result<Vec<Tok<'_>>, AsmError> {
+    let b = line.as_bytes();
+    let mut toks = Vec::new();
+    let mut i = 0;
+    'outer: while i < b.len() {
+        let c = b[i];
+        let col = i + 1;
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if is_ident_char(c) {
+            let start = i;
+            while i < b.len() && is_ident_char(b[i]) {
+                i += 1;
+            }
+            let word = &line[start..i];
+
+            if word == "s" {
+                for p in SIGNED_PUNCTS {
+                    if line[start..].starts_with(p) {
+                        i = start + p.len();
+                        toks.push(Tok { kind: TokKind::Punct(p), col });
+                        continue 'outer;
+                    }
+                }
+            }
+
+            let kind = if c.is_ascii_digit() {
+                TokKind::Num(parse_num(word).ok_or(AsmError {
+                    line: line_no,
+                    col,
+                    kind: AsmErrorKind::Syntax("invalid number"),
+                })?)
+            } else {
+                TokKind::Ident(word)
+            };
+            toks.push(Tok { kind, col });
+            continue;
+        }
+
+        for p in PUNCTS {
+            if line[i..].starts_with(p) {
+                i += p.len();
+                toks.push(Tok { kind: TokKind::Punct(p), col });
+                continue 'outer;
+            }
+        }
+
+        return Err(AsmError {
+            line: line_no,
+            col,
+            kind: AsmErrorKind::Syntax("unexpected character"),
+        });
+    }
+
+    Ok(toks)
+}
+
+#[derive(Debug,Clone,Copy)]
+enum Target<'a> {
+    Rel(i32),
+    Label(&'a str, usize),
+}
+
+/// An operand which may be either a register or an immediate
+#[derive(Debug,Clone,Copy)]
+enum Operand {
+    Reg(u8),
+    Imm(u32),
+}
+
+/// An instruction which may still need its jump offset resolved
+struct Pending<'a> {
+    line: usize,
+    inst: Inst,
+    target: Option<Target<'a>>,
+}
+
+struct Parser<'a, 't> {
+    toks: &'t [Tok<'a>],
+    pos: usize,
+    line: usize,
+    // column just past the end of the line, for errors about missing tokens
+    eol: usize,
+}
+
+fn alu_code(op: &str) -> Option<OpAlu> {
+    Some(match op {
+        "add" => OpAlu::Add,
+        "sub" => OpAlu::Sub,
+        "mul" => OpAlu::Mul,
+        "div" => OpAlu::Div,
+        "or" => OpAlu::Or,
+        "and" => OpAlu::And,
+        "lsh" => OpAlu::Lsh,
+        "rsh" => OpAlu::Rsh,
+        "mod" => OpAlu::Mod,
+        "xor" => OpAlu::Xor,
+        "mov" => OpAlu::Mov,
+        "arsh" => OpAlu::Arsh,
+        _ => return None,
+    })
+}
+
+fn alu_assign_code(op: &str) -> Option<OpAlu> {
+    Some(match op {
+        "+=" => OpAlu::Add,
+        "-=" => OpAlu::Sub,
+        "*=" => OpAlu::Mul,
+        "/=" => OpAlu::Div,
+        "|=" => OpAlu::Or,
+        "&=" => OpAlu::And,
+        "<<=" => OpAlu::Lsh,
+        ">>=" => OpAlu::Rsh,
+        "%=" => OpAlu::Mod,
+        "^=" => OpAlu::Xor,
+        "s>>=" => OpAlu::Arsh,
+        _ => return None,
+    })
+}
+
+fn jmp_code(op: &str) -> Option<OpJmp> {
+    Some(match op {
+        "jeq" => OpJmp::Jeq,
+        "jgt" => OpJmp::Jgt,
+        "jge" => OpJmp::Jge,
+        "jset" => OpJmp::Jset,
+        "jne" => OpJmp::Jne,
+        "jsgt" => OpJmp::Jsgt,
+        "jsge" => OpJmp::Jsge,
+        "jlt" => OpJmp::Jlt,
+        "jle" => OpJmp::Jle,
+        "jslt" => OpJmp::Jslt,
+        "jsle" => OpJmp::Jsle,
+        _ => return None,
+    })
+}
+
+fn jmp_cmp_code(op: &str) -> Option<OpJmp> {
+    Some(match op {
+        "==" => OpJmp::Jeq,
+        ">" => OpJmp::Jgt,
+        ">=" => OpJmp::Jge,
+        "&" => OpJmp::Jset,
+        "!=" => OpJmp::Jne,
+        "s>" => OpJmp::Jsgt,
+        "s>=" => OpJmp::Jsge,
+        "<" => OpJmp::Jlt,
+        "<=" => OpJmp::Jle,
+        "s<" => OpJmp::Jslt,
+        "s<=" => OpJmp::Jsle,
+        _ => return None,
+    })
+}
+
+fn size_suffix(s: &str) -> Option<Size> {
+    Some(match s {
+        "b" => Size::B,
+        "h" => Size::H,
+        "w" => Size::W,
+        "dw" => Size::DW,
+        _ => return None,
+    })
+}
+
+fn size_type(s: &str) -> Option<Size> {
+    Some(match s {
+        "u8" => Size::B,
+        "u16" => Size::H,
+        "u32" => Size::W,
+        "u64" => Size::DW,
+        _ => return None,
+    })
+}
+
+fn inst(op: u8, dst: u8, src: u8, off: i16, imm: u32) -> Inst {
+    Inst {
+        op,
+        src_dst: (src << 4) | dst,
+        off: off as u16,
+        imm,
+    }
+}
+
+fn alu(wide: bool, code: OpAlu, dst: u8, src: Operand) -> Inst {
+    let class = if wide { Class::Alu64 } else { Class::Alu } as u8;
+    match src {
+        Operand::Reg(r) => inst(class | code as u8 | Src::X as u8, dst, r, 0, 0),
+        Operand::Imm(k) => inst(class | code as u8 | Src::K as u8, dst, 0, 0, k),
+    }
+}
+
+fn jmp(wide: bool, code: OpJmp, dst: u8, src: Operand) -> Inst {
+    let class = if wide { Class::Jmp } else { Class::Jmp32 } as u8;
+    match src {
+        Operand::Reg(r) => inst(class | code as u8 | Src::X as u8, dst, r, 0, 0),
+        Operand::Imm(k) => inst(class | code as u8 | Src::K as u8, dst, 0, 0, k),
+    }
+}
+
+fn mem(class: Class, mode: Mode, size: Size) -> u8 {
+    class as u8 | mode as u8 | size as u8
+}
+
+impl<'a, 't> Parser<'a, 't> {
+    fn err(&self, col: usize, kind: AsmErrorKind) -> AsmError {
+        AsmError { line: self.line, col, kind }
+    }
+
+    fn col(&self) -> usize {
+        self.toks.get(self.pos).map(|t| t.col).unwrap_or(self.eol)
+    }
+
+    fn peek(&self) -> Option<TokKind<'a>> {
+        self.toks.get(self.pos).map(|t| t.kind)
+    }
+
+    fn peek_is(&self, p: &str) -> bool {
+        match self.peek() {
+            Some(TokKind::Punct(x)) => x == p,
+            _ => false,
+        }
+    }
+
+    fn next(&mut self) -> Option<TokKind<'a>> {
+        let t = self.peek();
+        if t.is_some() {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn eat(&mut self, p: &str) -> bool {
+        if self.peek_is(p) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, p: &str, what: &'static str) -> Result<(), AsmError> {
+        if self.eat(p) {
+            Ok(())
+        } else {
+            Err(self.err(self.col(), AsmErrorKind::Syntax(what)))
+        }
+    }
+
+    fn expect_ident(&mut self, what: &'static str) -> Result<&'a str, AsmError> {
+        match self.peek() {
+            Some(TokKind::Ident(s)) => {
+                self.pos += 1;
+                Ok(s)
+            },
+            _ => Err(self.err(self.col(), AsmErrorKind::Syntax(what))),
+        }
+    }
+
+    fn end(&self) -> Result<(), AsmError> {
+        if self.pos == self.toks.len() {
+            Ok(())
+        } else {
+            Err(self.err(self.col(), AsmErrorKind::Syntax("expected end of line")))
+        }
+    }
+
+    /// Try to parse a register, returning it's number and if it is 64-bit (`rN`) or 32-bit (`wN`)
+    fn try_reg(&mut self) -> Option<(u8, bool)> {
+        let s = match self.peek() {
+            Some(TokKind::Ident(s)) => s,
+            _ => return None,
+        };
+
+        let wide = match s.as_bytes()[0] {
+            b'r' => true,
+            b'w' => false,
+            _ => return None,
+        };
+        let n = match &s[1..] {
+            "0" => 0, "1" => 1, "2" => 2, "3" => 3, "4" => 4, "5" => 5,
+            "6" => 6, "7" => 7, "8" => 8, "9" => 9, "10" => 10,
+            _ => return None,
+        };
+        self.pos += 1;
+        Some((n, wide))
+    }
+
+    fn reg(&mut self) -> Result<(u8, bool), AsmError> {
+        let col = self.col();
+        self.try_reg().ok_or_else(|| self.err(col, AsmErrorKind::Syntax("expected register")))
+    }
+
+    /// A 64-bit register, as used in the mnemonic syntax & memory operands
+    fn reg64(&mut self) -> Result<u8, AsmError> {
+        let col = self.col();
+        match self.reg()? {
+            (r, true) => Ok(r),
+            (_, false) => Err(self.err(col, AsmErrorKind::Syntax("expected 64-bit register"))),
+        }
+    }
+
+    /// A signed number, with optional leading `+` or `-`
+    fn signed(&mut self) -> Result<i128, AsmError> {
+        let neg = if self.eat("-") {
+            true
+        } else {
+            self.eat("+");
+            false
+        };
+
+        match self.peek() {
+            Some(TokKind::Num(v)) => {
+                self.pos += 1;
+                Ok(if neg { -(v as i128) } else { v as i128 })
+            },
+            _ => Err(self.err(self.col(), AsmErrorKind::Syntax("expected number"))),
+        }
+    }
+
+    /// A 32-bit immediate, which may be given as either a signed or unsigned value
+    fn imm32(&mut self) -> Result<u32, AsmError> {
+        let col = self.col();
+        let v = self.signed()?;
+        if v < i128::from(i32::MIN) || v > i128::from(u32::MAX) {
+            return Err(self.err(col, AsmErrorKind::OutOfRange));
+        }
+        Ok(v as u32)
+    }
+
+    fn imm64(&mut self) -> Result<u64, AsmError> {
+        let col = self.col();
+        let v = self.signed()?;
+        if v < i128::from(i64::MIN) || v > i128::from(u64::MAX) {
+            return Err(self.err(col, AsmErrorKind::OutOfRange));
+        }
+        Ok(v as u64)
+    }
+
+    fn off16(&mut self) -> Result<i16, AsmError> {
+        let col = self.col();
+        let v = self.signed()?;
+        if v < i128::from(i16::MIN) || v > i128::from(i16::MAX) {
+            return Err(self.err(col, AsmErrorKind::OutOfRange));
+        }
+        Ok(v as i16)
+    }
+
+    /// A register (of the given width) or an immediate
+    fn operand(&mut self, wide: bool) -> Result<Operand, AsmError> {
+        let col = self.col();
+        match self.try_reg() {
+            Some((r, w)) if w == wide => Ok(Operand::Reg(r)),
+            Some(_) => Err(self.err(col, AsmErrorKind::Syntax("mismatched register width"))),
+            None => Ok(Operand::Imm(self.imm32()?)),
+        }
+    }
+
+    /// A label or relative offset. `long` offsets (for `gotol`) are 32 bits rather than 16.
+    fn target(&mut self, long: bool) -> Result<Target<'a>, AsmError> {
+        let col = self.col();
+        match self.peek() {
+            Some(TokKind::Ident(s)) => {
+                self.pos += 1;
+                Ok(Target::Label(s, col))
+            },
+            _ if long => Ok(Target::Rel(self.imm32()? as i32)),
+            _ => Ok(Target::Rel(i32::from(self.off16()?))),
+        }
+    }
+
+    /// `(rN + off)` or `[rN + off]`, returning the register and offset
+    fn mem_operand(&mut self, open: &str, close: &str) -> Result<(u8, i16), AsmError> {
+        self.expect(open, "expected memory operand")?;
+        let r = self.reg64()?;
+        let off = if self.peek_is(close) { 0 } else { self.off16()? };
+        self.expect(close, "expected closing bracket")?;
+        Ok((r, off))
+    }
+
+    /// `*(uN *)`
+    fn size_cast(&mut self) -> Result<Size, AsmError> {
+        self.expect("*", "expected `*`")?;
+        self.expect("(", "expected `(`")?;
+        let col = self.col();
+        let size = size_type(self.expect_ident("expected size")?)
+            .ok_or_else(|| self.err(col, AsmErrorKind::Syntax("expected u8, u16, u32 or u64")))?;
+        self.expect("*", "expected `*`")?;
+        self.expect(")", "expected `)`")?;
+        Ok(size)
+    }
+
+    /// Parse a single statement in either syntax, appending the resulting instruction(s) to
+    /// `out`.
+    fn statement(&mut self, out: &mut Vec<Pending<'a>>) -> Result<(), AsmError> {
+        let line = self.line;
+        let mut push = |inst: Inst, target: Option<Target<'a>>| {
+            out.push(Pending { line, inst, target });
+        };
+
+        let col = self.col();
+
+        // `*(uN *)(rD + off) = ...`
+        if self.peek_is("*") {
+            let size = self.size_cast()?;
+            let (dst, off) = self.mem_operand("(", ")")?;
+            self.expect("=", "expected `=`")?;
+            let i = match self.try_reg() {
+                Some((src, true)) => inst(mem(Class::Stx, Mode::Mem, size), dst, src, off, 0),
+                Some(_) => return Err(self.err(col, AsmErrorKind::Syntax("expected 64-bit register"))),
+                None => inst(mem(Class::St, Mode::Mem, size), dst, 0, off, self.imm32()?),
+            };
+            self.end()?;
+            push(i, None);
+            return Ok(());
+        }
+
+        // register destination, C-like syntax
+        if let Some((dst, wide)) = self.try_reg() {
+            let op_col = self.col();
+            let op = match self.next() {
+                Some(TokKind::Punct(p)) => p,
+                _ => return Err(self.err(op_col, AsmErrorKind::Syntax("expected operator"))),
+            };
+
+            if let Some(code) = alu_assign_code(op) {
+                let src = self.operand(wide)?;
+                self.end()?;
+                push(alu(wide, code, dst, src), None);
+                return Ok(());
+            }
+
+            if op != "=" {
+                return Err(self.err(op_col, AsmErrorKind::Syntax("expected assignment")));
+            }
+
+            // rD = -rD
+            if self.eat("-") {
+                if let Some((src, w)) = self.try_reg() {
+                    if src != dst || w != wide {
+                        return Err(self.err(op_col, AsmErrorKind::Syntax("negation must be in place")));
+                    }
+                    self.end()?;
+                    push(alu(wide, OpAlu::Neg, dst, Operand::Imm(0)), None);
+                    return Ok(());
+                }
+                self.pos -= 1;
+            }
+
+            // rD = *(uN *)...
+            if self.peek_is("*") {
+                if !wide {
+                    return Err(self.err(col, AsmErrorKind::Syntax("expected 64-bit register")));
+                }
+                let size = self.size_cast()?;
+                if self.peek() == Some(TokKind::Ident("skb")) {
+                    self.pos += 1;
+                    self.expect("[", "expected `[`")?;
+                    let i = match self.try_reg() {
+                        Some((src, true)) => {
+                            let k = if self.peek_is("]") { 0 } else { self.imm32()? };
+                            inst(mem(Class::Ld, Mode::Ind, size), dst, src, 0, k)
+                        },
+                        Some(_) => return Err(self.err(col, AsmErrorKind::Syntax("expected 64-bit register"))),
+                        None => inst(mem(Class::Ld, Mode::Abs, size), dst, 0, 0, self.imm32()?),
+                    };
+                    self.expect("]", "expected `]`")?;
+                    self.end()?;
+                    push(i, None);
+                    return Ok(());
+                }
+
+                let (src, off) = self.mem_operand("(", ")")?;
+                self.end()?;
+                push(inst(mem(Class::Ldx, Mode::Mem, size), dst, src, off, 0), None);
+                return Ok(());
+            }
+
+            // rD = map[idx] ll, rD = map_value[idx] + off ll
+            if let Some(TokKind::Ident(s)) = self.peek() {
+                let src = match s {
+                    "map" => 1,
+                    "map_value" => 2,
+                    _ => 0,
+                };
+                if src != 0 {
+                    if !wide {
+                        return Err(self.err(col, AsmErrorKind::Syntax("expected 64-bit register")));
+                    }
+                    self.pos += 1;
+                    self.expect("[", "expected `[`")?;
+                    let idx = self.imm32()?;
+                    self.expect("]", "expected `]`")?;
+                    let off = if src == 2 {
+                        self.expect("+", "expected `+`")?;
+                        self.imm32()?
+                    } else {
+                        0
+                    };
+                    if self.expect_ident("expected `ll`")? != "ll" {
+                        return Err(self.err(self.toks[self.pos - 1].col, AsmErrorKind::Syntax("expected `ll`")));
+                    }
+                    self.end()?;
+                    push(inst(mem(Class::Ld, Mode::Imm, Size::DW), dst, src, 0, idx), None);
+                    push(inst(0, 0, 0, 0, off), None);
+                    return Ok(());
+                }
+            }
+
+            // rD = be16 rD
+            if let Some(TokKind::Ident(s)) = self.peek() {
+                let (src, bits) = match s {
+                    "le16" => (Src::K, 16), "le32" => (Src::K, 32), "le64" => (Src::K, 64),
+                    "be16" => (Src::X, 16), "be32" => (Src::X, 32), "be64" => (Src::X, 64),
+                    _ => (Src::K, 0),
+                };
+                if bits != 0 {
+                    self.pos += 1;
+                    let rcol = self.col();
+                    if self.reg()? != (dst, wide) {
+                        return Err(self.err(rcol, AsmErrorKind::Syntax("byte swap must be in place")));
+                    }
+                    self.end()?;
+                    let op = Class::Alu as u8 | OpAlu::End as u8 | src as u8;
+                    push(inst(op, dst, 0, 0, bits), None);
+                    return Ok(());
+                }
+            }
+
+            // rD = imm ll
+            let save = self.pos;
+            if self.try_reg().is_none() {
+                let v = self.imm64()?;
+                if self.peek() == Some(TokKind::Ident("ll")) {
+                    self.pos += 1;
+                    self.end()?;
+                    if !wide {
+                        return Err(self.err(col, AsmErrorKind::Syntax("expected 64-bit register")));
+                    }
+                    push(inst(mem(Class::Ld, Mode::Imm, Size::DW), dst, 0, 0, v as u32), None);
+                    push(inst(0, 0, 0, 0, (v >> 32) as u32), None);
+                    return Ok(());
+                }
+            }
+            self.pos = save;
+
+            let src = self.operand(wide)?;
+            self.end()?;
+            push(alu(wide, OpAlu::Mov, dst, src), None);
+            return Ok(());
+        }
+
+        let word = self.expect_ident("expected instruction")?;
+        match word {
+            "exit" => {
+                self.end()?;
+                push(inst(Class::Jmp as u8 | OpJmp::Exit as u8, 0, 0, 0, 0), None);
+                return Ok(());
+            },
+            "call" => {
+                // `call +N` or `call label` calls a BPF function, `call N` a helper
+                let (src, k, t) = match self.peek() {
+                    Some(TokKind::Num(_)) => (0, self.imm32()?, None),
+                    _ => (1, 0, Some(self.target(true)?)),
+                };
+                self.end()?;
+                push(inst(Class::Jmp as u8 | OpJmp::Call as u8, 0, src, 0, k), t);
+                return Ok(());
+            },
+            "goto" | "ja" => {
+                let t = self.target(false)?;
+                self.end()?;
+                push(inst(Class::Jmp as u8 | OpJmp::Ja as u8, 0, 0, 0, 0), Some(t));
+                return Ok(());
+            },
+            "gotol" => {
+                let t = self.target(true)?;
+                self.end()?;
+                push(inst(Class::Jmp32 as u8 | OpJmp::Ja as u8, 0, 0, 0, 0), Some(t));
+                return Ok(());
+            },
+            "if" => {
+                let (dst, wide) = self.reg()?;
+                let op_col = self.col();
+                let code = match self.next() {
+                    Some(TokKind::Punct(p)) => jmp_cmp_code(p),
+                    _ => None,
+                }.ok_or_else(|| self.err(op_col, AsmErrorKind::Syntax("expected comparison")))?;
+                let src = self.operand(wide)?;
+                if self.expect_ident("expected `goto`")? != "goto" {
+                    return Err(self.err(self.toks[self.pos - 1].col, AsmErrorKind::Syntax("expected `goto`")));
+                }
+                let t = self.target(false)?;
+                self.end()?;
+                push(jmp(wide, code, dst, src), Some(t));
+                return Ok(());
+            },
+            "lock" => {
+                let size = self.size_cast()?;
+                let (dst, off) = self.mem_operand("(", ")")?;
+                self.expect("+=", "expected `+=`")?;
+                let src = self.reg64()?;
+                self.end()?;
+                push(inst(mem(Class::Stx, Mode::Xadd, size), dst, src, off, 0), None);
+                return Ok(());
+            },
+            _ => {},
+        }
+
+        // mnemonic syntax
+        let (base, wide) = match word.strip_suffix("32") {
+            Some(base) => (base, false),
+            None => (word, true),
+        };
+
+        if let Some(code) = alu_code(base) {
+            let dst = self.reg64()?;
+            self.expect(",", "expected `,`")?;
+            let src = self.operand(true)?;
+            self.end()?;
+            push(alu(wide, code, dst, src), None);
+            return Ok(());
+        }
+
+        if base == "neg" {
+            let dst = self.reg64()?;
+            self.end()?;
+            push(alu(wide, OpAlu::Neg, dst, Operand::Imm(0)), None);
+            return Ok(());
+        }
+
+        if let Some(code) = jmp_code(base) {
+            let dst = self.reg64()?;
+            self.expect(",", "expected `,`")?;
+            let src = self.operand(true)?;
+            self.expect(",", "expected `,`")?;
+            let t = self.target(false)?;
+            self.end()?;
+            push(jmp(wide, code, dst, src), Some(t));
+            return Ok(());
+        }
+
+        let (src, bits) = match word {
+            "le16" => (Src::K, 16), "le32" => (Src::K, 32), "le64" => (Src::K, 64),
+            "be16" => (Src::X, 16), "be32" => (Src::X, 32), "be64" => (Src::X, 64),
+            _ => (Src::K, 0),
+        };
+        if bits != 0 {
+            let dst = self.reg64()?;
+            self.end()?;
+            push(inst(Class::Alu as u8 | OpAlu::End as u8 | src as u8, dst, 0, 0, bits), None);
+            return Ok(());
+        }
+
+        if word == "lddw" {
+            let dst = self.reg64()?;
+            self.expect(",", "expected `,`")?;
+            let v = self.imm64()?;
+            self.end()?;
+            push(inst(mem(Class::Ld, Mode::Imm, Size::DW), dst, 0, 0, v as u32), None);
+            push(inst(0, 0, 0, 0, (v >> 32) as u32), None);
+            return Ok(());
+        }
+
+        if let Some(size) = word.strip_prefix("ldabs").and_then(size_suffix) {
+            let k = self.imm32()?;
+            self.end()?;
+            push(inst(mem(Class::Ld, Mode::Abs, size), 0, 0, 0, k), None);
+            return Ok(());
+        }
+
+        if let Some(size) = word.strip_prefix("ldind").and_then(size_suffix) {
+            let src = self.reg64()?;
+            self.expect(",", "expected `,`")?;
+            let k = self.imm32()?;
+            self.end()?;
+            push(inst(mem(Class::Ld, Mode::Ind, size), 0, src, 0, k), None);
+            return Ok(());
+        }
+
+        if let Some(size) = word.strip_prefix("ldx").and_then(size_suffix) {
+            let dst = self.reg64()?;
+            self.expect(",", "expected `,`")?;
+            let (src, off) = self.mem_operand("[", "]")?;
+            self.end()?;
+            push(inst(mem(Class::Ldx, Mode::Mem, size), dst, src, off, 0), None);
+            return Ok(());
+        }
+
+        if let Some(size) = word.strip_prefix("stx").and_then(size_suffix) {
+            let (dst, off) = self.mem_operand("[", "]")?;
+            self.expect(",", "expected `,`")?;
+            let src = self.reg64()?;
+            self.end()?;
+            push(inst(mem(Class::Stx, Mode::Mem, size), dst, src, off, 0), None);
+            return Ok(());
+        }
+
+        if let Some(size) = word.strip_prefix("xadd").and_then(size_suffix) {
+            let (dst, off) = self.mem_operand("[", "]")?;
+            self.expect(",", "expected `,`")?;
+            let src = self.reg64()?;
+            self.end()?;
+            push(inst(mem(Class::Stx, Mode::Xadd, size), dst, src, off, 0), None);
+            return Ok(());
+        }
+
+        if let Some(size) = word.strip_prefix("st").and_then(size_suffix) {
+            let (dst, off) = self.mem_operand("[", "]")?;
+            self.expect(",", "expected `,`")?;
+            let k = self.imm32()?;
+            self.end()?;
+            push(inst(mem(Class::St, Mode::Mem, size), dst, 0, off, k), None);
+            return Ok(());
+        }
+
+        // `ldw rD, imm`: Class::Ld, Mode::Imm with a 32-bit or smaller size
+        match word.strip_prefix("ld").and_then(size_suffix) {
+            Some(Size::DW) | None => {},
+            Some(size) => {
+                let dst = self.reg64()?;
+                self.expect(",", "expected `,`")?;
+                let k = self.imm32()?;
+                self.end()?;
+                push(inst(mem(Class::Ld, Mode::Imm, size), dst, 0, 0, k), None);
+                return Ok(());
+            },
+        }
+
+        Err(self.err(col, AsmErrorKind::UnknownMnemonic))
+    }
+}
+
+/// Assemble a program written in either of the syntaxes described in the module documentation
+pub fn assemble(src: &str) -> Result<Vec<u64>, AsmError> {
+    let mut pending: Vec<Pending> = Vec::new();
+    let mut labels: BTreeMap<&str, usize> = BTreeMap::new();
+
+    for (idx, raw_line) in src.lines().enumerate() {
+        let line = idx + 1;
+        let text = strip_comment(raw_line);
+        let toks = lex(text, line)?;
+        let mut p = Parser {
+            toks: &toks,
+            pos: 0,
+            line,
+            eol: text.trim_end().len() + 1,
+        };
+
+        // any number of leading `label:`
+        while let (Some(TokKind::Ident(name)), Some(TokKind::Punct(":"))) =
+                (p.peek(), toks.get(p.pos + 1).map(|t| t.kind)) {
+            let col = p.col();
+            if labels.insert(name, pending.len()).is_some() {
+                return Err(p.err(col, AsmErrorKind::DuplicateLabel));
+            }
+            p.pos += 2;
+        }
+
+        if p.pos == toks.len() {
+            continue;
+        }
+
+        p.statement(&mut pending)?;
+    }
+
+    let mut out = Vec::with_capacity(pending.len());
+    for (pc, mut p) in pending.into_iter().enumerate() {
+        // `gotol` and calls keep their offset in `imm`
+        let long = p.inst.op_class() == Some(Class::Jmp32) && p.inst.op_jmp() == Some(OpJmp::Ja)
+            || p.inst.call_off().is_some();
+        let off = match p.target {
+            Some(Target::Rel(off)) => i64::from(off),
+            Some(Target::Label(name, col)) => {
+                let dest = *labels.get(name).ok_or(AsmError {
+                    line: p.line,
+                    col,
+                    kind: AsmErrorKind::UndefinedLabel,
+                })?;
+                let off = dest as i64 - (pc as i64 + 1);
+                let in_range = if long {
+                    off >= i64::from(i32::MIN) && off <= i64::from(i32::MAX)
+                } else {
+                    off >= i64::from(i16::MIN) && off <= i64::from(i16::MAX)
+                };
+                if !in_range {
+                    return Err(AsmError {
+                        line: p.line,
+                        col,
+                        kind: AsmErrorKind::JumpOutOfRange,
+                    });
+                }
+                off
+            },
+            None => 0,
+        };
+        if long {
+            p.inst.imm = off as u32;
+        } else if p.target.is_some() {
+            p.inst.off = off as u16;
+        }
+        out.push(p.inst.to_u64());
+    }
+
+    Ok(out)
+}
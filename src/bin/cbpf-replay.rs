@@ -0,0 +1,129 @@
+//! Run a program over every packet in a pcap or pcapng capture
+//!
+//! The program is read from a text file containing one instruction per line, written as a
+//! hexadecimal `u64` (`0x15_00_00_01__00_00_08_00`), in the same form used in the tests.
+//! Everything following `//` or `#` on a line is ignored.
+
+extern crate cbpf;
+
+use cbpf::pcap::{Capture, Writer};
+use cbpf::profile::Profile;
+use cbpf::replay::Replay;
+use cbpf::verifier::Env;
+use std::{env, fs, io, process};
+use std::io::Write;
+
+fn usage() -> ! {
+    eprintln!("usage: cbpf-replay [-w OUTPUT] PROGRAM CAPTURE");
+    process::exit(2);
+}
+
+fn parse_program(text: &str) -> Result<Vec<u64>, String> {
+    let mut prgm = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split("//").next().unwrap();
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let digits: String = line.trim_start_matches("0x").chars().filter(|&c| c != '_').collect();
+        let v = u64::from_str_radix(&digits, 16)
+            .map_err(|e| format!("line {}: {:?}: {}", n + 1, line, e))?;
+        prgm.push(v);
+    }
+
+    Ok(prgm)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut output = None;
+    let mut positional = Vec::new();
+    while let Some(a) = args.next() {
+        if a == "-w" {
+            output = Some(args.next().unwrap_or_else(|| usage()));
+        } else if a.starts_with('-') {
+            usage();
+        } else {
+            positional.push(a);
+        }
+    }
+
+    if positional.len() != 2 {
+        usage();
+    }
+
+    let text = fs::read_to_string(&positional[0]).unwrap_or_else(|e| {
+        eprintln!("{}: {}", positional[0], e);
+        process::exit(1);
+    });
+    let insts = parse_program(&text).unwrap_or_else(|e| {
+        eprintln!("{}: {}", positional[0], e);
+        process::exit(1);
+    });
+    let prgm = Env::default().profile(Profile::socket_filter()).verify(&insts).unwrap_or_else(|e| {
+        eprintln!("{}: {}", positional[0], e);
+        process::exit(1);
+    });
+
+    let data = fs::read(&positional[1]).unwrap_or_else(|e| {
+        eprintln!("{}: {}", positional[1], e);
+        process::exit(1);
+    });
+    let cap = Capture::new(&data).unwrap_or_else(|e| {
+        eprintln!("{}: {:?}", positional[1], e);
+        process::exit(1);
+    });
+
+    // created before reading any packets, so a capture with none still gives an output
+    let mut writer = output.as_ref().map(|path| {
+        let iface = cap.interface().unwrap_or_else(|e| {
+            eprintln!("{}: {:?}", positional[1], e);
+            process::exit(1);
+        });
+        // pcapng uses 0 for no limit, which pcap has no way to say
+        let snap_len = if iface.snap_len == 0 { 262_144 } else { iface.snap_len };
+        let f = fs::File::create(path).and_then(|f| {
+            Writer::new(io::BufWriter::new(f), iface.link_type, snap_len)
+        });
+        f.unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        })
+    });
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut replay = Replay::new(prgm, cap.packets());
+    for (idx, o) in (&mut replay).enumerate() {
+        let o = o.unwrap_or_else(|e| {
+            eprintln!("{}: packet {}: {:?}", positional[1], idx, e);
+            process::exit(1);
+        });
+
+        let r = match o.ret {
+            Err(_) => writeln!(out, "{}\terror", idx),
+            Ok(_) if o.is_accept() => writeln!(out, "{}\taccept\t{}", idx, o.len()),
+            Ok(_) => writeln!(out, "{}\treject", idx),
+        };
+        r.unwrap();
+
+        if let Some(w) = writer.as_mut().filter(|_| o.is_accept()) {
+            w.write_packet(&o.kept()).unwrap_or_else(|e| {
+                eprintln!("{}: {}", output.as_ref().unwrap(), e);
+                process::exit(1);
+            });
+        }
+    }
+
+    if let Some(w) = writer {
+        w.into_inner().flush().unwrap_or_else(|e| {
+            eprintln!("{}: {}", output.unwrap(), e);
+            process::exit(1);
+        });
+    }
+
+    let s = replay.summary();
+    writeln!(out, "packets: {}, accepted: {}, rejected: {}, errors: {}",
+             s.packets, s.accepted, s.rejected, s.errors).unwrap();
+}
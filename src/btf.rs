@@ -0,0 +1,485 @@
+//! BTF, the type information clang and llc emit in the `.BTF` section of objects built with `-g`
+//!
+//! Types are numbered from 1, in the order they appear. 0 is `void`, and has no `Type`. The
+//! `.BTF.ext` section adds, for each section of code, where functions start (`FuncInfo`) and the
+//! source line each instruction came from (`LineInfo`).
+//!
+//! References:
+//!  - https://www.kernel.org/doc/html/latest/bpf/btf.html
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+const BTF_MAGIC: u16 = 0xeb9f;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum BtfError {
+    /// The data does not start with the BTF magic number
+    BadMagic,
+    /// The header, a type, or a string extends past the end of its section
+    Truncated,
+    /// The header or a type contains an invalid value
+    Malformed(&'static str),
+    /// Type `id` is of a kind this parser does not know
+    UnknownKind {
+        id: u32,
+        kind: u32,
+    },
+}
+
+impl fmt::Display for BtfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BtfError::BadMagic => write!(f, "not BTF: bad magic number"),
+            BtfError::Truncated => write!(f, "truncated BTF"),
+            BtfError::Malformed(s) => write!(f, "malformed BTF: {}", s),
+            BtfError::UnknownKind { id, kind } => write!(f, "BTF type {} has unknown kind {}", id, kind),
+        }
+    }
+}
+
+/// A member of a struct or union
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Member {
+    pub name: String,
+    pub ty: u32,
+    /// Offset from the start of the struct, in bits
+    pub bit_offset: u32,
+    /// Size of a bitfield in bits, or 0 if the member is not a bitfield
+    pub bitfield_size: u32,
+}
+
+/// A parameter of a function prototype. A last parameter with type 0 marks a variadic function.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Param {
+    pub name: String,
+    pub ty: u32,
+}
+
+/// A variable placed in a data section
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct VarSecInfo {
+    /// The `Kind::Var` of the variable
+    pub ty: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// What a type is, with the ids of the types it refers to
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Kind {
+    /// An integer of `size` bytes. `encoding` says if it is signed (1), a char (2), or a bool (4).
+    Int {
+        size: u32,
+        encoding: u8,
+        bit_offset: u8,
+        bits: u8,
+    },
+    Ptr {
+        ty: u32,
+    },
+    Array {
+        elem: u32,
+        index: u32,
+        nelems: u32,
+    },
+    Struct {
+        size: u32,
+        members: Vec<Member>,
+    },
+    Union {
+        size: u32,
+        members: Vec<Member>,
+    },
+    /// An enum of `size` bytes, with its values' names. Includes 64-bit enums.
+    Enum {
+        size: u32,
+        signed: bool,
+        values: Vec<(String, i64)>,
+    },
+    /// A forward declaration of a struct, or of a union if `union`
+    Fwd {
+        union: bool,
+    },
+    Typedef {
+        ty: u32,
+    },
+    Volatile {
+        ty: u32,
+    },
+    Const {
+        ty: u32,
+    },
+    Restrict {
+        ty: u32,
+    },
+    /// A function, of the `Kind::FuncProto` `proto`. `linkage` is static (0), global (1), or
+    /// extern (2).
+    Func {
+        proto: u32,
+        linkage: u32,
+    },
+    /// A function type, returning `ret`
+    FuncProto {
+        ret: u32,
+        params: Vec<Param>,
+    },
+    /// A variable. `linkage` is static (0), global (1), or extern (2).
+    Var {
+        ty: u32,
+        linkage: u32,
+    },
+    /// A section of the object holding variables, such as `.data` or `.maps`
+    DataSec {
+        size: u32,
+        vars: Vec<VarSecInfo>,
+    },
+    Float {
+        size: u32,
+    },
+    /// An attribute of `ty`, or of its member or parameter `component` if it is not -1
+    DeclTag {
+        ty: u32,
+        component: i32,
+    },
+    /// An attribute of a pointer's target type
+    TypeTag {
+        ty: u32,
+    },
+}
+
+/// A named (or anonymous, if `name` is empty) type
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Type {
+    pub name: String,
+    pub kind: Kind,
+}
+
+#[derive(Debug,Clone,Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offs: usize, len: usize) -> Result<&'a [u8], BtfError> {
+        let end = offs.checked_add(len).ok_or(BtfError::Truncated)?;
+        self.data.get(offs..end).ok_or(BtfError::Truncated)
+    }
+
+    fn u16(&self, offs: usize) -> Result<u16, BtfError> {
+        let b = self.bytes(offs, 2)?;
+        let b = [b[0], b[1]];
+        Ok(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32(&self, offs: usize) -> Result<u32, BtfError> {
+        let b = self.bytes(offs, 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    /// The nul terminated string at `offs`
+    fn str(&self, offs: u32) -> Result<String, BtfError> {
+        let rest = self.data.get(offs as usize..).ok_or(BtfError::Truncated)?;
+        let len = rest.iter().position(|&b| b == 0).ok_or(BtfError::Truncated)?;
+        core::str::from_utf8(&rest[..len])
+            .map(String::from)
+            .map_err(|_| BtfError::Malformed("string is not utf-8"))
+    }
+}
+
+/// The types of a `.BTF` section
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Btf {
+    types: Vec<Type>,
+    /// The string section, which `.BTF.ext` refers to as well
+    strs: Vec<u8>,
+}
+
+impl Btf {
+    /// Parse a `.BTF` section, in either byte order
+    pub fn parse(data: &[u8]) -> Result<Self, BtfError> {
+        let (r, hdr_len) = header(data)?;
+        let section = |off: usize| -> Result<Reader, BtfError> {
+            let start = hdr_len.checked_add(r.u32(off)? as usize).ok_or(BtfError::Truncated)?;
+            Ok(Reader { data: r.bytes(start, r.u32(off + 4)? as usize)?, ..r })
+        };
+        let t = section(8)?;
+        let strs = section(16)?;
+
+        let mut types = Vec::new();
+        let mut offs = 0;
+        while offs < t.data.len() {
+            let id = types.len() as u32 + 1;
+            let name = strs.str(t.u32(offs)?)?;
+            let info = t.u32(offs + 4)?;
+            let size_or_type = t.u32(offs + 8)?;
+            offs += 12;
+
+            let vlen = (info & 0xffff) as usize;
+            let kind_flag = info & (1 << 31) != 0;
+            // the `vlen` entries of `size` bytes following the type
+            let entries = |offs: &mut usize, size: usize| -> Result<Vec<usize>, BtfError> {
+                let start = *offs;
+                *offs += vlen * size;
+                t.bytes(start, vlen * size)?;
+                Ok((0..vlen).map(|i| start + i * size).collect())
+            };
+
+            let kind = match (info >> 24) & 0x1f {
+                1 => {
+                    let int = t.u32(offs)?;
+                    offs += 4;
+                    Kind::Int {
+                        size: size_or_type,
+                        encoding: (int >> 24) as u8 & 0xf,
+                        bit_offset: (int >> 16) as u8,
+                        bits: int as u8,
+                    }
+                },
+                2 => Kind::Ptr { ty: size_or_type },
+                3 => {
+                    let a = Kind::Array { elem: t.u32(offs)?, index: t.u32(offs + 4)?, nelems: t.u32(offs + 8)? };
+                    offs += 12;
+                    a
+                },
+                k @ 4 | k @ 5 => {
+                    let members = entries(&mut offs, 12)?.into_iter().map(|m| {
+                        let off = t.u32(m + 8)?;
+                        Ok(Member {
+                            name: strs.str(t.u32(m)?)?,
+                            ty: t.u32(m + 4)?,
+                            bit_offset: if kind_flag { off & 0xff_ffff } else { off },
+                            bitfield_size: if kind_flag { off >> 24 } else { 0 },
+                        })
+                    }).collect::<Result<_, BtfError>>()?;
+                    if k == 4 {
+                        Kind::Struct { size: size_or_type, members }
+                    } else {
+                        Kind::Union { size: size_or_type, members }
+                    }
+                },
+                6 => Kind::Enum {
+                    size: size_or_type,
+                    signed: kind_flag,
+                    values: entries(&mut offs, 8)?.into_iter().map(|v| {
+                        let val = t.u32(v + 4)?;
+                        let val = if kind_flag { i64::from(val as i32) } else { i64::from(val) };
+                        Ok((strs.str(t.u32(v)?)?, val))
+                    }).collect::<Result<_, BtfError>>()?,
+                },
+                7 => Kind::Fwd { union: kind_flag },
+                8 => Kind::Typedef { ty: size_or_type },
+                9 => Kind::Volatile { ty: size_or_type },
+                10 => Kind::Const { ty: size_or_type },
+                11 => Kind::Restrict { ty: size_or_type },
+                12 => Kind::Func { proto: size_or_type, linkage: vlen as u32 },
+                13 => Kind::FuncProto {
+                    ret: size_or_type,
+                    params: entries(&mut offs, 8)?.into_iter().map(|p| {
+                        Ok(Param { name: strs.str(t.u32(p)?)?, ty: t.u32(p + 4)? })
+                    }).collect::<Result<_, BtfError>>()?,
+                },
+                14 => {
+                    let linkage = t.u32(offs)?;
+                    offs += 4;
+                    Kind::Var { ty: size_or_type, linkage }
+                },
+                15 => Kind::DataSec {
+                    size: size_or_type,
+                    vars: entries(&mut offs, 12)?.into_iter().map(|v| {
+                        Ok(VarSecInfo { ty: t.u32(v)?, offset: t.u32(v + 4)?, size: t.u32(v + 8)? })
+                    }).collect::<Result<_, BtfError>>()?,
+                },
+                16 => Kind::Float { size: size_or_type },
+                17 => {
+                    let component = t.u32(offs)? as i32;
+                    offs += 4;
+                    Kind::DeclTag { ty: size_or_type, component }
+                },
+                18 => Kind::TypeTag { ty: size_or_type },
+                19 => Kind::Enum {
+                    size: size_or_type,
+                    signed: kind_flag,
+                    values: entries(&mut offs, 12)?.into_iter().map(|v| {
+                        let val = u64::from(t.u32(v + 4)?) | u64::from(t.u32(v + 8)?) << 32;
+                        Ok((strs.str(t.u32(v)?)?, val as i64))
+                    }).collect::<Result<_, BtfError>>()?,
+                },
+                kind => return Err(BtfError::UnknownKind { id, kind }),
+            };
+            types.push(Type { name, kind });
+        }
+
+        Ok(Btf { types, strs: strs.data.to_vec() })
+    }
+
+    /// Every type. Type `id` is at `id - 1`.
+    pub fn types(&self) -> &[Type] {
+        &self.types
+    }
+
+    /// Type `id`, or `None` for `void` and ids with no type
+    pub fn get(&self, id: u32) -> Option<&Type> {
+        self.types.get((id as usize).wrapping_sub(1))
+    }
+
+    /// The id of the first type named `name`
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.types.iter().position(|t| t.name == name).map(|i| i as u32 + 1)
+    }
+
+    /// The type `id` names, skipping typedefs, qualifiers, and type tags
+    pub fn resolve(&self, mut id: u32) -> u32 {
+        // a chain longer than the number of types must loop
+        for _ in 0..=self.types.len() {
+            match self.get(id).map(|t| &t.kind) {
+                Some(&Kind::Typedef { ty }) | Some(&Kind::Volatile { ty }) | Some(&Kind::Const { ty })
+                    | Some(&Kind::Restrict { ty }) | Some(&Kind::TypeTag { ty }) => id = ty,
+                _ => break,
+            }
+        }
+        id
+    }
+
+    /// The size in bytes of a value of type `id`, if it has one
+    pub fn size(&self, id: u32) -> Option<u32> {
+        self.size_within(id, self.types.len())
+    }
+
+    /// `size`, following at most `depth` arrays and variables to their element types
+    fn size_within(&self, id: u32, depth: usize) -> Option<u32> {
+        match self.get(self.resolve(id))?.kind {
+            Kind::Int { size, .. } | Kind::Struct { size, .. } | Kind::Union { size, .. }
+                | Kind::Enum { size, .. } | Kind::DataSec { size, .. } | Kind::Float { size } => Some(size),
+            Kind::Ptr { .. } => Some(8),
+            Kind::Array { elem, nelems, .. } if depth > 0 => self.size_within(elem, depth - 1)?.checked_mul(nelems),
+            Kind::Var { ty, .. } if depth > 0 => self.size_within(ty, depth - 1),
+            _ => None,
+        }
+    }
+}
+
+/// The start of a function
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct FuncInfo {
+    /// Index of the function's first instruction
+    pub insn: usize,
+    /// The function's `Kind::Func`
+    pub ty: u32,
+}
+
+/// The source line an instruction, and those after it up to the next `LineInfo`, came from
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct LineInfo {
+    /// Index of the instruction
+    pub insn: usize,
+    pub file: String,
+    /// The text of the line, if the compiler could read the source
+    pub line: String,
+    pub line_num: u32,
+    pub col: u32,
+}
+
+/// Shown as linux's verifier log shows lines: `text @ file:line`
+impl fmt::Display for LineInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} @ {}:{}", self.line.trim(), self.file, self.line_num)
+    }
+}
+
+/// The `FuncInfo` and `LineInfo` of each section of code, from a `.BTF.ext` section
+#[derive(Debug,Clone,PartialEq,Eq,Default)]
+pub struct BtfExt {
+    funcs: Vec<(String, Vec<FuncInfo>)>,
+    lines: Vec<(String, Vec<LineInfo>)>,
+}
+
+impl BtfExt {
+    /// Parse a `.BTF.ext` section, whose names are in the strings of `btf`. Objects give
+    /// instructions by their offset in bytes, which become indexes.
+    pub fn parse(data: &[u8], btf: &Btf) -> Result<Self, BtfError> {
+        let (r, hdr_len) = header(data)?;
+        let strs = Reader { data: &btf.strs, ..r };
+        let section = |off: usize| -> Result<Reader, BtfError> {
+            let start = hdr_len.checked_add(r.u32(off)? as usize).ok_or(BtfError::Truncated)?;
+            Ok(Reader { data: r.bytes(start, r.u32(off + 4)? as usize)?, ..r })
+        };
+
+        // a record size, then for each section its name, and `num_info` records
+        let records = |t: Reader, min_size: usize| -> Result<Vec<(String, Vec<usize>)>, BtfError> {
+            let mut secs = Vec::new();
+            if t.data.is_empty() {
+                return Ok(secs);
+            }
+            let rec_size = t.u32(0)? as usize;
+            if rec_size < min_size {
+                return Err(BtfError::Malformed(".BTF.ext records are too small"));
+            }
+            let mut offs = 4;
+            while offs < t.data.len() {
+                let name = strs.str(t.u32(offs)?)?;
+                let n = t.u32(offs + 4)? as usize;
+                offs += 8;
+                let len = n.checked_mul(rec_size).ok_or(BtfError::Truncated)?;
+                t.bytes(offs, len)?;
+                secs.push((name, (0..n).map(|i| offs + i * rec_size).collect()));
+                offs += len;
+            }
+            Ok(secs)
+        };
+
+        let t = section(8)?;
+        let funcs = records(t, 8)?.into_iter().map(|(name, recs)| {
+            let info = recs.into_iter().map(|rec| Ok(FuncInfo {
+                insn: t.u32(rec)? as usize / 8,
+                ty: t.u32(rec + 4)?,
+            })).collect::<Result<_, BtfError>>()?;
+            Ok((name, info))
+        }).collect::<Result<_, BtfError>>()?;
+
+        let t = section(16)?;
+        let lines = records(t, 16)?.into_iter().map(|(name, recs)| {
+            let info = recs.into_iter().map(|rec| {
+                let line_col = t.u32(rec + 12)?;
+                Ok(LineInfo {
+                    insn: t.u32(rec)? as usize / 8,
+                    file: strs.str(t.u32(rec + 4)?)?,
+                    line: strs.str(t.u32(rec + 8)?)?,
+                    line_num: line_col >> 10,
+                    col: line_col & 0x3ff,
+                })
+            }).collect::<Result<_, BtfError>>()?;
+            Ok((name, info))
+        }).collect::<Result<_, BtfError>>()?;
+
+        Ok(BtfExt { funcs, lines })
+    }
+
+    /// The functions of the section `section`
+    pub fn func_info(&self, section: &str) -> &[FuncInfo] {
+        self.funcs.iter().find(|s| s.0 == section).map_or(&[], |s| &s.1[..])
+    }
+
+    /// The source lines of the instructions of the section `section`, in order
+    pub fn line_info(&self, section: &str) -> &[LineInfo] {
+        self.lines.iter().find(|s| s.0 == section).map_or(&[], |s| &s.1[..])
+    }
+}
+
+/// Check the magic number and version, giving a reader in the data's byte order and the length of
+/// the header
+fn header<'a>(data: &'a [u8]) -> Result<(Reader<'a>, usize), BtfError> {
+    let le = Reader { data, big_endian: false };
+    let r = match le.u16(0)? {
+        BTF_MAGIC => le,
+        m if m.swap_bytes() == BTF_MAGIC => Reader { data, big_endian: true },
+        _ => return Err(BtfError::BadMagic),
+    };
+    if r.bytes(2, 1)?[0] != 1 {
+        return Err(BtfError::Malformed("unknown version"));
+    }
+    Ok((r, r.u32(4)? as usize))
+}
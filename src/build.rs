@@ -1,37 +1,572 @@
+//! Construct instructions without writing out their encoding
+//!
+//! Each instruction class has a module of functions, one per operation:
+//!
+//! ```
+//! use cbpf::build::{alu64, jmp, ldx, exit, Imm, Reg, Size};
+//!
+//! let prgm = [
+//!     ldx::mem(Size::W, Reg::R0, Reg::R1, 4),
+//!     alu64::add(Reg::R0, Imm(4)),
+//!     jmp::jeq(Reg::R0, Reg::R2, 1),
+//!     alu64::mov(Reg::R0, Imm(0)),
+//!     exit(),
+//! ];
+//! let raw: Vec<u64> = prgm.iter().map(|i| i.to_u64()).collect();
+//! ```
+//!
+//! Registers, sizes, and the other fields are typed so that only valid encodings can be built.
+//! Jump offsets are relative to the following instruction.
+
 use super::*;
-use num_traits::ToPrimitive;
+pub use {Inst, Size};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
+use core::fmt;
+
+/// A register
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[repr(u8)]
+pub enum Reg {
+    /// Return value
+    R0 = 0,
+    /// Context on entry, first argument
+    R1,
+    R2,
+    R3,
+    R4,
+    /// Fifth (and last) argument
+    R5,
+    /// Callee saved
+    R6,
+    R7,
+    R8,
+    R9,
+    /// Frame pointer, read only
+    R10,
+}
+
+/// A 32-bit immediate, sign extended to 64 bits where the operation is 64 bits wide
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Imm(pub i32);
+
+/// The source operand of `Class::Alu`, `Class::Alu64`, and `Class::Jmp`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(Imm),
+}
+
+impl From<Reg> for Operand {
+    fn from(r: Reg) -> Self {
+        Operand::Reg(r)
+    }
+}
 
-/*
-pub struct Ld;
-impl Ld {
-    pub fn imm(imm: u32) -> LdImm {
-        LdImm(imm)
+impl From<Imm> for Operand {
+    fn from(i: Imm) -> Self {
+        Operand::Imm(i)
     }
-    pub fn abs(src_reg: u8, abs: u32) -> LdAbs {
-        LdAbs(src_reg, abs)
+}
+
+/// Sizes allowed for `ld::abs`, `ld::ind`, and `ld::imm`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LdSize {
+    W,
+    H,
+    B,
+}
+
+impl From<LdSize> for Size {
+    fn from(s: LdSize) -> Size {
+        match s {
+            LdSize::W => Size::W,
+            LdSize::H => Size::H,
+            LdSize::B => Size::B,
+        }
     }
 }
-*/
 
-fn ja(off: i16) -> u64
-{
+/// Sizes allowed for `stx::xadd`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum AtomicSize {
+    W,
+    DW,
+}
+
+impl From<AtomicSize> for Size {
+    fn from(s: AtomicSize) -> Size {
+        match s {
+            AtomicSize::W => Size::W,
+            AtomicSize::DW => Size::DW,
+        }
+    }
+}
+
+/// Width of a byte swap (`alu::le`, `alu::be`)
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[repr(u8)]
+pub enum Swap {
+    S16 = 16,
+    S32 = 32,
+    S64 = 64,
+}
+
+fn inst(op: u8, dst: Reg, src: Reg, off: i16, imm: u32) -> Inst {
     Inst {
-        op: Class::Jmp.to_u8().unwrap() | OpJmp::Ja.to_u8().unwrap(),
-        src_dst: 0,
+        op,
+        src_dst: ((src as u8) << 4) | dst as u8,
         off: off as u16,
-        imm: 0,
-    }.to_u64()
+        imm,
+    }
+}
+
+fn with_src(op: u8, dst: Reg, src: Operand, off: i16) -> Inst {
+    match src {
+        Operand::Reg(r) => inst(op | Src::X as u8, dst, r, off, 0),
+        Operand::Imm(Imm(k)) => inst(op | Src::K as u8, dst, Reg::R0, off, k as u32),
+    }
+}
+
+fn mem(class: Class, mode: Mode, size: Size) -> u8 {
+    class as u8 | mode as u8 | size as u8
+}
+
+macro_rules! alu_ops {
+    ($class:expr) => {
+        use super::*;
+
+        fn op(code: OpAlu, dst: Reg, src: Operand) -> Inst {
+            with_src($class as u8 | code as u8, dst, src, 0)
+        }
+
+        /// `dst += src`
+        pub fn add<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Add, dst, src.into()) }
+        /// `dst -= src`
+        pub fn sub<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Sub, dst, src.into()) }
+        /// `dst *= src`
+        pub fn mul<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Mul, dst, src.into()) }
+        /// `dst /= src` (unsigned)
+        pub fn div<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Div, dst, src.into()) }
+        /// `dst |= src`
+        pub fn or<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Or, dst, src.into()) }
+        /// `dst &= src`
+        pub fn and<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::And, dst, src.into()) }
+        /// `dst <<= src`
+        pub fn lsh<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Lsh, dst, src.into()) }
+        /// `dst >>= src` (logical)
+        pub fn rsh<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Rsh, dst, src.into()) }
+        /// `dst %= src` (unsigned)
+        pub fn rem<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Mod, dst, src.into()) }
+        /// `dst ^= src`
+        pub fn xor<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Xor, dst, src.into()) }
+        /// `dst = src`
+        pub fn mov<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Mov, dst, src.into()) }
+        /// `dst s>>= src` (arithmetic)
+        pub fn arsh<S: Into<Operand>>(dst: Reg, src: S) -> Inst { op(OpAlu::Arsh, dst, src.into()) }
+
+        /// `dst = -dst`
+        pub fn neg(dst: Reg) -> Inst {
+            inst($class as u8 | OpAlu::Neg as u8, dst, Reg::R0, 0, 0)
+        }
+    };
 }
 
-/// 
-/// `*(sz *)(dst_reg + dst_off) = imm`
+/// Arithmetic on the lower 32 bits of registers, zero extending the result
+pub mod alu {
+    alu_ops!(Class::Alu);
+
+    /// Convert `dst` from little endian to host order, truncating to `width`
+    pub fn le(dst: Reg, width: Swap) -> Inst {
+        inst(Class::Alu as u8 | OpAlu::End as u8 | Src::K as u8, dst, Reg::R0, 0, width as u32)
+    }
+
+    /// Convert `dst` from big endian to host order, truncating to `width`
+    pub fn be(dst: Reg, width: Swap) -> Inst {
+        inst(Class::Alu as u8 | OpAlu::End as u8 | Src::X as u8, dst, Reg::R0, 0, width as u32)
+    }
+}
+
+/// Arithmetic on 64-bit registers
+pub mod alu64 {
+    alu_ops!(Class::Alu64);
+}
+
+macro_rules! jmp_ops {
+    ($class:expr) => {
+        use super::*;
+
+        fn op(code: OpJmp, dst: Reg, src: Operand, off: i16) -> Inst {
+            with_src($class as u8 | code as u8, dst, src, off)
+        }
+
+        /// `if dst == src goto +off`
+        pub fn jeq<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jeq, dst, src.into(), off) }
+        /// `if dst != src goto +off`
+        pub fn jne<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jne, dst, src.into(), off) }
+        /// `if dst > src goto +off` (unsigned)
+        pub fn jgt<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jgt, dst, src.into(), off) }
+        /// `if dst >= src goto +off` (unsigned)
+        pub fn jge<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jge, dst, src.into(), off) }
+        /// `if dst < src goto +off` (unsigned)
+        pub fn jlt<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jlt, dst, src.into(), off) }
+        /// `if dst <= src goto +off` (unsigned)
+        pub fn jle<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jle, dst, src.into(), off) }
+        /// `if dst & src goto +off`
+        pub fn jset<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jset, dst, src.into(), off) }
+        /// `if dst s> src goto +off`
+        pub fn jsgt<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jsgt, dst, src.into(), off) }
+        /// `if dst s>= src goto +off`
+        pub fn jsge<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jsge, dst, src.into(), off) }
+        /// `if dst s< src goto +off`
+        pub fn jslt<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jslt, dst, src.into(), off) }
+        /// `if dst s<= src goto +off`
+        pub fn jsle<S: Into<Operand>>(dst: Reg, src: S, off: i16) -> Inst { op(OpJmp::Jsle, dst, src.into(), off) }
+    };
+}
+
+/// Jumps, calls, and exit. Offsets are relative to the following instruction.
+pub mod jmp {
+    jmp_ops!(Class::Jmp);
+
+    /// `goto +off`
+    pub fn ja(off: i16) -> Inst {
+        inst(Class::Jmp as u8 | OpJmp::Ja as u8, Reg::R0, Reg::R0, off, 0)
+    }
+
+    /// Call the helper function numbered `helper`
+    pub fn call(helper: u32) -> Inst {
+        inst(Class::Jmp as u8 | OpJmp::Call as u8, Reg::R0, Reg::R0, 0, helper)
+    }
+
+    /// Return `r0`
+    pub fn exit() -> Inst {
+        inst(Class::Jmp as u8 | OpJmp::Exit as u8, Reg::R0, Reg::R0, 0, 0)
+    }
+}
+
+/// Jumps comparing the lower 32 bits of registers, and the unconditional jump with a 32-bit
+/// offset
+pub mod jmp32 {
+    jmp_ops!(Class::Jmp32);
+
+    /// `gotol +off`
+    pub fn ja(off: i32) -> Inst {
+        inst(Class::Jmp32 as u8 | OpJmp::Ja as u8, Reg::R0, Reg::R0, 0, off as u32)
+    }
+}
+
+pub use self::jmp::{call, exit};
+
+/// `Class::Ld`: immediates and loads from the data area
+pub mod ld {
+    use super::*;
+
+    /// `dst = imm`, occupying 2 instruction slots
+    pub fn imm64(dst: Reg, imm: u64) -> [Inst; 2] {
+        [
+            inst(mem(Class::Ld, Mode::Imm, Size::DW), dst, Reg::R0, 0, imm as u32),
+            inst(0, Reg::R0, Reg::R0, 0, (imm >> 32) as u32),
+        ]
+    }
+
+    /// `dst = imm`, truncated to `size`
+    pub fn imm(size: LdSize, dst: Reg, imm: u32) -> Inst {
+        inst(mem(Class::Ld, Mode::Imm, size.into()), dst, Reg::R0, 0, imm)
+    }
+
+    /// `r0 = *(size *)(data + k)`
+    pub fn abs(size: LdSize, k: u32) -> Inst {
+        inst(mem(Class::Ld, Mode::Abs, size.into()), Reg::R0, Reg::R0, 0, k)
+    }
+
+    /// `r0 = *(size *)(data + src + k)`
+    pub fn ind(size: LdSize, src: Reg, k: u32) -> Inst {
+        inst(mem(Class::Ld, Mode::Ind, size.into()), Reg::R0, src, 0, k)
+    }
+}
+
+/// `Class::Ldx`: loads from memory into a register
+pub mod ldx {
+    use super::*;
+
+    /// `dst = *(size *)(src + off)`
+    pub fn mem(size: Size, dst: Reg, src: Reg, off: i16) -> Inst {
+        inst(super::mem(Class::Ldx, Mode::Mem, size), dst, src, off, 0)
+    }
+}
+
+/// `Class::St`: stores of an immediate
+pub mod st {
+    use super::*;
+
+    /// `*(size *)(dst + off) = imm`
+    pub fn mem(size: Size, dst: Reg, off: i16, imm: Imm) -> Inst {
+        inst(super::mem(Class::St, Mode::Mem, size), dst, Reg::R0, off, imm.0 as u32)
+    }
+}
+
+/// `Class::Stx`: stores of a register
+pub mod stx {
+    use super::*;
+
+    /// `*(size *)(dst + off) = src`
+    pub fn mem(size: Size, dst: Reg, off: i16, src: Reg) -> Inst {
+        inst(super::mem(Class::Stx, Mode::Mem, size), dst, src, off, 0)
+    }
+
+    /// `lock *(size *)(dst + off) += src`
+    pub fn xadd(size: AtomicSize, dst: Reg, off: i16, src: Reg) -> Inst {
+        inst(super::mem(Class::Stx, Mode::Xadd, size.into()), dst, src, off, 0)
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// The condition of a jump emitted by `ProgramBuilder::jump_if`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Cond {
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `>` (unsigned)
+    Gt,
+    /// `>=` (unsigned)
+    Ge,
+    /// `<` (unsigned)
+    Lt,
+    /// `<=` (unsigned)
+    Le,
+    /// `&`
+    Set,
+    /// `s>`
+    Sgt,
+    /// `s>=`
+    Sge,
+    /// `s<`
+    Slt,
+    /// `s<=`
+    Sle,
+}
+
+#[cfg(feature = "alloc")]
+impl Cond {
+    fn op(self) -> OpJmp {
+        match self {
+            Cond::Eq => OpJmp::Jeq,
+            Cond::Ne => OpJmp::Jne,
+            Cond::Gt => OpJmp::Jgt,
+            Cond::Ge => OpJmp::Jge,
+            Cond::Lt => OpJmp::Jlt,
+            Cond::Le => OpJmp::Jle,
+            Cond::Set => OpJmp::Jset,
+            Cond::Sgt => OpJmp::Jsgt,
+            Cond::Sge => OpJmp::Jsge,
+            Cond::Slt => OpJmp::Jslt,
+            Cond::Sle => OpJmp::Jsle,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// A position in a `ProgramBuilder`, which may be jumped to before or after it is bound
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Label(usize);
+
+#[cfg(feature = "alloc")]
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum BuildError {
+    /// A label was jumped to, but never bound
+    UndefinedLabel(Label),
+    /// A label was bound more than once
+    DuplicateLabel(Label),
+    /// A label was jumped to, but is bound after the last instruction
+    LabelOutOfRange(Label),
+}
+
+#[cfg(feature = "alloc")]
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::UndefinedLabel(Label(l)) => write!(f, "label {} is never bound", l),
+            BuildError::DuplicateLabel(Label(l)) => write!(f, "label {} is bound more than once", l),
+            BuildError::LabelOutOfRange(Label(l)) => write!(f, "label {} is past the end of the program", l),
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+enum Item {
+    Inst(Inst),
+    /// A conditional jump (with the offset to be filled in), or `None` for an unconditional one
+    Jump(Option<Inst>, Label),
+}
+
+/// Build a program where jumps refer to labels instead of offsets
 ///
-fn st_mem(sz: Size, dst_reg: u8, dst_off: i16, imm: u32) -> u64
-{
-    Inst {
-        op: Class::St.to_u8().unwrap() | Mode::Mem.to_u8().unwrap() | sz.to_u8().unwrap(),
-        src_dst: dst_reg,
-        off: dst_off as u16,
-        imm: imm,
-    }.to_u64()
+/// Offsets are resolved by `finish()`. Jumps which are too far away for the 16-bit offset use a
+/// `gotol` (`jmp32::ja`) instead: unconditional jumps are replaced by one, and conditional ones
+/// become `if cond goto +1; goto +1; gotol label`.
+///
+/// ```
+/// use cbpf::build::{alu64, exit, Cond, Imm, ProgramBuilder, Reg};
+///
+/// let mut b = ProgramBuilder::new();
+/// let out = b.label();
+/// b.push(alu64::mov(Reg::R0, Imm(0)))
+///     .jump_if(Cond::Gt, Reg::R1, Imm(10), out)
+///     .push(alu64::mov(Reg::R0, Imm(1)))
+///     .bind(out)
+///     .push(exit());
+/// let prgm = b.finish().unwrap();
+/// assert_eq!(prgm.len(), 4);
+/// ```
+#[cfg(feature = "alloc")]
+#[derive(Default)]
+pub struct ProgramBuilder {
+    items: Vec<Item>,
+    // the index in `items` each label is bound to
+    labels: Vec<Option<usize>>,
+    error: Option<BuildError>,
+}
+
+#[cfg(feature = "alloc")]
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new label, which must be bound (with `bind()`) before `finish()` if it is used
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Bind `label` to the next instruction pushed
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        match self.labels.get_mut(label.0) {
+            Some(pos @ &mut None) => *pos = Some(self.items.len()),
+            _ => {
+                if self.error.is_none() {
+                    self.error = Some(BuildError::DuplicateLabel(label));
+                }
+            },
+        }
+        self
+    }
+
+    pub fn push(&mut self, inst: Inst) -> &mut Self {
+        self.items.push(Item::Inst(inst));
+        self
+    }
+
+    pub fn extend<I: IntoIterator<Item = Inst>>(&mut self, insts: I) -> &mut Self {
+        self.items.extend(insts.into_iter().map(Item::Inst));
+        self
+    }
+
+    /// `goto label`
+    pub fn jump(&mut self, label: Label) -> &mut Self {
+        self.items.push(Item::Jump(None, label));
+        self
+    }
+
+    /// `if dst <cond> src goto label`
+    pub fn jump_if<S: Into<Operand>>(&mut self, cond: Cond, dst: Reg, src: S, label: Label) -> &mut Self {
+        let i = with_src(Class::Jmp as u8 | cond.op() as u8, dst, src.into(), 0);
+        self.items.push(Item::Jump(Some(i), label));
+        self
+    }
+
+    /// `if wdst <cond> wsrc goto label`, comparing only the lower 32 bits
+    pub fn jump_if32<S: Into<Operand>>(&mut self, cond: Cond, dst: Reg, src: S, label: Label) -> &mut Self {
+        let i = with_src(Class::Jmp32 as u8 | cond.op() as u8, dst, src.into(), 0);
+        self.items.push(Item::Jump(Some(i), label));
+        self
+    }
+
+    /// The number of instructions `item` expands to
+    fn size(item: &Item, long: bool) -> usize {
+        match *item {
+            Item::Inst(_) => 1,
+            Item::Jump(Some(_), _) if long => 3,
+            Item::Jump(_, _) => 1,
+        }
+    }
+
+    /// Resolve all jumps, returning the encoded program
+    pub fn finish(&self) -> Result<Vec<u64>, BuildError> {
+        if let Some(ref e) = self.error {
+            return Err(e.clone());
+        }
+
+        let n = self.items.len();
+        let mut dest = Vec::with_capacity(n);
+        for item in &self.items {
+            dest.push(match *item {
+                Item::Jump(_, label) => match self.labels.get(label.0) {
+                    Some(&Some(d)) if d < n => d,
+                    Some(&Some(_)) => return Err(BuildError::LabelOutOfRange(label)),
+                    _ => return Err(BuildError::UndefinedLabel(label)),
+                },
+                Item::Inst(_) => 0,
+            });
+        }
+
+        // Start with every jump short, and lengthen those that don't fit until none change.
+        // Lengthening only moves instructions further apart, so this terminates.
+        let mut long = Vec::new();
+        long.resize(n, false);
+        let mut addr = Vec::with_capacity(n);
+        loop {
+            addr.clear();
+            let mut a = 0;
+            for (item, &l) in self.items.iter().zip(&long) {
+                addr.push(a);
+                a += Self::size(item, l);
+            }
+
+            let mut changed = false;
+            for (i, item) in self.items.iter().enumerate() {
+                if let Item::Jump(..) = *item {
+                    let off = addr[dest[i]] as i64 - (addr[i] as i64 + 1);
+                    if !long[i] && (off < i64::from(i16::MIN) || off > i64::from(i16::MAX)) {
+                        long[i] = true;
+                        changed = true;
+                    }
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        let mut out = Vec::with_capacity(n);
+        for (i, item) in self.items.iter().enumerate() {
+            match *item {
+                Item::Inst(inst) => out.push(inst.to_u64()),
+                Item::Jump(cond, _) => {
+                    let target = addr[dest[i]] as i64;
+                    match cond {
+                        Some(mut c) if long[i] => {
+                            c.off = 1;
+                            out.push(c.to_u64());
+                            out.push(jmp::ja(1).to_u64());
+                            out.push(jmp32::ja((target - (addr[i] as i64 + 3)) as i32).to_u64());
+                        },
+                        Some(mut c) => {
+                            c.off = (target - (addr[i] as i64 + 1)) as u16;
+                            out.push(c.to_u64());
+                        },
+                        None if long[i] => out.push(jmp32::ja((target - (addr[i] as i64 + 1)) as i32).to_u64()),
+                        None => out.push(jmp::ja((target - (addr[i] as i64 + 1)) as i16).to_u64()),
+                    }
+                },
+            }
+        }
+
+        Ok(out)
+    }
 }
@@ -0,0 +1,63 @@
+//! Programs as bytes, as they are stored in object files and passed to linux
+//!
+//! Each instruction takes 8 bytes: the opcode, the registers, a 16-bit offset, and a 32-bit
+//! immediate, in the order given by an `Endian`. `load` splits a program into `Inst`s and
+//! `load_raw` into the `u64`s `verifier::Env::verify` takes; `store` and `store_raw` reverse them.
+
+use super::*;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LoadError {
+    /// The program's length, `len` bytes, is not a multiple of 8
+    Length {
+        len: usize,
+    },
+    /// The instruction at `idx` can't be decoded
+    Inst {
+        idx: usize,
+        err: InstDecodeError,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Length { len } =>
+                write!(f, "program of {} bytes is not a whole number of instructions", len),
+            LoadError::Inst { idx, err: InstDecodeError::InvalidEncoding(s) }
+                | LoadError::Inst { idx, err: InstDecodeError::ForbiddenInst(s) }
+                | LoadError::Inst { idx, err: InstDecodeError::Other(s) } =>
+                write!(f, "instruction {}: invalid instruction: {}", idx, s),
+        }
+    }
+}
+
+/// Decode each 8 bytes of `bytes` as an instruction. The second half of a `ld_imm64` is included,
+/// so indexes match those of the encoded program.
+pub fn load(bytes: &[u8], endian: Endian) -> Result<Vec<Inst>, LoadError> {
+    let chunks = bytes.chunks_exact(8);
+    if !chunks.remainder().is_empty() {
+        return Err(LoadError::Length { len: bytes.len() });
+    }
+    chunks.enumerate().map(|(idx, c)| {
+        let b = [c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]];
+        Inst::from_bytes(b, endian).map_err(|err| LoadError::Inst { idx, err })
+    }).collect()
+}
+
+/// Like `load`, giving each instruction as a `u64`
+pub fn load_raw(bytes: &[u8], endian: Endian) -> Result<Vec<u64>, LoadError> {
+    Ok(load(bytes, endian)?.iter().map(Inst::to_u64).collect())
+}
+
+/// Encode `insts`, 8 bytes each
+pub fn store(insts: &[Inst], endian: Endian) -> Vec<u8> {
+    insts.iter().flat_map(|i| i.to_bytes(endian)).collect()
+}
+
+/// Like `store`, for instructions given as `u64`s
+pub fn store_raw(insts: &[u64], endian: Endian) -> Vec<u8> {
+    insts.iter().flat_map(|&raw| Inst::from_u64(raw).unwrap().to_bytes(endian)).collect()
+}
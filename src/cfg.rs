@@ -0,0 +1,335 @@
+//! Control flow graph of a program
+//!
+//! A program is split into basic blocks: runs of instructions that are only entered at their
+//! first instruction and only left after their last. Blocks start at the beginning of the
+//! program, at every jump target, and after every jump or `exit`. Blocks are numbered in program
+//! order, so block `0` is always the entry.
+//!
+//! A call to another BPF function ends its block as a jump does, with a `Call` edge to the
+//! function's first block and a `Fallthrough` edge to where it returns. The function's `exit`s
+//! have no edges.
+//!
+//! `Dot` renders the graph for Graphviz (`dot -Tsvg`), with each block's disassembly.
+
+use super::*;
+use alloc::vec::Vec;
+use core::fmt;
+use disasm::Disasm;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum CfgErrorKind {
+    /// An instruction is not a valid encoding
+    InstDecode(InstDecodeError),
+    /// A jump's target is outside of the program
+    JumpOutOfRange,
+    /// A jump's target is the second half of a `ld_imm64`
+    JumpIntoLdImm64,
+    /// Execution continues past the last instruction
+    FallsOffEnd,
+    /// The program has no instructions
+    Empty,
+}
+
+/// An error building a `Cfg`, and the index of the instruction which caused it
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct CfgError {
+    inst_idx: usize,
+    kind: CfgErrorKind,
+}
+
+impl CfgError {
+    pub fn inst_idx(&self) -> usize {
+        self.inst_idx
+    }
+
+    pub fn kind(&self) -> &CfgErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for CfgErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CfgErrorKind::InstDecode(InstDecodeError::InvalidEncoding(s))
+                | CfgErrorKind::InstDecode(InstDecodeError::ForbiddenInst(s))
+                | CfgErrorKind::InstDecode(InstDecodeError::Other(s)) => write!(f, "invalid instruction: {}", s),
+            CfgErrorKind::JumpOutOfRange => write!(f, "jump out of range"),
+            CfgErrorKind::JumpIntoLdImm64 => write!(f, "jump into the middle of ld_imm64"),
+            CfgErrorKind::FallsOffEnd => write!(f, "execution continues past the last instruction"),
+            CfgErrorKind::Empty => write!(f, "program is empty"),
+        }
+    }
+}
+
+impl fmt::Display for CfgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "instruction {}: {}", self.inst_idx, self.kind)
+    }
+}
+
+/// How control reaches a successor
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum EdgeKind {
+    /// Continuing to the next instruction, including when a conditional jump is not taken
+    Fallthrough,
+    /// A conditional jump that is taken
+    Taken,
+    /// An unconditional jump
+    Goto,
+    /// A call to another BPF function
+    Call,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Edge {
+    /// The block control moves to
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// A basic block
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Block {
+    start: usize,
+    end: usize,
+    last: usize,
+    succs: Vec<Edge>,
+    preds: Vec<usize>,
+}
+
+impl Block {
+    /// Index of the first instruction
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Index one past the last instruction
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Index of the last instruction (which is the first half of a `ld_imm64` when the block ends
+    /// with one)
+    pub fn last(&self) -> usize {
+        self.last
+    }
+
+    /// Blocks which control may move to after this one. Empty when the block ends with `exit`.
+    pub fn succs(&self) -> &[Edge] {
+        &self.succs
+    }
+
+    /// Blocks which may move to this one, in increasing order
+    pub fn preds(&self) -> &[usize] {
+        &self.preds
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Cfg {
+    blocks: Vec<Block>,
+}
+
+/// The index each jump at `pc` may continue at: the jump target (if any) and the fallthrough (if
+/// any). For a call to another function, the target is the function.
+fn branch_targets(i: &Inst, pc: usize, next: usize) -> (Option<i64>, Option<usize>) {
+    let target = i.jump_off().or_else(|| i.call_off()).map(|off| pc as i64 + 1 + i64::from(off));
+    let is_goto = i.op_jmp() == Some(OpJmp::Ja);
+    match (i.op_class(), i.op_jmp()) {
+        (Some(Class::Jmp), Some(OpJmp::Exit)) => (None, None),
+        (Some(Class::Jmp), Some(OpJmp::Call)) => (target, Some(next)),
+        (Some(Class::Jmp), _) | (Some(Class::Jmp32), _) if is_goto => (target, None),
+        (Some(Class::Jmp), _) | (Some(Class::Jmp32), _) => (target, Some(next)),
+        _ => (None, Some(next)),
+    }
+}
+
+impl Cfg {
+    /// Build the graph of `insts`, checking that every instruction is valid and that every jump
+    /// lands on an instruction.
+    pub fn new(insts: &[u64]) -> Result<Self, CfgError> {
+        let n = insts.len();
+        if n == 0 {
+            return Err(CfgError { inst_idx: 0, kind: CfgErrorKind::Empty });
+        }
+
+        // which indexes start an instruction, and which start a block
+        let mut start = Vec::new();
+        start.resize(n, false);
+        let mut leader = start.clone();
+        leader[0] = true;
+
+        let mut pc = 0;
+        while pc < n {
+            let err = |kind| CfgError { inst_idx: pc, kind };
+            let i = Inst::from_u64(insts[pc]).map_err(|e| err(CfgErrorKind::InstDecode(e)))?;
+            i.check().map_err(|e| err(CfgErrorKind::InstDecode(e)))?;
+            start[pc] = true;
+
+            let next = if i.is_ld_imm64() {
+                let hi = insts.get(pc + 1).ok_or_else(|| err(CfgErrorKind::InstDecode(
+                    InstDecodeError::InvalidEncoding("ld_imm64 is missing second half"))))?;
+                Inst::from_u64(*hi).and_then(|hi| hi.check_imm64_hi())
+                    .map_err(|e| err(CfgErrorKind::InstDecode(e)))?;
+                pc + 2
+            } else {
+                pc + 1
+            };
+
+            let (target, fall) = branch_targets(&i, pc, next);
+            let is_exit = i.op_class() == Some(Class::Jmp) && i.op_jmp() == Some(OpJmp::Exit);
+            if (target.is_some() || is_exit) && next < n {
+                leader[next] = true;
+            }
+            if let Some(t) = target {
+                if t < 0 || t as usize >= n {
+                    return Err(err(CfgErrorKind::JumpOutOfRange));
+                }
+                leader[t as usize] = true;
+            }
+            if fall == Some(n) {
+                return Err(err(CfgErrorKind::FallsOffEnd));
+            }
+
+            pc = next;
+        }
+
+        // jumps may only land on the start of an instruction
+        for (pc, &is_leader) in leader.iter().enumerate() {
+            if is_leader && !start[pc] {
+                let from = (0..n).find(|&p| {
+                    start[p] && Inst::from_u64(insts[p]).ok()
+                        .and_then(|i| branch_targets(&i, p, p + 1).0)
+                        == Some(pc as i64)
+                }).unwrap_or(pc);
+                return Err(CfgError { inst_idx: from, kind: CfgErrorKind::JumpIntoLdImm64 });
+            }
+        }
+
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut last = 0;
+        for pc in 0..n {
+            if leader[pc] {
+                if let Some(b) = blocks.last_mut() {
+                    b.end = pc;
+                }
+                blocks.push(Block { start: pc, end: n, last: pc, succs: Vec::new(), preds: Vec::new() });
+            }
+            if start[pc] {
+                last = pc;
+            }
+            blocks.last_mut().unwrap().last = last;
+        }
+
+        let mut cfg = Cfg { blocks };
+        for b in 0..cfg.blocks.len() {
+            let (pc, end) = (cfg.blocks[b].last, cfg.blocks[b].end);
+            let i = Inst::from_u64(insts[pc]).unwrap();
+            let (target, fall) = branch_targets(&i, pc, end);
+            let mut succs = Vec::new();
+            if let Some(f) = fall {
+                succs.push(Edge { to: cfg.block_at(f).unwrap(), kind: EdgeKind::Fallthrough });
+            }
+            if let Some(t) = target {
+                let kind = match fall {
+                    _ if i.call_off().is_some() => EdgeKind::Call,
+                    Some(_) => EdgeKind::Taken,
+                    None => EdgeKind::Goto,
+                };
+                succs.push(Edge { to: cfg.block_at(t as usize).unwrap(), kind });
+            }
+            for e in &succs {
+                let preds = &mut cfg.blocks[e.to].preds;
+                if preds.last() != Some(&b) {
+                    preds.push(b);
+                }
+            }
+            cfg.blocks[b].succs = succs;
+        }
+
+        Ok(cfg)
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn block(&self, id: usize) -> &Block {
+        &self.blocks[id]
+    }
+
+    /// The block containing the instruction at `pc`
+    pub fn block_at(&self, pc: usize) -> Option<usize> {
+        match self.blocks.binary_search_by(|b| b.start.cmp(&pc)) {
+            Ok(b) => Some(b),
+            Err(0) => None,
+            Err(b) if pc < self.blocks[b - 1].end => Some(b - 1),
+            Err(_) => None,
+        }
+    }
+
+    /// Render as a Graphviz digraph, labeling each block with the disassembly of `insts` (which
+    /// must be the program this graph was built from)
+    pub fn dot<'a>(&'a self, insts: &'a [u64]) -> Dot<'a> {
+        Dot { cfg: self, insts }
+    }
+}
+
+/// A `Cfg` in Graphviz DOT format
+#[derive(Debug,Clone,Copy)]
+pub struct Dot<'a> {
+    cfg: &'a Cfg,
+    insts: &'a [u64],
+}
+
+/// Escape for a double quoted DOT string
+struct Escape<T>(T);
+
+impl<T: fmt::Display> fmt::Display for Escape<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        struct W<'f, 'g: 'f>(&'f mut fmt::Formatter<'g>);
+        impl<'f, 'g> fmt::Write for W<'f, 'g> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for c in s.chars() {
+                    match c {
+                        '"' | '\\' => write!(self.0, "\\{}", c)?,
+                        '\n' => write!(self.0, "\\l")?,
+                        c => write!(self.0, "{}", c)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+        fmt::write(&mut W(f), format_args!("{}", self.0))
+    }
+}
+
+impl<'a> fmt::Display for Dot<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = Disasm::new(self.insts);
+        writeln!(f, "digraph cfg {{")?;
+        writeln!(f, "    node [shape=box, fontname=monospace];")?;
+        for (id, b) in self.cfg.blocks.iter().enumerate() {
+            write!(f, "    b{} [label=\"", id)?;
+            let mut pc = b.start;
+            while pc < b.end {
+                write!(f, "{}: {}\\l", pc, Escape(d.at(pc)))?;
+                let i = Inst::from_u64(self.insts[pc]).unwrap();
+                pc += if i.is_ld_imm64() { 2 } else { 1 };
+            }
+            writeln!(f, "\"];")?;
+        }
+        for (id, b) in self.cfg.blocks.iter().enumerate() {
+            for e in &b.succs {
+                let style = match e.kind {
+                    EdgeKind::Fallthrough if b.succs.iter().any(|e| e.kind == EdgeKind::Taken) => " [color=red]",
+                    EdgeKind::Taken => " [color=green]",
+                    EdgeKind::Call => " [style=dashed]",
+                    _ => "",
+                };
+                writeln!(f, "    b{} -> b{}{};", id, e.to, style)?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
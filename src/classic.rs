@@ -0,0 +1,1034 @@
+//! Classic BPF programs in the text formats used by `bpf_asm` and `tcpdump`
+//!
+//! Classic programs are a sequence of `SockFilter` (the kernel's `struct sock_filter`). Four
+//! representations are supported, both for reading and writing:
+//!
+//!  - Assembly. `assemble()` accepts the syntax of the kernel's `bpf_asm` tool, with labels:
+//!
+//!    ```text
+//!        ldh [12]
+//!        jne #0x800, drop
+//!        ret #65535
+//!    drop: ret #0
+//!    ```
+//!
+//!    as well as the listing printed by `tcpdump -d`, where jump targets are instruction indexes:
+//!
+//!    ```text
+//!    (000) ldh      [12]
+//!    (001) jeq      #0x800           jt 2    jf 3
+//!    (002) ret      #65535
+//!    (003) ret      #0
+//!    ```
+//!
+//!    `Listing` writes either of these.
+//!  - The decimal format of `tcpdump -ddd`: the instruction count, then one `code jt jf k` per
+//!    line. `bpf_asm` (and `iptables -m bpf`) separate the same values with `,` instead of
+//!    newlines, which is also accepted. See `parse_ddd()` and `Ddd`.
+//!  - The C array of `tcpdump -dd`: `{ 0x28, 0, 0, 0x0000000c },`. See `parse_c_array()` and
+//!    `CArray`.
+
+use asm::{AsmError, AsmErrorKind};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A single classic BPF instruction
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct SockFilter {
+    pub code: u16,
+    /// Offset added to the index of the next instruction if the condition is true
+    pub jt: u8,
+    /// Offset added to the index of the next instruction if the condition is false
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl SockFilter {
+    pub fn new(code: u16, jt: u8, jf: u8, k: u32) -> Self {
+        SockFilter { code, jt, jf, k }
+    }
+}
+
+// classes
+const LD: u16 = 0x00;
+const LDX: u16 = 0x01;
+const ST: u16 = 0x02;
+const STX: u16 = 0x03;
+const ALU: u16 = 0x04;
+const JMP: u16 = 0x05;
+const RET: u16 = 0x06;
+const MISC: u16 = 0x07;
+
+// sizes
+const W: u16 = 0x00;
+const H: u16 = 0x08;
+const B: u16 = 0x10;
+
+// modes
+const IMM: u16 = 0x00;
+const ABS: u16 = 0x20;
+const IND: u16 = 0x40;
+const MEM: u16 = 0x60;
+const LEN: u16 = 0x80;
+const MSH: u16 = 0xa0;
+
+// alu & jmp operations
+const ADD: u16 = 0x00;
+const SUB: u16 = 0x10;
+const MUL: u16 = 0x20;
+const DIV: u16 = 0x30;
+const OR: u16 = 0x40;
+const AND: u16 = 0x50;
+const LSH: u16 = 0x60;
+const RSH: u16 = 0x70;
+const NEG: u16 = 0x80;
+const MOD: u16 = 0x90;
+const XOR: u16 = 0xa0;
+
+const JA: u16 = 0x00;
+const JEQ: u16 = 0x10;
+const JGT: u16 = 0x20;
+const JGE: u16 = 0x30;
+const JSET: u16 = 0x40;
+
+// sources
+const K: u16 = 0x00;
+const X: u16 = 0x08;
+// only for `RET`
+const A: u16 = 0x10;
+
+// `MISC` operations
+const TAX: u16 = 0x00;
+const TXA: u16 = 0x80;
+
+/// The number of scratch memory slots (`M[0]` to `M[15]`)
+const MEMWORDS: u32 = 16;
+
+/// Offset of the ancillary data loads (`SKF_AD_OFF`)
+const AD_OFF: u32 = (-0x1000i32) as u32;
+
+/// Ancillary data which can be loaded with `ld`/`ldh`/`ldb` in place of packet data, and the
+/// offset (from `AD_OFF`) they are loaded from
+const ANCILLARY: &[(&str, u32)] = &[
+    ("proto", 0),
+    ("type", 4),
+    ("ifidx", 8),
+    ("nla", 12),
+    ("nlan", 16),
+    ("mark", 20),
+    ("queue", 24),
+    ("hatype", 28),
+    ("rxhash", 32),
+    ("cpu", 36),
+    ("vlan_tci", 44),
+    ("vlan_avail", 48),
+    ("poff", 52),
+    ("rand", 56),
+    ("vlan_tpid", 60),
+];
+
+fn ancillary_name(k: u32) -> Option<&'static str> {
+    let off = k.wrapping_sub(AD_OFF);
+    ANCILLARY.iter().find(|a| a.1 == off).map(|a| a.0)
+}
+
+fn ancillary_off(name: &str) -> Option<u32> {
+    let name = match name {
+        "protocol" => "proto",
+        "vlan_pr" => "vlan_avail",
+        "random" => "rand",
+        n => n,
+    };
+    ANCILLARY.iter().find(|a| a.0 == name).map(|a| AD_OFF.wrapping_add(a.1))
+}
+
+/// Which syntax a `Listing` is written in
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Syntax {
+    /// `tcpdump -d`: each line prefixed by its index, with absolute jump targets
+    Tcpdump,
+    /// `bpf_asm`: jump targets are labels (`l<index>`)
+    BpfAsm,
+}
+
+/// How a single operand is written
+enum Operand {
+    None,
+    Hex(u32),
+    Dec(u32),
+    X,
+    A,
+    Mem(u32),
+    Abs(u32),
+    Ind(u32),
+    Msh(u32),
+    Len,
+    Anc(&'static str),
+}
+
+impl Operand {
+    fn write(&self, f: &mut fmt::Formatter, syntax: Syntax) -> fmt::Result {
+        match *self {
+            Operand::None => Ok(()),
+            Operand::Hex(k) => write!(f, "#{:#x}", k),
+            Operand::Dec(k) => write!(f, "#{}", k),
+            Operand::X => write!(f, "x"),
+            Operand::A => write!(f, "a"),
+            Operand::Mem(k) => write!(f, "M[{}]", k),
+            Operand::Abs(k) => write!(f, "[{}]", k),
+            Operand::Ind(k) => write!(f, "[x + {}]", k),
+            Operand::Msh(k) => write!(f, "4*([{}]&0xf)", k),
+            Operand::Len if syntax == Syntax::Tcpdump => write!(f, "#pktlen"),
+            Operand::Len => write!(f, "#len"),
+            Operand::Anc(name) => write!(f, "#{}", name),
+        }
+    }
+}
+
+/// Is this a conditional jump (which uses `jt` & `jf`)?
+fn is_cond(code: u16) -> bool {
+    code & 0x07 == JMP && code & 0xf0 != JA
+}
+
+/// Split an instruction into its mnemonic and operand, or `None` if the encoding is invalid
+fn decode(i: &SockFilter) -> Option<(&'static str, Operand)> {
+    let k = i.k;
+    let src = |c: u16| if c & X != 0 { Operand::X } else { Operand::Hex(k) };
+    let c = i.code;
+    Some(match c {
+        _ if c == LD | W | ABS || c == LD | H | ABS || c == LD | B | ABS => {
+            let op = match c & 0x18 {
+                W => "ld",
+                H => "ldh",
+                _ => "ldb",
+            };
+            match ancillary_name(k) {
+                Some(name) => (op, Operand::Anc(name)),
+                None => (op, Operand::Abs(k)),
+            }
+        },
+        _ if c == LD | W | IND => ("ld", Operand::Ind(k)),
+        _ if c == LD | H | IND => ("ldh", Operand::Ind(k)),
+        _ if c == LD | B | IND => ("ldb", Operand::Ind(k)),
+        _ if c == LD | W | LEN => ("ld", Operand::Len),
+        _ if c == LD | W | IMM => ("ld", Operand::Hex(k)),
+        _ if c == LD | W | MEM && k < MEMWORDS => ("ld", Operand::Mem(k)),
+        _ if c == LDX | W | IMM => ("ldx", Operand::Hex(k)),
+        _ if c == LDX | W | LEN => ("ldx", Operand::Len),
+        _ if c == LDX | W | MEM && k < MEMWORDS => ("ldx", Operand::Mem(k)),
+        _ if c == LDX | B | MSH => ("ldxb", Operand::Msh(k)),
+        _ if c == ST && k < MEMWORDS => ("st", Operand::Mem(k)),
+        _ if c == STX && k < MEMWORDS => ("stx", Operand::Mem(k)),
+        _ if c & 0x07 == ALU && c & !0xf8 == ALU => {
+            let op = match c & 0xf0 {
+                ADD => "add",
+                SUB => "sub",
+                MUL => "mul",
+                DIV => "div",
+                MOD => "mod",
+                LSH => "lsh",
+                RSH => "rsh",
+                AND => "and",
+                OR => "or",
+                XOR => "xor",
+                NEG if c == ALU | NEG => return Some(("neg", Operand::None)),
+                _ => return None,
+            };
+            let arg = match src(c) {
+                Operand::Hex(k) if op != "and" && op != "or" && op != "xor" => Operand::Dec(k),
+                o => o,
+            };
+            (op, arg)
+        },
+        _ if c == JMP | JA => ("ja", Operand::None),
+        _ if c & 0x07 == JMP && c & !0xf8 == JMP => {
+            let op = match c & 0xf0 {
+                JEQ => "jeq",
+                JGT => "jgt",
+                JGE => "jge",
+                JSET => "jset",
+                _ => return None,
+            };
+            (op, src(c))
+        },
+        _ if c == RET | K => ("ret", Operand::Dec(k)),
+        _ if c == RET | X => ("ret", Operand::X),
+        _ if c == RET | A => ("ret", Operand::A),
+        _ if c == MISC | TAX => ("tax", Operand::None),
+        _ if c == MISC | TXA => ("txa", Operand::None),
+        _ => return None,
+    })
+}
+
+/// The instruction indexes which a jump at `pc` may continue at
+fn targets(prog: &[SockFilter], pc: usize) -> Option<(usize, usize)> {
+    let i = prog[pc];
+    if i.code == JMP | JA {
+        let t = (pc + 1).wrapping_add(i.k as usize);
+        Some((t, t))
+    } else if is_cond(i.code) && decode(&i).is_some() {
+        Some((pc + 1 + usize::from(i.jt), pc + 1 + usize::from(i.jf)))
+    } else {
+        None
+    }
+}
+
+/// How a jump at `pc` is written in `bpf_asm` syntax: the mnemonic, and the labels it refers to.
+///
+/// `jeq #k, lt` falls through when false. When only the false branch jumps, the negated form is
+/// used if there is one.
+fn asm_jump(prog: &[SockFilter], pc: usize) -> Option<(&'static str, usize, Option<usize>)> {
+    let (t, e) = targets(prog, pc)?;
+    let i = &prog[pc];
+    let (op, _) = decode(i)?;
+    if op == "ja" {
+        return Some((op, t, None));
+    }
+
+    let negated = match op {
+        "jeq" => Some("jneq"),
+        "jgt" => Some("jle"),
+        "jge" => Some("jlt"),
+        _ => None,
+    };
+    Some(match negated {
+        Some(n) if i.jt == 0 && i.jf != 0 => (n, e, None),
+        _ if i.jf == 0 => (op, t, None),
+        _ => (op, t, Some(e)),
+    })
+}
+
+/// A listing of a classic program, in either of the syntaxes accepted by `assemble()`
+#[derive(Debug,Clone,Copy)]
+pub struct Listing<'a> {
+    prog: &'a [SockFilter],
+    syntax: Syntax,
+}
+
+impl<'a> Listing<'a> {
+    /// A listing in `tcpdump -d` syntax
+    pub fn new(prog: &'a [SockFilter]) -> Self {
+        Listing { prog, syntax: Syntax::Tcpdump }
+    }
+
+    pub fn syntax(mut self, syntax: Syntax) -> Self {
+        self.syntax = syntax;
+        self
+    }
+
+    fn is_target(&self, pc: usize) -> bool {
+        (0..self.prog.len()).any(|i| match asm_jump(self.prog, i) {
+            Some((_, t, e)) => t == pc || e == Some(pc),
+            None => false,
+        })
+    }
+
+    fn write_inst(&self, f: &mut fmt::Formatter, pc: usize) -> fmt::Result {
+        let i = &self.prog[pc];
+        let (op, arg) = match decode(i) {
+            Some(d) => d,
+            None => return write!(f, "<invalid {{ {:#x}, {}, {}, {:#010x} }}>", i.code, i.jt, i.jf, i.k),
+        };
+
+        match self.syntax {
+            Syntax::Tcpdump => {
+                write!(f, "({:03}) {:<8} ", pc, op)?;
+                if let Some((t, e)) = targets(self.prog, pc) {
+                    if op == "ja" {
+                        return write!(f, "{}", t);
+                    }
+                    // operands are padded out to 16 characters, which requires knowing their width
+                    let mut w = Width(0);
+                    fmt::write(&mut w, format_args!("{}", OperandDisplay(&arg, self.syntax)))?;
+                    arg.write(f, self.syntax)?;
+                    write!(f, "{:1$}jt {2}\tjf {3}", "", 16usize.saturating_sub(w.0) + 1, t, e)
+                } else {
+                    arg.write(f, self.syntax)
+                }
+            },
+            Syntax::BpfAsm => {
+                match asm_jump(self.prog, pc) {
+                    Some((op, t, e)) => {
+                        write!(f, "{} ", op)?;
+                        if let Operand::None = arg {
+                        } else {
+                            arg.write(f, self.syntax)?;
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "l{}", t)?;
+                        match e {
+                            Some(e) => write!(f, ", l{}", e),
+                            None => Ok(()),
+                        }
+                    },
+                    None => {
+                        write!(f, "{}", op)?;
+                        if let Operand::None = arg {
+                            Ok(())
+                        } else {
+                            write!(f, " ")?;
+                            arg.write(f, self.syntax)
+                        }
+                    },
+                }
+            },
+        }
+    }
+}
+
+struct OperandDisplay<'o>(&'o Operand, Syntax);
+
+impl<'o> fmt::Display for OperandDisplay<'o> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.write(f, self.1)
+    }
+}
+
+/// Counts the characters written to it
+struct Width(usize);
+
+impl fmt::Write for Width {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for Listing<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for pc in 0..self.prog.len() {
+            if self.syntax == Syntax::BpfAsm {
+                if self.is_target(pc) {
+                    writeln!(f, "l{}:", pc)?;
+                }
+                write!(f, "    ")?;
+            }
+            self.write_inst(f, pc)?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A program in the decimal format printed by `tcpdump -ddd`
+#[derive(Debug,Clone,Copy)]
+pub struct Ddd<'a>(pub &'a [SockFilter]);
+
+impl<'a> fmt::Display for Ddd<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.0.len())?;
+        for i in self.0 {
+            writeln!(f, "{} {} {} {}", i.code, i.jt, i.jf, i.k)?;
+        }
+        Ok(())
+    }
+}
+
+/// A program as the C array elements printed by `tcpdump -dd`
+#[derive(Debug,Clone,Copy)]
+pub struct CArray<'a>(pub &'a [SockFilter]);
+
+impl<'a> fmt::Display for CArray<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for i in self.0 {
+            writeln!(f, "{{ {:#x}, {}, {}, {:#010x} }},", i.code, i.jt, i.jf, i.k)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse an integer in C syntax: decimal, hex (`0x`), binary (`0b`) or octal (leading `0`)
+fn parse_num(s: &str) -> Option<u64> {
+    let (digits, radix) = if let Some(h) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        (h, 16)
+    } else if let Some(b) = s.strip_prefix("0b").or_else(|| s.strip_prefix("0B")) {
+        (b, 2)
+    } else if s.len() > 1 && s.starts_with('0') {
+        (&s[1..], 8)
+    } else {
+        (s, 10)
+    };
+
+    let mut v: u64 = 0;
+    let mut any = false;
+    for c in digits.chars() {
+        let d = c.to_digit(radix)?;
+        v = v.checked_mul(u64::from(radix))?.checked_add(u64::from(d))?;
+        any = true;
+    }
+
+    if any { Some(v) } else { None }
+}
+
+fn err(line: usize, col: usize, kind: AsmErrorKind) -> AsmError {
+    AsmError::new(line, col, kind)
+}
+
+/// Parse the 4 numeric fields of an instruction (`code`, `jt`, `jf`, `k`), each given with its
+/// (1-based) column
+fn fields(line: usize, vals: &[(&str, usize)]) -> Result<SockFilter, AsmError> {
+    let mut v = [0u64; 4];
+    let max = [0xffff, 0xff, 0xff, 0xffff_ffff];
+    for (n, &(s, col)) in vals.iter().enumerate() {
+        let x = parse_num(s).ok_or_else(|| err(line, col, AsmErrorKind::Syntax("invalid number")))?;
+        if x > max[n] {
+            return Err(err(line, col, AsmErrorKind::OutOfRange));
+        }
+        v[n] = x;
+    }
+    Ok(SockFilter::new(v[0] as u16, v[1] as u8, v[2] as u8, v[3] as u32))
+}
+
+/// Split `s` into whitespace separated words, along with their (1-based) columns
+fn words(s: &str, base_col: usize) -> Vec<(&str, usize)> {
+    let mut out = Vec::new();
+    let mut start = None;
+    for (i, c) in s.char_indices().chain(Some((s.len(), ' '))) {
+        match (c.is_whitespace(), start) {
+            (true, Some(st)) => {
+                out.push((&s[st..i], base_col + st));
+                start = None;
+            },
+            (false, None) => start = Some(i),
+            _ => {},
+        }
+    }
+    out
+}
+
+/// Parse the `tcpdump -ddd` format: the number of instructions followed by each instruction as
+/// decimal `code jt jf k`. Entries may be separated by newlines or `,` (as `bpf_asm` does).
+pub fn parse_ddd(src: &str) -> Result<Vec<SockFilter>, AsmError> {
+    let mut count = None;
+    let mut prog = Vec::new();
+    let mut last = (1, 1);
+    for (idx, text) in src.lines().enumerate() {
+        let line = idx + 1;
+        let mut col = 1;
+        for entry in text.split(',') {
+            let w = words(entry, col);
+            col += entry.len() + 1;
+            if w.is_empty() {
+                continue;
+            }
+            last = (line, w[0].1);
+
+            if count.is_none() {
+                if w.len() != 1 {
+                    return Err(err(line, w[0].1, AsmErrorKind::Syntax("expected instruction count")));
+                }
+                let n = parse_num(w[0].0)
+                    .ok_or_else(|| err(line, w[0].1, AsmErrorKind::Syntax("invalid number")))?;
+                count = Some(n);
+                continue;
+            }
+
+            if w.len() != 4 {
+                return Err(err(line, w[0].1, AsmErrorKind::Syntax("expected `code jt jf k`")));
+            }
+            prog.push(fields(line, &w)?);
+        }
+    }
+
+    if count != Some(prog.len() as u64) {
+        return Err(err(last.0, last.1, AsmErrorKind::Syntax("instruction count does not match")));
+    }
+
+    Ok(prog)
+}
+
+/// Parse the C array elements printed by `tcpdump -dd`: `{ 0x28, 0, 0, 0x0000000c },`
+///
+/// Anything outside of the `{ ... }` elements (such as a surrounding declaration) is ignored.
+pub fn parse_c_array(src: &str) -> Result<Vec<SockFilter>, AsmError> {
+    let mut prog = Vec::new();
+    for (idx, text) in src.lines().enumerate() {
+        let line = idx + 1;
+        let text = match text.find("//") {
+            Some(i) => &text[..i],
+            None => text,
+        };
+
+        let mut rest = 0;
+        while let Some(open) = text[rest..].find('{').map(|i| i + rest) {
+            rest = open + 1;
+            let body_start = open + 1;
+            // an opening brace of an enclosing initializer
+            if !text[body_start..].trim_start().starts_with(|c: char| c.is_ascii_digit()) {
+                continue;
+            }
+
+            let close = text[body_start..].find('}').map(|i| i + body_start)
+                .ok_or_else(|| err(line, open + 1, AsmErrorKind::Syntax("expected `}`")))?;
+            let mut vals = Vec::new();
+            let mut col = body_start + 1;
+            for v in text[body_start..close].split(',') {
+                let w = words(v, col);
+                col += v.len() + 1;
+                if w.len() != 1 {
+                    return Err(err(line, col - v.len() - 1, AsmErrorKind::Syntax("expected a number")));
+                }
+                vals.push(w[0]);
+            }
+            if vals.len() != 4 {
+                return Err(err(line, open + 1, AsmErrorKind::Syntax("expected `{ code, jt, jf, k }`")));
+            }
+            prog.push(fields(line, &vals)?);
+            rest = close + 1;
+        }
+    }
+
+    Ok(prog)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Tok<'a> {
+    Ident(&'a str),
+    Num(&'a str),
+    Punct(char),
+}
+
+fn lex(line: &str, line_no: usize) -> Result<Vec<(Tok<'_>, usize)>, AsmError> {
+    let b = line.as_bytes();
+    let mut toks = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let c = b[i];
+        let col = i + 1;
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == b'_' || c == b'%' {
+            let start = i;
+            i += 1;
+            while i < b.len() && (b[i].is_ascii_alphanumeric() || b[i] == b'_') {
+                i += 1;
+            }
+            let word = &line[start..i];
+            let t = if c.is_ascii_digit() { Tok::Num(word) } else { Tok::Ident(word) };
+            toks.push((t, col));
+        } else if b"#,[]()+-*&:".contains(&c) {
+            toks.push((Tok::Punct(c as char), col));
+            i += 1;
+        } else {
+            return Err(err(line_no, col, AsmErrorKind::Syntax("unexpected character")));
+        }
+    }
+    Ok(toks)
+}
+
+/// An operand to an instruction, as written
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Arg {
+    None,
+    X,
+    A,
+    Imm(u32),
+    Mem(u32),
+    Abs(u32),
+    Ind(u32),
+    Msh(u32),
+    Len,
+    Anc(u32),
+}
+
+#[derive(Debug,Clone,Copy)]
+enum Target<'a> {
+    Label(&'a str),
+    Index(usize),
+}
+
+/// A jump target, with the column it was given at
+type JumpTo<'a> = Option<(Target<'a>, usize)>;
+
+struct Pending<'a> {
+    line: usize,
+    inst: SockFilter,
+    // target of `ja`, or `jt` & `jf`
+    jt: JumpTo<'a>,
+    jf: JumpTo<'a>,
+}
+
+struct Parser<'a, 't> {
+    toks: &'t [(Tok<'a>, usize)],
+    pos: usize,
+    line: usize,
+    eol: usize,
+}
+
+impl<'a, 't> Parser<'a, 't> {
+    fn err(&self, col: usize, kind: AsmErrorKind) -> AsmError {
+        err(self.line, col, kind)
+    }
+
+    fn col(&self) -> usize {
+        self.toks.get(self.pos).map(|t| t.1).unwrap_or(self.eol)
+    }
+
+    fn peek(&self) -> Option<Tok<'a>> {
+        self.toks.get(self.pos).map(|t| t.0)
+    }
+
+    fn eat(&mut self, p: char) -> bool {
+        if self.peek() == Some(Tok::Punct(p)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, p: char, what: &'static str) -> Result<(), AsmError> {
+        if self.eat(p) {
+            Ok(())
+        } else {
+            Err(self.err(self.col(), AsmErrorKind::Syntax(what)))
+        }
+    }
+
+    fn end(&self) -> Result<(), AsmError> {
+        if self.pos == self.toks.len() {
+            Ok(())
+        } else {
+            Err(self.err(self.col(), AsmErrorKind::Syntax("expected end of line")))
+        }
+    }
+
+    /// A number, optionally negative, which must fit in 32 bits
+    fn num(&mut self) -> Result<u32, AsmError> {
+        let col = self.col();
+        let neg = self.eat('-');
+        let s = match self.peek() {
+            Some(Tok::Num(s)) => s,
+            _ => return Err(self.err(self.col(), AsmErrorKind::Syntax("expected a number"))),
+        };
+        self.pos += 1;
+        let v = parse_num(s).ok_or_else(|| self.err(col, AsmErrorKind::Syntax("invalid number")))?;
+        if neg {
+            if v > 0x8000_0000 {
+                return Err(self.err(col, AsmErrorKind::OutOfRange));
+            }
+            Ok((v as u32).wrapping_neg())
+        } else if v > 0xffff_ffff {
+            Err(self.err(col, AsmErrorKind::OutOfRange))
+        } else {
+            Ok(v as u32)
+        }
+    }
+
+    fn arg(&mut self) -> Result<Arg, AsmError> {
+        let col = self.col();
+        match self.peek() {
+            None | Some(Tok::Punct(',')) => Ok(Arg::None),
+            Some(Tok::Ident("x")) | Some(Tok::Ident("%x")) => {
+                self.pos += 1;
+                Ok(Arg::X)
+            },
+            Some(Tok::Ident("a")) | Some(Tok::Ident("%a")) => {
+                self.pos += 1;
+                Ok(Arg::A)
+            },
+            Some(Tok::Ident("M")) => {
+                self.pos += 1;
+                self.expect('[', "expected `[`")?;
+                let k = self.num()?;
+                if k >= MEMWORDS {
+                    return Err(self.err(col, AsmErrorKind::OutOfRange));
+                }
+                self.expect(']', "expected `]`")?;
+                Ok(Arg::Mem(k))
+            },
+            Some(Tok::Ident(name)) => {
+                self.pos += 1;
+                self.named(name, col)
+            },
+            Some(Tok::Punct('#')) => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(Tok::Ident(name)) => {
+                        let col = self.col();
+                        self.pos += 1;
+                        self.named(name, col)
+                    },
+                    _ => Ok(Arg::Imm(self.num()?)),
+                }
+            },
+            Some(Tok::Punct('[')) => {
+                self.pos += 1;
+                let a = match self.peek() {
+                    Some(Tok::Ident("x")) | Some(Tok::Ident("%x")) => {
+                        self.pos += 1;
+                        if self.eat('+') {
+                            Arg::Ind(self.num()?)
+                        } else {
+                            Arg::Ind(0)
+                        }
+                    },
+                    _ => Arg::Abs(self.num()?),
+                };
+                self.expect(']', "expected `]`")?;
+                Ok(a)
+            },
+            Some(Tok::Num("4")) => {
+                // 4*([k]&0xf)
+                self.pos += 1;
+                self.expect('*', "expected `*`")?;
+                self.expect('(', "expected `(`")?;
+                self.expect('[', "expected `[`")?;
+                let k = self.num()?;
+                self.expect(']', "expected `]`")?;
+                self.expect('&', "expected `&`")?;
+                let m_col = self.col();
+                if self.num()? != 0xf {
+                    return Err(self.err(m_col, AsmErrorKind::Syntax("expected `0xf`")));
+                }
+                self.expect(')', "expected `)`")?;
+                Ok(Arg::Msh(k))
+            },
+            _ => Err(self.err(col, AsmErrorKind::Syntax("expected an operand"))),
+        }
+    }
+
+    /// An operand given by name: the packet length or ancillary data
+    fn named(&self, name: &str, col: usize) -> Result<Arg, AsmError> {
+        match name {
+            "len" | "pktlen" => Ok(Arg::Len),
+            _ => match ancillary_off(name) {
+                Some(k) => Ok(Arg::Anc(k)),
+                None => Err(self.err(col, AsmErrorKind::Syntax("unknown operand"))),
+            },
+        }
+    }
+
+    fn target(&mut self) -> Result<(Target<'a>, usize), AsmError> {
+        let col = self.col();
+        match self.peek() {
+            Some(Tok::Ident(name)) => {
+                self.pos += 1;
+                Ok((Target::Label(name), col))
+            },
+            Some(Tok::Num(_)) => Ok((Target::Index(self.num()? as usize), col)),
+            _ => Err(self.err(col, AsmErrorKind::Syntax("expected a jump target"))),
+        }
+    }
+
+    /// Jump targets following the operand of a conditional jump: either `, lt[, lf]` or
+    /// `jt N jf M`
+    fn cond_targets(&mut self) -> Result<(JumpTo<'a>, JumpTo<'a>), AsmError> {
+        if self.eat(',') {
+            let t = self.target()?;
+            let f = if self.eat(',') { Some(self.target()?) } else { None };
+            Ok((Some(t), f))
+        } else if self.peek() == Some(Tok::Ident("jt")) {
+            self.pos += 1;
+            let t = self.target()?;
+            if self.peek() != Some(Tok::Ident("jf")) {
+                return Err(self.err(self.col(), AsmErrorKind::Syntax("expected `jf`")));
+            }
+            self.pos += 1;
+            let f = self.target()?;
+            Ok((Some(t), Some(f)))
+        } else {
+            Err(self.err(self.col(), AsmErrorKind::Syntax("expected a jump target")))
+        }
+    }
+
+    fn statement(&mut self, out: &mut Vec<Pending<'a>>) -> Result<(), AsmError> {
+        let col = self.col();
+        let word = match self.peek() {
+            Some(Tok::Ident(w)) => w,
+            _ => return Err(self.err(col, AsmErrorKind::Syntax("expected an instruction"))),
+        };
+        self.pos += 1;
+
+        let bad = |p: &Self| Err(p.err(col, AsmErrorKind::Syntax("invalid operand for instruction")));
+        let mut jt = None;
+        let mut jf = None;
+
+        let code = match word {
+            "ld" | "ldi" | "ldh" | "ldb" | "ldx" | "ldxi" | "ldxb" | "st" | "stx" | "ret"
+                | "add" | "sub" | "mul" | "div" | "mod" | "and" | "or" | "xor" | "lsh" | "rsh"
+                | "neg" | "tax" | "txa" => {
+                let arg = self.arg()?;
+                let size = match word {
+                    "ldh" => H,
+                    "ldb" => B,
+                    _ => W,
+                };
+                let (code, k) = match (word, arg) {
+                    ("ld", Arg::Imm(k)) | ("ldi", Arg::Imm(k)) => (LD | W | IMM, k),
+                    ("ld", Arg::Len) => (LD | W | LEN, 0),
+                    ("ld", Arg::Mem(k)) => (LD | W | MEM, k),
+                    ("ld", Arg::Abs(k)) | ("ldh", Arg::Abs(k)) | ("ldb", Arg::Abs(k))
+                        | ("ld", Arg::Anc(k)) | ("ldh", Arg::Anc(k)) | ("ldb", Arg::Anc(k)) => (LD | size | ABS, k),
+                    ("ld", Arg::Ind(k)) | ("ldh", Arg::Ind(k)) | ("ldb", Arg::Ind(k)) => (LD | size | IND, k),
+                    ("ldx", Arg::Imm(k)) | ("ldxi", Arg::Imm(k)) => (LDX | W | IMM, k),
+                    ("ldx", Arg::Len) => (LDX | W | LEN, 0),
+                    ("ldx", Arg::Mem(k)) => (LDX | W | MEM, k),
+                    ("ldx", Arg::Msh(k)) | ("ldxb", Arg::Msh(k)) => (LDX | B | MSH, k),
+                    ("st", Arg::Mem(k)) => (ST, k),
+                    ("stx", Arg::Mem(k)) => (STX, k),
+                    ("ret", Arg::Imm(k)) => (RET | K, k),
+                    ("ret", Arg::X) => (RET | X, 0),
+                    ("ret", Arg::A) => (RET | A, 0),
+                    ("neg", Arg::None) => (ALU | NEG, 0),
+                    ("tax", Arg::None) => (MISC | TAX, 0),
+                    ("txa", Arg::None) => (MISC | TXA, 0),
+                    (_, Arg::Imm(_)) | (_, Arg::X) => {
+                        let op = match word {
+                            "add" => ADD,
+                            "sub" => SUB,
+                            "mul" => MUL,
+                            "div" => DIV,
+                            "mod" => MOD,
+                            "and" => AND,
+                            "or" => OR,
+                            "xor" => XOR,
+                            "lsh" => LSH,
+                            "rsh" => RSH,
+                            _ => return bad(self),
+                        };
+                        match arg {
+                            Arg::Imm(k) => (ALU | op | K, k),
+                            _ => (ALU | op | X, 0),
+                        }
+                    },
+                    _ => return bad(self),
+                };
+                SockFilter::new(code, 0, 0, k)
+            },
+            "ja" | "jmp" => {
+                jt = Some(self.target()?);
+                SockFilter::new(JMP | JA, 0, 0, 0)
+            },
+            "jeq" | "jneq" | "jne" | "jgt" | "jle" | "jge" | "jlt" | "jset" => {
+                let (op, negate) = match word {
+                    "jeq" => (JEQ, false),
+                    "jneq" | "jne" => (JEQ, true),
+                    "jgt" => (JGT, false),
+                    "jle" => (JGT, true),
+                    "jge" => (JGE, false),
+                    "jlt" => (JGE, true),
+                    _ => (JSET, false),
+                };
+                let (code, k) = match self.arg()? {
+                    Arg::Imm(k) => (JMP | op | K, k),
+                    Arg::X => (JMP | op | X, 0),
+                    _ => return bad(self),
+                };
+                let (t, f) = self.cond_targets()?;
+                // the negated forms jump on the false branch
+                if negate {
+                    if f.is_none() {
+                        jf = t;
+                    } else {
+                        jt = f;
+                        jf = t;
+                    }
+                } else {
+                    jt = t;
+                    jf = f;
+                }
+                SockFilter::new(code, 0, 0, k)
+            },
+            _ => return Err(self.err(col, AsmErrorKind::UnknownMnemonic)),
+        };
+
+        self.end()?;
+        out.push(Pending { line: self.line, inst: code, jt, jf });
+        Ok(())
+    }
+}
+
+/// Assemble a classic program written in `bpf_asm` syntax or as a `tcpdump -d` listing
+///
+/// Comments start with `;` and run to the end of the line.
+pub fn assemble(src: &str) -> Result<Vec<SockFilter>, AsmError> {
+    let mut pending: Vec<Pending> = Vec::new();
+    let mut labels: BTreeMap<&str, usize> = BTreeMap::new();
+
+    for (idx, raw_line) in src.lines().enumerate() {
+        let line = idx + 1;
+        let text = match raw_line.find(';') {
+            Some(i) => &raw_line[..i],
+            None => raw_line,
+        };
+        let toks = lex(text, line)?;
+        let mut p = Parser {
+            toks: &toks,
+            pos: 0,
+            line,
+            eol: text.trim_end().len() + 1,
+        };
+
+        // `(NNN)` prefix from `tcpdump -d`
+        if p.peek() == Some(Tok::Punct('(')) {
+            p.pos += 1;
+            let col = p.col();
+            let n = match p.peek() {
+                Some(Tok::Num(s)) => s.parse::<usize>().ok(),
+                _ => None,
+            };
+            p.pos += 1;
+            if n != Some(pending.len()) {
+                return Err(p.err(col, AsmErrorKind::Syntax("line number does not match instruction index")));
+            }
+            p.expect(')', "expected `)`")?;
+        }
+
+        // any number of leading `label:`
+        while let (Some(Tok::Ident(name)), Some(Tok::Punct(':'))) =
+                (p.peek(), toks.get(p.pos + 1).map(|t| t.0)) {
+            let col = p.col();
+            if labels.insert(name, pending.len()).is_some() {
+                return Err(p.err(col, AsmErrorKind::DuplicateLabel));
+            }
+            p.pos += 2;
+        }
+
+        if p.pos == toks.len() {
+            continue;
+        }
+
+        p.statement(&mut pending)?;
+    }
+
+    let mut out = Vec::with_capacity(pending.len());
+    for (pc, p) in pending.iter().enumerate() {
+        let resolve = |t: JumpTo| -> Result<u32, AsmError> {
+            let (t, col) = match t {
+                Some(t) => t,
+                // fall through
+                None => return Ok(0),
+            };
+            let dest = match t {
+                Target::Label(name) => *labels.get(name)
+                    .ok_or_else(|| err(p.line, col, AsmErrorKind::UndefinedLabel))?,
+                Target::Index(i) => i,
+            };
+            if dest <= pc || dest >= pending.len() {
+                return Err(err(p.line, col, AsmErrorKind::JumpOutOfRange));
+            }
+            Ok((dest - pc - 1) as u32)
+        };
+
+        let short = |t: JumpTo| -> Result<u8, AsmError> {
+            let off = resolve(t)?;
+            if off > 0xff {
+                return Err(err(p.line, t.map(|t| t.1).unwrap_or(1), AsmErrorKind::JumpOutOfRange));
+            }
+            Ok(off as u8)
+        };
+
+        let mut inst = p.inst;
+        if inst.code == JMP | JA {
+            inst.k = resolve(p.jt)?;
+        } else {
+            inst.jt = short(p.jt)?;
+            inst.jf = short(p.jf)?;
+        }
+        out.push(inst);
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,380 @@
+//! Render instructions in the C-like textual syntax used by llvm and the kernel's verifier
+//!
+//! `Inst` implements `Display` on its own, showing jump targets as relative offsets. `Disasm`
+//! renders an entire program: jump targets are shown as labels (`L<pc>`, named by the index of the
+//! instruction they refer to) and `ld_imm64` pairs are shown as a single instruction. The output
+//! of `Disasm` can be fed back into `asm::assemble()`.
+//!
+//! An `ld_imm64` of a map by its index (`src_reg` 1) is shown as `r1 = map[0] ll`, and one of an
+//! offset into the map's value (`src_reg` 2) as `r1 = map_value[0] + 8 ll`.
+//!
+//! Instructions which are not valid encodings are shown as `<invalid 0x...: reason>`.
+//!
+//! Given the `LineInfo` of a program, `Disasm` shows the source line each instruction came from
+//! before it as a `;` comment.
+
+use super::*;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
+use btf::LineInfo;
+use core::fmt;
+
+/// Format an immediate as signed, in decimal if it is small and hex otherwise
+struct Signed(i64);
+
+impl fmt::Display for Signed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let v = self.0;
+        if v > -256 && v < 256 {
+            write!(f, "{}", v)
+        } else if v < 0 {
+            write!(f, "-{:#x}", -(v as i128))
+        } else {
+            write!(f, "{:#x}", v)
+        }
+    }
+}
+
+/// Format an immediate as unsigned, in decimal if it is small and hex otherwise
+struct Unsigned(u64);
+
+impl fmt::Display for Unsigned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 < 256 {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{:#x}", self.0)
+        }
+    }
+}
+
+/// A memory operand: `(rN + off)`
+struct MemRef(u8, i16);
+
+impl fmt::Display for MemRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.1 < 0 {
+            write!(f, "(r{} - {})", self.0, -i32::from(self.1))
+        } else {
+            write!(f, "(r{} + {})", self.0, self.1)
+        }
+    }
+}
+
+fn size_type(s: Option<Size>) -> &'static str {
+    match s {
+        Some(Size::B) => "u8",
+        Some(Size::H) => "u16",
+        Some(Size::W) => "u32",
+        Some(Size::DW) => "u64",
+        None => "?",
+    }
+}
+
+fn size_suffix(s: Option<Size>) -> &'static str {
+    match s {
+        Some(Size::B) => "b",
+        Some(Size::H) => "h",
+        Some(Size::W) => "w",
+        Some(Size::DW) => "dw",
+        None => "?",
+    }
+}
+
+/// How the target of a jump should be shown
+#[derive(Debug,Clone,Copy)]
+enum Target {
+    Rel(i32),
+    Label(usize),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Rel(off) if off < 0 => write!(f, "{}", off),
+            Target::Rel(off) => write!(f, "+{}", off),
+            Target::Label(pc) => write!(f, "L{}", pc),
+        }
+    }
+}
+
+fn write_invalid(f: &mut fmt::Formatter, i: &Inst, e: &InstDecodeError) -> fmt::Result {
+    let why = match *e {
+        InstDecodeError::InvalidEncoding(s) | InstDecodeError::ForbiddenInst(s) | InstDecodeError::Other(s) => s,
+    };
+    write!(f, "<invalid {:#018x}: {}>", i.to_u64(), why)
+}
+
+/// Write a single instruction.
+///
+/// `hi` is the upper half of the immediate for `ld_imm64`, if known.
+fn write_inst(f: &mut fmt::Formatter, i: &Inst, hi: Option<u32>, target: Target) -> fmt::Result {
+    if let Err(e) = i.check() {
+        return write_invalid(f, i, &e);
+    }
+
+    let d = i.dst();
+    let s = i.src();
+    let k = i.imm32();
+    match i.op_class() {
+        Some(Class::Alu) | Some(Class::Alu64) => {
+            let r = if i.op_class() == Some(Class::Alu64) { 'r' } else { 'w' };
+            let op = match i.op_alu() {
+                Some(OpAlu::Neg) => return write!(f, "{}{} = -{}{}", r, d, r, d),
+                Some(OpAlu::End) => {
+                    let e = if i.op_src() == Some(Src::X) { "be" } else { "le" };
+                    return write!(f, "r{} = {}{} r{}", d, e, k, d);
+                },
+                Some(OpAlu::Add) => "+=",
+                Some(OpAlu::Sub) => "-=",
+                Some(OpAlu::Mul) => "*=",
+                Some(OpAlu::Div) => "/=",
+                Some(OpAlu::Or) => "|=",
+                Some(OpAlu::And) => "&=",
+                Some(OpAlu::Lsh) => "<<=",
+                Some(OpAlu::Rsh) => ">>=",
+                Some(OpAlu::Mod) => "%=",
+                Some(OpAlu::Xor) => "^=",
+                Some(OpAlu::Mov) => "=",
+                Some(OpAlu::Arsh) => "s>>=",
+                None => unreachable!(),
+            };
+            write!(f, "{}{} {} ", r, d, op)?;
+            if i.op_src() == Some(Src::X) {
+                write!(f, "{}{}", r, s)
+            } else {
+                match i.op_alu() {
+                    Some(OpAlu::Or) | Some(OpAlu::And) | Some(OpAlu::Xor) => write!(f, "{}", Unsigned(u64::from(k))),
+                    _ => write!(f, "{}", Signed(i64::from(k as i32))),
+                }
+            }
+        },
+        Some(Class::Ld) => match i.ld_mode() {
+            // the map with index `k`, or its value `hi` bytes in
+            Some(Mode::Imm) if i.ld_size() == Some(Size::DW) => match (s, hi) {
+                (1, _) => write!(f, "r{} = map[{}] ll", d, k),
+                (2, Some(hi)) => write!(f, "r{} = map_value[{}] + {} ll", d, k, Unsigned(u64::from(hi))),
+                (2, None) => write!(f, "r{} = map_value[{}] + ? ll", d, k),
+                (_, Some(hi)) => write!(f, "r{} = {:#x} ll", d, (u64::from(hi) << 32) | u64::from(k)),
+                (_, None) => write!(f, "r{} = 0x????????{:08x} ll", d, k),
+            },
+            Some(Mode::Imm) => write!(f, "ld{} r{}, {}", size_suffix(i.ld_size()), d, Unsigned(u64::from(k))),
+            Some(Mode::Abs) => write!(f, "r{} = *({} *)skb[{}]", d, size_type(i.ld_size()), Unsigned(u64::from(k))),
+            Some(Mode::Ind) => write!(f, "r{} = *({} *)skb[r{} + {}]", d, size_type(i.ld_size()), s, Unsigned(u64::from(k))),
+            _ => unreachable!(),
+        },
+        Some(Class::Ldx) => write!(f, "r{} = *({} *){}", d, size_type(i.ld_size()), MemRef(s, i.off16())),
+        Some(Class::St) => write!(f, "*({} *){} = {}", size_type(i.ld_size()), MemRef(d, i.off16()), Signed(i64::from(k as i32))),
+        Some(Class::Stx) => {
+            if i.ld_mode() == Some(Mode::Xadd) {
+                write!(f, "lock ")?;
+            }
+            write!(f, "*({} *){}", size_type(i.ld_size()), MemRef(d, i.off16()))?;
+            if i.ld_mode() == Some(Mode::Xadd) {
+                write!(f, " += r{}", s)
+            } else {
+                write!(f, " = r{}", s)
+            }
+        },
+        Some(Class::Jmp) | Some(Class::Jmp32) => {
+            let r = if i.op_class() == Some(Class::Jmp32) { 'w' } else { 'r' };
+            let cmp = match i.op_jmp() {
+                Some(OpJmp::Ja) if r == 'w' => return write!(f, "gotol {}", target),
+                Some(OpJmp::Ja) => return write!(f, "goto {}", target),
+                // a call to another BPF function, rather than a helper
+                Some(OpJmp::Call) if s == 1 => return write!(f, "call {}", target),
+                Some(OpJmp::Call) => return write!(f, "call {}", k),
+                Some(OpJmp::Exit) => return write!(f, "exit"),
+                Some(OpJmp::Jeq) => "==",
+                Some(OpJmp::Jgt) => ">",
+                Some(OpJmp::Jge) => ">=",
+                Some(OpJmp::Jset) => "&",
+                Some(OpJmp::Jne) => "!=",
+                Some(OpJmp::Jsgt) => "s>",
+                Some(OpJmp::Jsge) => "s>=",
+                Some(OpJmp::Jlt) => "<",
+                Some(OpJmp::Jle) => "<=",
+                Some(OpJmp::Jslt) => "s<",
+                Some(OpJmp::Jsle) => "s<=",
+                None => unreachable!(),
+            };
+            write!(f, "if {}{} {} ", r, d, cmp)?;
+            if i.op_src() == Some(Src::X) {
+                write!(f, "{}{}", r, s)?;
+            } else if i.op_jmp() == Some(OpJmp::Jset) {
+                write!(f, "{}", Unsigned(u64::from(k)))?;
+            } else {
+                write!(f, "{}", Signed(i64::from(k as i32)))?;
+            }
+            write!(f, " goto {}", target)
+        },
+        None => unreachable!(),
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_inst(f, self, None, Target::Rel(self.jump_off().or_else(|| self.call_off()).unwrap_or(0)))
+    }
+}
+
+/// Disassembly of an entire program
+#[derive(Debug,Clone,Copy)]
+pub struct Disasm<'a> {
+    insts: &'a [u64],
+    #[cfg(feature = "alloc")]
+    lines: &'a [LineInfo],
+}
+
+impl<'a> Disasm<'a> {
+    pub fn new(insts: &'a [u64]) -> Self {
+        Disasm {
+            insts,
+            #[cfg(feature = "alloc")]
+            lines: &[],
+        }
+    }
+
+    /// Show the source lines of the instructions, ordered by instruction as `.BTF.ext` gives them
+    #[cfg(feature = "alloc")]
+    pub fn lines(mut self, lines: &'a [LineInfo]) -> Self {
+        self.lines = lines;
+        self
+    }
+
+    /// Iterate over the indexes at which instructions start (skipping the second half of each
+    /// `ld_imm64`)
+    fn starts(&self) -> Starts<'a> {
+        Starts { insts: self.insts, pc: 0 }
+    }
+
+    fn is_start(&self, pc: usize) -> bool {
+        self.starts().take_while(|&s| s <= pc).any(|s| s == pc)
+    }
+
+    /// If the instruction at `pc` is a jump or a call to a BPF function, return the index it
+    /// targets
+    fn jump_target(&self, pc: usize) -> Option<i64> {
+        let i = Inst::from_u64(self.insts[pc]).ok()?;
+        if i.check().is_err() {
+            return None;
+        }
+        i.jump_off().or_else(|| i.call_off()).map(|off| pc as i64 + 1 + i64::from(off))
+    }
+
+    /// Is `pc` the target of any jump?
+    #[cfg(not(feature = "alloc"))]
+    fn is_target(&self, pc: usize) -> bool {
+        self.starts().any(|s| self.jump_target(s) == Some(pc as i64))
+    }
+
+    /// The single instruction at `pc`, rendered with the same labels used by the full listing.
+    pub fn at(&self, pc: usize) -> DisasmInst<'a> {
+        DisasmInst { disasm: *self, pc }
+    }
+}
+
+struct Starts<'a> {
+    insts: &'a [u64],
+    pc: usize,
+}
+
+impl<'a> Iterator for Starts<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let pc = self.pc;
+        let raw = *self.insts.get(pc)?;
+        let i = Inst::from_u64(raw).ok()?;
+        self.pc += if i.is_ld_imm64() { 2 } else { 1 };
+        Some(pc)
+    }
+}
+
+/// A single instruction within a `Disasm`
+#[derive(Debug,Clone,Copy)]
+pub struct DisasmInst<'a> {
+    disasm: Disasm<'a>,
+    pc: usize,
+}
+
+impl<'a> Disasm<'a> {
+    /// Write the instruction at `pc`. `is_start` reports if an index is the start of an
+    /// instruction, which decides if jump targets are shown as labels.
+    fn write_at<S: Fn(usize) -> bool>(&self, f: &mut fmt::Formatter, pc: usize, is_start: S) -> fmt::Result {
+        let i = match self.insts.get(pc) {
+            Some(&raw) => Inst::from_u64(raw).unwrap(),
+            None => return write!(f, "<out of bounds>"),
+        };
+
+        let hi = if i.is_ld_imm64() && i.check().is_ok() {
+            let hi = match self.insts.get(pc + 1) {
+                Some(&raw) => Inst::from_u64(raw).unwrap(),
+                None => return write_invalid(f, &i, &InstDecodeError::InvalidEncoding("ld_imm64 is missing second half")),
+            };
+            if let Err(e) = hi.check_imm64_hi() {
+                return write_invalid(f, &i, &e);
+            }
+            Some(hi.imm32())
+        } else {
+            None
+        };
+
+        let target = match self.jump_target(pc) {
+            Some(t) if t >= 0 && (t as usize) < self.insts.len() && is_start(t as usize) => Target::Label(t as usize),
+            _ => Target::Rel(i.jump_off().or_else(|| i.call_off()).unwrap_or(0)),
+        };
+
+        write_inst(f, &i, hi, target)
+    }
+}
+
+impl<'a> fmt::Display for DisasmInst<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.disasm;
+        d.write_at(f, self.pc, |t| d.is_start(t))
+    }
+}
+
+impl<'a> fmt::Display for Disasm<'a> {
+    #[cfg(feature = "alloc")]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // with an allocator, avoid rescanning the program for every instruction
+        let n = self.insts.len();
+        let mut start = Vec::new();
+        start.resize(n, false);
+        let mut target = start.clone();
+        for pc in self.starts() {
+            start[pc] = true;
+            match self.jump_target(pc) {
+                Some(t) if t >= 0 && (t as usize) < n => target[t as usize] = true,
+                _ => {},
+            }
+        }
+
+        for pc in self.starts() {
+            if target[pc] {
+                writeln!(f, "L{}:", pc)?;
+            }
+            if let Ok(l) = self.lines.binary_search_by_key(&pc, |l| l.insn) {
+                writeln!(f, "    ; {}", self.lines[l])?;
+            }
+            write!(f, "    ")?;
+            self.write_at(f, pc, |t| start[t])?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for pc in self.starts() {
+            if self.is_target(pc) {
+                writeln!(f, "L{}:", pc)?;
+            }
+            writeln!(f, "    {}", self.at(pc))?;
+        }
+        Ok(())
+    }
+}
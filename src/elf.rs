@@ -0,0 +1,668 @@
+//! Loading programs from the ELF relocatable objects clang and llc produce for `-target bpf`
+//!
+//! Each executable section other than `.text` holds a program, named by its section (`socket`,
+//! `xdp`, `classifier/ingress`, ...). `.text` holds functions programs may call: each function a
+//! program calls, directly or through another function, is appended to the program and the call's
+//! offset fixed to reach it. `ld_imm64` of a map (a symbol in the `maps` or `.maps` section)
+//! becomes a map load (`src_reg` 1) of the map's index in `Object::maps`.
+//!
+//! Maps in `maps` are defined as libbpf's legacy `struct bpf_map_def`: the type, key size, value
+//! size, max entries and flags as 32-bit integers. Maps in `.maps` are described by BTF, with a
+//! member for each of these: `__uint(type, ...)` gives `int (*type)[...]`, and `__type(key, ...)`
+//! gives a pointer to the key type. `Object::env` adds every map to an `Env`, in order, and
+//! `Object::create_maps` makes a `map::Map` of each for `Invoke`.
+//!
+//! With `.BTF.ext`, each program has the `FuncInfo` and `LineInfo` of its own instructions and of
+//! those of the functions appended to it.
+//!
+//! References:
+//!  - https://refspecs.linuxfoundation.org/elf/gabi4+/contents.html
+//!  - https://www.kernel.org/doc/html/latest/bpf/llvm_reloc.html
+
+use super::*;
+use alloc::string::String;
+use alloc::vec::Vec;
+use btf::{Btf, BtfError, BtfExt, FuncInfo, Kind, LineInfo};
+use bytes::{self, LoadError};
+use core::fmt;
+use map::Map;
+use profile::Profile;
+use verifier::{Env, MapDef};
+
+const EM_BPF: u16 = 247;
+const ET_REL: u16 = 1;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_REL: u32 = 9;
+const SHF_EXECINSTR: u64 = 4;
+
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STB_GLOBAL: u8 = 1;
+
+const R_BPF_64_64: u32 = 1;
+const R_BPF_64_32: u32 = 10;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ElfError {
+    /// Not a 64-bit ELF relocatable object for BPF
+    NotBpf,
+    /// A header, section, or table extends past the end of the file
+    Truncated,
+    /// A header, section, or table contains an invalid value
+    Malformed(&'static str),
+    /// The instructions of the section `section` can't be decoded
+    Load {
+        section: String,
+        err: LoadError,
+    },
+    /// The relocation at byte `offset` of the section `section` can't be applied
+    Relocation {
+        section: String,
+        offset: u64,
+        reason: &'static str,
+    },
+    /// The `.BTF` section can't be parsed
+    Btf(BtfError),
+    /// The definition of the map `name` can't be read
+    Map {
+        name: String,
+        reason: &'static str,
+    },
+}
+
+impl From<BtfError> for ElfError {
+    fn from(e: BtfError) -> Self {
+        ElfError::Btf(e)
+    }
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ElfError::NotBpf => write!(f, "not an ELF relocatable object for BPF"),
+            ElfError::Truncated => write!(f, "truncated ELF object"),
+            ElfError::Malformed(s) => write!(f, "malformed ELF object: {}", s),
+            ElfError::Load { ref section, ref err } => write!(f, "section {}: {}", section, err),
+            ElfError::Relocation { ref section, offset, reason } =>
+                write!(f, "section {}: relocation at offset {}: {}", section, offset, reason),
+            ElfError::Btf(ref e) => write!(f, "{}", e),
+            ElfError::Map { ref name, reason } => write!(f, "map {}: {}", name, reason),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offs: u64, len: u64) -> Result<&'a [u8], ElfError> {
+        let end = offs.checked_add(len).ok_or(ElfError::Truncated)?;
+        if end > self.data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+        Ok(&self.data[offs as usize..end as usize])
+    }
+
+    fn u8(&self, offs: u64) -> Result<u8, ElfError> {
+        Ok(self.bytes(offs, 1)?[0])
+    }
+
+    fn u16(&self, offs: u64) -> Result<u16, ElfError> {
+        let b = self.bytes(offs, 2)?;
+        let b = [b[0], b[1]];
+        Ok(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32(&self, offs: u64) -> Result<u32, ElfError> {
+        let b = self.bytes(offs, 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    fn u64(&self, offs: u64) -> Result<u64, ElfError> {
+        let b = self.bytes(offs, 8)?;
+        let b = [b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]];
+        Ok(if self.big_endian { u64::from_be_bytes(b) } else { u64::from_le_bytes(b) })
+    }
+
+    /// The nul terminated string at `offs`
+    fn str(&self, offs: u64) -> Result<&'a str, ElfError> {
+        let rest = self.data.get(offs as usize..).ok_or(ElfError::Truncated)?;
+        let len = rest.iter().position(|&b| b == 0).ok_or(ElfError::Truncated)?;
+        core::str::from_utf8(&rest[..len]).map_err(|_| ElfError::Malformed("name is not utf-8"))
+    }
+}
+
+#[derive(Debug,Clone)]
+struct Section<'a> {
+    name: &'a str,
+    ty: u32,
+    flags: u64,
+    data: &'a [u8],
+    link: u32,
+    info: u32,
+}
+
+#[derive(Debug,Clone)]
+struct Symbol<'a> {
+    name: &'a str,
+    ty: u8,
+    bind: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+#[derive(Debug,Clone,Copy)]
+struct Rel {
+    offset: u64,
+    sym: u32,
+    ty: u32,
+}
+
+/// A function of `.text`, from `start` to `end` in instructions
+#[derive(Debug,Clone,Copy)]
+struct Func {
+    start: usize,
+    end: usize,
+}
+
+/// A map defined by an object
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ElfMap {
+    /// The name of the map's symbol
+    pub name: String,
+    pub def: MapDef,
+}
+
+/// A program from an object, with the functions it calls appended and relocations applied
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ElfProgram {
+    section: String,
+    name: String,
+    insts: Vec<u64>,
+    func_info: Vec<FuncInfo>,
+    line_info: Vec<LineInfo>,
+}
+
+impl ElfProgram {
+    /// The name of the section holding the program, which gives its type
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    /// The name of the program's function, or of its section if it has no function symbol
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The program's instructions, ready for `Env::verify`
+    pub fn insts(&self) -> &[u64] {
+        &self.insts
+    }
+
+    /// Where each function in the program starts, from `.BTF.ext`
+    pub fn func_info(&self) -> &[FuncInfo] {
+        &self.func_info
+    }
+
+    /// The source lines of the program's instructions, from `.BTF.ext`
+    pub fn line_info(&self) -> &[LineInfo] {
+        &self.line_info
+    }
+
+    /// The `Profile` of the program's type, going by the section name as libbpf does, if it is
+    /// one of the types `Profile` describes
+    pub fn profile(&self) -> Option<Profile> {
+        let s = &self.section[..];
+        let is = |prefix: &str| s == prefix || s.starts_with(prefix) && s[prefix.len()..].starts_with('/');
+        if is("socket") {
+            Some(Profile::socket_filter())
+        } else if is("xdp") {
+            Some(Profile::xdp())
+        } else if ["kprobe", "kretprobe", "uprobe", "uretprobe", "tracepoint", "tp", "perf_event"]
+                .iter().any(|p| is(p)) {
+            Some(Profile::tracing())
+        } else {
+            None
+        }
+    }
+}
+
+/// The programs and maps of an ELF object
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Object {
+    endian: Endian,
+    programs: Vec<ElfProgram>,
+    maps: Vec<ElfMap>,
+    btf: Option<Btf>,
+}
+
+impl Object {
+    /// Parse the object in `data`, linking each program with the functions it calls
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        if data.len() < 16 || data[..4] != [0x7f, b'E', b'L', b'F'] || data[4] != 2 {
+            return Err(ElfError::NotBpf);
+        }
+        let (endian, big_endian) = match data[5] {
+            1 => (Endian::Little, false),
+            2 => (Endian::Big, true),
+            _ => return Err(ElfError::Malformed("unknown data encoding")),
+        };
+        let r = Reader { data, big_endian };
+        if r.u16(0x12)? != EM_BPF {
+            return Err(ElfError::NotBpf);
+        }
+        if r.u16(0x10)? != ET_REL {
+            return Err(ElfError::Malformed("not a relocatable object"));
+        }
+
+        let sections = Self::sections(r)?;
+        let symbols = Self::symbols(r, &sections)?;
+        let find = |name: &str| sections.iter().position(|s| s.name == name);
+
+        // maps, in order of their definitions
+        let map_sections = [find("maps"), find(".maps")];
+        let mut map_syms: Vec<&Symbol> = symbols.iter()
+            .filter(|s| s.ty != STT_SECTION && map_sections.contains(&Some(usize::from(s.shndx))))
+            .collect();
+        map_syms.sort_by_key(|s| (s.shndx, s.value));
+
+        let btf = match find(".BTF") {
+            Some(b) => Some(Btf::parse(sections[b].data)?),
+            None => None,
+        };
+        let ext = match (btf.as_ref(), find(".BTF.ext")) {
+            (Some(btf), Some(e)) => BtfExt::parse(sections[e].data, btf)?,
+            _ => BtfExt::default(),
+        };
+        let maps = map_syms.iter().map(|sym| {
+            let sec = &sections[usize::from(sym.shndx)];
+            let err = |reason| ElfError::Map { name: String::from(sym.name), reason };
+            let def = if sec.name == "maps" {
+                legacy_map_def(r, sec, sym).ok_or_else(|| err("definition extends past the end of maps"))?
+            } else {
+                let btf = btf.as_ref().ok_or_else(|| err("no BTF describing .maps"))?;
+                btf_map_def(btf, sym).map_err(err)?
+            };
+            Ok(ElfMap { name: String::from(sym.name), def })
+        }).collect::<Result<_, ElfError>>()?;
+
+        let text = find(".text");
+        let text_insts = match text {
+            Some(t) => Self::load(&sections[t], endian)?,
+            None => Vec::new(),
+        };
+
+        // functions of `.text`, sized to reach the next if their symbol has no size
+        let mut funcs: Vec<Func> = symbols.iter()
+            .filter(|s| s.ty == STT_FUNC && Some(usize::from(s.shndx)) == text)
+            .map(|s| Func { start: (s.value / 8) as usize, end: s.value.saturating_add(s.size).div_ceil(8) as usize })
+            .collect();
+        funcs.sort_by_key(|f| f.start);
+        funcs.dedup_by_key(|f| f.start);
+        for i in 0..funcs.len() {
+            if funcs[i].end <= funcs[i].start {
+                funcs[i].end = funcs.get(i + 1).map_or(text_insts.len(), |f| f.start);
+            }
+        }
+
+        let linker = Linker {
+            big_endian,
+            sections: &sections,
+            symbols: &symbols,
+            maps: &map_syms,
+            text,
+            text_insts: &text_insts,
+            funcs: &funcs,
+        };
+        let mut programs = Vec::new();
+        for (idx, s) in sections.iter().enumerate() {
+            if s.flags & SHF_EXECINSTR == 0 || Some(idx) == text || s.data.is_empty() {
+                continue;
+            }
+
+            let name = symbols.iter()
+                .find(|sym| sym.ty == STT_FUNC && sym.bind == STB_GLOBAL && usize::from(sym.shndx) == idx
+                    && sym.value == 0)
+                .map_or(s.name, |sym| sym.name);
+
+            let (insts, placed) = linker.link(idx, Self::load(s, endian)?)?;
+            programs.push(ElfProgram {
+                section: String::from(s.name),
+                name: String::from(name),
+                insts,
+                func_info: linker.info(ext.func_info(s.name), ext.func_info(".text"), &placed, |f| &mut f.insn),
+                line_info: linker.info(ext.line_info(s.name), ext.line_info(".text"), &placed, |l| &mut l.insn),
+            });
+        }
+
+        Ok(Object { endian, programs, maps, btf })
+    }
+
+    fn sections(r: Reader) -> Result<Vec<Section>, ElfError> {
+        let shoff = r.u64(0x28)?;
+        let shentsize = u64::from(r.u16(0x3a)?);
+        let shnum = u64::from(r.u16(0x3c)?);
+        let shstrndx = u64::from(r.u16(0x3e)?);
+        if shnum != 0 && shentsize < 64 {
+            return Err(ElfError::Malformed("section headers are too small"));
+        }
+        if shstrndx >= shnum {
+            return Err(ElfError::Malformed("no section name table"));
+        }
+
+        // a header at the end of the address space is as far past the end of the file as any
+        let field = |h: u64, off: u64| h.checked_add(off).ok_or(ElfError::Truncated);
+        let header = |i: u64| field(shoff, i * shentsize);
+        let strtab = header(shstrndx)?;
+        let names = Reader { data: r.bytes(r.u64(field(strtab, 24)?)?, r.u64(field(strtab, 32)?)?)?, ..r };
+
+        (0..shnum).map(|i| {
+            let h = header(i)?;
+            let ty = r.u32(field(h, 4)?)?;
+            // `SHT_NOBITS` takes no space in the file
+            let data = if ty == 8 { &[][..] } else { r.bytes(r.u64(field(h, 24)?)?, r.u64(field(h, 32)?)?)? };
+            Ok(Section {
+                name: names.str(u64::from(r.u32(h)?))?,
+                ty,
+                flags: r.u64(field(h, 8)?)?,
+                data,
+                link: r.u32(field(h, 40)?)?,
+                info: r.u32(field(h, 44)?)?,
+            })
+        }).collect()
+    }
+
+    fn symbols<'a>(r: Reader<'a>, sections: &[Section<'a>]) -> Result<Vec<Symbol<'a>>, ElfError> {
+        let symtab = match sections.iter().find(|s| s.ty == SHT_SYMTAB) {
+            Some(s) => s,
+            None => return Ok(Vec::new()),
+        };
+        let strtab = sections.get(symtab.link as usize)
+            .ok_or(ElfError::Malformed("symbol table has no string table"))?;
+        let names = Reader { data: strtab.data, ..r };
+        let t = Reader { data: symtab.data, ..r };
+
+        (0..symtab.data.len() as u64 / 24).map(|i| {
+            let s = i * 24;
+            let info = t.u8(s + 4)?;
+            let shndx = t.u16(s + 6)?;
+            let ty = info & 0xf;
+            Ok(Symbol {
+                // section symbols are named by their section
+                name: if ty == STT_SECTION {
+                    sections.get(usize::from(shndx)).map_or("", |sec| sec.name)
+                } else {
+                    names.str(u64::from(t.u32(s)?))?
+                },
+                ty,
+                bind: info >> 4,
+                shndx,
+                value: t.u64(s + 8)?,
+                size: t.u64(s + 16)?,
+            })
+        }).collect()
+    }
+
+    fn load(s: &Section, endian: Endian) -> Result<Vec<u64>, ElfError> {
+        bytes::load_raw(s.data, endian).map_err(|err| ElfError::Load { section: String::from(s.name), err })
+    }
+
+    /// The byte order of the object
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// Every program, in the order of their sections
+    pub fn programs(&self) -> &[ElfProgram] {
+        &self.programs
+    }
+
+    /// The program with the function or section name `name`
+    pub fn program(&self, name: &str) -> Option<&ElfProgram> {
+        self.programs.iter().find(|p| p.name == name).or_else(|| self.programs.iter().find(|p| p.section == name))
+    }
+
+    /// The maps programs refer to, by the index their `ld_imm64` instructions hold
+    pub fn maps(&self) -> &[ElfMap] {
+        &self.maps
+    }
+
+    /// The types of the `.BTF` section, if there is one
+    pub fn btf(&self) -> Option<&Btf> {
+        self.btf.as_ref()
+    }
+
+    /// An `Env` for verifying `prgm`, with the `Profile` of its type if it has one, every map, and
+    /// the program's source lines
+    pub fn env(&self, prgm: &ElfProgram) -> Env {
+        let env = match prgm.profile() {
+            Some(p) => Env::default().profile(p),
+            None => Env::default(),
+        };
+        self.maps.iter().fold(env, |env, m| env.map(m.def)).line_info(prgm.line_info.clone())
+    }
+
+    /// An empty `Map` for each of `maps`, in order, for the programs to use when run by `Invoke`
+    pub fn create_maps(&self) -> Result<Vec<Map>, ElfError> {
+        self.maps.iter()
+            .map(|m| Map::new(m.def).map_err(|reason| ElfError::Map { name: m.name.clone(), reason }))
+            .collect()
+    }
+}
+
+/// Read a `struct bpf_map_def`, which may be shorter than ours in older objects
+fn legacy_map_def(r: Reader, sec: &Section, sym: &Symbol) -> Option<MapDef> {
+    let size = if sym.size == 0 { 20 } else { sym.size.min(20) };
+    let data = sec.data.get(sym.value as usize..)?.get(..size as usize)?;
+    let r = Reader { data, ..r };
+    let field = |i: u64| r.u32(i * 4).unwrap_or(0);
+    Some(MapDef {
+        map_type: field(0),
+        key_size: field(1),
+        value_size: field(2),
+        max_entries: field(3),
+        flags: field(4),
+    })
+}
+
+/// Read the definition of a map in `.maps` from the BTF of its variable
+fn btf_map_def(btf: &Btf, sym: &Symbol) -> Result<MapDef, &'static str> {
+    let vars = btf.types().iter()
+        .filter_map(|t| match t.kind {
+            Kind::DataSec { ref vars, .. } if t.name == ".maps" => Some(vars),
+            _ => None,
+        })
+        .next()
+        .ok_or("no BTF for .maps")?;
+    let var = vars.iter().find(|v| u64::from(v.offset) == sym.value).ok_or("no BTF variable")?;
+    let members = match btf.get(var.ty).map(|t| &t.kind) {
+        Some(&Kind::Var { ty, .. }) => match btf.get(btf.resolve(ty)).map(|t| &t.kind) {
+            Some(Kind::Struct { members, .. }) => members,
+            _ => return Err("BTF variable is not a struct"),
+        },
+        _ => return Err("BTF of .maps refers to something which is not a variable"),
+    };
+
+    // `__uint(name, val)` is `int (*name)[val]`, and `__type(name, val)` is `typeof(val) *name`
+    let target = |ty| match btf.get(btf.resolve(ty)).map(|t| &t.kind) {
+        Some(&Kind::Ptr { ty }) => Ok(ty),
+        _ => Err("map attribute is not a pointer"),
+    };
+    let uint = |ty| match btf.get(btf.resolve(target(ty)?)).map(|t| &t.kind) {
+        Some(&Kind::Array { nelems, .. }) => Ok(nelems),
+        _ => Err("__uint map attribute does not point to an array"),
+    };
+    let size = |ty| btf.size(target(ty)?).ok_or("__type map attribute has no size");
+
+    let mut def = MapDef::default();
+    for m in members {
+        match &m.name[..] {
+            "type" => def.map_type = uint(m.ty)?,
+            "key_size" => def.key_size = uint(m.ty)?,
+            "key" => def.key_size = size(m.ty)?,
+            "value_size" => def.value_size = uint(m.ty)?,
+            "value" => def.value_size = size(m.ty)?,
+            "max_entries" => def.max_entries = uint(m.ty)?,
+            "map_flags" => def.flags = uint(m.ty)?,
+            // `pinning`, `values`, and others which don't change the map's format
+            _ => {},
+        }
+    }
+    Ok(def)
+}
+
+/// The functions of `.text` appended to a program, by index in `Linker::funcs`, and where each
+/// starts in the program
+type Placed = Vec<(usize, usize)>;
+
+/// Links a program with the functions of `.text` it calls
+struct Linker<'s, 'a: 's> {
+    big_endian: bool,
+    sections: &'s [Section<'a>],
+    symbols: &'s [Symbol<'a>],
+    maps: &'s [&'s Symbol<'a>],
+    text: Option<usize>,
+    text_insts: &'s [u64],
+    funcs: &'s [Func],
+}
+
+impl<'s, 'a> Linker<'s, 'a> {
+    /// Append the functions the program in `section` calls to `insts`
+    fn link(&self, section: usize, mut insts: Vec<u64>) -> Result<(Vec<u64>, Placed), ElfError> {
+        // the placed functions from `next` on are still to be relocated
+        let mut placed = Vec::new();
+        let len = insts.len();
+        self.relocate(&mut insts, &mut placed, section, 0, 0, len)?;
+
+        let mut next = 0;
+        while let Some(&(f, base)) = placed.get(next) {
+            let func = self.funcs[f];
+            self.relocate(&mut insts, &mut placed, self.text.unwrap(), func.start, base, func.end - func.start)?;
+            next += 1;
+        }
+        Ok((insts, placed))
+    }
+
+    /// The `prgm` info of a program, followed by the `text` info of the functions `placed` after
+    /// it, moved to where they were placed
+    fn info<T: Clone, F: Fn(&mut T) -> &mut usize>(&self, prgm: &[T], text: &[T], placed: &Placed,
+        insn: F) -> Vec<T>
+    {
+        let mut info = prgm.to_vec();
+        for &(f, base) in placed {
+            let func = self.funcs[f];
+            info.extend(text.iter().cloned().filter_map(|mut t| {
+                let i = insn(&mut t);
+                if *i < func.start || *i >= func.end {
+                    return None;
+                }
+                *i = base + *i - func.start;
+                Some(t)
+            }));
+        }
+        info
+    }
+
+    /// Apply the relocations of `len` instructions from `start` in `section`, which have been
+    /// placed at `base` in `insts`
+    fn relocate(&self, insts: &mut Vec<u64>, placed: &mut Placed, section: usize, start: usize,
+        base: usize, len: usize) -> Result<(), ElfError>
+    {
+        let name = self.sections[section].name;
+        let err = |offset: u64, reason| ElfError::Relocation { section: String::from(name), offset, reason };
+        let rels = self.rels(section)?;
+        let range = (start as u64 * 8)..((start + len) as u64 * 8);
+
+        for rel in rels.iter().filter(|rel| range.contains(&rel.offset)) {
+            if rel.offset % 8 != 0 {
+                return Err(err(rel.offset, "not at the start of an instruction"));
+            }
+            let pc = base + (rel.offset / 8) as usize - start;
+            let sym = self.symbols.get(rel.sym as usize).ok_or_else(|| err(rel.offset, "no such symbol"))?;
+            let i = Inst::from_u64(insts[pc]).unwrap();
+
+            match rel.ty {
+                R_BPF_64_64 => {
+                    if !i.is_ld_imm64() || pc + 1 >= insts.len() {
+                        return Err(err(rel.offset, "R_BPF_64_64 is not on a ld_imm64"));
+                    }
+                    let off = sym.value.wrapping_add(u64::from(i.imm32()));
+                    let map = self.maps.iter()
+                        .position(|m| m.shndx == sym.shndx && m.value == off)
+                        .ok_or_else(|| err(rel.offset, "ld_imm64 of a symbol which is not a map"))?;
+                    insts[pc] = (insts[pc] & !0x00f0_0000_ffff_ffff) | (1 << 52) | map as u64;
+                },
+                R_BPF_64_32 => {
+                    if i.call_off().is_none() {
+                        return Err(err(rel.offset, "R_BPF_64_32 is not on a call to a function"));
+                    }
+                    if Some(usize::from(sym.shndx)) != self.text {
+                        return Err(err(rel.offset, "call to a function outside .text"));
+                    }
+                    let target = (sym.value / 8) as i64 + i64::from(i.imm32() as i32) + 1;
+                    let to = self.place(insts, placed, target).ok_or_else(|| err(rel.offset, "call to no function"))?;
+                    set_call(insts, pc, to);
+                },
+                _ => return Err(err(rel.offset, "unsupported relocation type")),
+            }
+        }
+
+        // calls between functions of `.text` may be left for the assembler to resolve, and are
+        // relative to where the function was in `.text`
+        if Some(section) == self.text {
+            for pc in base..base + len {
+                let i = Inst::from_u64(insts[pc]).unwrap();
+                let offset = (start + pc - base) as u64 * 8;
+                if i.call_off().is_none() || rels.iter().any(|rel| rel.offset == offset) {
+                    continue;
+                }
+                let target = (start + pc - base) as i64 + i64::from(i.imm32() as i32) + 1;
+                let to = self.place(insts, placed, target).ok_or_else(|| err(offset, "call to no function"))?;
+                set_call(insts, pc, to);
+            }
+        }
+        Ok(())
+    }
+
+    /// The relocations for `section`
+    fn rels(&self, section: usize) -> Result<Vec<Rel>, ElfError> {
+        let mut rels = Vec::new();
+        for s in self.sections.iter().filter(|s| s.ty == SHT_REL && s.info as usize == section) {
+            let r = Reader { data: s.data, big_endian: self.big_endian };
+            for i in 0..s.data.len() as u64 / 16 {
+                let info = r.u64(i * 16 + 8)?;
+                rels.push(Rel { offset: r.u64(i * 16)?, sym: (info >> 32) as u32, ty: info as u32 });
+            }
+        }
+        Ok(rels)
+    }
+
+    /// Where instruction `target` of `.text` is in `insts`, appending the function holding it if
+    /// it is not there yet
+    fn place(&self, insts: &mut Vec<u64>, placed: &mut Placed, target: i64) -> Option<usize> {
+        let f = self.funcs.iter().position(|f| f.start as i64 <= target && target < f.end as i64)?;
+        let func = self.funcs[f];
+        let base = match placed.iter().find(|p| p.0 == f) {
+            Some(&(_, base)) => base,
+            None => {
+                let base = insts.len();
+                insts.extend_from_slice(self.text_insts.get(func.start..func.end)?);
+                placed.push((f, base));
+                base
+            },
+        };
+        Some(base + target as usize - func.start)
+    }
+}
+
+/// Point the call at `pc` to `to`
+fn set_call(insts: &mut [u64], pc: usize, to: usize) {
+    let imm = (to as i64 - pc as i64 - 1) as i32;
+    insts[pc] = (insts[pc] & !0xffff_ffff) | u64::from(imm as u32);
+}
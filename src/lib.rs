@@ -18,18 +18,46 @@
 #![no_std]
 
 // TODO: can this be made performant for 32-bit systems?
-// TODO: can we make sanitize mode? (iow: verify on run)
+
+#[cfg(feature = "std")]
+extern crate std;
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
 #[macro_use]
 extern crate enum_primitive_derive;
 extern crate num_traits;
+extern crate bit_domains;
 
-mod build;
-mod verifier;
+pub mod build;
+#[cfg(feature = "alloc")]
+pub mod verifier;
 //mod buffer;
+pub mod pcap;
+pub mod replay;
+pub mod disasm;
+pub mod sanitize;
+#[cfg(feature = "alloc")]
+pub mod bytes;
+#[cfg(feature = "alloc")]
+pub mod elf;
+#[cfg(feature = "alloc")]
+pub mod btf;
+#[cfg(feature = "alloc")]
+pub mod asm;
+#[cfg(feature = "alloc")]
+pub mod classic;
+#[cfg(feature = "alloc")]
+pub mod cfg;
+#[cfg(feature = "alloc")]
+pub mod liveness;
+#[cfg(feature = "alloc")]
+pub mod profile;
+#[cfg(feature = "alloc")]
+pub mod map;
 
-//mod tnum;
-//pub use tnum::Tnum;
+#[cfg(feature = "alloc")]
+mod scalar;
 
 /// Broad class that an instruction fits into
 ///
@@ -51,6 +79,10 @@ enum Class {
     /// Conditional & unconditional jumps
     Jmp = 0x05,
 
+    /// Conditional jumps comparing the lower 32 bits of registers. `Ja` from this class (`gotol`)
+    /// takes its offset from `imm` rather than `off`.
+    Jmp32 = 0x06,
+
     /// Arithmetic in 64 bits
     Alu64 = 0x07,
 }
@@ -130,9 +162,9 @@ enum OpJmp {
 }
 
 /// Size for `Class::St`, `Class::Stx`, `Class:Ld`, and `Class::Ldx`
-#[derive(Debug,Eq,PartialEq,Primitive)]
+#[derive(Debug,Clone,Copy,Eq,PartialEq,Primitive)]
 #[repr(u8)]
-enum Size {
+pub enum Size {
     /// u32, "word"
     W = 0x00,
     /// u16, "half word"
@@ -143,6 +175,18 @@ enum Size {
     DW= 0x18,
 }
 
+impl Size {
+    /// The number of bytes loaded or stored
+    pub fn bytes(self) -> usize {
+        match self {
+            Size::W => 4,
+            Size::H => 2,
+            Size::B => 1,
+            Size::DW => 8,
+        }
+    }
+}
+
 /// Mode for `Class::St`, `Class::Stx`, `Class:Ld`, and `Class::Ldx`
 ///
 /// Indicates where the meaning of the destination
@@ -186,17 +230,30 @@ enum Mode {
     Xadd = 0xc0,
 }
 
-#[derive(Debug,Eq,PartialEq)]
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
 pub enum InstDecodeError {
     InvalidEncoding(&'static str),
     ForbiddenInst(&'static str),
     Other(&'static str)
 }
 
+/// The byte order of an instruction's 8 byte encoding
+///
+/// This orders the offset and immediate, and also the register nibbles: `dst_reg` is the low
+/// nibble of the second byte in little endian, and the high nibble in big endian.
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
 /// An instruction split into rough fields.
-#[derive(Debug,Eq,PartialEq)]
+///
+/// Note that `ld_imm64` is encoded as 2 consecutive instructions, the second of which holds the
+/// upper 32-bits of the immediate.
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
 #[repr(C)]
-struct Inst {
+pub struct Inst {
     /// layout for ld/st:
     /// 
     ///   +- 3b -+- 2b -+-- 3b -+
@@ -213,7 +270,7 @@ struct Inst {
 }
 
 impl Inst {
-    fn op(&self) -> u8 {
+    pub fn op(&self) -> u8 {
         self.op
     }
 
@@ -256,7 +313,7 @@ impl Inst {
         num_traits::FromPrimitive::from_u8(self.raw_op_code())
     }
 
-    fn op_alu(&self) -> Option<OpJmp> {
+    fn op_alu(&self) -> Option<OpAlu> {
         num_traits::FromPrimitive::from_u8(self.raw_op_code())
     }
 
@@ -268,62 +325,345 @@ impl Inst {
         num_traits::FromPrimitive::from_u8(self.raw_ld_mode())
     }
 
-    fn src(&self) -> u8
+    pub fn src(&self) -> u8
     {
         (self.src_dst & 0xf0) >> 4
     }
 
-    fn dst(&self) -> u8
+    pub fn dst(&self) -> u8
     {
         self.src_dst & 0x0f
     }
 
-    fn off16(&self) -> i16
+    pub fn off16(&self) -> i16
     {
         self.off as i16
     }
 
-    fn imm32(&self) -> u32
+    pub fn imm32(&self) -> u32
     {
         self.imm
     }
 
     fn from_raw_parts(op: u8, src_dst: u8, off: u16, imm: u32) -> Result<Self, InstDecodeError> {
         Ok(Self {
-            op: op, src_dst: src_dst, off: off, imm: imm
+            op, src_dst, off, imm
         })
     }
 
-    fn from_u64(raw: u64) -> Result<Self, InstDecodeError> {
-        let op      = ((raw & 0xff_00_00_00__00_00_00_00) >> (24+32)) as u8;
-        let src_dst = ((raw & 0x00_ff_00_00__00_00_00_00) >> (16+32)) as u8;
-        let off     = ((raw & 0x00_00_ff_ff__00_00_00_00) >> 32) as u16;
-        let imm     =  (raw & 0x00_00_00_00__ff_ff_ff_ff) as u32;
+    /// Decode the 8 bytes of an instruction, as stored in memory in `endian` order
+    pub fn from_bytes(b: [u8;8], endian: Endian) -> Result<Self, InstDecodeError> {
+        match endian {
+            Endian::Little => Self::from_raw_parts(b[0], b[1],
+                u16::from_le_bytes([b[2], b[3]]),
+                u32::from_le_bytes([b[4], b[5], b[6], b[7]])),
+            Endian::Big => Self::from_raw_parts(b[0], b[1].rotate_left(4),
+                u16::from_be_bytes([b[2], b[3]]),
+                u32::from_be_bytes([b[4], b[5], b[6], b[7]])),
+        }
+    }
+
+    /// The 8 bytes of this instruction, as stored in memory in `endian` order
+    pub fn to_bytes(&self, endian: Endian) -> [u8;8] {
+        let (regs, off, imm) = match endian {
+            Endian::Little => (self.src_dst, self.off.to_le_bytes(), self.imm.to_le_bytes()),
+            Endian::Big => (self.src_dst.rotate_left(4), self.off.to_be_bytes(), self.imm.to_be_bytes()),
+        };
+        [self.op, regs, off[0], off[1], imm[0], imm[1], imm[2], imm[3]]
+    }
+
+    pub fn from_u64(raw: u64) -> Result<Self, InstDecodeError> {
+        let op      = (raw >> (24+32)) as u8;
+        let src_dst = (raw >> (16+32)) as u8;
+        let off     = (raw >> 32) as u16;
+        let imm     =  raw as u32;
         let x = Self {
-            op: op, src_dst: src_dst, off: off, imm: imm
+            op, src_dst, off, imm
         };
 
         Ok(x)
     }
 
-    fn to_u64(&self) -> u64
+    pub fn to_u64(&self) -> u64
     {
         ((self.op as u64) << (24+32))
             | ((self.src_dst as u64) << (16+32))
             | ((self.off as u64) << 32)
             | (self.imm as u64)
     }
+
+    /// Is this the first half of a `ld_imm64`?
+    fn is_ld_imm64(&self) -> bool {
+        self.op_class() == Some(Class::Ld)
+            && self.ld_mode() == Some(Mode::Imm)
+            && self.ld_size() == Some(Size::DW)
+    }
+
+    /// If this is a jump (conditional or not), the offset of its target from the following
+    /// instruction
+    fn jump_off(&self) -> Option<i32> {
+        match (self.op_class(), self.op_jmp()) {
+            (Some(Class::Jmp), Some(OpJmp::Call)) | (Some(Class::Jmp), Some(OpJmp::Exit)) => None,
+            (Some(Class::Jmp32), Some(OpJmp::Ja)) => Some(self.imm32() as i32),
+            (Some(Class::Jmp), Some(_)) | (Some(Class::Jmp32), Some(_)) => Some(i32::from(self.off16())),
+            _ => None,
+        }
+    }
+
+    /// If this is a call to another BPF function (`src_reg` 1) rather than to a helper, the offset
+    /// of the function's first instruction from the following instruction
+    fn call_off(&self) -> Option<i32> {
+        match (self.op_class(), self.op_jmp()) {
+            (Some(Class::Jmp), Some(OpJmp::Call)) if self.src() == 1 => Some(self.imm32() as i32),
+            _ => None,
+        }
+    }
+
+    /// The source operand of an `Alu`, `Alu64`, `Jmp`, or `Jmp32` instruction: `imm` for
+    /// `Src::K`, otherwise `src_reg`'s value as given by `reg`
+    ///
+    /// `imm` is sign extended, as in linux. `Jmp32` compares only its low 32 bits.
+    fn src_operand<R: FnOnce(u8) -> u64>(&self, reg: R) -> u64 {
+        match self.op_src() {
+            Some(Src::X) => reg(self.src()),
+            _ => self.imm32() as i32 as i64 as u64,
+        }
+    }
+
+    /// The result of an `Alu` or `Alu64` instruction with destination value `dst` and source
+    /// value `src`. Results of `Alu` are zero extended.
+    ///
+    /// As in linux, division by zero gives zero and modulo by zero leaves `dst` unchanged.
+    fn eval_alu(&self, dst: u64, src: u64) -> u64 {
+        let wide = self.op_class() == Some(Class::Alu64);
+        if self.op_alu() == Some(OpAlu::End) {
+            let to_be = self.op_src() == Some(Src::X);
+            return match (self.imm32(), to_be) {
+                (16, false) => dst as u16 as u64,
+                (32, false) => dst as u32 as u64,
+                (16, true) => (dst as u16).swap_bytes() as u64,
+                (32, true) => (dst as u32).swap_bytes() as u64,
+                (_, false) => dst,
+                (_, true) => dst.swap_bytes(),
+            };
+        }
+
+        let (d, s) = if wide { (dst, src) } else { (dst as u32 as u64, src as u32 as u64) };
+        let r = match self.op_alu() {
+            Some(OpAlu::Add) => d.wrapping_add(s),
+            Some(OpAlu::Sub) => d.wrapping_sub(s),
+            Some(OpAlu::Mul) => d.wrapping_mul(s),
+            Some(OpAlu::Div) => d.checked_div(s).unwrap_or(0),
+            Some(OpAlu::Or) => d | s,
+            Some(OpAlu::And) => d & s,
+            Some(OpAlu::Lsh) if wide => d.wrapping_shl(s as u32),
+            Some(OpAlu::Lsh) => (d as u32).wrapping_shl(s as u32) as u64,
+            Some(OpAlu::Rsh) if wide => d.wrapping_shr(s as u32),
+            Some(OpAlu::Rsh) => (d as u32).wrapping_shr(s as u32) as u64,
+            Some(OpAlu::Neg) => d.wrapping_neg(),
+            Some(OpAlu::Mod) => d.checked_rem(s).unwrap_or(d),
+            Some(OpAlu::Xor) => d ^ s,
+            Some(OpAlu::Mov) => s,
+            Some(OpAlu::Arsh) if wide => (d as i64).wrapping_shr(s as u32) as u64,
+            Some(OpAlu::Arsh) => (d as u32 as i32).wrapping_shr(s as u32) as u32 as u64,
+            Some(OpAlu::End) | None => panic!("not an alu instruction"),
+        };
+        if wide { r } else { r as u32 as u64 }
+    }
+
+    /// Whether a `Jmp` or `Jmp32` instruction comparing `a` (from `dst_reg`) and `b` (the source
+    /// operand) is taken. `Jmp32` compares only the lower 32 bits.
+    fn jmp_taken(&self, a: u64, b: u64) -> bool {
+        let (a, b, sa, sb) = if self.op_class() == Some(Class::Jmp32) {
+            (a as u32 as u64, b as u32 as u64, a as i32 as i64, b as i32 as i64)
+        } else {
+            (a, b, a as i64, b as i64)
+        };
+        match self.op_jmp() {
+            Some(OpJmp::Ja) => true,
+            Some(OpJmp::Jeq) => a == b,
+            Some(OpJmp::Jgt) => a > b,
+            Some(OpJmp::Jge) => a >= b,
+            Some(OpJmp::Jset) => (a & b) != 0,
+            Some(OpJmp::Jne) => a != b,
+            Some(OpJmp::Jsgt) => sa > sb,
+            Some(OpJmp::Jsge) => sa >= sb,
+            Some(OpJmp::Jlt) => a < b,
+            Some(OpJmp::Jle) => a <= b,
+            Some(OpJmp::Jslt) => sa < sb,
+            Some(OpJmp::Jsle) => sa <= sb,
+            Some(OpJmp::Call) | Some(OpJmp::Exit) | None => panic!("not a conditional jump"),
+        }
+    }
+
+    /// Check that the instruction is a valid encoding: the opcode is known, and the fields that
+    /// are unused by that opcode are zeroed.
+    ///
+    /// For `ld_imm64`, only the first half is examined.
+    pub fn check(&self) -> Result<(), InstDecodeError> {
+        use InstDecodeError::InvalidEncoding as E;
+
+        if self.dst() > 10 || self.src() > 10 {
+            return Err(E("register out of range"));
+        }
+
+        match self.op_class() {
+            Some(Class::Ld) => match self.ld_mode() {
+                Some(Mode::Imm) => {
+                    if self.off16() != 0 {
+                        return Err(E("ld.imm has off != 0"));
+                    }
+                    // `ld_imm64` may refer to a map (1) or a map value (2) instead of a constant
+                    let max_src = if self.ld_size() == Some(Size::DW) { 2 } else { 0 };
+                    if self.src() > max_src {
+                        return Err(E("ld.imm has invalid src_reg"));
+                    }
+                },
+                Some(Mode::Abs) => {
+                    if self.src() != 0 || self.off16() != 0 {
+                        return Err(E("ld.abs has src_reg or off != 0"));
+                    }
+                },
+                Some(Mode::Ind) => {
+                    if self.off16() != 0 {
+                        return Err(E("ld.ind has off != 0"));
+                    }
+                },
+                _ => return Err(E("invalid ld mode")),
+            },
+            Some(Class::Ldx) => {
+                if self.ld_mode() != Some(Mode::Mem) {
+                    return Err(E("invalid ldx mode"));
+                }
+                if self.imm32() != 0 {
+                    return Err(E("ldx has imm != 0"));
+                }
+            },
+            Some(Class::St) => {
+                if self.ld_mode() != Some(Mode::Mem) {
+                    return Err(E("invalid st mode"));
+                }
+                if self.src() != 0 {
+                    return Err(E("st has src_reg != 0"));
+                }
+            },
+            Some(Class::Stx) => {
+                match (self.ld_mode(), self.ld_size()) {
+                    (Some(Mode::Mem), _) => {},
+                    (Some(Mode::Xadd), Some(Size::W)) | (Some(Mode::Xadd), Some(Size::DW)) => {},
+                    (Some(Mode::Xadd), _) => return Err(E("xadd must be W or DW")),
+                    _ => return Err(E("invalid stx mode")),
+                }
+                if self.imm32() != 0 {
+                    return Err(E("stx has imm != 0"));
+                }
+            },
+            Some(Class::Alu) | Some(Class::Alu64) => {
+                if self.off16() != 0 {
+                    return Err(E("alu has off != 0"));
+                }
+                match self.op_alu() {
+                    Some(OpAlu::Neg) => {
+                        if self.op_src() != Some(Src::K) || self.src() != 0 || self.imm32() != 0 {
+                            return Err(E("neg has src_reg or imm != 0"));
+                        }
+                    },
+                    Some(OpAlu::End) => {
+                        if self.op_class() != Some(Class::Alu) {
+                            return Err(E("end must be Alu"));
+                        }
+                        if self.src() != 0 {
+                            return Err(E("end has src_reg != 0"));
+                        }
+                        match self.imm32() {
+                            16 | 32 | 64 => {},
+                            _ => return Err(E("end has imm not one of 16, 32, 64")),
+                        }
+                    },
+                    Some(_) => self.check_src()?,
+                    None => return Err(E("invalid alu op")),
+                }
+            },
+            Some(Class::Jmp) => {
+                match self.op_jmp() {
+                    Some(OpJmp::Ja) => {
+                        if self.op_src() != Some(Src::K) || self.src_dst != 0 || self.imm32() != 0 {
+                            return Err(E("ja has src_reg, dst_reg, or imm != 0"));
+                        }
+                    },
+                    Some(OpJmp::Call) => {
+                        // `src_reg` 1 calls another BPF function, rather than a helper
+                        if self.op_src() != Some(Src::K) || self.src() > 1 || self.dst() != 0 || self.off16() != 0 {
+                            return Err(E("call has src_reg other than 0 or 1, or dst_reg or off != 0"));
+                        }
+                    },
+                    Some(OpJmp::Exit) => {
+                        if self.op_src() != Some(Src::K) || self.src_dst != 0 || self.off16() != 0
+                            || self.imm32() != 0 {
+                            return Err(E("exit has non-zero fields"));
+                        }
+                    },
+                    Some(_) => self.check_src()?,
+                    None => return Err(E("invalid jmp op")),
+                }
+            },
+            Some(Class::Jmp32) => {
+                match self.op_jmp() {
+                    Some(OpJmp::Ja) => {
+                        if self.op_src() != Some(Src::K) || self.src_dst != 0 || self.off16() != 0 {
+                            return Err(E("gotol has src_reg, dst_reg, or off != 0"));
+                        }
+                    },
+                    Some(OpJmp::Call) | Some(OpJmp::Exit) => return Err(E("invalid jmp32 op")),
+                    Some(_) => self.check_src()?,
+                    None => return Err(E("invalid jmp32 op")),
+                }
+            },
+            None => return Err(E("invalid class")),
+        }
+
+        Ok(())
+    }
+
+    /// For instructions with a `Src` bit, check the unused source is zeroed
+    fn check_src(&self) -> Result<(), InstDecodeError> {
+        match self.op_src() {
+            Some(Src::K) if self.src() != 0 => Err(InstDecodeError::InvalidEncoding("src_reg != 0 with Src::K")),
+            Some(Src::X) if self.imm32() != 0 => Err(InstDecodeError::InvalidEncoding("imm != 0 with Src::X")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that this is a valid second half of a `ld_imm64`
+    fn check_imm64_hi(&self) -> Result<(), InstDecodeError> {
+        if self.op != 0 || self.src_dst != 0 || self.off != 0 {
+            return Err(InstDecodeError::InvalidEncoding("ld_imm64 has malformed second half"));
+        }
+        Ok(())
+    }
 }
 
+/// A program `Invoke` is able to run
+///
+/// Obtained safely from `verifier::Env::verify`, through its `VerifiedProgram`, or unsafely from
+/// `from_raw`.
 #[derive(Clone,PartialEq,Eq,Debug)]
 pub struct Program<'a> {
     data: &'a [u64], 
 }
 
 impl<'a> Program<'a> {
+    /// Use `data` without verifying it, as an escape hatch for programs which were verified
+    /// elsewhere
+    ///
+    /// # Safety
+    ///
+    /// `data` is not checked in any way. The caller must ensure it is a program that `Invoke` is
+    /// able to run (ie: one that would pass verification). `Invoke::run_sanitized` checks this as
+    /// the program runs.
     pub unsafe fn from_raw(data: &'a [u64]) -> Self {
         Self {
-            data: data
+            data
         }
     }
 }
@@ -349,8 +689,150 @@ impl DataArea for EmptyDataArea {
     fn load_u8(&self, _:usize) -> Option<u8> { None }
 }
 
+/// A `DataArea` backed by a slice of bytes, such as a packet
+///
+/// Multi-byte loads are big endian (network byte order), matching how packet data is loaded in
+/// classic BPF.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct SliceDataArea<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SliceDataArea<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data
+        }
+    }
+
+    fn bytes(&self, offs: usize, len: usize) -> Option<&'a [u8]> {
+        self.data.get(offs..offs.checked_add(len)?)
+    }
+}
+
+impl<'a> DataArea for SliceDataArea<'a> {
+    fn load_u64(&self, offs: usize) -> Option<u64> {
+        let b = self.bytes(offs, 8)?;
+        Some(u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    fn load_u32(&self, offs: usize) -> Option<u32> {
+        let b = self.bytes(offs, 4)?;
+        Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn load_u16(&self, offs: usize) -> Option<u16> {
+        let b = self.bytes(offs, 2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn load_u8(&self, offs: usize) -> Option<u8> {
+        self.data.get(offs).cloned()
+    }
+}
+
+/// The maps a program run by `Invoke` uses, by the index its `ld_imm64`s (`src_reg` 1 or 2) give
+///
+/// Each value has a `slot` of its map, which it keeps until its key is deleted, so that pointers
+/// to it stay valid as they do in linux. `map::Map` provides the common map types.
+pub trait Maps {
+    /// The key and value sizes of `map`, if there is such a map
+    fn sizes(&self, map: usize) -> Option<(usize, usize)>;
+
+    /// The slot of the value of `key` in `map`, if it has one
+    fn lookup(&mut self, map: usize, key: &[u8]) -> Option<usize>;
+
+    /// The value in `slot` of `map`
+    fn value(&mut self, map: usize, slot: usize) -> Option<&mut [u8]>;
+
+    /// Set the value of `key`, as `bpf_map_update_elem` with `flags` does. Gives 0, or a negative
+    /// errno.
+    fn update(&mut self, map: usize, key: &[u8], value: &[u8], flags: u64) -> i64;
+
+    /// Remove `key`, as `bpf_map_delete_elem` does. Gives 0, or a negative errno.
+    fn delete(&mut self, map: usize, key: &[u8]) -> i64;
+
+    /// The slot an `ld_imm64` of a value of `map` (`src_reg` 2) points into, if it has one
+    fn direct_value(&mut self, map: usize) -> Option<usize>;
+}
+
+impl<M: Maps + ?Sized> Maps for &mut M {
+    fn sizes(&self, map: usize) -> Option<(usize, usize)> { (**self).sizes(map) }
+    fn lookup(&mut self, map: usize, key: &[u8]) -> Option<usize> { (**self).lookup(map, key) }
+    fn value(&mut self, map: usize, slot: usize) -> Option<&mut [u8]> { (**self).value(map, slot) }
+    fn update(&mut self, map: usize, key: &[u8], value: &[u8], flags: u64) -> i64 { (**self).update(map, key, value, flags) }
+    fn delete(&mut self, map: usize, key: &[u8]) -> i64 { (**self).delete(map, key) }
+    fn direct_value(&mut self, map: usize) -> Option<usize> { (**self).direct_value(map) }
+}
+
+/// `Maps` with no maps in it, so that loading one fails
+#[derive(Clone,Copy,PartialEq,Eq,Debug,Default)]
+pub struct NoMaps;
+
+impl Maps for NoMaps {
+    fn sizes(&self, _: usize) -> Option<(usize, usize)> { None }
+    fn lookup(&mut self, _: usize, _: &[u8]) -> Option<usize> { None }
+    fn value(&mut self, _: usize, _: usize) -> Option<&mut [u8]> { None }
+    fn update(&mut self, _: usize, _: &[u8], _: &[u8], _: u64) -> i64 { -EINVAL }
+    fn delete(&mut self, _: usize, _: &[u8]) -> i64 { -EINVAL }
+    fn direct_value(&mut self, _: usize) -> Option<usize> { None }
+}
+
+/// The errno, negated, of an invalid argument to a map helper
+const EINVAL: i64 = 22;
+
+/// Bytes of stack below `r10`
+const STACK_SIZE: usize = 512;
+
+/// The address `r10` holds on entry, just past the top of the stack. Loads and stores through
+/// any address outside the stack fail.
+const STACK_TOP: u64 = 1 << 32;
+
+/// The address `ld_imm64` of map `n` (`src_reg` 1) gives is `MAP_ADDR + n`
+const MAP_ADDR: u64 = 1 << 62;
+
+/// Byte `off` of the value in `slot` of map `n` is at `VALUE_ADDR | n << 48 | slot << 24 | off`
+const VALUE_ADDR: u64 = 1 << 63;
+
+/// The address of byte `off` of the value in `slot` of `map`, if they fit
+fn value_addr(map: usize, slot: usize, off: u64) -> Option<u64> {
+    if map >= 1 << 15 || slot >= 1 << 24 || off >= 1 << 24 {
+        return None;
+    }
+    Some(VALUE_ADDR | (map as u64) << 48 | (slot as u64) << 24 | off)
+}
+
+/// The map, slot and offset in its value of `addr`, if it is a map value's address
+fn value_parts(addr: u64) -> Option<(usize, usize, usize)> {
+    if addr & VALUE_ADDR == 0 {
+        return None;
+    }
+    Some(((addr >> 48 & 0x7fff) as usize, (addr >> 24 & 0xff_ffff) as usize, (addr & 0xff_ffff) as usize))
+}
+
+/// Functions which may be on the call stack at once, including the program's own. Matches
+/// linux's `MAX_CALL_FRAMES`.
+const MAX_CALL_FRAMES: usize = 8;
+
+/// What a call to another BPF function saves, to restore at its `exit`
+#[derive(Clone,Copy,PartialEq,Eq,Debug,Default)]
+struct Frame {
+    /// The caller's `r6` to `r10`
+    regs: [u64;5],
+    /// Where the caller continues
+    ret: usize,
+}
+
+/// What `Invoke::step` did
+enum Step {
+    /// Continue at this instruction
+    Next(usize),
+    /// The program exited, returning this
+    Exit(u64),
+}
+
 #[derive(Clone,PartialEq,Eq,Debug)]
-pub struct Invoke<'a, D: DataArea> {
+pub struct Invoke<'a, D: DataArea, M: Maps = NoMaps> {
     prgm: Program<'a>,
 
     // TODO: need a way to specify the argument-registers, and the potential for them to be
@@ -359,44 +841,144 @@ pub struct Invoke<'a, D: DataArea> {
     // in.
  
     regs: [u64;16],
+    /// The registers set by `arg_raw`
+    args: u16,
+    /// The stack of each function called, the program's own at the top. Each has `STACK_SIZE`
+    /// bytes below its `r10`.
+    stack: [u8;STACK_SIZE * MAX_CALL_FRAMES],
+    /// The functions which called the one running, outermost first
+    frames: [Frame;MAX_CALL_FRAMES],
+    depth: usize,
     data_area: D,
+    maps: M,
 }
 
 impl<'a> Invoke<'a, EmptyDataArea> {
-    pub fn new(prgm: Program<'a>) -> Invoke<'a, EmptyDataArea> {
+    /// Run `prgm`: a `verifier::VerifiedProgram`, or a `Program`
+    pub fn new<P: Into<Program<'a>>>(prgm: P) -> Invoke<'a, EmptyDataArea> {
         Self::with_data_area(prgm, EmptyDataArea)
     }
 }
 
 impl<'a, D: DataArea> Invoke<'a, D> {
-    pub fn with_data_area(prgm: Program<'a>, data_area: D) -> Self {
+    pub fn with_data_area<P: Into<Program<'a>>>(prgm: P, data_area: D) -> Self {
+        let mut regs: [u64;16] = Default::default();
+        regs[10] = STACK_TOP;
         Self {
-            prgm: prgm,
-            regs: Default::default(),
-            data_area: data_area,
+            prgm: prgm.into(),
+            regs,
+            args: 0,
+            stack: [0;STACK_SIZE * MAX_CALL_FRAMES],
+            frames: [Frame::default();MAX_CALL_FRAMES],
+            depth: 0,
+            data_area,
+            maps: NoMaps,
+        }
+    }
+
+    /// Give the program `maps`. Pass `&mut maps` to look at them after it has run.
+    pub fn maps<M: Maps>(self, maps: M) -> Invoke<'a, D, M> {
+        Invoke {
+            prgm: self.prgm,
+            regs: self.regs,
+            args: self.args,
+            stack: self.stack,
+            frames: self.frames,
+            depth: self.depth,
+            data_area: self.data_area,
+            maps,
         }
     }
+}
+
+impl<'a, D: DataArea, M: Maps> Invoke<'a, D, M> {
 
     // this API is _bad_
     pub fn arg_raw(&mut self, reg: usize, val: u64) {
         self.regs[reg] = val; 
+        self.args |= 1 << reg;
+    }
+
+    /// The index in `stack` of `size` bytes at `addr`, if they are all within it
+    fn stack_idx(addr: u64, size: usize) -> Option<usize> {
+        let bottom = STACK_TOP - (STACK_SIZE * MAX_CALL_FRAMES) as u64;
+        if addr >= bottom && addr.checked_add(size as u64)? <= STACK_TOP {
+            Some((addr - bottom) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// The `size` bytes at `addr`, if they are all on the stack or in one map value
+    fn mem(&mut self, addr: u64, size: usize) -> Option<&mut [u8]> {
+        if let Some(idx) = Self::stack_idx(addr, size) {
+            return Some(&mut self.stack[idx..idx + size]);
+        }
+        let (map, slot, off) = value_parts(addr)?;
+        self.maps.value(map, slot)?.get_mut(off..off + size)
     }
 
-    fn data_area_load(&mut self, offs: usize, sz: Option<Size>) -> Option<u64> {
+    /// Load `size` bytes at `addr`, little endian
+    fn load(&mut self, addr: u64, size: usize) -> Option<u64> {
+        Some(self.mem(addr, size)?.iter().rev().fold(0, |v, &b| (v << 8) | u64::from(b)))
+    }
+
+    /// Store the low `size` bytes of `v` at `addr`, little endian
+    fn store(&mut self, addr: u64, size: usize, v: u64) -> Option<()> {
+        for (n, b) in self.mem(addr, size)?.iter_mut().enumerate() {
+            *b = (v >> (n * 8)) as u8;
+        }
+        Some(())
+    }
+
+    /// Call helper `id`, giving what it returns. Only the helpers for `Maps` are provided: map
+    /// lookup (1), update (2) and delete (3).
+    fn helper(&mut self, id: u32) -> Option<u64> {
+        if !(1..=3).contains(&id) {
+            return None;
+        }
+        let map = self.regs[1].checked_sub(MAP_ADDR)? as usize;
+        let (key_size, value_size) = self.maps.sizes(map)?;
+
+        // copied out, as the key or value may be in a map value
+        let mut key = [0;STACK_SIZE];
+        let key = key.get_mut(..key_size)?;
+        key.copy_from_slice(self.mem(self.regs[2], key_size)?);
+        match id {
+            1 => match self.maps.lookup(map, key) {
+                Some(slot) => value_addr(map, slot, 0),
+                None => Some(0),
+            },
+            2 => {
+                let mut value = [0;STACK_SIZE];
+                let value = value.get_mut(..value_size)?;
+                value.copy_from_slice(self.mem(self.regs[3], value_size)?);
+                Some(self.maps.update(map, key, value, self.regs[4]) as u64)
+            },
+            _ => Some(self.maps.delete(map, key) as u64),
+        }
+    }
+
+    /// Load `sz` bytes at `offs` in the data area, if all of them have a `usize` offset
+    fn data_area_load(&mut self, offs: u64, sz: Option<Size>) -> Option<u64> {
+        let sz = sz?;
+        if offs.checked_add(sz.bytes() as u64)? > usize::MAX as u64 {
+            return None;
+        }
+        let offs = offs as usize;
         match sz {
-            Some(Size::W) => {
+            Size::W => {
                 self.data_area.load_u32(offs).map(|x| x as u64)
             },
-            Some(Size::H) => {
+            Size::H => {
                 self.data_area.load_u16(offs).map(|x| x as u64)
             },
-            Some(Size::B) => {
+            Size::B => {
                 self.data_area.load_u8(offs).map(|x| x as u64)
             },
-            Some(Size::DW) => {
-                self.data_area.load_u64(offs).map(|x| x as u64)
+            Size::DW => {
+                self.data_area.load_u64(offs)
             },
-            None => panic!(),
         }
     }
 
@@ -406,159 +988,143 @@ impl<'a, D: DataArea> Invoke<'a, D> {
     //  - could be a return of a larger structure via the stack, or via some context mechanism
     //  - might not have a real return-via-reg at all and instead only interact with the system via
     //  context.
+    /// Run the program until it exits, giving `r0`
+    ///
+    /// Fails if a packet load is outside the `DataArea`, a map or its value is not in the `Maps`
+    /// given, or the program does something `Invoke` can't provide for yet: calling a helper
+    /// other than those for maps, or accessing its context (there is none, so `r1` is 0 unless set
+    /// by `arg_raw`). Such programs may still pass verification for a `Profile` that allows them.
+    #[allow(clippy::result_unit_err)]
     pub fn run(mut self) -> Result<u64, ()> {
         let mut pc = 0;
 
         // TODO: allow restricting this to 32bit for perf?
         // TODO: should this be allocated per-run?
         loop {
-            let i = Inst::from_u64(self.prgm.data[pc]).unwrap();
-            match i.op_class() {
-                Some(Class::Ld) => {
-                    match i.ld_mode() {
-                        Some(Mode::Imm) => {
-                            // check: i.src() == 0
-                            // check: i.off16() == 0
-
-                            match i.ld_size() {
-                                Some(Size::W) => {
-                                    self.regs[i.dst() as usize] = i.imm32() as u64;
-                                },
-                                Some(Size::H) => {
-                                    self.regs[i.dst() as usize] = i.imm32() as u64;
-                                },
-                                Some(Size::B) => {
-                                    self.regs[i.dst() as usize] = i.imm32() as u64;
-                                },
-                                Some(Size::DW) => {
-                                    panic!("ld.imm.64 is not implimented");
-                                    self.regs[i.dst() as usize] = i.imm32() as u64;
-                                    // ???
-                                },
-                                _ => panic!(),
-                            }
-                        },
-                        Some(Mode::Abs) => {
-                            let offs = i.imm32() as usize;
-                            self.regs[i.dst() as usize] = self.data_area_load(offs, i.ld_size()).ok_or(())?;
-                        },
-                        Some(Mode::Ind) => {
-                            let offs = i.imm32() as usize + self.regs[i.src() as usize] as usize;
-                            self.regs[i.dst() as usize] = self.data_area_load(offs, i.ld_size()).ok_or(())?;
-                        },
-                        _ => panic!(),
-                    }
-                },
-                /*
-                Some(Class::Stx) => {
-                    match i.ld_mode() {
-                        Some(Mode::Mem) => {}
-                    }
-                },
-                Some(Class::St) => {
-                    match i.ld_mode() {
-                        Some(Mode::Mem) => {}
-                    }
-                },
-                */
-                Some(Class::Jmp) => {
-                    // FIXME: we don't always need to `a` & `b`. `Call` and `Exit` don't use them. 
-                    let a = self.regs[i.dst() as usize];
-                    let b = match i.op_src() {
-                        // immediate
-                        Some(Src::K) => {
-                            // check: i.src() == 0
-                            i.imm32() as u64
-                        },
-                        // register
-                        Some(Src::X) => {
-                            // check: i.imm32() == 0
-                            self.regs[i.src() as usize]
-                        },
-                        None => panic!(),
-                    };
-
-                    let jmp = match i.op_jmp() {
-                        Some(OpJmp::Ja) => {
-                            // check: pc + i.off16() < instruction_ct
-                            // check: src_dst == 0
-                            true
-                        },
-                        Some(OpJmp::Jeq) => {
-                            // check: pc + i.off16() < instruction_ct
-                            // check: i.src() != i.dst()
-                            a == b
-                        },
-                        Some(OpJmp::Jgt) => {
-                            // check: pc + i.off16() < instruction_ct
-                            // check: i.src() != i.dst()
-                            a > b
-                        },
-                        Some(OpJmp::Jge) => {
-                            // check: pc + i.off16() < instruction_ct
-                            // check: i.src() != i.dst()
-                            a >= b
-                        },
-                        Some(OpJmp::Jset) => {
-                            (a & b) != 0
-                        },
-
-                        // eBPF only follow:
-                        Some(OpJmp::Jne) => {
-                            a != b
-                        },
-                        Some(OpJmp::Jsgt) => {
-                            (a as i64) > (b as i64)
-                        },
-                        Some(OpJmp::Jsge) => {
-                            (a as i64) >= (b as i64)
-                        },
-                        Some(OpJmp::Call) => {
-                            // push `pc+1` on the return stack, and jump to i.imm32()
-                            // TODO: we don't currently support calling
-                            panic!()
-                        },
-                        Some(OpJmp::Exit) => {
-                            // check: i.off16() == 0
-                            // check: i.imm32() == 0
-                            return Ok(self.regs[0]);
-                        },
-
-                        Some(OpJmp::Jlt)  => {
-                            a < b
-                        },
-                        Some(OpJmp::Jle)  => {
-                            a <= b 
-                        },
-                        Some(OpJmp::Jslt) => {
-                            (a as i64) < (b as i64)
-                        },
-                        Some(OpJmp::Jsle) => {
-                            (a as i64) <= (b as i64)
-                        },
-                        None => panic!(),
-                    };
-
-                    if jmp {
-                        pc += i.off16() as usize;
-                    }
-                },
-                /*
-                Some(Class::Alu) => {
-                    match i.op_alu() {
-                        _ => panic!(),
-                    }
-                },
-                Some(Class::Alu64) => {
-                    match i.op_alu() {
-                        _ => panic!(),
-                    }
-                },
-                */
-                _ => panic!(),
+            match self.step(pc)? {
+                Step::Next(next) => pc = next,
+                Step::Exit(v) => return Ok(v),
             }
+        }
+    }
+
+    /// Run the instruction at `pc`. Fails if a packet load is outside the `DataArea`, a load or
+    /// store is outside the stack and map values, calls are nested more than `MAX_CALL_FRAMES`
+    /// deep, or a map or helper is not provided.
+    fn step(&mut self, mut pc: usize) -> Result<Step, ()> {
+        let i = Inst::from_u64(self.prgm.data[pc]).unwrap();
+        match i.op_class() {
+            Some(Class::Ld) => {
+                match i.ld_mode() {
+                    Some(Mode::Imm) => {
+                        // check: i.src() == 0
+                        // check: i.off16() == 0
 
-            pc += 1;
+                        match i.ld_size() {
+                            Some(Size::W) => {
+                                self.regs[i.dst() as usize] = i.imm32() as u64;
+                            },
+                            Some(Size::H) => {
+                                self.regs[i.dst() as usize] = i.imm32() as u64;
+                            },
+                            Some(Size::B) => {
+                                self.regs[i.dst() as usize] = i.imm32() as u64;
+                            },
+                            Some(Size::DW) => {
+                                let hi = Inst::from_u64(self.prgm.data[pc + 1]).unwrap().imm32();
+                                let map = i.imm32() as usize;
+                                self.regs[i.dst() as usize] = match i.src() {
+                                    1 => {
+                                        self.maps.sizes(map).ok_or(())?;
+                                        MAP_ADDR + map as u64
+                                    },
+                                    2 => {
+                                        let slot = self.maps.direct_value(map).ok_or(())?;
+                                        value_addr(map, slot, u64::from(hi)).ok_or(())?
+                                    },
+                                    _ => (u64::from(hi) << 32) | u64::from(i.imm32()),
+                                };
+                                pc += 1;
+                            },
+                            _ => panic!(),
+                        }
+                    },
+                    Some(Mode::Abs) => {
+                        let offs = u64::from(i.imm32());
+                        self.regs[i.dst() as usize] = self.data_area_load(offs, i.ld_size()).ok_or(())?;
+                    },
+                    Some(Mode::Ind) => {
+                        let offs = u64::from(i.imm32()).checked_add(self.regs[i.src() as usize]).ok_or(())?;
+                        self.regs[i.dst() as usize] = self.data_area_load(offs, i.ld_size()).ok_or(())?;
+                    },
+                    _ => panic!(),
+                }
+            },
+            Some(Class::Ldx) => {
+                let addr = self.regs[i.src() as usize].wrapping_add(i.off16() as i64 as u64);
+                self.regs[i.dst() as usize] = self.load(addr, i.ld_size().unwrap().bytes()).ok_or(())?;
+            },
+            Some(Class::St) => {
+                let addr = self.regs[i.dst() as usize].wrapping_add(i.off16() as i64 as u64);
+                let v = i.imm32() as i32 as i64 as u64;
+                self.store(addr, i.ld_size().unwrap().bytes(), v).ok_or(())?;
+            },
+            Some(Class::Stx) => {
+                let addr = self.regs[i.dst() as usize].wrapping_add(i.off16() as i64 as u64);
+                let size = i.ld_size().unwrap().bytes();
+                let mut v = self.regs[i.src() as usize];
+                if i.ld_mode() == Some(Mode::Xadd) {
+                    v = v.wrapping_add(self.load(addr, size).ok_or(())?);
+                }
+                self.store(addr, size, v).ok_or(())?;
+            },
+            Some(Class::Jmp) | Some(Class::Jmp32) => {
+                match i.op_jmp() {
+                    Some(OpJmp::Call) => {
+                        let off = match i.call_off() {
+                            Some(off) => off,
+                            None => {
+                                self.regs[0] = self.helper(i.imm32()).ok_or(())?;
+                                return Ok(Step::Next(pc + 1));
+                            },
+                        };
+                        if self.depth + 1 == MAX_CALL_FRAMES {
+                            return Err(());
+                        }
+                        let mut regs = [0;5];
+                        regs.copy_from_slice(&self.regs[6..11]);
+                        self.frames[self.depth] = Frame { regs, ret: pc + 1 };
+                        self.depth += 1;
+                        // the callee's stack is below the caller's
+                        self.regs[10] -= STACK_SIZE as u64;
+                        pc = (pc as i64 + i64::from(off)) as usize;
+                    },
+                    Some(OpJmp::Exit) if self.depth > 0 => {
+                        self.depth -= 1;
+                        let f = self.frames[self.depth];
+                        self.regs[6..11].copy_from_slice(&f.regs);
+                        return Ok(Step::Next(f.ret));
+                    },
+                    Some(OpJmp::Exit) => {
+                        return Ok(Step::Exit(self.regs[0]));
+                    },
+                    _ => {
+                        let a = self.regs[i.dst() as usize];
+                        let b = i.src_operand(|r| self.regs[r as usize]);
+                        if i.jmp_taken(a, b) {
+                            pc = (pc as i64 + i64::from(i.jump_off().unwrap())) as usize;
+                        }
+                    },
+                }
+            },
+            Some(Class::Alu) | Some(Class::Alu64) => {
+                let d = i.dst() as usize;
+                let s = i.src_operand(|r| self.regs[r as usize]);
+                self.regs[d] = i.eval_alu(self.regs[d], s);
+            },
+            _ => panic!(),
         }
+
+        Ok(Step::Next(pc + 1))
     }
 }
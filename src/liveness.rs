@@ -0,0 +1,130 @@
+//! Which registers hold a value that may still be read
+//!
+//! A register is live at an instruction when some path from there reads it before writing it.
+//! `Liveness` finds the registers live on entry to each instruction by carrying reads backwards
+//! through a program's `Cfg` until nothing changes.
+//!
+//! Each BPF function has registers of its own, so reads do not carry back along `Call` edges. A
+//! call to a function reads the arguments live on entry to it instead.
+
+use super::*;
+use alloc::vec::Vec;
+use cfg::{Cfg, EdgeKind};
+
+/// A set of registers, with bit `n` set for `rN`
+pub type RegSet = u16;
+
+/// The registers passed to a program (or helper): `r1` to `r5`
+pub const ARGS: RegSet = 0b11_1110;
+
+/// The registers `i` reads, and those it writes. A call reads the first `args(id)` arguments.
+fn uses_defs<F: Fn(u32) -> usize>(i: &Inst, args: &F) -> (RegSet, RegSet) {
+    let dst = 1 << i.dst();
+    let src = 1 << i.src();
+    let x = if i.op_src() == Some(Src::X) { src } else { 0 };
+    match i.op_class() {
+        Some(Class::Ld) if i.ld_mode() == Some(Mode::Ind) => (src, dst),
+        Some(Class::Ld) => (0, dst),
+        Some(Class::Ldx) => (src, dst),
+        Some(Class::St) => (dst, 0),
+        Some(Class::Stx) => (dst | src, 0),
+        Some(Class::Alu) | Some(Class::Alu64) => match i.op_alu() {
+            Some(OpAlu::Mov) => (x, dst),
+            Some(OpAlu::Neg) | Some(OpAlu::End) => (dst, dst),
+            _ => (dst | x, dst),
+        },
+        Some(Class::Jmp) | Some(Class::Jmp32) => match i.op_jmp() {
+            Some(OpJmp::Exit) => (1, 0),
+            Some(OpJmp::Ja) => (0, 0),
+            // the scratch registers are clobbered
+            Some(OpJmp::Call) => ((2 << args(i.imm32()).min(5)) - 2, ARGS | 1),
+            _ => (dst | x, 0),
+        },
+        None => (0, 0),
+    }
+}
+
+/// The result of liveness analysis on a program
+#[derive(Debug,Clone,PartialEq,Eq,Default)]
+pub struct Liveness {
+    live_in: Vec<RegSet>,
+    dead_stores: Vec<usize>,
+}
+
+impl Liveness {
+    /// Analyse `insts`, which `cfg` was built from. `helper_args` gives how many arguments the
+    /// helper `id` takes, as `HelperProto::args.len()`.
+    pub fn new<F: Fn(u32) -> usize>(cfg: &Cfg, insts: &[u64], helper_args: F) -> Self {
+        let blocks = cfg.blocks();
+        let decode = |pc: usize| Inst::from_u64(insts[pc]).unwrap();
+
+        // the instructions of each block, skipping the second half of each `ld_imm64`
+        let pcs: Vec<Vec<usize>> = blocks.iter().map(|b| {
+            let mut v = Vec::new();
+            let mut pc = b.start();
+            while pc < b.end() {
+                v.push(pc);
+                pc += if decode(pc).is_ld_imm64() { 2 } else { 1 };
+            }
+            v
+        }).collect();
+
+        let mut live_in: Vec<RegSet> = insts.iter().map(|_| 0).collect();
+        let mut dead_stores = Vec::new();
+
+        // blocks are in program order, and most edges go forwards, so work from the end
+        let mut changed = true;
+        while changed {
+            changed = false;
+            dead_stores.clear();
+            for (b, pcs) in blocks.iter().zip(pcs.iter()).rev() {
+                let mut live = b.succs().iter()
+                    .filter(|e| e.kind != EdgeKind::Call)
+                    .fold(0, |l, e| l | live_in[blocks[e.to].start()]);
+                for &pc in pcs.iter().rev() {
+                    let i = decode(pc);
+                    let (uses, defs) = match i.call_off() {
+                        Some(off) => (live_in[(pc as i64 + 1 + i64::from(off)) as usize] & ARGS, ARGS | 1),
+                        None => uses_defs(&i, &helper_args),
+                    };
+                    let pure = match i.op_class() {
+                        Some(Class::Ld) => i.ld_mode() == Some(Mode::Imm),
+                        Some(Class::Ldx) | Some(Class::Alu) | Some(Class::Alu64) => true,
+                        _ => false,
+                    };
+                    if pure && defs & live == 0 {
+                        dead_stores.push(pc);
+                    }
+
+                    live = uses | (live & !defs);
+                    if live_in[pc] != live {
+                        live_in[pc] = live;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        dead_stores.sort();
+
+        Liveness {
+            live_in,
+            dead_stores,
+        }
+    }
+
+    /// The registers live on entry to the instruction at `pc`. Empty for the second half of a
+    /// `ld_imm64`.
+    pub fn live_in(&self, pc: usize) -> RegSet {
+        self.live_in[pc]
+    }
+
+    /// The arguments the program reads
+    pub fn args(&self) -> RegSet {
+        self.live_in.first().map_or(0, |&l| l & ARGS)
+    }
+
+    /// Instructions which only write a register that is never read afterwards, in program order
+    pub fn dead_stores(&self) -> &[usize] {
+        &self.dead_stores
+    }
+}
@@ -0,0 +1,174 @@
+//! Maps, in memory, for programs run by `Invoke`
+//!
+//! A `Map` holds the keys and values of a map as its `MapDef` describes. Hash maps and array maps
+//! are provided, and their per-CPU variants are taken to have a single CPU. A slice of `Map`s,
+//! in the order of the indexes a program's `ld_imm64`s give, implements `Maps`.
+//!
+//! Array maps are keyed by a 32-bit index, little endian as `Invoke` stores it, and hold a zeroed
+//! value for every index from the start. Their values can be updated, but not deleted.
+//!
+//! As the sizes may come from an untrusted object, keys and values are limited to the 512 bytes of
+//! a stack frame, which `Invoke` copies them through, and array maps to `MAX_ARRAY_BYTES` of
+//! values.
+
+use super::*;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use verifier::MapDef;
+
+/// The `map_type`s provided
+pub const HASH: u32 = 1;
+pub const ARRAY: u32 = 2;
+pub const PERCPU_HASH: u32 = 5;
+pub const PERCPU_ARRAY: u32 = 6;
+
+/// The most bytes the values of an array map may take up, as they are all allocated up front.
+/// Matches linux's `KMALLOC_MAX_SIZE` with 4 KiB pages.
+pub const MAX_ARRAY_BYTES: u64 = 1 << 22;
+
+/// The largest key or value, the size of the buffers `Invoke` copies them through
+const MAX_SIZE: u32 = STACK_SIZE as u32;
+
+/// `bpf_map_update_elem` flags: only add a key, or only change one
+const NOEXIST: u64 = 1;
+const EXIST: u64 = 2;
+
+/// The errnos, negated, the map helpers give besides `EINVAL`
+const ENOENT: i64 = 2;
+const E2BIG: i64 = 7;
+const EEXIST: i64 = 17;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Map {
+    def: MapDef,
+
+    /// The slot of each key, for hash maps
+    slots: BTreeMap<Vec<u8>, usize>,
+
+    /// The value in each slot, or `None` once its key is deleted. Slots are reused by later keys.
+    values: Vec<Option<Vec<u8>>>,
+}
+
+impl Map {
+    /// An empty map as `def` describes, if its type is one provided and its sizes are within the
+    /// limits above
+    pub fn new(def: MapDef) -> Result<Self, &'static str> {
+        if def.key_size == 0 || def.value_size == 0 || def.max_entries == 0 {
+            return Err("key size, value size, or max entries is 0");
+        }
+        if def.key_size > MAX_SIZE || def.value_size > MAX_SIZE {
+            return Err("key or value size is larger than a stack frame");
+        }
+        let array_bytes = u64::from(def.value_size) * u64::from(def.max_entries);
+        let values = match def.map_type {
+            HASH | PERCPU_HASH => Vec::new(),
+            ARRAY | PERCPU_ARRAY if def.key_size != 4 => return Err("array map key size is not 4"),
+            ARRAY | PERCPU_ARRAY if array_bytes > MAX_ARRAY_BYTES =>
+                return Err("array map is larger than MAX_ARRAY_BYTES"),
+            ARRAY | PERCPU_ARRAY => vec![Some(vec![0; def.value_size as usize]); def.max_entries as usize],
+            _ => return Err("unsupported map type"),
+        };
+        Ok(Map { def, slots: BTreeMap::new(), values })
+    }
+
+    pub fn def(&self) -> &MapDef {
+        &self.def
+    }
+
+    fn is_array(&self) -> bool {
+        self.def.map_type == ARRAY || self.def.map_type == PERCPU_ARRAY
+    }
+
+    /// The slot of `key`, if it has a value
+    fn slot(&self, key: &[u8]) -> Option<usize> {
+        if key.len() != self.def.key_size as usize {
+            return None;
+        }
+        if self.is_array() {
+            let idx = u32::from_le_bytes([key[0], key[1], key[2], key[3]]) as usize;
+            return if idx < self.values.len() { Some(idx) } else { None };
+        }
+        self.slots.get(key).cloned()
+    }
+
+    /// The value of `key`
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.values[self.slot(key)?].as_ref().map(|v| &v[..])
+    }
+
+    /// Set the value of `key`, as `bpf_map_update_elem` does: with `flags` 0 whether or not it has
+    /// one, 1 only if it has none, and 2 only if it has one. Gives 0, or a negative errno.
+    pub fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> i64 {
+        if key.len() != self.def.key_size as usize || value.len() != self.def.value_size as usize || flags > EXIST {
+            return -EINVAL;
+        }
+        match (self.slot(key), flags) {
+            (Some(_), NOEXIST) => -EEXIST,
+            // in place, so pointers to the old value see the new one
+            (Some(slot), _) => {
+                if let Some(v) = self.values[slot].as_mut() {
+                    v.copy_from_slice(value);
+                }
+                0
+            },
+            (None, _) if self.is_array() => -E2BIG,
+            (None, EXIST) => -ENOENT,
+            (None, _) if self.slots.len() == self.def.max_entries as usize => -E2BIG,
+            (None, _) => {
+                let slot = match self.values.iter().position(Option::is_none) {
+                    Some(slot) => slot,
+                    None => {
+                        self.values.push(None);
+                        self.values.len() - 1
+                    },
+                };
+                self.values[slot] = Some(value.to_vec());
+                self.slots.insert(key.to_vec(), slot);
+                0
+            },
+        }
+    }
+
+    /// Remove `key`, as `bpf_map_delete_elem` does. Gives 0, or a negative errno.
+    pub fn delete(&mut self, key: &[u8]) -> i64 {
+        if key.len() != self.def.key_size as usize || self.is_array() {
+            return -EINVAL;
+        }
+        match self.slots.remove(key) {
+            Some(slot) => {
+                self.values[slot] = None;
+                0
+            },
+            None => -ENOENT,
+        }
+    }
+}
+
+impl Maps for [Map] {
+    fn sizes(&self, map: usize) -> Option<(usize, usize)> {
+        let def = self.get(map)?.def;
+        Some((def.key_size as usize, def.value_size as usize))
+    }
+
+    fn lookup(&mut self, map: usize, key: &[u8]) -> Option<usize> {
+        self.get(map)?.slot(key)
+    }
+
+    fn value(&mut self, map: usize, slot: usize) -> Option<&mut [u8]> {
+        self.get_mut(map)?.values.get_mut(slot)?.as_mut().map(|v| &mut v[..])
+    }
+
+    fn update(&mut self, map: usize, key: &[u8], value: &[u8], flags: u64) -> i64 {
+        self.get_mut(map).map_or(-EINVAL, |m| m.update(key, value, flags))
+    }
+
+    fn delete(&mut self, map: usize, key: &[u8]) -> i64 {
+        self.get_mut(map).map_or(-EINVAL, |m| m.delete(key))
+    }
+
+    /// Index 0 of an array map, as linux allows only for arrays
+    fn direct_value(&mut self, map: usize) -> Option<usize> {
+        if self.get(map)?.is_array() { Some(0) } else { None }
+    }
+}
@@ -0,0 +1,419 @@
+//! Reading (and writing) packet captures in the pcap & pcapng formats
+//!
+//! Parsing works directly on a byte slice holding the entire capture, so it is usable without
+//! `std`. Writing is only provided with the `std` feature.
+//!
+//! References:
+//!  - https://wiki.wireshark.org/Development/LibpcapFileFormat
+//!  - https://www.ietf.org/archive/id/draft-tuexen-opsawg-pcapng-05.html
+
+#[cfg(feature = "std")]
+use std::io;
+
+const PCAP_MAGIC_USEC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b2_3c4d;
+
+const PCAPNG_BLOCK_SHB: u32 = 0x0a0d_0d0a;
+const PCAPNG_BLOCK_IDB: u32 = 0x0000_0001;
+const PCAPNG_BLOCK_OPB: u32 = 0x0000_0002;
+const PCAPNG_BLOCK_SPB: u32 = 0x0000_0003;
+const PCAPNG_BLOCK_EPB: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_IF_TSRESOL: u16 = 9;
+
+#[derive(Debug,Eq,PartialEq)]
+pub enum PcapError {
+    /// The capture does not start with a pcap or pcapng magic number
+    UnknownFormat,
+    /// The capture ended in the middle of a header, block or packet
+    Truncated,
+    /// A header or block contained an invalid value
+    Malformed(&'static str),
+}
+
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
+pub enum Format {
+    Pcap,
+    PcapNg,
+}
+
+/// A single captured packet
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
+pub struct Packet<'a> {
+    /// The captured bytes, which may be shorter than `orig_len` if the capture was truncated
+    pub data: &'a [u8],
+    /// Length of the packet on the wire
+    pub orig_len: u32,
+    /// Nanoseconds since the unix epoch
+    pub timestamp_ns: u64,
+    /// `LINKTYPE_*` value of the interface the packet was captured on
+    pub link_type: u32,
+}
+
+/// The interface a capture's packets were captured on
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
+pub struct Interface {
+    /// `LINKTYPE_*` value
+    pub link_type: u32,
+    /// The most bytes of a packet captured, or 0 for no limit
+    pub snap_len: u32,
+}
+
+#[derive(Debug,Clone,Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offs: usize, len: usize) -> Result<&'a [u8], PcapError> {
+        let end = offs.checked_add(len).ok_or(PcapError::Truncated)?;
+        self.data.get(offs..end).ok_or(PcapError::Truncated)
+    }
+
+    fn u16(&self, offs: usize) -> Result<u16, PcapError> {
+        let b = self.bytes(offs, 2)?;
+        let b = [b[0], b[1]];
+        Ok(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32(&self, offs: usize) -> Result<u32, PcapError> {
+        let b = self.bytes(offs, 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+}
+
+/// A pcap or pcapng capture held in memory
+#[derive(Debug,Clone)]
+pub struct Capture<'a> {
+    format: Format,
+    reader: Reader<'a>,
+    // pcap only
+    nsec: bool,
+    link_type: u32,
+}
+
+impl<'a> Capture<'a> {
+    /// Examine the header of `data` to determine the format of the capture
+    pub fn new(data: &'a [u8]) -> Result<Self, PcapError> {
+        let le = Reader { data, big_endian: false };
+        let magic = le.u32(0)?;
+
+        if magic == PCAPNG_BLOCK_SHB {
+            let bom = le.u32(8)?;
+            let big_endian = if bom == PCAPNG_BYTE_ORDER_MAGIC {
+                false
+            } else if bom.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC {
+                true
+            } else {
+                return Err(PcapError::Malformed("section header has bad byte order magic"));
+            };
+
+            return Ok(Capture {
+                format: Format::PcapNg,
+                reader: Reader { data, big_endian },
+                nsec: false,
+                link_type: 0,
+            });
+        }
+
+        let (big_endian, nsec) = match magic {
+            PCAP_MAGIC_USEC => (false, false),
+            PCAP_MAGIC_NSEC => (false, true),
+            m if m.swap_bytes() == PCAP_MAGIC_USEC => (true, false),
+            m if m.swap_bytes() == PCAP_MAGIC_NSEC => (true, true),
+            _ => return Err(PcapError::UnknownFormat),
+        };
+
+        let reader = Reader { data, big_endian };
+        Ok(Capture {
+            format: Format::Pcap,
+            reader,
+            nsec,
+            link_type: reader.u32(20)?,
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The interface described by the file header, for pcap, or by the first interface
+    /// description block, for pcapng
+    pub fn interface(&self) -> Result<Interface, PcapError> {
+        match self.format {
+            Format::Pcap => Ok(Interface { link_type: self.link_type, snap_len: self.reader.u32(16)? }),
+            Format::PcapNg => {
+                let mut packets = self.packets();
+                packets.offs = self.reader.data.len();
+                let iface = packets.iface(0)?;
+                Ok(Interface { link_type: iface.link_type, snap_len: iface.snap_len })
+            },
+        }
+    }
+
+    /// Iterate over the packets in the capture
+    ///
+    /// Iteration stops after the first error.
+    pub fn packets(&self) -> Packets<'a> {
+        Packets {
+            cap: self.clone(),
+            offs: match self.format {
+                Format::Pcap => 24,
+                Format::PcapNg => 0,
+            },
+            section: 0,
+            iface: None,
+            done: false,
+        }
+    }
+}
+
+/// Cached description of the most recently referenced pcapng interface
+#[derive(Debug,Clone,Copy)]
+struct Iface {
+    id: u32,
+    link_type: u32,
+    snap_len: u32,
+    tsresol: u8,
+}
+
+impl Iface {
+    fn timestamp_ns(&self, ts: u64) -> u64 {
+        let ts = ts as u128;
+        let ns = if self.tsresol & 0x80 != 0 {
+            (ts * 1_000_000_000) >> (self.tsresol & 0x7f)
+        } else {
+            let exp = u32::from(self.tsresol);
+            if exp <= 9 {
+                ts * 10u128.pow(9 - exp)
+            } else {
+                ts / 10u128.pow(core::cmp::min(exp - 9, 38))
+            }
+        };
+        ns as u64
+    }
+}
+
+/// Iterator over the packets of a `Capture`
+#[derive(Debug,Clone)]
+pub struct Packets<'a> {
+    cap: Capture<'a>,
+    offs: usize,
+    // pcapng: offset of the current section header
+    section: usize,
+    iface: Option<Iface>,
+    done: bool,
+}
+
+impl<'a> Packets<'a> {
+    fn next_pcap(&mut self) -> Result<Option<Packet<'a>>, PcapError> {
+        let r = self.cap.reader;
+        if self.offs == r.data.len() {
+            return Ok(None);
+        }
+
+        let ts_sec = r.u32(self.offs)?;
+        let ts_frac = r.u32(self.offs + 4)?;
+        let incl_len = r.u32(self.offs + 8)? as usize;
+        let orig_len = r.u32(self.offs + 12)?;
+        let data = r.bytes(self.offs + 16, incl_len)?;
+        self.offs += 16 + incl_len;
+
+        let frac_ns = if self.cap.nsec { ts_frac } else { ts_frac.wrapping_mul(1000) };
+        Ok(Some(Packet {
+            data,
+            orig_len,
+            timestamp_ns: u64::from(ts_sec) * 1_000_000_000 + u64::from(frac_ns),
+            link_type: self.cap.link_type,
+        }))
+    }
+
+    /// Locate the description of interface `id` in the current section
+    fn iface(&mut self, id: u32) -> Result<Iface, PcapError> {
+        if let Some(iface) = self.iface {
+            if iface.id == id {
+                return Ok(iface);
+            }
+        }
+
+        let r = self.cap.reader;
+        let mut offs = self.section;
+        let mut n = 0;
+        while offs < self.offs {
+            let ty = r.u32(offs)?;
+            let len = r.u32(offs + 4)? as usize;
+            if len < 12 {
+                return Err(PcapError::Malformed("block has invalid length"));
+            }
+            if ty == PCAPNG_BLOCK_SHB && offs != self.section {
+                break;
+            }
+
+            if ty == PCAPNG_BLOCK_IDB {
+                // link type, reserved, and snap length, then options
+                if len < 20 {
+                    return Err(PcapError::Malformed("interface description block is too short"));
+                }
+                if n == id {
+                    let iface = Iface {
+                        id,
+                        link_type: u32::from(r.u16(offs + 8)?),
+                        snap_len: r.u32(offs + 12)?,
+                        tsresol: idb_tsresol(r, offs + 16, offs + len - 4)?,
+                    };
+                    self.iface = Some(iface);
+                    return Ok(iface);
+                }
+                n += 1;
+            }
+            offs += len;
+        }
+
+        Err(PcapError::Malformed("packet references undefined interface"))
+    }
+
+    fn next_pcapng(&mut self) -> Result<Option<Packet<'a>>, PcapError> {
+        loop {
+            let r = self.cap.reader;
+            if self.offs == r.data.len() {
+                return Ok(None);
+            }
+
+            let ty = r.u32(self.offs)?;
+            if ty == PCAPNG_BLOCK_SHB {
+                let bom = Reader { data: r.data, big_endian: false }.u32(self.offs + 8)?;
+                self.cap.reader.big_endian = bom != PCAPNG_BYTE_ORDER_MAGIC;
+                self.section = self.offs;
+                self.iface = None;
+            }
+
+            let r = self.cap.reader;
+            let block = self.offs;
+            let len = r.u32(block + 4)? as usize;
+            if len < 12 || len & 3 != 0 {
+                return Err(PcapError::Malformed("block has invalid length"));
+            }
+            r.bytes(block, len)?;
+            self.offs += len;
+
+            let (iface, ts, cap_len, orig_len, data_offs) = match ty {
+                PCAPNG_BLOCK_EPB => {
+                    let iface = self.iface(r.u32(block + 8)?)?;
+                    let ts = (u64::from(r.u32(block + 12)?) << 32) | u64::from(r.u32(block + 16)?);
+                    (iface, ts, r.u32(block + 20)?, r.u32(block + 24)?, block + 28)
+                },
+                PCAPNG_BLOCK_OPB => {
+                    let iface = self.iface(u32::from(r.u16(block + 8)?))?;
+                    let ts = (u64::from(r.u32(block + 12)?) << 32) | u64::from(r.u32(block + 16)?);
+                    (iface, ts, r.u32(block + 20)?, r.u32(block + 24)?, block + 28)
+                },
+                PCAPNG_BLOCK_SPB => {
+                    let iface = self.iface(0)?;
+                    let orig_len = r.u32(block + 8)?;
+                    let mut cap_len = core::cmp::min(orig_len, len.saturating_sub(16) as u32);
+                    if iface.snap_len != 0 {
+                        cap_len = core::cmp::min(cap_len, iface.snap_len);
+                    }
+                    (iface, 0, cap_len, orig_len, block + 12)
+                },
+                _ => continue,
+            };
+
+            if data_offs + cap_len as usize > block + len - 4 {
+                return Err(PcapError::Malformed("packet data overruns block"));
+            }
+
+            return Ok(Some(Packet {
+                data: r.bytes(data_offs, cap_len as usize)?,
+                orig_len,
+                timestamp_ns: iface.timestamp_ns(ts),
+                link_type: iface.link_type,
+            }));
+        }
+    }
+}
+
+/// Find the `if_tsresol` option of an interface description block, defaulting to microseconds
+fn idb_tsresol(r: Reader, mut offs: usize, end: usize) -> Result<u8, PcapError> {
+    while offs + 4 <= end {
+        let code = r.u16(offs)?;
+        let len = r.u16(offs + 2)? as usize;
+        if code == PCAPNG_OPT_END {
+            break;
+        }
+        if code == PCAPNG_OPT_IF_TSRESOL && len == 1 {
+            return Ok(r.bytes(offs + 4, 1)?[0]);
+        }
+        offs += 4 + ((len + 3) & !3);
+    }
+
+    Ok(6)
+}
+
+impl<'a> Iterator for Packets<'a> {
+    type Item = Result<Packet<'a>, PcapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let r = match self.cap.format {
+            Format::Pcap => self.next_pcap(),
+            Format::PcapNg => self.next_pcapng(),
+        };
+
+        match r {
+            Ok(Some(p)) => Some(Ok(p)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Writes packets to a (microsecond resolution, little endian) pcap file
+#[cfg(feature = "std")]
+pub struct Writer<W: io::Write> {
+    w: W,
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write> Writer<W> {
+    /// Emit the file header. All packets written must have the given `link_type`.
+    pub fn new(mut w: W, link_type: u32, snap_len: u32) -> io::Result<Self> {
+        let mut h = [0u8; 24];
+        h[0..4].copy_from_slice(&PCAP_MAGIC_USEC.to_le_bytes());
+        h[4..6].copy_from_slice(&2u16.to_le_bytes());
+        h[6..8].copy_from_slice(&4u16.to_le_bytes());
+        h[16..20].copy_from_slice(&snap_len.to_le_bytes());
+        h[20..24].copy_from_slice(&link_type.to_le_bytes());
+        w.write_all(&h)?;
+        Ok(Writer { w })
+    }
+
+    pub fn write_packet(&mut self, pkt: &Packet) -> io::Result<()> {
+        let sec = pkt.timestamp_ns / 1_000_000_000;
+        let usec = (pkt.timestamp_ns % 1_000_000_000) / 1000;
+        let mut h = [0u8; 16];
+        h[0..4].copy_from_slice(&(sec as u32).to_le_bytes());
+        h[4..8].copy_from_slice(&(usec as u32).to_le_bytes());
+        h[8..12].copy_from_slice(&(pkt.data.len() as u32).to_le_bytes());
+        h[12..16].copy_from_slice(&pkt.orig_len.to_le_bytes());
+        self.w.write_all(&h)?;
+        self.w.write_all(pkt.data)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
@@ -0,0 +1,283 @@
+//! Program types, describing what a program run in a particular place may do
+//!
+//! A `Profile` gives the layout of the context a program is passed in `r1`, the helpers it may
+//! call, the values it may return, and whether it may use `Mode::Abs`/`Mode::Ind` packet loads.
+//! `Env::profile` selects the one programs are verified against.
+//!
+//! Each helper has a `HelperProto`, which the verifier checks every call against.
+
+use alloc::vec::Vec;
+use verifier::PtrKind;
+
+/// Helper ids, as numbered by linux, and their prototypes
+pub mod helper {
+    use super::{ArgKind, HelperProto, RetKind};
+    use super::ArgKind::*;
+
+    pub const MAP_LOOKUP_ELEM: u32 = 1;
+    pub const MAP_UPDATE_ELEM: u32 = 2;
+    pub const MAP_DELETE_ELEM: u32 = 3;
+    pub const PROBE_READ: u32 = 4;
+    pub const KTIME_GET_NS: u32 = 5;
+    pub const TRACE_PRINTK: u32 = 6;
+    pub const GET_PRANDOM_U32: u32 = 7;
+    pub const GET_SMP_PROCESSOR_ID: u32 = 8;
+    pub const GET_CURRENT_PID_TGID: u32 = 14;
+    pub const GET_CURRENT_UID_GID: u32 = 15;
+    pub const GET_CURRENT_COMM: u32 = 16;
+    pub const PERF_EVENT_OUTPUT: u32 = 25;
+    pub const SKB_LOAD_BYTES: u32 = 26;
+    pub const XDP_ADJUST_HEAD: u32 = 44;
+    pub const REDIRECT_MAP: u32 = 51;
+
+    /// The prototype of the helper `id`, for those above
+    pub fn proto(id: u32) -> Option<HelperProto> {
+        let p = |args: &[ArgKind], ret| HelperProto::new(args, ret);
+        Some(match id {
+            MAP_LOOKUP_ELEM => p(&[Map, MapKey], RetKind::MapValueOrNull),
+            MAP_UPDATE_ELEM => p(&[Map, MapKey, MapValue, Scalar], RetKind::Scalar),
+            MAP_DELETE_ELEM => p(&[Map, MapKey], RetKind::Scalar),
+            PROBE_READ => p(&[UninitStackMem, ConstSize, Scalar], RetKind::Scalar),
+            TRACE_PRINTK => p(&[StackMem, ConstSize, Scalar, Scalar, Scalar], RetKind::Scalar),
+            KTIME_GET_NS | GET_PRANDOM_U32 | GET_SMP_PROCESSOR_ID | GET_CURRENT_PID_TGID
+                | GET_CURRENT_UID_GID => p(&[], RetKind::Scalar),
+            GET_CURRENT_COMM => p(&[UninitStackMem, ConstSize], RetKind::Scalar),
+            PERF_EVENT_OUTPUT => p(&[Ctx, Map, Scalar, StackMem, ConstSize], RetKind::Scalar),
+            SKB_LOAD_BYTES => p(&[Ctx, Scalar, UninitStackMem, ConstSize], RetKind::Scalar),
+            XDP_ADJUST_HEAD => HelperProto {
+                changes_packet: true,
+                ..p(&[Ctx, Scalar], RetKind::Scalar)
+            },
+            REDIRECT_MAP => p(&[Map, Scalar, Scalar], RetKind::Scalar),
+            _ => return None,
+        })
+    }
+}
+
+/// What a helper expects in an argument register
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ArgKind {
+    /// Any number
+    Scalar,
+    /// The context pointer, as passed in `r1`
+    Ctx,
+    /// A map, from `ld_imm64`
+    Map,
+    /// A pointer to the stack, holding a key of the map in an earlier `Map` argument
+    MapKey,
+    /// A pointer to the stack, holding a value of the map in an earlier `Map` argument
+    MapValue,
+    /// A pointer to initialized stack, which the helper reads as many bytes of as the next
+    /// argument (a `ConstSize`) gives
+    StackMem,
+    /// Like `StackMem`, but the helper writes the stack instead, so it need not be initialized
+    UninitStackMem,
+    /// The size of the memory in the previous argument: a number known to be between 1 and the
+    /// size of the stack
+    ConstSize,
+}
+
+/// What a helper returns in `r0`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RetKind {
+    /// Any number
+    Scalar,
+    /// A value of the map in the `Map` argument, or null
+    MapValueOrNull,
+    /// Nothing, leaving `r0` uninitialized
+    Void,
+}
+
+/// The arguments and return value of a helper
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct HelperProto {
+    /// What `r1`, `r2`, ... are expected to hold. At most 5.
+    pub args: Vec<ArgKind>,
+    pub ret: RetKind,
+    /// The helper may move or resize the packet, so earlier packet pointers can't be used
+    pub changes_packet: bool,
+}
+
+impl HelperProto {
+    pub fn new(args: &[ArgKind], ret: RetKind) -> Self {
+        HelperProto {
+            args: args.to_vec(),
+            ret,
+            changes_packet: false,
+        }
+    }
+
+    /// At most 5 arguments, with a `ConstSize` after each `StackMem` and `UninitStackMem`
+    pub fn is_valid(&self) -> bool {
+        self.args.len() <= 5 && self.args.iter().enumerate().all(|(n, &a)| match a {
+            ArgKind::StackMem | ArgKind::UninitStackMem => self.args.get(n + 1) == Some(&ArgKind::ConstSize),
+            _ => true,
+        })
+    }
+}
+
+/// A field of the context which a program may access
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct CtxField {
+    /// Offset from the start of the context
+    pub off: u32,
+    /// Size in bytes. Smaller loads from within the field are allowed, unless it is a pointer.
+    pub size: u32,
+    /// Stores to the field are allowed
+    pub write: bool,
+    /// Loading the field gives a pointer to the start (`Packet`) or end (`PacketEnd`) of the
+    /// packet, instead of a number
+    pub ptr: Option<PtrKind>,
+}
+
+impl CtxField {
+    /// A field which may only be read
+    pub fn read(off: u32, size: u32) -> Self {
+        CtxField { off, size, write: false, ptr: None }
+    }
+
+    /// A field which may be read or written
+    pub fn write(off: u32, size: u32) -> Self {
+        CtxField { off, size, write: true, ptr: None }
+    }
+
+    /// A read only field holding a pointer of `kind`
+    pub fn ptr(off: u32, size: u32, kind: PtrKind) -> Self {
+        CtxField { off, size, write: false, ptr: Some(kind) }
+    }
+}
+
+/// The values a program may return in `r0`
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum RetValues {
+    /// Any value from the first to the second, inclusive
+    Range(u64, u64),
+    /// Only these values
+    Set(Vec<u64>),
+}
+
+/// What programs of one type may do
+///
+/// The default has an empty context, no helpers, and allows any return value and packet loads.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Profile {
+    /// Fields of the context which may be accessed. Any other access is rejected.
+    pub ctx: Vec<CtxField>,
+    /// Ids of the helpers which may be called
+    pub helpers: Vec<u32>,
+    /// The values the program may return, or `None` for any value
+    pub ret: Option<RetValues>,
+    /// `Mode::Abs` and `Mode::Ind` loads of packet data are allowed
+    pub ld_abs: bool,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            ctx: Vec::new(),
+            helpers: Vec::new(),
+            ret: None,
+            ld_abs: true,
+        }
+    }
+}
+
+/// Read only fields of `size` bytes, one after another from `off`
+fn fields(off: u32, size: u32, n: u32) -> impl Iterator<Item = CtxField> {
+    (0..n).map(move |i| CtxField::read(off + i * size, size))
+}
+
+impl Profile {
+    /// A socket filter, passed linux's `struct __sk_buff`. Returns how many bytes of the packet to
+    /// keep.
+    pub fn socket_filter() -> Self {
+        // `len` to `tc_index`, then `cb[5]`, then `hash`
+        let mut ctx: Vec<CtxField> = fields(0, 4, 12).collect();
+        ctx.extend((0..5).map(|i| CtxField::write(48 + i * 4, 4)));
+        ctx.push(CtxField::read(68, 4));
+
+        Profile {
+            ctx,
+            helpers: [
+                helper::MAP_LOOKUP_ELEM,
+                helper::MAP_UPDATE_ELEM,
+                helper::MAP_DELETE_ELEM,
+                helper::KTIME_GET_NS,
+                helper::GET_PRANDOM_U32,
+                helper::GET_SMP_PROCESSOR_ID,
+                helper::SKB_LOAD_BYTES,
+            ].to_vec(),
+            ret: Some(RetValues::Range(0, u64::from(u32::MAX))),
+            ld_abs: true,
+        }
+    }
+
+    /// An XDP program, passed linux's `struct xdp_md`. Returns an action from `XDP_ABORTED` (0) to
+    /// `XDP_REDIRECT` (4).
+    pub fn xdp() -> Self {
+        let mut ctx = [
+            CtxField::ptr(0, 4, PtrKind::Packet),
+            CtxField::ptr(4, 4, PtrKind::PacketEnd),
+        ].to_vec();
+        // `ingress_ifindex`, `rx_queue_index`, and `egress_ifindex`
+        ctx.extend(fields(12, 4, 3));
+
+        Profile {
+            ctx,
+            helpers: [
+                helper::MAP_LOOKUP_ELEM,
+                helper::MAP_UPDATE_ELEM,
+                helper::MAP_DELETE_ELEM,
+                helper::KTIME_GET_NS,
+                helper::GET_PRANDOM_U32,
+                helper::GET_SMP_PROCESSOR_ID,
+                helper::XDP_ADJUST_HEAD,
+                helper::REDIRECT_MAP,
+            ].to_vec(),
+            ret: Some(RetValues::Range(0, 4)),
+            ld_abs: false,
+        }
+    }
+
+    /// A seccomp filter, passed linux's `struct seccomp_data`. Returns an action word.
+    pub fn seccomp() -> Self {
+        // `nr` and `arch`, then `instruction_pointer` and `args[6]`
+        let ctx = fields(0, 4, 2).chain(fields(8, 8, 7)).collect();
+
+        Profile {
+            ctx,
+            helpers: Vec::new(),
+            ret: Some(RetValues::Range(0, u64::from(u32::MAX))),
+            ld_abs: false,
+        }
+    }
+
+    /// A tracing program (such as a kprobe), passed x86-64's `struct pt_regs`. The return value is
+    /// ignored.
+    pub fn tracing() -> Self {
+        Profile {
+            ctx: fields(0, 8, 21).collect(),
+            helpers: [
+                helper::MAP_LOOKUP_ELEM,
+                helper::MAP_UPDATE_ELEM,
+                helper::MAP_DELETE_ELEM,
+                helper::PROBE_READ,
+                helper::KTIME_GET_NS,
+                helper::TRACE_PRINTK,
+                helper::GET_PRANDOM_U32,
+                helper::GET_SMP_PROCESSOR_ID,
+                helper::GET_CURRENT_PID_TGID,
+                helper::GET_CURRENT_UID_GID,
+                helper::GET_CURRENT_COMM,
+                helper::PERF_EVENT_OUTPUT,
+            ].to_vec(),
+            ret: None,
+            ld_abs: false,
+        }
+    }
+
+    /// The field containing all of `size` bytes at `off`, if any
+    pub fn ctx_field(&self, off: i64, size: i64) -> Option<&CtxField> {
+        self.ctx.iter().find(|f| i64::from(f.off) <= off && off + size <= i64::from(f.off) + i64::from(f.size))
+    }
+}
@@ -0,0 +1,115 @@
+//! Run a program over each packet of a capture
+//!
+//! Each packet is presented to the program as a `SliceDataArea`, and the program's return value is
+//! interpreted the way a socket filter's is: `0` rejects the packet, anything else accepts it and
+//! gives the number of bytes of the packet to keep.
+
+use super::*;
+use core::cmp;
+use pcap::{Packet, Packets, PcapError};
+
+/// The result of running a program over a single packet
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Outcome<'a> {
+    pub packet: Packet<'a>,
+    /// Value returned by the program, or `Err` if the program failed to run to completion
+    pub ret: Result<u64, ()>,
+}
+
+impl<'a> Outcome<'a> {
+    pub fn is_accept(&self) -> bool {
+        match self.ret {
+            Ok(v) => v != 0,
+            Err(_) => false,
+        }
+    }
+
+    /// Number of bytes of the packet retained by the program
+    pub fn len(&self) -> usize {
+        match self.ret {
+            Ok(v) => cmp::min(v, self.packet.data.len() as u64) as usize,
+            Err(_) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The packet as retained by the program: truncated to the returned length
+    pub fn kept(&self) -> Packet<'a> {
+        Packet {
+            data: &self.packet.data[..self.len()],
+            ..self.packet
+        }
+    }
+}
+
+/// Counts of the outcomes of a replay
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct Summary {
+    pub packets: usize,
+    pub accepted: usize,
+    pub rejected: usize,
+    /// Packets for which the program did not complete (for example, due to an out of bounds load)
+    pub errors: usize,
+}
+
+impl Summary {
+    pub fn record(&mut self, o: &Outcome) {
+        self.packets += 1;
+        if o.ret.is_err() {
+            self.errors += 1;
+        } else if o.is_accept() {
+            self.accepted += 1;
+        } else {
+            self.rejected += 1;
+        }
+    }
+}
+
+/// Run `prgm` with `data` as its data area
+#[allow(clippy::result_unit_err)]
+pub fn run_packet(prgm: &Program, data: &[u8]) -> Result<u64, ()> {
+    Invoke::with_data_area(prgm.clone(), SliceDataArea::new(data)).run()
+}
+
+/// Iterator that runs a program over each packet in a capture, yielding an `Outcome` per packet
+pub struct Replay<'a, 'p> {
+    prgm: Program<'p>,
+    packets: Packets<'a>,
+    summary: Summary,
+}
+
+impl<'a, 'p> Replay<'a, 'p> {
+    pub fn new<P: Into<Program<'p>>>(prgm: P, packets: Packets<'a>) -> Self {
+        Replay {
+            prgm: prgm.into(),
+            packets,
+            summary: Summary::default(),
+        }
+    }
+
+    /// Counts of the outcomes yielded so far
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+}
+
+impl<'a, 'p> Iterator for Replay<'a, 'p> {
+    type Item = Result<Outcome<'a>, PcapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let packet = match self.packets.next()? {
+            Ok(p) => p,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let o = Outcome {
+            packet,
+            ret: run_packet(&self.prgm, packet.data),
+        };
+        self.summary.record(&o);
+        Some(Ok(o))
+    }
+}
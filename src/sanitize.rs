@@ -0,0 +1,494 @@
+//! Checks, while a program runs, of what the verifier checks before it runs
+//!
+//! `Invoke::run_sanitized` runs a program as `Invoke::run` does, but first checks each
+//! instruction's encoding, and tracks alongside every register and byte of the stack whether it
+//! has been written, and whether it holds a number or a pointer: into the stack of a function, to
+//! a map, or into a map value. Registers read before they are written, arithmetic the verifier
+//! does not allow on pointers, loads and stores outside the stack or map value or through
+//! numbers, reads of stack which was never written, and pointers leaked through `r0` or a store
+//! are reported, with the index of the instruction which did it.
+//!
+//! Calls to other BPF functions are followed as `Invoke` makes them, each function with a stack
+//! of its own. Of the helpers, only those `Invoke` provides for `Maps` may be called, with a map
+//! and keys and values on the stack or in a map value. A map value a lookup gives need not be
+//! compared with 0 before use, as the verifier requires, as long as it is not null.
+//!
+//! A verified program should never fail these checks, so a failure shows a case where the
+//! verifier's model of a program differs from how it really runs.
+
+use super::*;
+use core::fmt;
+
+/// What a sanitized run of a program found wrong
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SanitizeErrorKind {
+    /// The instruction is not a valid encoding, including when a reserved field is not zero
+    InvalidInst(InstDecodeError),
+    /// Execution continues outside of the program
+    OutOfProgram,
+    /// The instruction is valid, but `Invoke` can't run it
+    Unsupported(&'static str),
+    /// A register is read before it is written. For `exit`, this is `r0`.
+    UninitializedRegister {
+        reg: u8,
+    },
+    /// An operation which the verifier does not allow on pointers
+    InvalidPointerArithmetic(&'static str),
+    /// A load or store through `reg`, which does not hold a pointer
+    InvalidMemoryAccess {
+        reg: u8,
+    },
+    /// A load or store of `size` bytes at `off` from the top of a function's stack is outside of it
+    OutOfBounds {
+        off: i64,
+        size: u64,
+    },
+    /// A load or store of `size` bytes at `off` from the top of a function's stack is not aligned
+    /// to `size`
+    Misaligned {
+        off: i64,
+        size: u64,
+    },
+    /// A load or store of `size` bytes at `off` in a map value is outside of it
+    MapValueOutOfBounds {
+        off: i64,
+        size: u64,
+    },
+    /// The stack byte at `off` from the top of a function's stack is read before it is written
+    UninitializedStack {
+        off: i64,
+    },
+    /// A load or store of `size` bytes at `off` covers only part of a pointer spilled to the stack
+    PartialPointer {
+        off: i64,
+        size: u64,
+    },
+    /// The pointer in `reg` is returned, or stored where it may outlive what it points to
+    PointerLeak {
+        reg: u8,
+    },
+    /// `ld_imm64` refers to a map the `Invoke` does not have
+    UnknownMap {
+        index: u32,
+    },
+    /// The argument in `reg` of a helper call is not what the helper expects
+    InvalidHelperArg {
+        reg: u8,
+    },
+    /// A call to another BPF function would put more than `limit` functions on the call stack
+    CallDepthExceeded {
+        limit: usize,
+    },
+    /// A `Mode::Abs` or `Mode::Ind` load was outside the `DataArea`. This ends `Invoke::run` too,
+    /// and is not a fault in the program.
+    LoadFailed,
+}
+
+impl fmt::Display for SanitizeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SanitizeErrorKind::*;
+        match *self {
+            InvalidInst(InstDecodeError::InvalidEncoding(s))
+                | InvalidInst(InstDecodeError::ForbiddenInst(s))
+                | InvalidInst(InstDecodeError::Other(s)) => write!(f, "invalid instruction: {}", s),
+            OutOfProgram => write!(f, "execution left the program"),
+            Unsupported(s) => write!(f, "unsupported: {}", s),
+            UninitializedRegister { reg } => write!(f, "r{} is read before it is written", reg),
+            InvalidPointerArithmetic(s) => write!(f, "invalid pointer arithmetic: {}", s),
+            InvalidMemoryAccess { reg } => write!(f, "r{} is not a pointer, and can't be used for this access", reg),
+            OutOfBounds { off, size } => write!(f, "access of {} bytes at fp{} is outside the stack", size, off),
+            Misaligned { off, size } => write!(f, "access of {} bytes at fp{} is misaligned", size, off),
+            MapValueOutOfBounds { off, size } =>
+                write!(f, "access of {} bytes at offset {} is outside the map value", size, off),
+            UninitializedStack { off } => write!(f, "stack at fp{} is read before it is written", off),
+            PartialPointer { off, size } =>
+                write!(f, "access of {} bytes at fp{} covers part of a pointer", size, off),
+            PointerLeak { reg } => write!(f, "r{} leaks a pointer", reg),
+            UnknownMap { index } => write!(f, "no map with index {}", index),
+            InvalidHelperArg { reg } => write!(f, "r{} does not hold the helper argument expected", reg),
+            CallDepthExceeded { limit } => write!(f, "more than {} functions on the call stack", limit),
+            LoadFailed => write!(f, "packet load outside the data area"),
+        }
+    }
+}
+
+/// An error from `Invoke::run_sanitized`, and the index of the instruction which caused it
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct SanitizeError {
+    pc: usize,
+    kind: SanitizeErrorKind,
+}
+
+impl SanitizeError {
+    /// Index of the instruction the error was found at
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn kind(&self) -> &SanitizeErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for SanitizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "instruction {}: {}", self.pc, self.kind)
+    }
+}
+
+/// What a register or byte of the stack holds
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Shadow {
+    /// Not yet written
+    Uninit,
+    /// A number
+    Scalar,
+    /// A pointer into the stack of the function `frame` calls deep, or for the stack, part of one
+    /// spilled there
+    StackPtr(u8),
+    /// A map, from `ld_imm64`
+    MapPtr,
+    /// A pointer into a map value
+    ValuePtr,
+}
+
+impl Shadow {
+    fn is_ptr(self) -> bool {
+        self != Shadow::Uninit && self != Shadow::Scalar
+    }
+}
+
+/// The bytes of stack of every frame, as `Invoke` holds them
+const STACK_BYTES: usize = STACK_SIZE * MAX_CALL_FRAMES;
+
+/// The index in the stack of byte `off` from the `r10` of function `frame`
+fn stack_idx(frame: usize, off: i64) -> usize {
+    (STACK_BYTES as i64 - (frame * STACK_SIZE) as i64 + off) as usize
+}
+
+/// The offset from its function's `r10` of the stack byte at `idx`
+fn stack_off(idx: usize) -> i64 {
+    (idx % STACK_SIZE) as i64 - STACK_SIZE as i64
+}
+
+/// The function whose stack holds the byte at `idx`, as `Shadow::StackPtr` counts them
+fn stack_frame(idx: usize) -> u8 {
+    (MAX_CALL_FRAMES - 1 - idx / STACK_SIZE) as u8
+}
+
+/// What a load or store accesses
+enum Target {
+    /// The stack, from this index
+    Stack(usize),
+    /// A map value, which only ever holds numbers
+    Value,
+}
+
+/// The `Shadow` of each register and stack byte of an `Invoke`
+struct Sanitizer {
+    regs: [Shadow;11],
+    stack: [Shadow;STACK_BYTES],
+    /// The `r6` to `r10` of each function waiting on a call
+    frames: [[Shadow;5];MAX_CALL_FRAMES],
+}
+
+impl Sanitizer {
+    fn read(&self, reg: u8) -> Result<Shadow, SanitizeErrorKind> {
+        match self.regs[reg as usize] {
+            Shadow::Uninit => Err(SanitizeErrorKind::UninitializedRegister { reg }),
+            s => Ok(s),
+        }
+    }
+
+    /// What loading `size` bytes at `idx` gives
+    fn load(&self, idx: usize, size: usize) -> Result<Shadow, SanitizeErrorKind> {
+        let off = stack_off(idx);
+        let bytes = &self.stack[idx..idx + size];
+        if let Some(n) = bytes.iter().position(|&b| b == Shadow::Uninit) {
+            return Err(SanitizeErrorKind::UninitializedStack { off: off + n as i64 });
+        }
+        match bytes.iter().filter(|b| b.is_ptr()).count() {
+            0 => Ok(Shadow::Scalar),
+            8 if bytes.iter().all(|&b| b == bytes[0]) => Ok(bytes[0]),
+            _ => Err(SanitizeErrorKind::PartialPointer { off, size: size as u64 }),
+        }
+    }
+
+    fn store(&mut self, idx: usize, size: usize, v: Shadow) -> Result<(), SanitizeErrorKind> {
+        if v.is_ptr() && size != 8 {
+            return Err(SanitizeErrorKind::PartialPointer { off: stack_off(idx), size: size as u64 });
+        }
+        for b in self.stack[idx..idx + size].iter_mut() {
+            *b = v;
+        }
+        Ok(())
+    }
+
+    /// What an `Alu` or `Alu64` instruction gives, from its operands
+    fn alu(&self, i: &Inst) -> Result<Shadow, SanitizeErrorKind> {
+        use self::SanitizeErrorKind::InvalidPointerArithmetic as Invalid;
+
+        let src = if i.op_src() == Some(Src::X) { self.read(i.src())? } else { Shadow::Scalar };
+        let op = i.op_alu();
+        if op == Some(OpAlu::Mov) {
+            if src.is_ptr() && i.op_class() == Some(Class::Alu) {
+                return Err(Invalid("32-bit operation on a pointer"));
+            }
+            return Ok(src);
+        }
+
+        let dst = self.read(i.dst())?;
+        let (dst, src) = match (dst, src) {
+            (Shadow::Scalar, Shadow::Scalar) => return Ok(Shadow::Scalar),
+            _ if i.op_class() == Some(Class::Alu) => return Err(Invalid("32-bit operation on a pointer")),
+            (Shadow::MapPtr, _) | (_, Shadow::MapPtr) if op == Some(OpAlu::Add) || op == Some(OpAlu::Sub) =>
+                return Err(Invalid("arithmetic on a pointer that can't be moved")),
+            ds => ds,
+        };
+        match (op, dst, src) {
+            (Some(OpAlu::Add), Shadow::Scalar, p) | (Some(OpAlu::Add), p, Shadow::Scalar) => Ok(p),
+            (Some(OpAlu::Add), _, _) => Err(Invalid("adding two pointers")),
+            (Some(OpAlu::Sub), p, Shadow::Scalar) => Ok(p),
+            (Some(OpAlu::Sub), Shadow::Scalar, _) => Err(Invalid("subtracting a pointer from a number")),
+            // the distance between two pointers into the same region
+            (Some(OpAlu::Sub), Shadow::StackPtr(_), Shadow::StackPtr(_)) => Ok(Shadow::Scalar),
+            (Some(OpAlu::Sub), Shadow::ValuePtr, Shadow::ValuePtr) => Ok(Shadow::Scalar),
+            (Some(OpAlu::Sub), _, _) => Err(Invalid("subtracting pointers of different kinds")),
+            _ => Err(Invalid("only add and sub may be used on pointers")),
+        }
+    }
+}
+
+impl<'a, D: DataArea, M: Maps> Invoke<'a, D, M> {
+    /// Run the program as `run` does, checking each instruction as it goes
+    ///
+    /// Registers set by `arg_raw` are numbers, and `r10` points to the top of the stack. Any other
+    /// register is uninitialized.
+    pub fn run_sanitized(mut self) -> Result<u64, SanitizeError> {
+        let mut san = Sanitizer {
+            regs: [Shadow::Uninit;11],
+            stack: [Shadow::Uninit;STACK_BYTES],
+            frames: [[Shadow::Uninit;5];MAX_CALL_FRAMES],
+        };
+        for (r, s) in san.regs.iter_mut().enumerate() {
+            if self.args & (1 << r) != 0 {
+                *s = Shadow::Scalar;
+            }
+        }
+        san.regs[10] = Shadow::StackPtr(0);
+
+        let mut pc = 0;
+        loop {
+            let err = |kind| SanitizeError { pc, kind };
+            self.check(&mut san, pc).map_err(err)?;
+            // everything else `step` may fail on was checked first
+            match self.step(pc).map_err(|_| err(SanitizeErrorKind::LoadFailed))? {
+                Step::Next(next) if next >= self.prgm.data.len() => return Err(err(SanitizeErrorKind::OutOfProgram)),
+                Step::Next(next) => pc = next,
+                Step::Exit(v) => return Ok(v),
+            }
+            // a map lookup which found nothing gives a number, not a pointer
+            if san.regs[0] == Shadow::ValuePtr && self.regs[0] == 0 {
+                san.regs[0] = Shadow::Scalar;
+            }
+        }
+    }
+
+    /// Where a `size` byte access at `off` from the pointer in `reg` goes, checking it is within
+    /// the stack of a function still running or within a map value, and if `aligned`, that it is
+    /// aligned on the stack
+    fn access(&mut self, san: &Sanitizer, reg: u8, off: i16, size: usize, aligned: bool)
+        -> Result<Target, SanitizeErrorKind>
+    {
+        let addr = self.regs[reg as usize].wrapping_add(off as i64 as u64);
+        match san.read(reg)? {
+            Shadow::StackPtr(frame) => {
+                let frame = frame as usize;
+                let top = STACK_TOP - (frame * STACK_SIZE) as u64;
+                let off = addr.wrapping_sub(top) as i64;
+                let size = size as i64;
+                if off < -(STACK_SIZE as i64) || off > -size {
+                    return Err(SanitizeErrorKind::OutOfBounds { off, size: size as u64 });
+                }
+                if aligned && off % size != 0 {
+                    return Err(SanitizeErrorKind::Misaligned { off, size: size as u64 });
+                }
+                Ok(Target::Stack(stack_idx(frame, off)))
+            },
+            Shadow::ValuePtr => {
+                let out = SanitizeErrorKind::MapValueOutOfBounds { off: (addr & 0xff_ffff) as i64, size: size as u64 };
+                let (map, slot, off) = value_parts(addr).ok_or(out)?;
+                match self.maps.sizes(map) {
+                    Some((_, value_size)) if off + size <= value_size => {},
+                    _ => return Err(out),
+                }
+                match self.maps.value(map, slot) {
+                    Some(_) => Ok(Target::Value),
+                    None => Err(SanitizeErrorKind::Unsupported("access to a deleted map value")),
+                }
+            },
+            _ => Err(SanitizeErrorKind::InvalidMemoryAccess { reg }),
+        }
+    }
+
+    /// Check `reg` points to `size` bytes a helper may read: numbers, on the stack or in a map
+    /// value
+    fn helper_mem(&mut self, san: &Sanitizer, reg: u8, size: usize) -> Result<(), SanitizeErrorKind> {
+        match san.read(reg)? {
+            Shadow::StackPtr(_) | Shadow::ValuePtr => {},
+            _ => return Err(SanitizeErrorKind::InvalidHelperArg { reg }),
+        }
+        if let Target::Stack(idx) = self.access(san, reg, 0, size, false)? {
+            for (n, &b) in san.stack[idx..idx + size].iter().enumerate() {
+                match b {
+                    Shadow::Scalar => {},
+                    Shadow::Uninit => return Err(SanitizeErrorKind::UninitializedStack { off: stack_off(idx + n) }),
+                    _ => return Err(SanitizeErrorKind::PartialPointer { off: stack_off(idx), size: size as u64 }),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a call to the helper `id` may be made, then clobber `r1` to `r5`
+    fn helper_call(&mut self, san: &mut Sanitizer, id: u32) -> Result<(), SanitizeErrorKind> {
+        if !(1..=3).contains(&id) {
+            return Err(SanitizeErrorKind::Unsupported("helper other than a map lookup, update or delete"));
+        }
+        if san.read(1)? != Shadow::MapPtr {
+            return Err(SanitizeErrorKind::InvalidHelperArg { reg: 1 });
+        }
+        // a `MapPtr` is only given for a map `Invoke` has
+        let (key_size, value_size) = self.maps.sizes((self.regs[1] - MAP_ADDR) as usize).unwrap();
+        self.helper_mem(san, 2, key_size)?;
+        if id == 2 {
+            self.helper_mem(san, 3, value_size)?;
+            if san.read(4)? != Shadow::Scalar {
+                return Err(SanitizeErrorKind::InvalidHelperArg { reg: 4 });
+            }
+        }
+        for r in 1..6 {
+            san.regs[r] = Shadow::Uninit;
+        }
+        san.regs[0] = if id == 1 { Shadow::ValuePtr } else { Shadow::Scalar };
+        Ok(())
+    }
+
+    /// Check the instruction at `pc` may be run from the state in `san`, then update `san` to
+    /// what running it gives
+    fn check(&mut self, san: &mut Sanitizer, pc: usize) -> Result<(), SanitizeErrorKind> {
+        let data = self.prgm.data;
+        let raw = *data.get(pc).ok_or(SanitizeErrorKind::OutOfProgram)?;
+        let i = Inst::from_u64(raw).map_err(SanitizeErrorKind::InvalidInst)?;
+        i.check().map_err(SanitizeErrorKind::InvalidInst)?;
+        let mut hi = 0;
+        if i.is_ld_imm64() {
+            let next = data.get(pc + 1).ok_or(SanitizeErrorKind::OutOfProgram)?;
+            let next = Inst::from_u64(*next).map_err(SanitizeErrorKind::InvalidInst)?;
+            next.check_imm64_hi().map_err(SanitizeErrorKind::InvalidInst)?;
+            hi = next.imm32();
+        }
+
+        let size = i.ld_size().map_or(0, Size::bytes);
+        let dst = i.dst() as usize;
+        match i.op_class() {
+            Some(Class::Ld) => {
+                if i.ld_mode() == Some(Mode::Ind) && san.read(i.src())? != Shadow::Scalar {
+                    return Err(SanitizeErrorKind::InvalidPointerArithmetic("packet load indexed by a pointer"));
+                }
+                let map = i.imm32() as usize;
+                if i.is_ld_imm64() && i.src() != 0 && self.maps.sizes(map).is_none() {
+                    return Err(SanitizeErrorKind::UnknownMap { index: i.imm32() });
+                }
+                san.regs[dst] = match i.src() {
+                    1 if i.is_ld_imm64() => Shadow::MapPtr,
+                    2 if i.is_ld_imm64() => {
+                        let slot = self.maps.direct_value(map)
+                            .ok_or(SanitizeErrorKind::Unsupported("map without a direct value"))?;
+                        value_addr(map, slot, u64::from(hi))
+                            .ok_or(SanitizeErrorKind::Unsupported("map value offset too large"))?;
+                        Shadow::ValuePtr
+                    },
+                    _ => Shadow::Scalar,
+                };
+            },
+            Some(Class::Ldx) => {
+                san.regs[dst] = match self.access(san, i.src(), i.off16(), size, true)? {
+                    Target::Stack(idx) => san.load(idx, size)?,
+                    Target::Value => Shadow::Scalar,
+                };
+            },
+            Some(Class::St) => {
+                if let Target::Stack(idx) = self.access(san, i.dst(), i.off16(), size, true)? {
+                    san.store(idx, size, Shadow::Scalar)?;
+                }
+            },
+            Some(Class::Stx) => {
+                let v = san.read(i.src())?;
+                let target = self.access(san, i.dst(), i.off16(), size, true)?;
+                let old = match target {
+                    Target::Stack(idx) => san.load(idx, size),
+                    Target::Value => Ok(Shadow::Scalar),
+                };
+                if i.ld_mode() == Some(Mode::Xadd) && (v != Shadow::Scalar || old? != Shadow::Scalar) {
+                    return Err(SanitizeErrorKind::InvalidPointerArithmetic("xadd of a pointer"));
+                }
+                match (target, v) {
+                    // only the stack may hold pointers, and not to the stack of a function called
+                    // from the one it is in, which returns first
+                    (Target::Value, _) if v.is_ptr() => return Err(SanitizeErrorKind::PointerLeak { reg: i.src() }),
+                    (Target::Stack(idx), Shadow::StackPtr(frame)) if frame > stack_frame(idx) =>
+                        return Err(SanitizeErrorKind::PointerLeak { reg: i.src() }),
+                    (Target::Stack(idx), _) => san.store(idx, size, v)?,
+                    (Target::Value, _) => {},
+                }
+            },
+            Some(Class::Alu) | Some(Class::Alu64) => san.regs[dst] = san.alu(&i)?,
+            Some(Class::Jmp) | Some(Class::Jmp32) => match i.op_jmp() {
+                // returning from a function called by another
+                Some(OpJmp::Exit) if self.depth > 0 => {
+                    if let Shadow::StackPtr(frame) = san.read(0)? {
+                        if frame as usize >= self.depth {
+                            return Err(SanitizeErrorKind::PointerLeak { reg: 0 });
+                        }
+                    }
+                    for r in 1..6 {
+                        san.regs[r] = Shadow::Uninit;
+                    }
+                    san.regs[6..11].copy_from_slice(&san.frames[self.depth - 1]);
+                },
+                Some(OpJmp::Exit) => {
+                    if san.read(0)?.is_ptr() {
+                        return Err(SanitizeErrorKind::PointerLeak { reg: 0 });
+                    }
+                },
+                Some(OpJmp::Call) if i.call_off().is_none() => self.helper_call(san, i.imm32())?,
+                Some(OpJmp::Call) => {
+                    if self.depth + 1 == MAX_CALL_FRAMES {
+                        return Err(SanitizeErrorKind::CallDepthExceeded { limit: MAX_CALL_FRAMES });
+                    }
+                    // the callee gets `r1` to `r5`, and a stack of its own with nothing written
+                    let depth = self.depth + 1;
+                    san.frames[self.depth].copy_from_slice(&san.regs[6..11]);
+                    for r in [0, 6, 7, 8, 9].iter() {
+                        san.regs[*r] = Shadow::Uninit;
+                    }
+                    san.regs[10] = Shadow::StackPtr(depth as u8);
+                    for b in san.stack[stack_idx(depth, -(STACK_SIZE as i64))..stack_idx(depth, 0)].iter_mut() {
+                        *b = Shadow::Uninit;
+                    }
+                },
+                Some(OpJmp::Ja) => {},
+                _ => {
+                    let a = san.read(i.dst())?;
+                    let b = if i.op_src() == Some(Src::X) { san.read(i.src())? } else { Shadow::Scalar };
+                    if (a.is_ptr() || b.is_ptr()) && i.op_class() == Some(Class::Jmp32) {
+                        return Err(SanitizeErrorKind::InvalidPointerArithmetic("32-bit comparison of a pointer"));
+                    }
+                },
+            },
+            None => unreachable!(),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+//! What the verifier knows of a register holding a number
+//!
+//! A `Scalar` pairs the bits known to be set or clear (`Tnum`) with the signed and unsigned ranges
+//! of the value (`Rnum`), each narrowed using the other.
+
+use super::*;
+use bit_domains::{Rnum, Tnum};
+use core::{cmp, fmt};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Scalar {
+    bits: Tnum,
+    range: Rnum,
+}
+
+impl Scalar {
+    pub fn unknown() -> Self {
+        Scalar {
+            bits: Tnum::default(),
+            range: Rnum::default(),
+        }
+    }
+
+    pub fn from_value(v: u64) -> Self {
+        Scalar {
+            bits: Tnum::from_value(v),
+            range: Rnum::from_value(v),
+        }
+    }
+
+    /// Any value of at most `width` bits, zero extended
+    pub fn unknown_bits(width: u32) -> Self {
+        let mask = if width >= 64 { !0 } else { (1 << width) - 1 };
+        Scalar {
+            bits: Tnum::from_parts(0, mask),
+            range: Rnum::from_range(0, mask).unwrap(),
+        }
+    }
+
+    /// The values in both `bits` and `range`, or `None` if there are none
+    pub fn new(bits: Tnum, range: Rnum) -> Option<Self> {
+        let range = range.intersection(Rnum::from(bits))?;
+        let bits = bits.intersection(Tnum::from_range(range.min(), range.max()))?;
+        let range = range.intersection(Rnum::from(bits))?;
+        Some(Scalar { bits, range })
+    }
+
+    /// Every value of `other` is also one of `self`
+    pub fn contains(&self, other: Scalar) -> bool {
+        self.bits.contains(other.bits) && self.range.contains(other.range)
+    }
+
+    /// Every value is one of `set`. Values are only enumerated when at most 16 bits are unknown;
+    /// with more, this is `false`.
+    pub fn within(&self, set: &[u64]) -> bool {
+        let mask = self.bits.mask();
+        if mask.count_ones() > 16 {
+            return false;
+        }
+        // each subset of the unknown bits, in increasing order
+        let mut sub = 0u64;
+        loop {
+            let v = self.bits.value() | sub;
+            if self.range.contains_value(v) && !set.contains(&v) {
+                return false;
+            }
+            if sub == mask {
+                return true;
+            }
+            sub = sub.wrapping_sub(mask) & mask;
+        }
+    }
+
+    pub fn range(&self) -> Rnum {
+        self.range
+    }
+
+    /// `self + other`, wrapping
+    pub fn add(self, other: Scalar) -> Scalar {
+        Scalar::new(self.bits + other.bits, self.range + other.range).unwrap_or_else(Scalar::unknown)
+    }
+
+    /// `self - other`, wrapping
+    pub fn sub(self, other: Scalar) -> Scalar {
+        Scalar::new(self.bits - other.bits, self.range - other.range).unwrap_or_else(Scalar::unknown)
+    }
+
+    /// The value, if only one is possible
+    pub fn value(&self) -> Option<u64> {
+        if self.bits.is_const() {
+            Some(self.bits.value())
+        } else {
+            None
+        }
+    }
+
+    /// Only the values which are also in `range`
+    fn narrow(self, range: Rnum) -> Option<Self> {
+        Scalar::new(self.bits, self.range.intersection(range)?)
+    }
+
+    /// Truncated to the lower 32 bits
+    pub fn zext32(self) -> Self {
+        let range = if self.range.max() <= u64::from(u32::MAX) { self.range } else { Rnum::default() };
+        Scalar::new(self.bits.cast(4), range).unwrap_or_else(|| Scalar::unknown_bits(32))
+    }
+
+    /// The result of the `Alu` or `Alu64` instruction `i` on `dst` and `src`, the value of its
+    /// source operand. Exact when both are known, as given by `Inst::eval_alu`.
+    pub fn alu(i: &Inst, dst: Scalar, src: Scalar) -> Scalar {
+        let op = i.op_alu();
+        let wide = i.op_class() == Some(Class::Alu64);
+
+        if op == Some(OpAlu::End) {
+            let width = i.imm32();
+            return match (dst.value(), i.op_src()) {
+                (Some(d), _) => Scalar::from_value(i.eval_alu(d, 0)),
+                (None, Some(Src::K)) if width < 64 => {
+                    let range = if dst.range.max() >> width == 0 { dst.range } else { Rnum::default() };
+                    Scalar::new(dst.bits.cast((width / 8) as u8), range)
+                        .unwrap_or_else(|| Scalar::unknown_bits(width))
+                },
+                (None, Some(Src::K)) => dst,
+                (None, _) => Scalar::unknown_bits(width),
+            };
+        }
+
+        // operands the op ignores
+        let d = if op == Some(OpAlu::Mov) { Scalar::from_value(0) } else { dst };
+        let s = if op == Some(OpAlu::Neg) { Scalar::from_value(0) } else { src };
+        if let (Some(x), Some(y)) = (d.value(), s.value()) {
+            return Scalar::from_value(i.eval_alu(x, y));
+        }
+
+        let (d, s) = if wide { (d, s) } else { (d.zext32(), s.zext32()) };
+        let shift = s.value().map(|v| (v & if wide { 63 } else { 31 }) as u8);
+        let r = match (op, shift) {
+            (Some(OpAlu::Add), _) => Some(d.add(s)),
+            (Some(OpAlu::Sub), _) => Some(d.sub(s)),
+            (Some(OpAlu::Mul), _) => Scalar::new(d.bits * s.bits, d.range * s.range),
+            (Some(OpAlu::And), _) => Scalar::new(d.bits & s.bits, d.range & s.range),
+            (Some(OpAlu::Or), _) => Scalar::new(d.bits | s.bits, d.range | s.range),
+            (Some(OpAlu::Xor), _) => Scalar::new(d.bits ^ s.bits, d.range ^ s.range),
+            (Some(OpAlu::Lsh), Some(sh)) => Scalar::new(d.bits << sh, d.range << sh),
+            (Some(OpAlu::Rsh), Some(sh)) => Scalar::new(d.bits >> sh, d.range >> sh),
+            (Some(OpAlu::Arsh), Some(sh)) if wide => Scalar::new(d.bits.arshift(sh), Rnum::default()),
+            // sign extend from bit 31 first
+            (Some(OpAlu::Arsh), Some(sh)) => Scalar::new((d.bits << 32).arshift(32 + sh), Rnum::default()),
+            // `x / 0 == 0` and `x % 0 == x`, so neither result exceeds `x`
+            (Some(OpAlu::Div), _) => Rnum::from_range(0, d.range.max())
+                .and_then(|r| Scalar::new(Tnum::default(), r)),
+            (Some(OpAlu::Mod), _) => {
+                let max = if s.range.min() > 0 {
+                    cmp::min(d.range.max(), s.range.max() - 1)
+                } else {
+                    d.range.max()
+                };
+                Rnum::from_range(0, max).and_then(|r| Scalar::new(Tnum::default(), r))
+            },
+            (Some(OpAlu::Neg), _) => Scalar::new(Tnum::from_value(0) - d.bits, Rnum::from_value(0) - d.range),
+            (Some(OpAlu::Mov), _) => Some(s),
+            _ => None,
+        };
+
+        let r = r.unwrap_or_else(Scalar::unknown);
+        if wide { r } else { r.zext32() }
+    }
+
+    /// The values the destination (`a`) and source operand (`b`) of the conditional jump `i` may
+    /// have when it is `taken` (or not). `None` if that can't happen.
+    pub fn refine_jmp(i: &Inst, taken: bool, a: Scalar, b: Scalar) -> Option<(Scalar, Scalar)> {
+        if let (Some(x), Some(y)) = (a.value(), b.value()) {
+            return if i.jmp_taken(x, y) == taken { Some((a, b)) } else { None };
+        }
+
+        if i.op_class() == Some(Class::Jmp32) {
+            // 32 and 64 bit comparisons only agree when both values fit in 31 bits
+            let small = |s: Scalar| s.range.max() <= i32::MAX as u64;
+            if !small(a) || !small(b) {
+                return Some((a, b));
+            }
+        }
+
+        let swap = |(x, y)| (y, x);
+        match (i.op_jmp(), taken) {
+            (Some(OpJmp::Jeq), true) | (Some(OpJmp::Jne), false) => {
+                let s = Scalar::new(a.bits.intersection(b.bits)?, a.range.intersection(b.range)?)?;
+                Some((s, s))
+            },
+            (Some(OpJmp::Jeq), false) | (Some(OpJmp::Jne), true) => Some((ne(a, b)?, ne(b, a)?)),
+
+            (Some(OpJmp::Jgt), true) | (Some(OpJmp::Jle), false) => lt(b, a, true, false).map(swap),
+            (Some(OpJmp::Jgt), false) | (Some(OpJmp::Jle), true) => lt(a, b, false, false),
+            (Some(OpJmp::Jge), true) | (Some(OpJmp::Jlt), false) => lt(b, a, false, false).map(swap),
+            (Some(OpJmp::Jge), false) | (Some(OpJmp::Jlt), true) => lt(a, b, true, false),
+
+            (Some(OpJmp::Jsgt), true) | (Some(OpJmp::Jsle), false) => lt(b, a, true, true).map(swap),
+            (Some(OpJmp::Jsgt), false) | (Some(OpJmp::Jsle), true) => lt(a, b, false, true),
+            (Some(OpJmp::Jsge), true) | (Some(OpJmp::Jslt), false) => lt(b, a, false, true).map(swap),
+            (Some(OpJmp::Jsge), false) | (Some(OpJmp::Jslt), true) => lt(a, b, true, true),
+
+            (Some(OpJmp::Jset), true) => {
+                let common = a.bits & b.bits;
+                if common.is_const() && common.value() == 0 {
+                    None
+                } else {
+                    Some((a, b))
+                }
+            },
+            (Some(OpJmp::Jset), false) => Some((clear(a, b)?, clear(b, a)?)),
+
+            _ => Some((a, b)),
+        }
+    }
+}
+
+/// `x` knowing that `x != y`
+fn ne(x: Scalar, y: Scalar) -> Option<Scalar> {
+    let v = match y.value() {
+        Some(v) => v,
+        None => return Some(x),
+    };
+    if x.range.min() == v {
+        x.narrow(Rnum::from_range(v.checked_add(1)?, u64::MAX)?)
+    } else if x.range.max() == v {
+        x.narrow(Rnum::from_range(0, v.checked_sub(1)?)?)
+    } else {
+        Some(x)
+    }
+}
+
+/// `x` and `y` knowing that `x < y` (or `x <= y` when not `strict`)
+fn lt(x: Scalar, y: Scalar, strict: bool, signed: bool) -> Option<(Scalar, Scalar)> {
+    if signed {
+        let d = strict as i64;
+        let xmax = y.range.smax().checked_sub(d)?;
+        let ymin = x.range.smin().checked_add(d)?;
+        Some((x.narrow(Rnum::from_srange(i64::MIN, xmax)?)?, y.narrow(Rnum::from_srange(ymin, i64::MAX)?)?))
+    } else {
+        let d = strict as u64;
+        let xmax = y.range.max().checked_sub(d)?;
+        let ymin = x.range.min().checked_add(d)?;
+        Some((x.narrow(Rnum::from_range(0, xmax)?)?, y.narrow(Rnum::from_range(ymin, u64::MAX)?)?))
+    }
+}
+
+/// `x` knowing that `x & y == 0`
+fn clear(x: Scalar, y: Scalar) -> Option<Scalar> {
+    match y.value() {
+        Some(m) if x.bits.value() & m != 0 => None,
+        Some(m) => Scalar::new(x.bits & Tnum::from_value(!m), x.range),
+        None => Some(x),
+    }
+}
+
+impl fmt::Display for Scalar {
+    /// The value if it is known, otherwise `scalar(...)` listing the bounds and bits which are
+    /// known, as linux's verifier log does
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(v) = self.value() {
+            return write!(f, "{}", v as i64);
+        }
+        let r = self.range;
+        let mut sep = "";
+        write!(f, "scalar(")?;
+        if r.min() != 0 {
+            write!(f, "{}umin={}", sep, r.min())?;
+            sep = ",";
+        }
+        if r.max() != u64::MAX {
+            write!(f, "{}umax={}", sep, r.max())?;
+            sep = ",";
+        }
+        if r.smin() != i64::MIN {
+            write!(f, "{}smin={}", sep, r.smin())?;
+            sep = ",";
+        }
+        if r.smax() != i64::MAX {
+            write!(f, "{}smax={}", sep, r.smax())?;
+            sep = ",";
+        }
+        if self.bits.mask() != !0 {
+            write!(f, "{}var_off=({:#x}; {:#x})", sep, self.bits.value(), self.bits.mask())?;
+        }
+        write!(f, ")")
+    }
+}
@@ -1,8 +1,43 @@
+//! Static checks of programs before they are run
+//!
+//! `Env::verify` builds the program's `Cfg`, rejects unreachable code, and checks each
+//! instruction is one `Invoke` is able to run. It then simulates every path through the program,
+//! tracking which registers are initialized and the known bits and range of each value. Conditional
+//! jumps narrow those ranges, and branches which can't be taken are not followed. Each path must
+//! reach `exit` without reading an uninitialized register, and return a value the program type
+//! allows. Loops are accepted when that simulation shows they end within the `Env`'s complexity
+//! limit. A path reaching a block in a state contained by one already fully explored from there is
+//! not followed again, and one reaching it in a state contained by one it passed through there is
+//! rejected as an infinite loop.
+//!
+//! Registers may also hold pointers into the context, stack, packet, or a map value. Only bounded
+//! offsets may be added to them, and every load and store through one must stay within its region.
+//! Packet accesses must first be checked against the packet end, and a map value which may be null
+//! must be compared with 0 before use. `ld_abs` and `ld_ind` loads are not bounded here: one
+//! outside the `DataArea`, or whose offset overflows, makes `Invoke::run` fail. Each byte of the
+//! stack is tracked, so registers spilled to it are restored intact and bytes which were never
+//! written can't be read. Calls to helpers are checked against their prototypes. Calls to other BPF
+//! functions are followed into them, each with a stack of its own which no pointer may outlive.
+//!
+//! `Env::verify_log` also writes the instructions simulated and the state before each to a
+//! `fmt::Write`, as linux's verifier log does, with the source lines given by `Env::line_info`.
+
 use super::*;
 
-use core::{cmp, usize, convert::From};
+use alloc::vec::Vec;
+use btf::LineInfo;
+use cfg::{Cfg, CfgError, CfgErrorKind};
+use liveness::{Liveness, RegSet};
+use profile::{helper, ArgKind, HelperProto, Profile, RetKind, RetValues};
+use core::cmp;
+use core::convert::From;
+use core::fmt;
+use core::mem;
+use disasm::Disasm;
+use scalar::Scalar;
 
-#[derive(Debug,Eq,PartialEq)]
+/// Why `Env::verify` rejected a program
+#[derive(Debug,Clone,Eq,PartialEq)]
 pub enum PrgmVerifyErrorKind {
     /// Instruction was not decodable
     InstDecode(InstDecodeError),
@@ -10,16 +45,166 @@ pub enum PrgmVerifyErrorKind {
     InvalidInstIdx,
     /// Tried to load a program that exceeds the instruction limit
     InstLimitExceeded,
-    /// 
-    Other(&'static str),
+    /// The control flow is malformed (a jump leaves the program, execution runs off the end, ...)
+    Cfg(CfgErrorKind),
+    /// More instructions were simulated than the complexity limit allows, usually because of a
+    /// loop that could not be shown to end
+    ComplexityLimitExceeded {
+        limit: usize,
+    },
+    /// A path came back to this instruction in a state contained by one it passed through here
+    /// before, so it loops forever
+    InfiniteLoop,
+    /// The instruction can never be executed
+    Unreachable,
+    /// A register is read before it is written. For `exit`, this is `r0`.
+    UninitializedRegister {
+        reg: u8,
+    },
+    /// `ld_imm64` refers to a map the `Env` does not have
+    UnknownMap {
+        index: u32,
+    },
+    /// A pointer is used in a way that could make it point outside its region, or leak its value
+    InvalidPointerArithmetic(&'static str),
+    /// A load or store through `reg`, which is a pointer of `kind` (or not a pointer if `None`)
+    /// that can't be used that way
+    InvalidMemoryAccess {
+        reg: u8,
+        kind: Option<PtrKind>,
+    },
+    /// A load or store of `size` bytes at `off` might fall outside `region`
+    OutOfBounds {
+        region: PtrKind,
+        off: i64,
+        size: u64,
+    },
+    /// A call to a helper which has no prototype, or which the program type does not allow
+    UnknownHelper {
+        id: u32,
+    },
+    /// The prototype of helper `id` has more than 5 arguments, or a `StackMem` or
+    /// `UninitStackMem` without a `ConstSize` after it
+    InvalidHelperProto {
+        id: u32,
+    },
+    /// A call to another BPF function would put more than `limit` functions on the call stack
+    CallDepthExceeded {
+        limit: usize,
+    },
+    /// The argument in `reg` does not match the helper's prototype
+    HelperArgMismatch {
+        reg: u8,
+        expected: ArgKind,
+    },
+    /// A load or store of `size` bytes at `off` in `region` is not aligned to `size`
+    Misaligned {
+        region: PtrKind,
+        off: i64,
+        size: u64,
+    },
+    /// The stack byte at `off` from `r10` is read before it is written
+    UninitializedStack {
+        off: i64,
+    },
+    /// The pointer in `reg` would be seen outside the program: returned, or stored somewhere
+    /// other than the stack
+    PointerLeak {
+        reg: u8,
+    },
+    /// A load or store of `size` bytes at `off` in `region` covers only part of a pointer: a
+    /// register spilled to the stack, or a pointer field of the context
+    PartialPointer {
+        region: PtrKind,
+        off: i64,
+        size: u64,
+    },
+    /// A load or store of `size` bytes at `off` in the context is not within one of the fields the
+    /// `Profile` allows
+    InvalidContextAccess {
+        off: i64,
+        size: u64,
+    },
+    /// A jump back to `to`, when the `Env` forbids loops
+    BackEdge {
+        to: usize,
+    },
+    /// `r0` may hold a value from `min` to `max` at `exit`, not all of which the program type
+    /// allows
+    BadReturnValue {
+        min: u64,
+        max: u64,
+    },
 }
 
-#[derive(Debug,Eq,PartialEq)]
+impl fmt::Display for PrgmVerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PrgmVerifyErrorKind::*;
+        match *self {
+            InstDecode(InstDecodeError::InvalidEncoding(s))
+                | InstDecode(InstDecodeError::ForbiddenInst(s))
+                | InstDecode(InstDecodeError::Other(s)) => write!(f, "invalid instruction: {}", s),
+            InvalidInstIdx => write!(f, "no instruction at this index"),
+            InstLimitExceeded => write!(f, "program is longer than the instruction limit"),
+            Cfg(ref k) => write!(f, "{}", k),
+            ComplexityLimitExceeded { limit } =>
+                write!(f, "more than {} instructions simulated without every path ending", limit),
+            InfiniteLoop => write!(f, "infinite loop"),
+            Unreachable => write!(f, "unreachable instruction"),
+            UninitializedRegister { reg } => write!(f, "r{} is read before it is written", reg),
+            UnknownMap { index } => write!(f, "no map with index {}", index),
+            InvalidPointerArithmetic(s) => write!(f, "invalid pointer arithmetic: {}", s),
+            InvalidMemoryAccess { reg, kind: Some(kind) } =>
+                write!(f, "r{} ({}) can't be used for this access", reg, kind),
+            InvalidMemoryAccess { reg, kind: None } =>
+                write!(f, "r{} is not a pointer, and can't be used for this access", reg),
+            OutOfBounds { region, off, size } =>
+                write!(f, "access of {} bytes at offset {} may be outside {}", size, off, region),
+            UnknownHelper { id } => write!(f, "helper {} is unknown or not allowed", id),
+            InvalidHelperProto { id } => write!(f, "helper {} has an invalid prototype", id),
+            CallDepthExceeded { limit } => write!(f, "more than {} functions on the call stack", limit),
+            HelperArgMismatch { reg, expected } =>
+                write!(f, "r{} does not hold the helper argument expected, {:?}", reg, expected),
+            Misaligned { region, off, size } =>
+                write!(f, "access of {} bytes at offset {} of {} is misaligned", size, off, region),
+            UninitializedStack { off } => write!(f, "stack at fp{} is read before it is written", off),
+            PointerLeak { reg } => write!(f, "r{} leaks a pointer", reg),
+            PartialPointer { region, off, size } =>
+                write!(f, "access of {} bytes at offset {} of {} covers part of a pointer", size, off, region),
+            InvalidContextAccess { off, size } =>
+                write!(f, "access of {} bytes at offset {} of ctx is not within one field", size, off),
+            BackEdge { to } => write!(f, "back-edge to instruction {}", to),
+            BadReturnValue { min, max } =>
+                write!(f, "r0 may be from {} to {} at exit, outside the values allowed", min, max),
+        }
+    }
+}
+
+/// An error from `Env::verify`, and the index of the instruction which caused it
+#[derive(Debug,Clone,Eq,PartialEq)]
 pub struct PrgmVerifyError {
     inst_idx: usize,
     kind: PrgmVerifyErrorKind,
 }
 
+impl PrgmVerifyError {
+    /// Index of the instruction the error was found at
+    pub fn inst_idx(&self) -> usize {
+        self.inst_idx
+    }
+
+    /// What was wrong with the program
+    pub fn kind(&self) -> &PrgmVerifyErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for PrgmVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "instruction {}: {}", self.inst_idx, self.kind)
+    }
+}
+
 impl From<InstDecodeError> for PrgmVerifyErrorKind
 {
     fn from(v: InstDecodeError) -> Self {
@@ -37,130 +222,1074 @@ impl From<(usize, InstDecodeError)> for PrgmVerifyError
     }
 }
 
-#[derive(Clone,PartialEq,Eq,Debug)]
+impl From<CfgError> for PrgmVerifyError
+{
+    fn from(v: CfgError) -> Self {
+        let kind = match *v.kind() {
+            CfgErrorKind::InstDecode(e) => PrgmVerifyErrorKind::InstDecode(e),
+            ref k => PrgmVerifyErrorKind::Cfg(k.clone()),
+        };
+        PrgmVerifyError {
+            inst_idx: v.inst_idx(),
+            kind,
+        }
+    }
+}
+
+/// Reject any block which can't be reached from the entry
+fn check_reachable(cfg: &Cfg) -> Result<(), PrgmVerifyError> {
+    let blocks = cfg.blocks();
+    let mut seen = Vec::new();
+    seen.resize(blocks.len(), false);
+
+    let mut stack = Vec::new();
+    stack.push(0);
+    seen[0] = true;
+    while let Some(b) = stack.pop() {
+        for e in blocks[b].succs() {
+            if !seen[e.to] {
+                seen[e.to] = true;
+                stack.push(e.to);
+            }
+        }
+    }
+
+    match seen.iter().position(|&s| !s) {
+        Some(b) => Err(PrgmVerifyError {
+            inst_idx: blocks[b].start(),
+            kind: PrgmVerifyErrorKind::Unreachable,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Check there is no cycle in `cfg`, every block of which is reachable. A jump backwards is not
+/// always a back-edge, so this follows edges depth first.
+fn check_acyclic(cfg: &Cfg) -> Result<(), PrgmVerifyError> {
+    #[derive(Clone,Copy,PartialEq,Eq)]
+    enum Mark {
+        Unseen,
+        OnPath,
+        Done,
+    }
+
+    let blocks = cfg.blocks();
+    let mut mark = Vec::new();
+    mark.resize(blocks.len(), Mark::Unseen);
+
+    // (block, index of the next successor to visit)
+    let mut stack = Vec::new();
+    stack.push((0, 0));
+    mark[0] = Mark::OnPath;
+    while let Some(&mut (b, ref mut next)) = stack.last_mut() {
+        let succs = blocks[b].succs();
+        if *next == succs.len() {
+            mark[b] = Mark::Done;
+            stack.pop();
+            continue;
+        }
+
+        let to = succs[*next].to;
+        *next += 1;
+        match mark[to] {
+            Mark::Unseen => {
+                mark[to] = Mark::OnPath;
+                stack.push((to, 0));
+            },
+            Mark::OnPath => return Err(PrgmVerifyError {
+                inst_idx: blocks[b].last(),
+                kind: PrgmVerifyErrorKind::BackEdge { to: blocks[to].start() },
+            }),
+            Mark::Done => {},
+        }
+    }
+    Ok(())
+}
+
+/// What a pointer register points into
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
+pub enum PtrKind {
+    /// The context passed in `r1`
+    Ctx,
+    /// The stack, below the frame pointer in `r10`
+    Stack,
+    /// Packet data, loaded from the context
+    Packet,
+    /// The end of packet data, loaded from the context. Only useful in comparisons.
+    PacketEnd,
+    /// A value in a map
+    MapValue,
+    /// A value in a map, or null. Must be compared with 0 before use.
+    MapValueOrNull,
+    /// A map, from `ld_imm64`
+    Map,
+}
+
+impl fmt::Display for PtrKind {
+    /// The names linux's verifier log uses
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            PtrKind::Ctx => "ctx",
+            PtrKind::Stack => "fp",
+            PtrKind::Packet => "pkt",
+            PtrKind::PacketEnd => "pkt_end",
+            PtrKind::MapValue => "map_value",
+            PtrKind::MapValueOrNull => "map_value_or_null",
+            PtrKind::Map => "map_ptr",
+        })
+    }
+}
+
+/// Bytes of stack below `r10`
+const STACK_SIZE: i64 = 512;
+
+/// The largest offset a pointer may be moved by, matching linux's `BPF_MAX_VAR_OFF`
+const MAX_OFF: i64 = 1 << 29;
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
 enum RegType {
     NotInit,
     Value,
-    Ptr,
-}
-
-#[derive(Clone,PartialEq,Eq,Debug)]
-enum RegLiveness {
-    No,
-    Read,
-    Write,
+    Ptr(PtrKind),
 }
 
 #[derive(Clone,PartialEq,Eq,Debug)]
 struct RegState {
     ty: RegType,
 
-    /*
-    smin: i64,
-    smax: i64,
-    umin: u64,
-    umax: u64,
-    val: Tnum,
-    */
+    /// The values possible on the paths reaching this point when `ty` is `Value`. For pointers,
+    /// the variable part of the offset.
+    val: Scalar,
+
+    /// The fixed part of a pointer's offset
+    off: i32,
+
+    /// For `Packet`, the number of bytes known to be in the packet
+    range: u32,
+
+    /// For `Map`, `MapValue`, and `MapValueOrNull`, the index of the map
+    map: usize,
 
-    live: RegLiveness,
+    /// For `Stack`, the function whose stack it points into: 0 for the program's own, 1 for a
+    /// function it calls, and so on
+    frame: usize,
+
+    /// Shared by pointers known to have the same variable offset (`Packet`) or to be null
+    /// together (`MapValueOrNull`)
+    id: u32,
 }
 
 impl Default for RegState {
     fn default() -> Self {
         Self {
             ty: RegType::NotInit,
-            live: RegLiveness::No,
+            val: Scalar::unknown(),
+            off: 0,
+            range: 0,
+            map: 0,
+            frame: 0,
+            id: 0,
         }
     }
 }
 
+/// Whether `old` and `new` ids pair up the same way as every pair seen before, recorded in `ids`
+fn check_id(ids: &mut Vec<(u32, u32)>, old: u32, new: u32) -> bool
+{
+    for &(o, n) in ids.iter() {
+        if o == old || n == new {
+            return o == old && n == new;
+        }
+    }
+    ids.push((old, new));
+    true
+}
+
 impl RegState {
-    fn load_imm(&mut self, imm: u64)
+    /// Any program that could continue safely with `self` in this register could also with
+    /// `other`
+    fn contains(&self, other: &RegState, ids: &mut Vec<(u32, u32)>) -> bool
     {
-        //self.umax = cmp::max(imm, self.umax);
-        self.ty = RegType::Value;
-        self.live = RegLiveness::Write;
+        match (self.ty, other.ty) {
+            // never read, or the program would have been rejected
+            (RegType::NotInit, _) => true,
+            (RegType::Value, RegType::Value) => self.val.contains(other.val),
+            (RegType::Ptr(a), RegType::Ptr(b)) => a == b && self.off == other.off && self.map == other.map
+                && self.frame == other.frame && self.range <= other.range && self.val.contains(other.val)
+                && check_id(ids, self.id, other.id),
+            _ => false,
+        }
+    }
+
+    fn scalar(val: Scalar) -> Self
+    {
+        Self {
+            ty: RegType::Value,
+            val,
+            ..Self::default()
+        }
+    }
+
+    fn ptr(kind: PtrKind) -> Self
+    {
+        Self {
+            ty: RegType::Ptr(kind),
+            val: Scalar::from_value(0),
+            ..Self::default()
+        }
     }
 }
 
-#[derive(Debug,PartialEq,Eq,Default)]
+impl fmt::Display for RegState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.ty {
+            RegType::NotInit => return write!(f, "?"),
+            RegType::Value => return write!(f, "{}", self.val),
+            RegType::Ptr(kind) => kind,
+        };
+        // `fp-8` rather than `fp(off=-8)`, as the stack offset is always fixed. The stacks of
+        // called functions are `fp[1]`, `fp[2]`, ...
+        if kind == PtrKind::Stack {
+            match self.frame {
+                0 => write!(f, "fp")?,
+                frame => write!(f, "fp[{}]", frame)?,
+            }
+            return match self.off {
+                0 => Ok(()),
+                off => write!(f, "{}", off),
+            };
+        }
+
+        write!(f, "{}", kind)?;
+        let mut sep = "(";
+        if self.off != 0 {
+            write!(f, "{}off={}", sep, self.off)?;
+            sep = ",";
+        }
+        if self.val.value() != Some(0) {
+            write!(f, "{}var={}", sep, self.val)?;
+            sep = ",";
+        }
+        if kind == PtrKind::Packet {
+            write!(f, "{}r={}", sep, self.range)?;
+            sep = ",";
+        }
+        if kind == PtrKind::Map || kind == PtrKind::MapValue || kind == PtrKind::MapValueOrNull {
+            write!(f, "{}map={}", sep, self.map)?;
+            sep = ",";
+        }
+        if self.id != 0 {
+            write!(f, "{}id={}", sep, self.id)?;
+            sep = ",";
+        }
+        if sep == "," {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// What a byte of the stack holds
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+enum SlotType {
+    /// Not yet written
+    Invalid,
+    /// Part of a register spilled to this slot
+    Spill,
+    /// Some other data
+    Misc,
+}
+
+/// An 8-byte aligned part of the stack
+#[derive(Clone,PartialEq,Eq,Debug)]
+struct StackSlot {
+    bytes: [SlotType;8],
+
+    /// The register stored here, when every byte is `Spill`
+    spill: RegState,
+}
+
+impl StackSlot {
+    /// Like `RegState::contains`, for each byte
+    fn contains(&self, other: &StackSlot, ids: &mut Vec<(u32, u32)>) -> bool
+    {
+        if self.bytes[0] == SlotType::Spill {
+            return other.bytes[0] == SlotType::Spill && self.spill.contains(&other.spill, ids);
+        }
+        self.bytes.iter().zip(other.bytes.iter()).all(|(&o, &n)| match (o, n) {
+            (SlotType::Invalid, _) | (SlotType::Misc, SlotType::Misc) => true,
+            // a spilled pointer is not plain data
+            (SlotType::Misc, SlotType::Spill) => other.spill.ty == RegType::Value,
+            _ => false,
+        })
+    }
+}
+
+impl Default for StackSlot {
+    fn default() -> Self {
+        Self {
+            bytes: [SlotType::Invalid;8],
+            spill: RegState::default(),
+        }
+    }
+}
+
+/// A function waiting for a BPF function it called to return
+#[derive(Debug,Clone,PartialEq,Eq)]
+struct Caller {
+   regs: [RegState;11],
+   stack: Vec<StackSlot>,
+
+   /// Where it continues
+   ret: usize,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
 struct State {
-   regs: [RegState;10],
+   regs: [RegState;11],
+
+   /// The stack, lowest address first
+   stack: Vec<StackSlot>,
+
+   /// The functions waiting on this one, outermost first
+   callers: Vec<Caller>,
+
+   /// The last `id` given out
+   last_id: u32,
+}
+
+/// A stack with nothing written
+fn new_stack() -> Vec<StackSlot> {
+    let mut stack = Vec::new();
+    stack.resize(STACK_SIZE as usize / 8, StackSlot::default());
+    stack
+}
+
+impl Default for State {
+    /// The state on entry: `r1` (the context) and `r10` (the frame pointer) are initialized
+    fn default() -> Self {
+        let mut st = State { regs: Default::default(), stack: new_stack(), callers: Vec::new(), last_id: 0 };
+        st.regs[1] = RegState::ptr(PtrKind::Ctx);
+        st.regs[10] = RegState::ptr(PtrKind::Stack);
+        st
+    }
+}
+
+/// The registers in `live` and the written bytes of the stack of a `State`, as the log shows
+/// them. Spilled registers are shown whole; other slots byte by byte, lowest address first, as `m`
+/// (data), `r` (part of a spilled register) or `?` (not written).
+struct Dump<'a> {
+    st: &'a State,
+    live: RegSet,
+}
+
+impl<'a> fmt::Display for Dump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sep = " ; ";
+        for (r, reg) in self.st.regs.iter().enumerate() {
+            if self.live & (1 << r) != 0 && reg.ty != RegType::NotInit {
+                write!(f, "{}R{}={}", sep, r, reg)?;
+                sep = " ";
+            }
+        }
+        for (s, slot) in self.st.stack.iter().enumerate() {
+            if slot.bytes.iter().all(|&b| b == SlotType::Invalid) {
+                continue;
+            }
+            write!(f, "{}fp{}=", sep, s as i64 * 8 - STACK_SIZE)?;
+            sep = " ";
+            if slot.bytes[0] == SlotType::Spill {
+                write!(f, "{}", slot.spill)?;
+                continue;
+            }
+            for &b in slot.bytes.iter() {
+                f.write_str(match b {
+                    SlotType::Invalid => "?",
+                    SlotType::Spill => "r",
+                    SlotType::Misc => "m",
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The registers spilled to `stack`
+fn spills<'a>(stack: &'a mut [StackSlot]) -> impl Iterator<Item = &'a mut RegState> + 'a
+{
+    stack.iter_mut()
+        .filter(|s| s.bytes[0] == SlotType::Spill)
+        .map(|s| &mut s.spill)
+}
+
+fn ptr_error(pc: usize, msg: &'static str) -> PrgmVerifyError
+{
+    PrgmVerifyError {
+        kind: PrgmVerifyErrorKind::InvalidPointerArithmetic(msg),
+        inst_idx: pc,
+    }
+}
+
+/// `op` with its operands swapped
+fn swap_jmp(op: Option<OpJmp>) -> Option<OpJmp> {
+    match op {
+        Some(OpJmp::Jgt) => Some(OpJmp::Jlt),
+        Some(OpJmp::Jge) => Some(OpJmp::Jle),
+        Some(OpJmp::Jlt) => Some(OpJmp::Jgt),
+        Some(OpJmp::Jle) => Some(OpJmp::Jge),
+        op => op,
+    }
+}
+
+/// The unsigned comparison true when `op` is not
+fn negate_jmp(op: Option<OpJmp>) -> Option<OpJmp> {
+    match op {
+        Some(OpJmp::Jgt) => Some(OpJmp::Jle),
+        Some(OpJmp::Jge) => Some(OpJmp::Jlt),
+        Some(OpJmp::Jlt) => Some(OpJmp::Jge),
+        Some(OpJmp::Jle) => Some(OpJmp::Jgt),
+        _ => None,
+    }
 }
 
 impl State {
-//    fn call(&mut self)
+    /// Call the BPF function which continues at `ret` when it returns. It is passed `r1` to `r5`,
+    /// and gets a stack of its own.
+    fn call(&mut self, ret: usize)
+    {
+        let mut regs: [RegState;11] = Default::default();
+        regs[1..6].clone_from_slice(&self.regs[1..6]);
+        regs[10] = RegState { frame: self.callers.len() + 1, ..RegState::ptr(PtrKind::Stack) };
+        self.callers.push(Caller {
+            regs: mem::replace(&mut self.regs, regs),
+            stack: mem::replace(&mut self.stack, new_stack()),
+            ret,
+        });
+    }
+
+    /// Return `r0` to the caller, giving where it continues. `r1` to `r5` are not preserved.
+    fn ret(&mut self) -> usize
+    {
+        let caller = self.callers.pop().unwrap();
+        let r0 = mem::replace(&mut self.regs, caller.regs)[0].clone();
+        self.regs[0] = r0;
+        for r in &mut self.regs[1..6] {
+            *r = RegState::default();
+        }
+        self.stack = caller.stack;
+        caller.ret
+    }
+
+    /// The stack of the function `frame` calls deep
+    fn frame_stack(&mut self, frame: usize) -> &mut Vec<StackSlot>
+    {
+        match self.callers.get_mut(frame) {
+            Some(c) => &mut c.stack,
+            None => &mut self.stack,
+        }
+    }
+
+    /// The state of `reg`, which must be initialized
+    fn reg(&self, pc: usize, reg: u8) -> Result<&RegState, PrgmVerifyError>
+    {
+        let r = &self.regs[reg as usize];
+        if r.ty == RegType::NotInit {
+            return Err(PrgmVerifyError {
+                kind: PrgmVerifyErrorKind::UninitializedRegister { reg },
+                inst_idx: pc,
+            });
+        }
+        Ok(r)
+    }
+
+    /// The value of `reg`, which must be initialized and not a pointer
+    fn scalar(&self, pc: usize, reg: u8) -> Result<Scalar, PrgmVerifyError>
+    {
+        let r = self.reg(pc, reg)?;
+        match r.ty {
+            RegType::Value => Ok(r.val),
+            _ => Err(ptr_error(pc, "pointer used as a number")),
+        }
+    }
+
+    /// The source operand of an `Alu`, `Alu64`, `Jmp`, or `Jmp32` instruction
+    fn src_operand(&self, pc: usize, i: &Inst) -> Result<RegState, PrgmVerifyError>
+    {
+        match i.op_src() {
+            Some(Src::X) => Ok(self.reg(pc, i.src())?.clone()),
+            _ => Ok(RegState::scalar(Scalar::from_value(i.src_operand(|_| 0)))),
+        }
+    }
+
+    /// Every path from `self` is at least as permissive as the same path from `other`, ignoring
+    /// registers which are not `live`
+    fn contains(&self, other: &State, live: RegSet) -> bool
+    {
+        // the callers' registers are all compared, as their liveness is not known here
+        let mut ids = Vec::new();
+        self.callers.len() == other.callers.len()
+            && self.callers.iter().zip(other.callers.iter()).all(|(o, n)| o.ret == n.ret
+                && o.regs.iter().zip(n.regs.iter()).all(|(o, n)| o.contains(n, &mut ids))
+                && o.stack.iter().zip(n.stack.iter()).all(|(o, n)| o.contains(n, &mut ids)))
+            && self.regs.iter().zip(other.regs.iter()).enumerate()
+                .all(|(r, (o, n))| live & (1 << r) == 0 || o.contains(n, &mut ids))
+            && self.stack.iter().zip(other.stack.iter()).all(|(o, n)| o.contains(n, &mut ids))
+    }
+
+    /// Registers, including those spilled to the stack, of every function on the call stack
+    fn regs_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut RegState> + 'a
+    {
+        let callers = self.callers.iter_mut().flat_map(|c| c.regs.iter_mut().chain(spills(&mut c.stack)));
+        self.regs.iter_mut().chain(spills(&mut self.stack)).chain(callers)
+    }
+
+    /// Bytes of stack below `r10` down to the lowest written, a multiple of 8, summed over every
+    /// function on the call stack
+    fn stack_depth(&self) -> u32
+    {
+        let depth = |stack: &[StackSlot]| match stack.iter().position(|s| s.bytes.iter().any(|&b| b != SlotType::Invalid)) {
+            Some(idx) => ((stack.len() - idx) * 8) as u32,
+            None => 0,
+        };
+        depth(&self.stack) + self.callers.iter().map(|c| depth(&c.stack)).sum::<u32>()
+    }
+
+    /// Store `size` bytes of `v` at `off` from the `r10` of function `frame`. Only a full 8 bytes
+    /// keeps what is known about `v`, and only a full 8 bytes may overwrite a spilled pointer.
+    fn stack_write(&mut self, pc: usize, frame: usize, off: i64, size: i64, v: RegState) -> Result<(), PrgmVerifyError>
+    {
+        let idx = (off + STACK_SIZE) as usize;
+        let slot = &mut self.frame_stack(frame)[idx / 8];
+        if size == 8 {
+            *slot = StackSlot { bytes: [SlotType::Spill;8], spill: v };
+            return Ok(());
+        }
+
+        // the rest of the pointer's bytes could then be read back as a number
+        if slot.bytes[0] == SlotType::Spill && slot.spill.ty != RegType::Value {
+            return Err(PrgmVerifyError {
+                kind: PrgmVerifyErrorKind::PartialPointer { region: PtrKind::Stack, off, size: size as u64 },
+                inst_idx: pc,
+            });
+        }
+
+        // the spilled number is no longer whole
+        for b in slot.bytes.iter_mut() {
+            if *b == SlotType::Spill {
+                *b = SlotType::Misc;
+            }
+        }
+        for b in &mut slot.bytes[idx % 8..idx % 8 + size as usize] {
+            *b = SlotType::Misc;
+        }
+        Ok(())
+    }
+
+    /// Load `size` bytes at `off` from the `r10` of function `frame`
+    fn stack_read(&mut self, pc: usize, frame: usize, off: i64, size: i64) -> Result<RegState, PrgmVerifyError>
+    {
+        let idx = (off + STACK_SIZE) as usize;
+        let slot = &self.frame_stack(frame)[idx / 8];
+        if size == 8 && slot.bytes[0] == SlotType::Spill {
+            return Ok(slot.spill.clone());
+        }
+
+        for (n, b) in slot.bytes[idx % 8..idx % 8 + size as usize].iter().enumerate() {
+            match *b {
+                SlotType::Invalid => return Err(PrgmVerifyError {
+                    kind: PrgmVerifyErrorKind::UninitializedStack { off: off + n as i64 },
+                    inst_idx: pc,
+                }),
+                SlotType::Spill if slot.spill.ty != RegType::Value => return Err(PrgmVerifyError {
+                    kind: PrgmVerifyErrorKind::PartialPointer { region: PtrKind::Stack, off, size: size as u64 },
+                    inst_idx: pc,
+                }),
+                _ => {},
+            }
+        }
+        Ok(RegState::scalar(Scalar::unknown_bits(size as u32 * 8)))
+    }
+
+    /// Check `min` to `max` bytes at the stack pointer `r` may be passed to a helper, which reads
+    /// them if `read` and otherwise writes them. Past `min`, bytes which were not written before
+    /// may still not be.
+    fn helper_mem(&mut self, pc: usize, r: &RegState, min: i64, max: i64, read: bool) -> Result<(), PrgmVerifyError>
+    {
+        let lo = i64::from(r.off);
+        if lo < -STACK_SIZE || lo + max > 0 {
+            return Err(PrgmVerifyError {
+                kind: PrgmVerifyErrorKind::OutOfBounds { region: PtrKind::Stack, off: lo, size: max as u64 },
+                inst_idx: pc,
+            });
+        }
+        for off in lo..lo + max {
+            let idx = (off + STACK_SIZE) as usize;
+            if read {
+                self.stack_read(pc, r.frame, off, 1)?;
+            } else if off < lo + min || self.frame_stack(r.frame)[idx / 8].bytes[idx % 8] != SlotType::Invalid {
+                self.stack_write(pc, r.frame, off, 1, RegState::scalar(Scalar::unknown()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn new_id(&mut self) -> u32
+    {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    /// Simulate the `Alu` or `Alu64` instruction `i`
+    fn alu(&mut self, pc: usize, i: &Inst) -> Result<(), PrgmVerifyError>
+    {
+        let op = i.op_alu();
+        let d = match op {
+            Some(OpAlu::Mov) => RegState::scalar(Scalar::unknown()),
+            _ => self.reg(pc, i.dst())?.clone(),
+        };
+        let s = match op {
+            Some(OpAlu::Neg) | Some(OpAlu::End) => RegState::scalar(Scalar::unknown()),
+            _ => self.src_operand(pc, i)?,
+        };
+
+        let add = op == Some(OpAlu::Add);
+        let sub = op == Some(OpAlu::Sub);
+        let r = match (d.ty, s.ty) {
+            (RegType::Value, RegType::Value) => RegState::scalar(Scalar::alu(i, d.val, s.val)),
+            _ if i.op_class() != Some(Class::Alu64) => return Err(ptr_error(pc, "32-bit operation on a pointer")),
+            // a copy keeps everything known about the pointer
+            (_, RegType::Ptr(_)) if op == Some(OpAlu::Mov) => s,
+            (RegType::Ptr(_), RegType::Value) if add || sub => self.offset(pc, d, s.val, sub)?,
+            (RegType::Value, RegType::Ptr(_)) if add => self.offset(pc, s, d.val, false)?,
+            (RegType::Value, RegType::Ptr(_)) if sub => return Err(ptr_error(pc, "subtracting a pointer from a number")),
+            (RegType::Ptr(_), RegType::Ptr(_)) if add => return Err(ptr_error(pc, "adding two pointers")),
+            // the distance between two pointers into the same region
+            (RegType::Ptr(a), RegType::Ptr(b)) if sub && a == b => RegState::scalar(Scalar::unknown()),
+            (RegType::Ptr(_), RegType::Ptr(_)) if sub => return Err(ptr_error(pc, "subtracting pointers of different kinds")),
+            _ => return Err(ptr_error(pc, "only add and sub may be used on pointers")),
+        };
+        self.regs[i.dst() as usize] = r;
+        Ok(())
+    }
+
+    /// The pointer `ptr` moved by `delta`, or by `-delta` if `neg`
+    fn offset(&mut self, pc: usize, ptr: RegState, delta: Scalar, neg: bool) -> Result<RegState, PrgmVerifyError>
+    {
+        let kind = match ptr.ty {
+            RegType::Ptr(k) => k,
+            _ => unreachable!(),
+        };
+        match kind {
+            PtrKind::PacketEnd | PtrKind::MapValueOrNull | PtrKind::Map =>
+                return Err(ptr_error(pc, "arithmetic on a pointer that can't be moved")),
+            _ => {},
+        }
+
+        let mut r = ptr;
+        match delta.value() {
+            Some(v) => {
+                let v = if neg { (v as i64).wrapping_neg() } else { v as i64 };
+                match i64::from(r.off).checked_add(v) {
+                    Some(off) if (-MAX_OFF..=MAX_OFF).contains(&off) => r.off = off as i32,
+                    _ => return Err(ptr_error(pc, "pointer offset out of range")),
+                }
+            },
+            None => {
+                if kind == PtrKind::Stack || kind == PtrKind::Ctx {
+                    return Err(ptr_error(pc, "variable offset into the stack or context"));
+                }
+                let bounded = |s: Scalar| -MAX_OFF <= s.range().smin() && s.range().smax() <= MAX_OFF;
+                if !bounded(delta) {
+                    return Err(ptr_error(pc, "pointer offset is not bounded"));
+                }
+                r.val = if neg { r.val.sub(delta) } else { r.val.add(delta) };
+                if !bounded(r.val) {
+                    return Err(ptr_error(pc, "pointer offset out of range"));
+                }
+                if kind == PtrKind::Packet {
+                    // no longer the same distance from other packet pointers
+                    r.id = self.new_id();
+                    r.range = 0;
+                }
+            },
+        }
+        Ok(r)
+    }
+
+    /// Narrow pointers after the conditional jump `i` comparing `a` (the destination) with `b`,
+    /// at least one of which is a pointer, is `taken` (or not)
+    fn compare_ptrs(&mut self, i: &Inst, taken: bool, a: &RegState, b: &RegState)
+    {
+        let null = |r: &RegState| r.ty == RegType::Value && r.val.value() == Some(0);
+        match (a.ty, b.ty) {
+            (RegType::Ptr(PtrKind::MapValueOrNull), _) if null(b) => self.null_check(i, taken, a.id),
+            (_, RegType::Ptr(PtrKind::MapValueOrNull)) if null(a) => self.null_check(i, taken, b.id),
+            (RegType::Ptr(PtrKind::Packet), RegType::Ptr(PtrKind::PacketEnd)) =>
+                self.packet_check(i.op_jmp(), taken, a),
+            (RegType::Ptr(PtrKind::PacketEnd), RegType::Ptr(PtrKind::Packet)) =>
+                self.packet_check(swap_jmp(i.op_jmp()), taken, b),
+            _ => {},
+        }
+    }
+
+    /// Resolve the `MapValueOrNull` pointers with `id` after comparing one of them with 0
+    fn null_check(&mut self, i: &Inst, taken: bool, id: u32)
+    {
+        let is_null = match (i.op_jmp(), taken) {
+            (Some(OpJmp::Jeq), true) | (Some(OpJmp::Jne), false) => true,
+            (Some(OpJmp::Jeq), false) | (Some(OpJmp::Jne), true) => false,
+            _ => return,
+        };
+        for r in self.regs_mut() {
+            if r.ty == RegType::Ptr(PtrKind::MapValueOrNull) && r.id == id {
+                if is_null {
+                    *r = RegState::scalar(Scalar::from_value(0));
+                } else {
+                    r.ty = RegType::Ptr(PtrKind::MapValue);
+                }
+            }
+        }
+    }
+
+    /// Extend the range of the packet pointers sharing `pkt`'s id after `pkt op end` is `taken`
+    /// (or not)
+    fn packet_check(&mut self, op: Option<OpJmp>, taken: bool, pkt: &RegState)
+    {
+        let op = if taken { op } else { negate_jmp(op) };
+        let end = match op {
+            Some(OpJmp::Jlt) => i64::from(pkt.off) + 1,
+            Some(OpJmp::Jle) => i64::from(pkt.off),
+            _ => return,
+        };
+        if end <= 0 {
+            return;
+        }
+        for r in self.regs_mut() {
+            if r.ty == RegType::Ptr(PtrKind::Packet) && r.id == pkt.id {
+                r.range = cmp::max(r.range, end as u32);
+            }
+        }
+    }
+}
+
+/// A program which `Env::verify` accepted, and what it found out about it
+///
+/// Converts into the `Program` which `Invoke` runs.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct VerifiedProgram<'a> {
+    prgm: Program<'a>,
+    insts: Vec<Inst>,
+    stack_depth: u32,
+    helpers: Vec<u32>,
+    call_depth: u32,
+}
+
+impl<'a> VerifiedProgram<'a> {
+    /// The program, to run with `Invoke`
+    pub fn program(&self) -> Program<'a> {
+        self.prgm.clone()
+    }
+
+    /// Each instruction, decoded. The second half of a `ld_imm64` is included, so indexes match
+    /// those of the raw program.
+    pub fn insts(&self) -> &[Inst] {
+        &self.insts
+    }
+
+    /// The most bytes below `r10` any path uses, rounded up to a multiple of 8. Where BPF
+    /// functions are called, the stacks of those on the call stack are added together.
+    pub fn stack_depth(&self) -> u32 {
+        self.stack_depth
+    }
+
+    /// Ids of the helpers called, in increasing order
+    pub fn helpers(&self) -> &[u32] {
+        &self.helpers
+    }
+
+    /// The deepest nesting of calls to other BPF functions: 0 if there are none, 1 if no function
+    /// called makes calls of its own, and so on
+    pub fn max_call_depth(&self) -> u32 {
+        self.call_depth
+    }
+}
+
+impl<'a> From<VerifiedProgram<'a>> for Program<'a> {
+    fn from(v: VerifiedProgram<'a>) -> Self {
+        v.prgm
+    }
+}
+
+impl<'a, 'b> From<&'b VerifiedProgram<'a>> for Program<'a> {
+    fn from(v: &'b VerifiedProgram<'a>) -> Self {
+        v.program()
+    }
+}
+
+/// What `Env::verify` did to check the last program given to it
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct Stats {
+    /// Instructions simulated, summed over all paths
+    pub insns_processed: usize,
+    /// States saved at the start of a block, to compare later paths with
+    pub states_explored: usize,
+    /// Paths which were not followed further as their state was contained by a saved one
+    pub states_pruned: usize,
+    /// The most states held at once, saved or waiting to be explored
+    pub peak_states: usize,
+}
+
+/// How much `Env::verify_log` writes
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum LogLevel {
+    /// Only if the program is rejected: the instructions simulated on the path which reached the
+    /// error, the state before the one that failed, and the error
+    Path,
+    /// As well, every instruction as it is simulated with the live registers and stack before it,
+    /// where each path starts, where paths are pruned, and the `Stats`
+    Verbose,
+}
+
+/// Where a log is written, and the path being simulated
+struct Log<'w> {
+    level: LogLevel,
+    out: &'w mut dyn fmt::Write,
+    /// The instructions simulated to reach the current one, including it
+    path: Vec<usize>,
+    /// The state before the current instruction
+    state: Option<State>,
+    lines: Vec<LineInfo>,
+}
+
+impl<'w> Log<'w> {
+    /// Write one line. A log that can't be written doesn't change whether a program is accepted,
+    /// so errors are ignored.
+    fn line(&mut self, args: fmt::Arguments) {
+        let _ = self.out.write_fmt(args);
+        let _ = self.out.write_char('\n');
+    }
+
+    /// Write the source line starting at `pc`, if there is one
+    fn source(&mut self, pc: usize) {
+        if let Ok(i) = self.lines.binary_search_by_key(&pc, |l| l.insn) {
+            let _ = writeln!(self.out, "; {}", self.lines[i]);
+        }
+    }
+}
+
+/// The format of a map a program refers to
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct MapDef {
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub flags: u32,
 }
 
 /// The environemnt a BPF program is invoked in, describes the limitations/requirements on that BPF
 /// program.
 ///
-/// Currently only provides a instruction limit.
+/// Currently provides an instruction limit, a complexity limit, the program type's `Profile`, and
+/// the maps the program may refer to.
 #[derive(Debug,PartialEq,Eq,Default)]
-struct Env {
+pub struct Env {
     //states: Vec<State>,
     inst_limit: Option<usize>,
+    complexity_limit: Option<usize>,
+    forbid_loops: bool,
+    profile: Profile,
+    helpers: Vec<(u32, HelperProto)>,
+    maps: Vec<MapDef>,
+    line_info: Vec<LineInfo>,
+    stats: Stats,
+    liveness: Liveness,
 }
 
-// A basic block of the 
-pub struct Block {
-    start: usize,
-    end: usize,
-}
+/// The number of instructions `Env::verify` simulates, summed over all paths, before giving up.
+/// Matches linux's `BPF_COMPLEXITY_LIMIT_INSNS`.
+pub const COMPLEXITY_LIMIT: usize = 1_000_000;
+
+/// The most states saved at one instruction. Paths reaching it after that are followed without
+/// being saved, so the time spent comparing states stays bounded.
+const MAX_SAVED_STATES: usize = 64;
 
 impl Env {
     pub fn with_inst_limit(inst_limit: usize) -> Self
     {
         Self {
             inst_limit: Some(inst_limit),
-            ..Default::default()
+            ..Self::default()
+        }
+    }
+
+    /// Limit the number of instructions simulated, instead of using `COMPLEXITY_LIMIT`
+    pub fn complexity_limit(mut self, limit: usize) -> Self
+    {
+        self.complexity_limit = Some(limit);
+        self
+    }
+
+    /// Reject any loop in the control flow, as linux did before it accepted bounded loops
+    pub fn forbid_loops(mut self) -> Self
+    {
+        self.forbid_loops = true;
+        self
+    }
+
+    /// Verify programs as being of the type described by `profile`, instead of
+    /// `Profile::default()`
+    pub fn profile(mut self, profile: Profile) -> Self
+    {
+        self.profile = profile;
+        self
+    }
+
+    /// Only accept programs which return one of `values`, instead of those the `Profile` allows
+    pub fn ret(mut self, values: RetValues) -> Self
+    {
+        self.profile.ret = Some(values);
+        self
+    }
+
+    /// Allow calls to the helper `id`, checking them against `proto`. Without this, only the
+    /// helpers the `Profile` lists may be called, with their prototypes from `helper::proto`.
+    pub fn helper(mut self, id: u32, proto: HelperProto) -> Self
+    {
+        self.helpers.push((id, proto));
+        self
+    }
+
+    /// The prototype a call to helper `id` is checked against, if it may be called
+    fn helper_proto(&self, id: u32) -> Option<HelperProto>
+    {
+        match self.helpers.iter().rev().find(|h| h.0 == id) {
+            Some(h) => Some(h.1.clone()),
+            None if self.profile.helpers.contains(&id) => helper::proto(id),
+            None => None,
+        }
+    }
+
+    /// Add a map. `ld_imm64` refers to maps by the order they were added in, starting at 0.
+    pub fn map(mut self, def: MapDef) -> Self
+    {
+        self.maps.push(def);
+        self
+    }
+
+    /// Show the source lines of the instructions in the log of `verify_log`. `lines` is ordered
+    /// by instruction, as `.BTF.ext` gives it.
+    pub fn line_info(mut self, lines: Vec<LineInfo>) -> Self
+    {
+        self.line_info = lines;
+        self
+    }
+
+    /// Statistics from the last call to `verify`, whether or not it succeeded
+    pub fn stats(&self) -> Stats
+    {
+        self.stats
+    }
+
+    /// The liveness of registers in the last program given to `verify`, including which
+    /// arguments it reads and any dead stores. Empty if the program's `Cfg` could not be built.
+    pub fn liveness(&self) -> &Liveness
+    {
+        &self.liveness
+    }
+
+    /// Check `data` is safe to run in this environment. Programs stored as bytes can be split into
+    /// instructions with `bytes::load_raw`.
+    pub fn verify<'a>(&mut self, data: &'a [u64]) -> Result<VerifiedProgram<'a>, PrgmVerifyError>
+    {
+        self.check(data, None)
+    }
+
+    /// Like `verify`, also writing what was checked to `out` in the style of linux's verifier log
+    pub fn verify_log<'a>(&mut self, data: &'a [u64], level: LogLevel, out: &mut dyn fmt::Write)
+        -> Result<VerifiedProgram<'a>, PrgmVerifyError>
+    {
+        let mut log = Log { level, out, path: Vec::new(), state: None, lines: self.line_info.clone() };
+        let r = self.check(data, Some(&mut log));
+
+        if let Err(ref e) = r {
+            // the path, with the state before the last instruction on it
+            let disasm = Disasm::new(data);
+            let path = log.path.clone();
+            let st = log.state.take().unwrap_or_default();
+            if let Some((&last, rest)) = path.split_last() {
+                log.line(format_args!("path to the error:"));
+                for &pc in rest {
+                    log.source(pc);
+                    log.line(format_args!("{}: {}", pc, disasm.at(pc)));
+                }
+                let live = self.liveness.live_in(last);
+                log.source(last);
+                log.line(format_args!("{}: {}{}", last, disasm.at(last), Dump { st: &st, live }));
+            }
+            log.line(format_args!("{}", e));
+        }
+        if level >= LogLevel::Verbose {
+            let s = self.stats;
+            log.line(format_args!("processed {} insns, {} states explored, {} pruned, peak {} states",
+                s.insns_processed, s.states_explored, s.states_pruned, s.peak_states));
         }
+        r
     }
 
-    // TODO: consider construction from raw bytes so we can handle endianness internally.
-    pub fn verify<'a>(&mut self, data: &'a [u64]) -> Result<Program<'a>, PrgmVerifyError>
+    fn check<'a>(&mut self, data: &'a [u64], log: Option<&mut Log>) -> Result<VerifiedProgram<'a>, PrgmVerifyError>
     {
-        let inst_ct = data.len();
+        self.stats = Stats::default();
+        self.liveness = Liveness::default();
+
+        if let Some(limit) = self.inst_limit {
+            if data.len() > limit {
+                return Err(PrgmVerifyError {
+                    kind: PrgmVerifyErrorKind::InstLimitExceeded,
+                    inst_idx: limit
+                });
+            }
+        }
+
+        // check that all instructions are valid encodings, and that every jump lands on one
+        let cfg = Cfg::new(data)?;
+        self.liveness = Liveness::new(&cfg, data, |id| self.helper_proto(id).map_or(5, |p| p.args.len()));
 
-        // check that all instructions are valid encodings
-        for (idx, inst) in data.iter().enumerate() {
-            let _ = Inst::from_u64(*inst).map_err(|e| PrgmVerifyError {
-                kind: From::from(e),
-                inst_idx: idx
-            })?;
+        // check that we don't have any instructions that can't be reached
+        check_reachable(&cfg)?;
+        if self.forbid_loops {
+            check_acyclic(&cfg)?;
         }
 
-        // check that we don't have any loops
         // check data flow to forbid uninitialized & out of bound reads
         // check data flow wrt context to forbid certain reads/writes
         // check that the return value (if any) is initialized
-        // forbid dead stores (?)
+        // dead stores are allowed, but reported by `liveness()`
 
         // simulation can tell us what registers require an initial value
         // alternately, us saying "these will be the initial values" could simplify validation in
         // simulation.
 
-        let mut pc = 0;
-        loop {
-            if pc > inst_ct {
-                return Err(PrgmVerifyError {
-                    kind: PrgmVerifyErrorKind::InvalidInstIdx,
-                    inst_idx: pc
-                });
-            }
-
-            if pc > self.inst_limit.unwrap_or(usize::MAX) {
-                return Err(PrgmVerifyError {
-                    kind: PrgmVerifyErrorKind::InstLimitExceeded,
-                    inst_idx: pc
-                });
+        // the second half of an `ld_imm64` is not an instruction of its own
+        let mut imm64_hi = false;
+        for (pc, &raw) in data.iter().enumerate() {
+            if imm64_hi {
+                imm64_hi = false;
+                continue;
             }
 
-            let i = Inst::from_u64(data[pc]).unwrap();
+            let i = Inst::from_u64(raw).unwrap();
 
             match i.op_class() {
                 Some(Class::Ld) => {
+                    let packet_load = i.ld_mode() == Some(Mode::Abs) || i.ld_mode() == Some(Mode::Ind);
+                    if packet_load && !self.profile.ld_abs {
+                        return Err(From::from((
+                                    pc,
+                                    InstDecodeError::ForbiddenInst("ld.abs and ld.ind are not allowed for this program type")
+                        )));
+                    }
+
                     match i.ld_mode() {
                         Some(Mode::Imm) => {
                             if i.off16() != 0 {
@@ -171,10 +1300,13 @@ impl Env {
                             }
 
                             if i.ld_size() == Some(Size::DW) {
-                                return Err(From::from((
-                                            pc,
-                                            InstDecodeError::ForbiddenInst("ld.imm.dw(64) not implimented")
-                                )));
+                                imm64_hi = true;
+                                if i.src() != 0 && i.imm32() as usize >= self.maps.len() {
+                                    return Err(PrgmVerifyError {
+                                        kind: PrgmVerifyErrorKind::UnknownMap { index: i.imm32() },
+                                        inst_idx: pc,
+                                    });
+                                }
                             }
                         },
                         Some(Mode::Abs) => {
@@ -216,35 +1348,507 @@ impl Env {
                         ))),
                     }
                 },
-                Some(Class::Jmp) => {
+                // `Inst::check` has limited these to `Mem` (and `Xadd` for `Stx`)
+                Some(Class::St) | Some(Class::Stx) => {},
+                Some(Class::Alu) | Some(Class::Alu64) => {},
+                Some(Class::Jmp) | Some(Class::Jmp32) => {
                     match i.op_jmp() {
-                        Some(OpJmp::Exit) => {
-                            if i.off16() != 0 || i.imm32() != 0 {
-                                return Err(From::from((
-                                            pc,
-                                            InstDecodeError::ForbiddenInst("Exit has non-zero imm or off")
-                                )));
-                            }
-                        },
-                        Some(OpJmp::Ja) => {
-                            // jump always
-                            unimplemented!()
-                        },
-                        _ => return Err(From::from((
+                        Some(OpJmp::Exit) if i.off16() != 0 || i.imm32() != 0 => return Err(From::from((
                                     pc,
-                                    InstDecodeError::ForbiddenInst("not Exit")
+                                    InstDecodeError::ForbiddenInst("Exit has non-zero imm or off")
                         ))),
+                        Some(OpJmp::Call) if i.call_off().is_none() => match self.helper_proto(i.imm32()) {
+                            None => return Err(PrgmVerifyError {
+                                kind: PrgmVerifyErrorKind::UnknownHelper { id: i.imm32() },
+                                inst_idx: pc,
+                            }),
+                            Some(ref proto) if !proto.is_valid() => return Err(PrgmVerifyError {
+                                kind: PrgmVerifyErrorKind::InvalidHelperProto { id: i.imm32() },
+                                inst_idx: pc,
+                            }),
+                            _ => {},
+                        },
+                        // the target was checked when building the cfg
+                        _ => {},
                     }
                 },
                 _ => return Err(From::from((
                             pc,
-                            InstDecodeError::ForbiddenInst("not Ld or Jmp")
+                            InstDecodeError::ForbiddenInst("invalid class")
                 ))),
             }
+        }
+
+        // check that every path ends, which loops may prevent
+        let (stack_depth, call_depth) = self.explore(&cfg, data, log)?;
+
+        let mut helpers: Vec<u32> = data.iter()
+            .map(|&raw| Inst::from_u64(raw).unwrap())
+            .filter(|i| i.op_class() == Some(Class::Jmp) && i.op_jmp() == Some(OpJmp::Call) && i.call_off().is_none())
+            .map(|i| i.imm32())
+            .collect();
+        helpers.sort();
+        helpers.dedup();
+
+        Ok(VerifiedProgram {
+            prgm: unsafe { Program::from_raw(data) },
+            insts: data.iter().map(|&raw| Inst::from_u64(raw).unwrap()).collect(),
+            stack_depth,
+            helpers,
+            call_depth,
+        })
+    }
+
+    /// Simulate each path through `data` from the entry until it exits. A conditional jump is
+    /// followed each way its operands allow.
+    ///
+    /// The state at the start of each block is saved. Once every path from a saved state has been
+    /// explored, later paths reaching that block in a state it contains are not followed further.
+    /// A path reaching it in a state contained by one still being explored, which it must have
+    /// started from, loops forever.
+    ///
+    /// A call to a BPF function is followed into it, with the caller's state kept aside until it
+    /// returns.
+    ///
+    /// `data` must already have passed the per-instruction checks in `verify`. Returns the most
+    /// stack any path uses, and the deepest nesting of calls.
+    fn explore(&mut self, cfg: &Cfg, data: &[u64], mut log: Option<&mut Log>) -> Result<(u32, u32), PrgmVerifyError>
+    {
+        let mut stack_depth = 0;
+        let mut call_depth = 0;
+        let disasm = Disasm::new(data);
+        let limit = self.complexity_limit.unwrap_or(COMPLEXITY_LIMIT);
+
+        let mut block_start = Vec::new();
+        block_start.resize(data.len(), false);
+        for b in cfg.blocks() {
+            block_start[b.start()] = true;
+        }
+
+        // states saved at each instruction, and whether every path from them has been explored
+        let mut saved: Vec<Vec<(State, bool)>> = Vec::new();
+        saved.resize(data.len(), Vec::new());
+        // saved states still being explored, with the number of `pending` paths when they were
+        // saved. Every path pushed after that starts from it.
+        let mut open: Vec<(usize, usize, usize)> = Vec::new();
+
+        // paths left to simulate, starting at the given instruction, with the length of the logged
+        // path which reached them
+        let mut pending = Vec::new();
+        pending.push((0, State::default(), 0));
+        while let Some((mut pc, mut st, depth)) = pending.pop() {
+            if let Some(log) = log.as_mut() {
+                log.path.truncate(depth);
+                if log.level >= LogLevel::Verbose && depth > 0 {
+                    let live = self.liveness.live_in(pc);
+                    let from = log.path[depth - 1];
+                    log.line(format_args!("from {} to {}:{}", from, pc, Dump { st: &st, live }));
+                }
+            }
 
-            pc += 1;
+            loop {
+                if block_start[pc] {
+                    let live = self.liveness.live_in(pc);
+                    if saved[pc].iter().any(|&(ref s, done)| done && s.contains(&st, live)) {
+                        self.stats.states_pruned += 1;
+                        if let Some(log) = log.as_mut() {
+                            if log.level >= LogLevel::Verbose {
+                                log.line(format_args!("{}: safe", pc));
+                            }
+                        }
+                        break;
+                    }
+                    if saved[pc].iter().any(|&(ref s, done)| !done && s.contains(&st, live)) {
+                        return Err(PrgmVerifyError {
+                            kind: PrgmVerifyErrorKind::InfiniteLoop,
+                            inst_idx: pc,
+                        });
+                    }
+                    if saved[pc].len() < MAX_SAVED_STATES {
+                        open.push((pc, saved[pc].len(), pending.len()));
+                        saved[pc].push((st.clone(), false));
+                        self.stats.states_explored += 1;
+                        self.stats.peak_states = cmp::max(self.stats.peak_states,
+                            self.stats.states_explored + pending.len());
+                    }
+                }
+
+                self.stats.insns_processed += 1;
+                if self.stats.insns_processed > limit {
+                    return Err(PrgmVerifyError {
+                        kind: PrgmVerifyErrorKind::ComplexityLimitExceeded { limit },
+                        inst_idx: pc,
+                    });
+                }
+
+                if let Some(log) = log.as_mut() {
+                    if log.level >= LogLevel::Verbose {
+                        let live = self.liveness.live_in(pc);
+                        log.source(pc);
+                        log.line(format_args!("{}: {}{}", pc, disasm.at(pc), Dump { st: &st, live }));
+                    }
+                    log.path.push(pc);
+                    log.state = Some(st.clone());
+                }
+
+                let i = Inst::from_u64(data[pc]).unwrap();
+                let dst = i.dst() as usize;
+                match i.op_class() {
+                    Some(Class::Ld) => {
+                        if i.ld_mode() == Some(Mode::Ind) {
+                            st.scalar(pc, i.src())?;
+                        }
+                        st.regs[dst] = match (i.ld_mode(), i.ld_size()) {
+                            (Some(Mode::Imm), Some(Size::DW)) => {
+                                let hi = Inst::from_u64(data[pc + 1]).unwrap().imm32();
+                                let map = i.imm32() as usize;
+                                pc += 1;
+                                match i.src() {
+                                    1 => RegState { map, ..RegState::ptr(PtrKind::Map) },
+                                    2 => RegState { map, off: hi as i32, ..RegState::ptr(PtrKind::MapValue) },
+                                    _ => RegState::scalar(Scalar::from_value((u64::from(hi) << 32) | u64::from(i.imm32()))),
+                                }
+                            },
+                            (Some(Mode::Imm), _) => RegState::scalar(Scalar::from_value(u64::from(i.imm32()))),
+                            (_, Some(Size::W)) => RegState::scalar(Scalar::unknown_bits(32)),
+                            (_, Some(Size::H)) => RegState::scalar(Scalar::unknown_bits(16)),
+                            (_, Some(Size::B)) => RegState::scalar(Scalar::unknown_bits(8)),
+                            _ => RegState::scalar(Scalar::unknown()),
+                        };
+                    },
+                    Some(Class::Ldx) => {
+                        st.regs[dst] = self.access(&mut st, pc, &i, i.src(), None)?;
+                    },
+                    Some(Class::St) => {
+                        let imm = Scalar::from_value(i.imm32() as i32 as u64);
+                        self.access(&mut st, pc, &i, i.dst(), Some(RegState::scalar(imm)))?;
+                    },
+                    Some(Class::Stx) => {
+                        let v = st.reg(pc, i.src())?.clone();
+                        if i.ld_mode() == Some(Mode::Xadd) {
+                            st.scalar(pc, i.src())?;
+                            let to = st.reg(pc, i.dst())?.ty;
+                            if to == RegType::Ptr(PtrKind::Packet) {
+                                return Err(PrgmVerifyError {
+                                    kind: PrgmVerifyErrorKind::InvalidMemoryAccess {
+                                        reg: i.dst(),
+                                        kind: Some(PtrKind::Packet),
+                                    },
+                                    inst_idx: pc,
+                                });
+                            }
+                            // the old value must be a number, and the sum could be any
+                            if let RegType::Ptr(_) = self.access(&mut st, pc, &i, i.dst(), None)?.ty {
+                                return Err(ptr_error(pc, "xadd to a spilled pointer"));
+                            }
+                            self.access(&mut st, pc, &i, i.dst(), Some(RegState::scalar(Scalar::unknown())))?;
+                        } else {
+                            self.access(&mut st, pc, &i, i.dst(), Some(v))?;
+                        }
+                    },
+                    Some(Class::Alu) | Some(Class::Alu64) => st.alu(pc, &i)?,
+                    Some(Class::Jmp) | Some(Class::Jmp32) => {
+                        let off = i.jump_off().or_else(|| i.call_off()).unwrap_or(0);
+                        let target = (pc as i64 + 1 + i64::from(off)) as usize;
+                        match i.op_jmp() {
+                            // back to the calling function, which can't be given a pointer to the
+                            // stack it loses
+                            Some(OpJmp::Exit) if !st.callers.is_empty() => {
+                                let r0 = st.reg(pc, 0)?;
+                                if r0.ty == RegType::Ptr(PtrKind::Stack) && r0.frame == st.callers.len() {
+                                    return Err(PrgmVerifyError {
+                                        kind: PrgmVerifyErrorKind::PointerLeak { reg: 0 },
+                                        inst_idx: pc,
+                                    });
+                                }
+                                stack_depth = cmp::max(stack_depth, st.stack_depth());
+                                pc = st.ret();
+                                continue;
+                            },
+                            Some(OpJmp::Exit) => {
+                                self.check_ret(pc, st.reg(pc, 0)?)?;
+                                break;
+                            },
+                            Some(OpJmp::Ja) => {
+                                pc = target;
+                                continue;
+                            },
+                            Some(OpJmp::Call) if i.call_off().is_some() => {
+                                if st.callers.len() + 1 == MAX_CALL_FRAMES {
+                                    return Err(PrgmVerifyError {
+                                        kind: PrgmVerifyErrorKind::CallDepthExceeded { limit: MAX_CALL_FRAMES },
+                                        inst_idx: pc,
+                                    });
+                                }
+                                st.call(pc + 1);
+                                call_depth = cmp::max(call_depth, st.callers.len() as u32);
+                                pc = target;
+                                continue;
+                            },
+                            Some(OpJmp::Call) => {
+                                self.call(&mut st, pc, i.imm32())?;
+                                pc += 1;
+                                continue;
+                            },
+                            _ => {},
+                        }
+
+                        let a = st.reg(pc, i.dst())?.clone();
+                        let b = st.src_operand(pc, &i)?;
+                        let scalars = a.ty == RegType::Value && b.ty == RegType::Value;
+                        if !scalars && i.op_class() == Some(Class::Jmp32) {
+                            return Err(ptr_error(pc, "32-bit comparison of a pointer"));
+                        }
+                        let branch = |taken| if scalars {
+                            Scalar::refine_jmp(&i, taken, a.val, b.val).map(|(x, y)| {
+                                let mut st = st.clone();
+                                st.regs[dst].val = x;
+                                if i.op_src() == Some(Src::X) {
+                                    st.regs[i.src() as usize].val = y;
+                                }
+                                st
+                            })
+                        } else {
+                            let mut st = st.clone();
+                            st.compare_ptrs(&i, taken, &a, &b);
+                            Some(st)
+                        };
+                        match (branch(true), branch(false)) {
+                            (Some(taken), Some(fall)) => {
+                                let depth = log.as_ref().map_or(0, |log| log.path.len());
+                                pending.push((target, taken, depth));
+                                st = fall;
+                            },
+                            (Some(taken), None) => {
+                                st = taken;
+                                pc = target;
+                                continue;
+                            },
+                            (None, Some(fall)) => st = fall,
+                            // the values reaching here are contradictory
+                            (None, None) => break,
+                        }
+                    },
+                    _ => unreachable!(),
+                }
+
+                pc += 1;
+            }
+            stack_depth = cmp::max(stack_depth, st.stack_depth());
+
+            while let Some(&(at, idx, depth)) = open.last() {
+                if depth < pending.len() {
+                    break;
+                }
+                saved[at][idx].1 = true;
+                open.pop();
+            }
+        }
+
+        Ok((stack_depth, call_depth))
+    }
+
+    /// Check `r0`, at the `exit` at `pc`, is a number the program type allows
+    fn check_ret(&self, pc: usize, r0: &RegState) -> Result<(), PrgmVerifyError>
+    {
+        if let RegType::Ptr(_) = r0.ty {
+            return Err(PrgmVerifyError {
+                kind: PrgmVerifyErrorKind::PointerLeak { reg: 0 },
+                inst_idx: pc,
+            });
+        }
+        let r = r0.val.range();
+        let ok = match self.profile.ret {
+            None => true,
+            Some(RetValues::Range(min, max)) => min <= r.min() && r.max() <= max,
+            Some(RetValues::Set(ref set)) => r0.val.within(set),
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(PrgmVerifyError {
+                kind: PrgmVerifyErrorKind::BadReturnValue { min: r.min(), max: r.max() },
+                inst_idx: pc,
+            })
+        }
+    }
+
+    /// Check the arguments of a call to the helper `id` match its prototype, then clobber `r1` to
+    /// `r5` and set `r0` to what it returns
+    fn call(&self, st: &mut State, pc: usize, id: u32) -> Result<(), PrgmVerifyError>
+    {
+        // checked by `verify`
+        let proto = self.helper_proto(id).unwrap();
+        let mismatch = |reg, expected| PrgmVerifyError {
+            kind: PrgmVerifyErrorKind::HelperArgMismatch { reg, expected },
+            inst_idx: pc,
+        };
+
+        let mut map = None;
+        for (n, &kind) in proto.args.iter().enumerate() {
+            let reg = n as u8 + 1;
+            let r = st.reg(pc, reg)?.clone();
+            let ok = match kind {
+                ArgKind::Scalar | ArgKind::ConstSize => r.ty == RegType::Value,
+                ArgKind::Ctx => r.ty == RegType::Ptr(PtrKind::Ctx) && r.off == 0,
+                ArgKind::Map => {
+                    map = Some(r.map);
+                    r.ty == RegType::Ptr(PtrKind::Map)
+                },
+                ArgKind::MapKey | ArgKind::MapValue => {
+                    let def = match map {
+                        Some(m) => self.maps[m],
+                        None => return Err(mismatch(reg, kind)),
+                    };
+                    if r.ty != RegType::Ptr(PtrKind::Stack) {
+                        return Err(mismatch(reg, kind));
+                    }
+                    let size = if kind == ArgKind::MapKey { def.key_size } else { def.value_size };
+                    st.helper_mem(pc, &r, i64::from(size), i64::from(size), true)?;
+                    true
+                },
+                ArgKind::StackMem | ArgKind::UninitStackMem => {
+                    // `verify` checked a `ConstSize` follows
+                    let s = st.reg(pc, reg + 1)?;
+                    if s.ty != RegType::Value || s.val.range().min() == 0 || s.val.range().max() > STACK_SIZE as u64 {
+                        return Err(mismatch(reg + 1, ArgKind::ConstSize));
+                    }
+                    let size = s.val.range();
+                    if r.ty != RegType::Ptr(PtrKind::Stack) {
+                        return Err(mismatch(reg, kind));
+                    }
+                    st.helper_mem(pc, &r, size.min() as i64, size.max() as i64, kind == ArgKind::StackMem)?;
+                    true
+                },
+            };
+            if !ok {
+                return Err(mismatch(reg, kind));
+            }
+        }
+
+        for r in 1..6 {
+            st.regs[r] = RegState::default();
+        }
+        if proto.changes_packet {
+            for r in st.regs_mut() {
+                if let RegType::Ptr(PtrKind::Packet) | RegType::Ptr(PtrKind::PacketEnd) = r.ty {
+                    *r = RegState::scalar(Scalar::unknown());
+                }
+            }
+        }
+        st.regs[0] = match (proto.ret, map) {
+            (RetKind::Scalar, _) => RegState::scalar(Scalar::unknown()),
+            (RetKind::MapValueOrNull, Some(map)) => RegState {
+                map,
+                id: st.new_id(),
+                ..RegState::ptr(PtrKind::MapValueOrNull)
+            },
+            // no map to return a value of
+            (RetKind::MapValueOrNull, None) | (RetKind::Void, _) => RegState::default(),
+        };
+        Ok(())
+    }
+
+    /// Check the `Ldx`, `St`, or `Stx` instruction `i` only accesses memory `reg` may point to.
+    /// Gives what is loaded, or stores `store`.
+    fn access(&self, st: &mut State, pc: usize, i: &Inst, reg: u8, store: Option<RegState>)
+        -> Result<RegState, PrgmVerifyError>
+    {
+        let r = st.reg(pc, reg)?.clone();
+        let write = store.is_some();
+        let invalid = |kind| Err(PrgmVerifyError {
+            kind: PrgmVerifyErrorKind::InvalidMemoryAccess { reg, kind },
+            inst_idx: pc,
+        });
+        let kind = match r.ty {
+            RegType::Ptr(k) => k,
+            _ => return invalid(None),
+        };
+        let ctx_size = self.profile.ctx.iter().map(|f| i64::from(f.off) + i64::from(f.size)).max();
+        let (min, max) = match kind {
+            PtrKind::Stack => (-STACK_SIZE, 0),
+            PtrKind::Ctx => (0, ctx_size.unwrap_or(0)),
+            PtrKind::Packet => (0, i64::from(r.range)),
+            PtrKind::MapValue => (0, i64::from(self.maps[r.map].value_size)),
+            _ => return invalid(Some(kind)),
+        };
+
+        let size = match i.ld_size() {
+            Some(Size::B) => 1,
+            Some(Size::H) => 2,
+            Some(Size::W) => 4,
+            _ => 8,
+        };
+        // packet ranges already account for the variable offset of pointers sharing an id
+        let (vmin, vmax) = match kind {
+            PtrKind::Packet => (cmp::min(r.val.range().smin(), 0), 0),
+            _ => (r.val.range().smin(), r.val.range().smax()),
+        };
+        let off = i64::from(r.off) + i64::from(i.off16());
+        let lo = off + vmin;
+        let hi = off + vmax + size;
+        if lo < min || hi > max {
+            return Err(PrgmVerifyError {
+                kind: PrgmVerifyErrorKind::OutOfBounds { region: kind, off: lo, size: size as u64 },
+                inst_idx: pc,
+            });
+        }
+
+        if let Some(v) = store.as_ref().filter(|v| v.ty != RegType::Value) {
+            // nor may a caller keep a pointer to the stack of a function which has returned
+            let callee_stack = v.ty == RegType::Ptr(PtrKind::Stack) && v.frame > r.frame;
+            if kind != PtrKind::Stack || callee_stack {
+                return Err(PrgmVerifyError {
+                    kind: PrgmVerifyErrorKind::PointerLeak { reg: i.src() },
+                    inst_idx: pc,
+                });
+            }
+            if size != 8 {
+                return Err(PrgmVerifyError {
+                    kind: PrgmVerifyErrorKind::PartialPointer { region: kind, off: lo, size: size as u64 },
+                    inst_idx: pc,
+                });
+            }
+        }
+
+        if kind == PtrKind::Stack {
+            if lo % size != 0 {
+                return Err(PrgmVerifyError {
+                    kind: PrgmVerifyErrorKind::Misaligned { region: kind, off: lo, size: size as u64 },
+                    inst_idx: pc,
+                });
+            }
+            return match store {
+                Some(v) => {
+                    st.stack_write(pc, r.frame, lo, size, v)?;
+                    Ok(RegState::default())
+                },
+                None => st.stack_read(pc, r.frame, lo, size),
+            };
+        }
+
+        if kind == PtrKind::Ctx {
+            let f = match self.profile.ctx_field(lo, size) {
+                Some(f) => *f,
+                None => return Err(PrgmVerifyError {
+                    kind: PrgmVerifyErrorKind::InvalidContextAccess { off: lo, size: size as u64 },
+                    inst_idx: pc,
+                }),
+            };
+            if write && !f.write {
+                return invalid(Some(kind));
+            }
+            if let Some(ptr) = f.ptr {
+                if lo != i64::from(f.off) || size != i64::from(f.size) {
+                    return Err(PrgmVerifyError {
+                        kind: PrgmVerifyErrorKind::PartialPointer { region: kind, off: lo, size: size as u64 },
+                        inst_idx: pc,
+                    });
+                }
+                return Ok(RegState::ptr(ptr));
+            }
         }
 
-        Ok(unsafe { Program::from_raw(data) })
+        Ok(RegState::scalar(Scalar::unknown_bits(size as u32 * 8)))
     }
 }
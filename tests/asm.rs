@@ -0,0 +1,196 @@
+extern crate cbpf;
+
+use cbpf::asm::{assemble, AsmErrorKind};
+
+#[test]
+fn c_syntax() {
+    // expected encodings from `llvm-mc -triple bpfel -show-encoding`
+    let p = assemble("
+        r0 = 1
+        r0 = -1
+        w1 = 2
+        r1 = r2
+        r1 += r2
+        w3 -= 7
+        r4 *= r5
+        r1 /= 3
+        r1 |= r2
+        r1 &= 0xff
+        r1 <<= 4
+        r1 >>= r3
+        r1 s>>= 3
+        r1 ^= r9
+        r1 = -r1
+        w1 = -w1
+        r1 = be16 r1
+        r2 = le32 r2
+        r0 = 0x123456789 ll
+        r1 = *(u32 *)(r10 - 4)
+        r1 = *(u64 *)(r2 + 8)
+        r1 = *(u8 *)(r2 + 0)
+        *(u32 *)(r10 - 4) = r1
+        *(u16 *)(r1 + 2) = r3
+        lock *(u64 *)(r1 + 0) += r2
+        lock *(u32 *)(r1 + 4) += r2
+        r0 = *(u16 *)skb[12]
+        r0 = *(u32 *)skb[0]
+        if r1 > 0x10 goto +2
+        if r1 s< r2 goto -1
+        if r1 != 5 goto +1
+        if r3 s>= r4 goto +0
+        if r3 <= 4 goto +0
+        goto +1
+        call 1
+        exit
+    ").unwrap();
+
+    assert_eq!(p, vec![
+        0xb700000000000001,
+        0xb7000000ffffffff,
+        0xb401000000000002,
+        0xbf21000000000000,
+        0x0f21000000000000,
+        0x1403000000000007,
+        0x2f54000000000000,
+        0x3701000000000003,
+        0x4f21000000000000,
+        0x57010000000000ff,
+        0x6701000000000004,
+        0x7f31000000000000,
+        0xc701000000000003,
+        0xaf91000000000000,
+        0x8701000000000000,
+        0x8401000000000000,
+        0xdc01000000000010,
+        0xd402000000000020,
+        0x1800000023456789,
+        0x0000000000000001,
+        0x61a1fffc00000000,
+        0x7921000800000000,
+        0x7121000000000000,
+        0x631afffc00000000,
+        0x6b31000200000000,
+        0xdb21000000000000,
+        0xc321000400000000,
+        0x280000000000000c,
+        0x2000000000000000,
+        0x2501000200000010,
+        0xcd21ffff00000000,
+        0x5501000100000005,
+        0x7d43000000000000,
+        0xb503000000000004,
+        0x0500000100000000,
+        0x8500000000000001,
+        0x9500000000000000,
+    ]);
+}
+
+#[test]
+fn mnemonic_syntax() {
+    let a = assemble("
+        mov r0, 1
+        mov32 r1, 2
+        add r1, r2
+        sub32 r3, 7
+        neg r1
+        be16 r1
+        lddw r0, 0x123456789
+        ldxw r1, [r10-4]
+        stxw [r10-4], r1
+        stdw [r10-8], 5
+        xadddw [r1], r2
+        ldabsh 12
+        ldindb r1, 3
+        jgt r1, 0x10, +2
+        jslt r1, r2, -1
+        ja +1
+        call 1
+        exit
+    ").unwrap();
+
+    let b = assemble("
+        r0 = 1
+        w1 = 2
+        r1 += r2
+        w3 -= 7
+        r1 = -r1
+        r1 = be16 r1
+        r0 = 0x123456789 ll
+        r1 = *(u32 *)(r10 - 4)
+        *(u32 *)(r10 - 4) = r1
+        *(u64 *)(r10 - 8) = 5
+        lock *(u64 *)(r1 + 0) += r2
+        r0 = *(u16 *)skb[12]
+        r0 = *(u8 *)skb[r1 + 3]
+        if r1 > 0x10 goto +2
+        if r1 s< r2 goto -1
+        goto +1
+        call 1
+        exit
+    ").unwrap();
+
+    assert_eq!(a, b);
+}
+
+#[test]
+fn labels_and_comments() {
+    let p = assemble("
+        // accept ipv4
+        r0 = *(u16 *)skb[12]   ; ethertype
+        if r0 == 0x800 goto accept
+    reject:
+        r0 = 0                 # nope
+        exit
+    accept: r0 = -1
+        goto reject
+    ").unwrap();
+
+    assert_eq!(p, vec![
+        0x280000000000000c,
+        0x1500000200000800,
+        0xb700000000000000,
+        0x9500000000000000,
+        0xb7000000ffffffff,
+        0x0500fffc00000000,
+    ]);
+}
+
+#[test]
+fn run_ld_imm() {
+    let p = assemble("
+        ldw r0, 0xdeadbeef
+        jeq32 r0, 0xdeadbeef, +1
+        ldw r0, 2
+        exit
+    ").unwrap();
+
+    assert_eq!(p[0], 0x00000000deadbeef);
+    let c = cbpf::Invoke::new(cbpf::verifier::Env::default().verify(&p).unwrap());
+    assert_eq!(c.run(), Ok(0xdeadbeef));
+}
+
+#[test]
+fn errors() {
+    let e = assemble("r0 = 1\nr11 = 2").unwrap_err();
+    assert_eq!((e.line(), e.col(), e.kind()), (2, 1, &AsmErrorKind::UnknownMnemonic));
+
+    let e = assemble("r0 = 1\n  if r0 > 1 goto nowhere").unwrap_err();
+    assert_eq!((e.line(), e.col(), e.kind()), (2, 18, &AsmErrorKind::UndefinedLabel));
+
+    let e = assemble("a:\na: exit").unwrap_err();
+    assert_eq!((e.line(), e.col(), e.kind()), (2, 1, &AsmErrorKind::DuplicateLabel));
+
+    let e = assemble("r1 = *(u32 *)(r10 - 40000)").unwrap_err();
+    assert_eq!((e.line(), e.col(), e.kind()), (1, 19, &AsmErrorKind::OutOfRange));
+
+    let e = assemble("r1 += w2").unwrap_err();
+    assert_eq!((e.line(), e.col()), (1, 7));
+    assert_eq!(format!("{}", e), "1:7: syntax error: mismatched register width");
+
+    let e = assemble("exit r0").unwrap_err();
+    assert_eq!((e.line(), e.col()), (1, 6));
+
+    // classic BPF syntax is not accepted
+    let e = assemble("\n  // comment\n  ldh [12]").unwrap_err();
+    assert_eq!((e.line(), e.col()), (3, 7));
+}
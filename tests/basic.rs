@@ -1,3 +1,5 @@
+#![allow(clippy::unusual_byte_groupings)]
+
 extern crate cbpf;
 
 /*
@@ -66,8 +68,8 @@ fn je_imm_true() {
         // ld r0, 0x1u32
         //  LD|MEM|W
         0x00_00_00_00__DEADBEEF,
-        // je #0xDEADBEEF, r0, 1
-        0x15_00_00_01__DEADBEEF,
+        // je32 #0xDEADBEEF, w0, 1: the 64-bit compare would sign extend the immediate
+        0x16_00_00_01__DEADBEEF,
         // ld r0, 0x2u32
         //  LD|MEM|W
         0x00_00_00_00__00_00_00_02,
@@ -178,3 +180,55 @@ fn jge_imm_false() {
     let c = cbpf::Invoke::new(p);
     assert_eq!(c.run(), Ok(0x2));
 }
+
+#[test]
+fn alu() {
+    let run = |src: &str| {
+        let p = cbpf::asm::assemble(src).unwrap();
+        cbpf::Invoke::new(unsafe { cbpf::Program::from_raw(&p) }).run()
+    };
+
+    assert_eq!(run("r0 = -1\nexit"), Ok(!0));
+    assert_eq!(run("w0 = -1\nexit"), Ok(0xffff_ffff));
+    assert_eq!(run("r0 = 7\nr1 = 2\nr0 *= r1\nr0 -= 4\nr0 /= 3\nexit"), Ok(3));
+    assert_eq!(run("r0 = 7\nr0 /= 0\nexit"), Ok(0));
+    assert_eq!(run("r0 = 7\nr0 %= 0\nexit"), Ok(7));
+    assert_eq!(run("r0 = -16\nr0 s>>= 2\nexit"), Ok(-4i64 as u64));
+    assert_eq!(run("w0 = -16\nw0 s>>= 2\nexit"), Ok(0xffff_fffc));
+    assert_eq!(run("r0 = 1\nw0 <<= 33\nexit"), Ok(2));
+    assert_eq!(run("r0 = 0x11223344\nr0 = be16 r0\nexit"), Ok(0x4433));
+    assert_eq!(run("r0 = -2\nr0 = be64 r0\nexit"), Ok(0xfeff_ffff_ffff_ffff));
+    assert_eq!(run("r0 = -2\nr0 = le32 r0\nexit"), Ok(0xffff_fffe));
+}
+
+#[test]
+fn packet_loads() {
+    let run = |src: &str| {
+        let p = cbpf::asm::assemble(src).unwrap();
+        let v = cbpf::verifier::Env::default().verify(&p).unwrap();
+        cbpf::Invoke::with_data_area(v, cbpf::SliceDataArea::new(&[1, 2, 3, 4, 5])).run()
+    };
+
+    assert_eq!(run("ldabsh 1\nexit"), Ok(0x0203));
+    assert_eq!(run("r1 = 0\nldindw r1, 1\nexit"), Ok(0x0203_0405));
+    assert_eq!(run("r1 = 2\nldindw r1, 2\nexit"), Err(()));
+    // the offset, or its end, would overflow
+    assert_eq!(run("r1 = -1\nldindb r1, 2\nexit"), Err(()));
+    assert_eq!(run("r1 = -2\nldindb r1, 1\nexit"), Err(()));
+}
+
+#[test]
+fn jump_imm_sign_extended() {
+    // the path not taken reads r0 before it is written, so the verifier must agree with `Invoke`
+    let run = |setup: &str, jump: &str| {
+        let p = cbpf::asm::assemble(&format!("{}\n{} goto +1\nexit\nr0 = 1\nexit", setup, jump)).unwrap();
+        let v = cbpf::verifier::Env::default().verify(&p).unwrap();
+        cbpf::Invoke::new(v).run()
+    };
+
+    assert_eq!(run("r1 = -1", "if r1 == -1"), Ok(1));
+    assert_eq!(run("w1 = -1", "if w1 == -1"), Ok(1));
+    assert_eq!(run("w1 = -1", "if r1 != -1"), Ok(1));
+    assert_eq!(run("r1 = -2", "if r1 s< -1"), Ok(1));
+    assert_eq!(run("r1 = 5", "if r1 < -1"), Ok(1));
+}
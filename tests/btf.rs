@@ -0,0 +1,104 @@
+extern crate cbpf;
+
+use cbpf::btf::{Btf, BtfError, FuncInfo, Kind};
+use cbpf::disasm::Disasm;
+use cbpf::elf::Object;
+use cbpf::verifier::LogLevel;
+
+// built from tests/data/maps.ll and tests/data/lines.ll
+static MAPS_BPFEL: &[u8] = include_bytes!("data/maps.bpfel.o");
+static MAPS_BPFEB: &[u8] = include_bytes!("data/maps.bpfeb.o");
+static LINES_BPFEL: &[u8] = include_bytes!("data/lines.bpfel.o");
+
+#[test]
+fn types() {
+    let le = Object::parse(MAPS_BPFEL).unwrap();
+    let be = Object::parse(MAPS_BPFEB).unwrap();
+    let btf = le.btf().unwrap();
+    assert_eq!(Some(btf), be.btf());
+
+    let def = btf.find("bpf_map_def").unwrap();
+    assert_eq!(btf.size(def), Some(20));
+    match btf.get(def).unwrap().kind {
+        Kind::Struct { ref members, .. } => {
+            let names: Vec<_> = members.iter().map(|m| (&m.name[..], m.bit_offset)).collect();
+            assert_eq!(names, [("type", 0), ("key_size", 32), ("value_size", 64), ("max_entries", 96),
+                ("map_flags", 128)]);
+            assert_eq!(btf.get(members[0].ty).unwrap().name, "unsigned int");
+        },
+        ref k => panic!("unexpected {:?}", k),
+    }
+
+    // `count` is a global function taking a `void *`
+    let count = btf.find("count").unwrap();
+    let proto = match btf.get(count).unwrap().kind {
+        Kind::Func { proto, linkage: 1 } => proto,
+        ref k => panic!("unexpected {:?}", k),
+    };
+    match btf.get(proto).unwrap().kind {
+        Kind::FuncProto { ret, ref params } => {
+            assert_eq!(btf.get(ret).unwrap().name, "int");
+            assert_eq!(params.len(), 1);
+            assert_eq!(btf.get(params[0].ty).unwrap().kind, Kind::Ptr { ty: 0 });
+        },
+        ref k => panic!("unexpected {:?}", k),
+    }
+
+    assert_eq!(btf.get(0), None);
+    assert_eq!(btf.size(btf.find("hits").unwrap()), Some(40));
+}
+
+#[test]
+fn errors() {
+    assert_eq!(Btf::parse(b"\x00\x00\x01\x00"), Err(BtfError::BadMagic));
+    assert_eq!(Btf::parse(b"\x9f\xeb\x01\x00"), Err(BtfError::Truncated));
+    assert_eq!(Btf::parse(b"\x9f\xeb\x02\x00"), Err(BtfError::Malformed("unknown version")));
+
+    // a header with one type of kind 31, and an empty name
+    let mut data = vec![0x9f, 0xeb, 1, 0, 24, 0, 0, 0, 0, 0, 0, 0, 12, 0, 0, 0, 12, 0, 0, 0, 1, 0, 0, 0];
+    data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x1f, 0, 0, 0, 0, 0]);
+    assert_eq!(Btf::parse(&data), Err(BtfError::UnknownKind { id: 1, kind: 31 }));
+}
+
+#[test]
+fn lines() {
+    let obj = Object::parse(LINES_BPFEL).unwrap();
+    let btf = obj.btf().unwrap();
+    let p = obj.program("filter").unwrap();
+
+    // `classify` is appended after the 3 instructions of `filter`
+    assert_eq!(p.func_info(), [
+        FuncInfo { insn: 0, ty: btf.find("filter").unwrap() },
+        FuncInfo { insn: 3, ty: btf.find("classify").unwrap() },
+    ]);
+    let lines: Vec<_> = p.line_info().iter().map(|l| (l.insn, l.line_num, l.col)).collect();
+    assert_eq!(lines, [(0, 11, 9), (3, 1, 0), (6, 3, 10), (7, 4, 3), (9, 5, 2)]);
+    assert_eq!(p.line_info()[2].file, "./lines.c");
+    assert_eq!(p.line_info()[2].line, "\tif (len > 64)");
+
+    let text = format!("{}", Disasm::new(p.insts()).lines(p.line_info()));
+    assert!(text.ends_with("    ; static int classify(unsigned int len) @ ./lines.c:1
+    r1 <<= 32
+    r1 >>= 32
+    r2 = 65
+    ; if (len > 64) @ ./lines.c:3
+    if r2 > r1 goto L9
+    ; return 1; @ ./lines.c:4
+    r0 = 1
+    exit
+L9:
+    ; return 2; @ ./lines.c:5
+    r0 = 2
+    exit
+"), "{}", text);
+}
+
+#[test]
+fn log() {
+    let obj = Object::parse(MAPS_BPFEL).unwrap();
+    let p = obj.program("count").unwrap();
+    let mut log = String::new();
+    obj.env(p).verify_log(p.insts(), LogLevel::Verbose, &mut log).unwrap();
+    assert!(log.contains("; if (v) @ ./maps.c:19\n7: if r0 == 0 goto L13 ;"), "{}", log);
+    assert!(log.contains("; return 0; @ ./maps.c:22\n18: r0 = 0 ;"), "{}", log);
+}
@@ -0,0 +1,205 @@
+extern crate cbpf;
+
+use cbpf::asm::assemble;
+use cbpf::build::{alu, alu64, call, exit, jmp, jmp32, ld, ldx, st, stx};
+use cbpf::build::{AtomicSize, BuildError, Cond, Imm, Inst, LdSize, ProgramBuilder, Reg, Size, Swap};
+use cbpf::disasm::Disasm;
+
+fn raw(p: &[Inst]) -> Vec<u64> {
+    p.iter().map(|i| i.to_u64()).collect()
+}
+
+#[test]
+fn matches_asm() {
+    let mut p = vec![
+        alu64::mov(Reg::R0, Imm(1)),
+        alu64::mov(Reg::R0, Imm(-1)),
+        alu::mov(Reg::R1, Imm(2)),
+        alu64::mov(Reg::R1, Reg::R2),
+        alu64::add(Reg::R1, Reg::R2),
+        alu::sub(Reg::R3, Imm(7)),
+        alu64::mul(Reg::R4, Reg::R5),
+        alu64::div(Reg::R1, Imm(3)),
+        alu64::or(Reg::R1, Reg::R2),
+        alu64::and(Reg::R1, Imm(0xff)),
+        alu64::lsh(Reg::R1, Imm(4)),
+        alu64::rsh(Reg::R1, Reg::R3),
+        alu64::rem(Reg::R1, Imm(10)),
+        alu64::arsh(Reg::R1, Imm(3)),
+        alu64::xor(Reg::R1, Reg::R9),
+        alu64::neg(Reg::R1),
+        alu::neg(Reg::R1),
+        alu::be(Reg::R1, Swap::S16),
+        alu::le(Reg::R2, Swap::S32),
+    ];
+    p.extend_from_slice(&ld::imm64(Reg::R0, 0x1_2345_6789));
+    p.extend_from_slice(&[
+        ldx::mem(Size::W, Reg::R1, Reg::R10, -4),
+        ldx::mem(Size::DW, Reg::R1, Reg::R2, 8),
+        stx::mem(Size::W, Reg::R10, -4, Reg::R1),
+        st::mem(Size::H, Reg::R1, 2, Imm(-3)),
+        stx::xadd(AtomicSize::DW, Reg::R1, 0, Reg::R2),
+        stx::xadd(AtomicSize::W, Reg::R1, 4, Reg::R2),
+        ld::abs(LdSize::H, 12),
+        ld::ind(LdSize::B, Reg::R1, 3),
+        ld::imm(LdSize::W, Reg::R0, 0xdeadbeef),
+        jmp::jgt(Reg::R1, Imm(0x10), 2),
+        jmp::jslt(Reg::R1, Reg::R2, -1),
+        jmp::jne(Reg::R1, Imm(5), 1),
+        jmp::jsge(Reg::R3, Reg::R4, 0),
+        jmp::jle(Reg::R3, Imm(4), 0),
+        jmp::jset(Reg::R3, Imm(1), 0),
+        jmp::ja(1),
+        call(1),
+        exit(),
+    ]);
+
+    assert_eq!(raw(&p), assemble("
+        r0 = 1
+        r0 = -1
+        w1 = 2
+        r1 = r2
+        r1 += r2
+        w3 -= 7
+        r4 *= r5
+        r1 /= 3
+        r1 |= r2
+        r1 &= 0xff
+        r1 <<= 4
+        r1 >>= r3
+        r1 %= 10
+        r1 s>>= 3
+        r1 ^= r9
+        r1 = -r1
+        w1 = -w1
+        r1 = be16 r1
+        r2 = le32 r2
+        r0 = 0x123456789 ll
+        r1 = *(u32 *)(r10 - 4)
+        r1 = *(u64 *)(r2 + 8)
+        *(u32 *)(r10 - 4) = r1
+        *(u16 *)(r1 + 2) = -3
+        lock *(u64 *)(r1 + 0) += r2
+        lock *(u32 *)(r1 + 4) += r2
+        r0 = *(u16 *)skb[12]
+        r0 = *(u8 *)skb[r1 + 3]
+        ldw r0, 0xdeadbeef
+        if r1 > 0x10 goto +2
+        if r1 s< r2 goto -1
+        if r1 != 5 goto +1
+        if r3 s>= r4 goto +0
+        if r3 <= 4 goto +0
+        if r3 & 1 goto +0
+        goto +1
+        call 1
+        exit
+    ").unwrap());
+
+    for i in &p {
+        i.check().unwrap();
+    }
+}
+
+#[test]
+fn run() {
+    let p = raw(&[
+        ld::imm(LdSize::W, Reg::R0, 0x10),
+        jmp::jgt(Reg::R0, Imm(9), 1),
+        ld::imm(LdSize::W, Reg::R0, 2),
+        exit(),
+    ]);
+    let c = cbpf::Invoke::new(cbpf::verifier::Env::default().verify(&p).unwrap());
+    assert_eq!(c.run(), Ok(0x10));
+}
+
+#[test]
+fn jmp32() {
+    // expected encodings from `llvm-mc -triple bpfel -show-encoding`
+    let p = raw(&[
+        jmp32::jgt(Reg::R1, Imm(5), 2),
+        jmp32::jslt(Reg::R1, Reg::R2, -1),
+        jmp32::ja(-100000),
+    ]);
+    assert_eq!(p, vec![0x2601000200000005, 0xce21ffff00000000, 0x06000000fffe7960]);
+    assert_eq!(assemble("if w1 > 5 goto +2\nif w1 s< w2 goto -1\ngotol -100000").unwrap(), p);
+    assert_eq!(assemble("jgt32 r1, 5, +2\njslt32 r1, r2, -1\ngotol -100000").unwrap(), p);
+}
+
+#[test]
+fn labels() {
+    let mut b = ProgramBuilder::new();
+    let (top, accept, reject) = (b.label(), b.label(), b.label());
+    b.push(ld::abs(LdSize::H, 12))
+        .jump_if(Cond::Eq, Reg::R0, Imm(0x800), accept)
+        .jump(reject)
+        .bind(top)
+        .push(alu64::add(Reg::R1, Imm(1)))
+        .jump_if32(Cond::Slt, Reg::R1, Reg::R2, top)
+        .bind(accept)
+        .extend(ld::imm64(Reg::R0, 0xffff_ffff_ffff))
+        .push(exit())
+        .bind(reject)
+        .push(alu64::mov(Reg::R0, Imm(0)))
+        .push(exit());
+
+    assert_eq!(b.finish().unwrap(), assemble("
+        r0 = *(u16 *)skb[12]
+        if r0 == 0x800 goto accept
+        goto reject
+    top:
+        r1 += 1
+        if w1 s< w2 goto top
+    accept:
+        r0 = 0xffffffffffff ll
+        exit
+    reject:
+        r0 = 0
+        exit
+    ").unwrap());
+}
+
+#[test]
+fn long_jumps() {
+    let mut b = ProgramBuilder::new();
+    let (far, back) = (b.label(), b.label());
+    b.bind(back)
+        .jump_if(Cond::Ne, Reg::R1, Imm(0), far)
+        .jump(far)
+        .jump_if(Cond::Eq, Reg::R1, Imm(0), back);
+    for _ in 0..40000 {
+        b.push(alu64::mov(Reg::R0, Imm(0)));
+    }
+    b.bind(far)
+        .push(exit())
+        .jump(back);
+
+    let p = b.finish().unwrap();
+    assert_eq!(p.len(), 3 + 1 + 1 + 40000 + 1 + 1);
+    let d = Disasm::new(&p);
+    let l = 3 + 1 + 1 + 40000;
+    assert_eq!(format!("{}", d.at(0)), "if r1 != 0 goto L2");
+    assert_eq!(format!("{}", d.at(1)), "goto L3");
+    assert_eq!(format!("{}", d.at(2)), format!("gotol L{}", l));
+    assert_eq!(format!("{}", d.at(3)), format!("gotol L{}", l));
+    assert_eq!(format!("{}", d.at(4)), "if r1 == 0 goto L0");
+    assert_eq!(format!("{}", d.at(l + 1)), "gotol L0");
+
+    // and the listing assembles back to the same program
+    assert_eq!(assemble(&format!("{}", d)).unwrap(), p);
+}
+
+#[test]
+fn label_errors() {
+    let mut b = ProgramBuilder::new();
+    let l = b.label();
+    b.jump(l).push(exit());
+    assert_eq!(b.finish(), Err(BuildError::UndefinedLabel(l)));
+
+    b.bind(l);
+    assert_eq!(b.finish(), Err(BuildError::LabelOutOfRange(l)));
+
+    let mut b = ProgramBuilder::new();
+    let l = b.label();
+    b.bind(l).push(exit()).bind(l);
+    assert_eq!(b.finish(), Err(BuildError::DuplicateLabel(l)));
+}
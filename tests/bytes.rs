@@ -0,0 +1,49 @@
+extern crate cbpf;
+
+use cbpf::asm::assemble;
+use cbpf::bytes::{load, load_raw, store, store_raw, LoadError};
+use cbpf::{Endian, Inst};
+
+#[test]
+fn encodings() {
+    let p = assemble("
+        r1 = r2
+        if r1 > 3 goto +1
+        r0 = 0x1122334455667788 ll
+        exit
+    ").unwrap();
+
+    let le = [
+        0xbf, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x25, 0x01, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x18, 0x00, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55,
+        0x00, 0x00, 0x00, 0x00, 0x44, 0x33, 0x22, 0x11,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let be = [
+        0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x25, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+        0x18, 0x00, 0x00, 0x00, 0x55, 0x66, 0x77, 0x88,
+        0x00, 0x00, 0x00, 0x00, 0x11, 0x22, 0x33, 0x44,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    assert_eq!(store_raw(&p, Endian::Little), le.to_vec());
+    assert_eq!(store_raw(&p, Endian::Big), be.to_vec());
+    assert_eq!(load_raw(&le, Endian::Little), Ok(p.clone()));
+    assert_eq!(load_raw(&be, Endian::Big), Ok(p.clone()));
+
+    let insts = load(&be, Endian::Big).unwrap();
+    assert_eq!((insts[0].dst(), insts[0].src()), (1, 2));
+    assert_eq!(insts[1].off16(), 1);
+    assert_eq!(store(&insts, Endian::Little), le.to_vec());
+    assert_eq!(Inst::from_bytes([0xbf, 0x21, 0, 0, 0, 0, 0, 0], Endian::Little).unwrap().to_u64(), p[0]);
+}
+
+#[test]
+fn errors() {
+    assert_eq!(load(&[0x95, 0, 0, 0], Endian::Little), Err(LoadError::Length { len: 4 }));
+    assert_eq!(format!("{}", LoadError::Length { len: 12 }),
+        "program of 12 bytes is not a whole number of instructions");
+    assert_eq!(load(&[], Endian::Big), Ok(vec![]));
+}
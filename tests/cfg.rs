@@ -0,0 +1,117 @@
+extern crate cbpf;
+
+use cbpf::asm::assemble;
+use cbpf::cfg::{Cfg, CfgErrorKind, Edge, EdgeKind};
+
+#[test]
+fn diamond_and_loop() {
+    let p = assemble("
+        r0 = 0
+        r1 = 10
+    top:
+        if r1 == 0 goto done
+        r0 += r1
+        r2 = 0x100000000 ll
+        r1 -= 1
+        goto top
+    done:
+        if r0 > 5 goto big
+        r0 = 0
+        exit
+    big:
+        r0 = 1
+        exit
+    ").unwrap();
+
+    let cfg = Cfg::new(&p).unwrap();
+    let spans: Vec<_> = cfg.blocks().iter().map(|b| (b.start(), b.end(), b.last())).collect();
+    assert_eq!(spans, vec![(0, 2, 1), (2, 3, 2), (3, 8, 7), (8, 9, 8), (9, 11, 10), (11, 13, 12)]);
+
+    let succs: Vec<_> = cfg.blocks().iter().map(|b| b.succs().to_vec()).collect();
+    let e = |to, kind| Edge { to, kind };
+    assert_eq!(succs, vec![
+        vec![e(1, EdgeKind::Fallthrough)],
+        vec![e(2, EdgeKind::Fallthrough), e(3, EdgeKind::Taken)],
+        vec![e(1, EdgeKind::Goto)],
+        vec![e(4, EdgeKind::Fallthrough), e(5, EdgeKind::Taken)],
+        vec![],
+        vec![],
+    ]);
+
+    let preds: Vec<_> = cfg.blocks().iter().map(|b| b.preds().to_vec()).collect();
+    assert_eq!(preds, vec![vec![], vec![0, 2], vec![1], vec![1], vec![3], vec![3]]);
+
+    assert_eq!(cfg.block_at(0), Some(0));
+    assert_eq!(cfg.block_at(5), Some(2));
+    assert_eq!(cfg.block_at(12), Some(5));
+    assert_eq!(cfg.block_at(13), None);
+}
+
+#[test]
+fn dot() {
+    let p = assemble("
+        if r1 == 0 goto +1
+        r0 = 1
+        exit
+    ").unwrap();
+    let cfg = Cfg::new(&p).unwrap();
+    assert_eq!(format!("{}", cfg.dot(&p)), r#"digraph cfg {
+    node [shape=box, fontname=monospace];
+    b0 [label="0: if r1 == 0 goto L2\l"];
+    b1 [label="1: r0 = 1\l"];
+    b2 [label="2: exit\l"];
+    b0 -> b1 [color=red];
+    b0 -> b2 [color=green];
+    b1 -> b2;
+}
+"#);
+}
+
+#[test]
+fn calls() {
+    // a call ends its block, with an edge to the function as well as to the next instruction
+    let p = assemble("
+        call f
+        exit
+    f:
+        r0 = 0
+        exit
+    ").unwrap();
+    let cfg = Cfg::new(&p).unwrap();
+    let succs: Vec<_> = cfg.blocks().iter().map(|b| b.succs().to_vec()).collect();
+    let e = |to, kind| Edge { to, kind };
+    assert_eq!(succs, vec![vec![e(1, EdgeKind::Fallthrough), e(2, EdgeKind::Call)], vec![], vec![]]);
+    assert_eq!(format!("{}", cfg.dot(&p)), r#"digraph cfg {
+    node [shape=box, fontname=monospace];
+    b0 [label="0: call L2\l"];
+    b1 [label="1: exit\l"];
+    b2 [label="2: r0 = 0\l3: exit\l"];
+    b0 -> b1;
+    b0 -> b2 [style=dashed];
+}
+"#);
+
+    let kind = |src: &str| Cfg::new(&assemble(src).unwrap()).unwrap_err().kind().clone();
+    assert_eq!(kind("call +2\nexit"), CfgErrorKind::JumpOutOfRange);
+    assert_eq!(kind("call +2\nexit\nr0 = 1 ll\nexit"), CfgErrorKind::JumpIntoLdImm64);
+}
+
+#[test]
+fn errors() {
+    let kind = |src: &str| {
+        let e = Cfg::new(&assemble(src).unwrap()).unwrap_err();
+        (e.inst_idx(), e.kind().clone())
+    };
+
+    assert_eq!(kind("r0 = 0\ngoto +5\nexit"), (1, CfgErrorKind::JumpOutOfRange));
+    assert_eq!(kind("r0 = 0\nif r0 > 1 goto -3\nexit"), (1, CfgErrorKind::JumpOutOfRange));
+    assert_eq!(kind("goto +1\nr0 = 1 ll\nexit"), (0, CfgErrorKind::JumpIntoLdImm64));
+    assert_eq!(kind("r0 = 0\nif r0 > 1 goto -2"), (1, CfgErrorKind::FallsOffEnd));
+    assert_eq!(kind("call 1"), (0, CfgErrorKind::FallsOffEnd));
+
+    let e = Cfg::new(&[0xb70b000000000001, 0x9500000000000000]).unwrap_err();
+    assert_eq!(e.inst_idx(), 0);
+    assert_eq!(format!("{}", e), "instruction 0: invalid instruction: register out of range");
+
+    assert_eq!(Cfg::new(&[]).unwrap_err().kind(), &CfgErrorKind::Empty);
+}
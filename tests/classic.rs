@@ -0,0 +1,174 @@
+extern crate cbpf;
+
+use cbpf::asm::AsmErrorKind;
+use cbpf::classic::{assemble, parse_c_array, parse_ddd, CArray, Ddd, Listing, SockFilter, Syntax};
+
+// `tcpdump -d tcp port 22`
+const TCP_22: &str = "(000) ldh      [12]
+(001) jeq      #0x86dd          jt 2\tjf 8
+(002) ldb      [20]
+(003) jeq      #0x6             jt 4\tjf 19
+(004) ldh      [54]
+(005) jeq      #0x16            jt 18\tjf 6
+(006) ldh      [56]
+(007) jeq      #0x16            jt 18\tjf 19
+(008) jeq      #0x800           jt 9\tjf 19
+(009) ldb      [23]
+(010) jeq      #0x6             jt 11\tjf 19
+(011) ldh      [20]
+(012) jset     #0x1fff          jt 19\tjf 13
+(013) ldxb     4*([14]&0xf)
+(014) ldh      [x + 14]
+(015) jeq      #0x16            jt 18\tjf 16
+(016) ldh      [x + 16]
+(017) jeq      #0x16            jt 18\tjf 19
+(018) ret      #262144
+(019) ret      #0
+";
+
+// `tcpdump -dd ip`
+const IP_DD: &str = "{ 0x28, 0, 0, 0x0000000c },
+{ 0x15, 0, 1, 0x00000800 },
+{ 0x6, 0, 0, 0x00040000 },
+{ 0x6, 0, 0, 0x00000000 },
+";
+
+// `tcpdump -ddd ip`
+const IP_DDD: &str = "4
+40 0 0 12
+21 0 1 2048
+6 0 0 262144
+6 0 0 0
+";
+
+fn ip() -> Vec<SockFilter> {
+    vec![
+        SockFilter::new(0x28, 0, 0, 12),
+        SockFilter::new(0x15, 0, 1, 0x800),
+        SockFilter::new(0x06, 0, 0, 0x40000),
+        SockFilter::new(0x06, 0, 0, 0),
+    ]
+}
+
+#[test]
+fn tcpdump_listing() {
+    let p = assemble(TCP_22).unwrap();
+    assert_eq!(p.len(), 20);
+    assert_eq!(p[1], SockFilter::new(0x15, 0, 6, 0x86dd));
+    assert_eq!(p[13], SockFilter::new(0xb1, 0, 0, 14));
+    assert_eq!(p[14], SockFilter::new(0x48, 0, 0, 14));
+    assert_eq!(format!("{}", Listing::new(&p)), TCP_22);
+}
+
+#[test]
+fn bpf_asm() {
+    // from the kernel's filter documentation
+    let p = assemble("
+        ; only accept ARP
+        ldh [12]
+        jne #0x806, drop
+        ret #-1
+    drop: ret #0
+    ").unwrap();
+    assert_eq!(p, vec![
+        SockFilter::new(0x28, 0, 0, 12),
+        SockFilter::new(0x15, 0, 1, 0x806),
+        SockFilter::new(0x06, 0, 0, 0xffffffff),
+        SockFilter::new(0x06, 0, 0, 0),
+    ]);
+
+    let p = assemble("
+        ld #len
+        ldh #proto
+        ldb [x + 2]
+        ld M[3]
+        st M[15]
+        ldx #4
+        ldxb 4*([14]&0xf)
+        add x
+        and #0xff
+        neg
+        tax
+        jgt x, yes, no
+    yes: ret a
+    no: ret %x
+    ").unwrap();
+    assert_eq!(p, vec![
+        SockFilter::new(0x80, 0, 0, 0),
+        SockFilter::new(0x28, 0, 0, 0xfffff000),
+        SockFilter::new(0x50, 0, 0, 2),
+        SockFilter::new(0x60, 0, 0, 3),
+        SockFilter::new(0x02, 0, 0, 15),
+        SockFilter::new(0x01, 0, 0, 4),
+        SockFilter::new(0xb1, 0, 0, 14),
+        SockFilter::new(0x0c, 0, 0, 0),
+        SockFilter::new(0x54, 0, 0, 0xff),
+        SockFilter::new(0x84, 0, 0, 0),
+        SockFilter::new(0x07, 0, 0, 0),
+        SockFilter::new(0x2d, 0, 1, 0),
+        SockFilter::new(0x16, 0, 0, 0),
+        SockFilter::new(0x0e, 0, 0, 0),
+    ]);
+
+    // and back again
+    let text = format!("{}", Listing::new(&p).syntax(Syntax::BpfAsm));
+    assert_eq!(assemble(&text).unwrap(), p);
+}
+
+#[test]
+fn bpf_asm_listing() {
+    let p = assemble(TCP_22).unwrap();
+    let text = format!("{}", Listing::new(&p).syntax(Syntax::BpfAsm));
+    assert!(text.starts_with("    ldh [12]\n    jneq #0x86dd, l8\n    ldb [20]\n    jneq #0x6, l19\n"));
+    assert!(text.contains("    jeq #0x16, l18, l19\nl8:\n"));
+    assert!(text.contains("    jset #0x1fff, l19\n"));
+    assert_eq!(assemble(&text).unwrap(), p);
+
+    let text = format!("{}", Listing::new(&ip()).syntax(Syntax::BpfAsm));
+    assert_eq!(text, "    ldh [12]\n    jneq #0x800, l3\n    ret #262144\nl3:\n    ret #0\n");
+}
+
+#[test]
+fn dd_and_ddd() {
+    assert_eq!(parse_c_array(IP_DD).unwrap(), ip());
+    assert_eq!(format!("{}", CArray(&ip())), IP_DD);
+
+    let wrapped = format!("struct sock_filter code[] = {{\n{}}};\n", IP_DD);
+    assert_eq!(parse_c_array(&wrapped).unwrap(), ip());
+
+    assert_eq!(parse_ddd(IP_DDD).unwrap(), ip());
+    assert_eq!(format!("{}", Ddd(&ip())), IP_DDD);
+
+    // as output by `bpf_asm`
+    assert_eq!(parse_ddd("4,40 0 0 12,21 0 1 2048,6 0 0 262144,6 0 0 0,\n").unwrap(), ip());
+}
+
+#[test]
+fn errors() {
+    let e = assemble("ldh [12]\njeq #1, nowhere").unwrap_err();
+    assert_eq!((e.line(), e.col(), e.kind()), (2, 9, &AsmErrorKind::UndefinedLabel));
+
+    let e = assemble("a: ret #0\njeq #1, a").unwrap_err();
+    assert_eq!((e.line(), e.col(), e.kind()), (2, 9, &AsmErrorKind::JumpOutOfRange));
+
+    let e = assemble("ld M[16]").unwrap_err();
+    assert_eq!((e.line(), e.col(), e.kind()), (1, 4, &AsmErrorKind::OutOfRange));
+
+    let e = assemble("(000) ret #0\n(002) ret #0").unwrap_err();
+    assert_eq!((e.line(), e.col()), (2, 2));
+
+    let e = assemble("st #1").unwrap_err();
+    assert_eq!((e.line(), e.col()), (1, 1));
+
+    let e = assemble("mov a, x").unwrap_err();
+    assert_eq!(e.kind(), &AsmErrorKind::UnknownMnemonic);
+
+    let e = parse_ddd("3\n6 0 0 0\n6 0 0 1\n").unwrap_err();
+    assert_eq!((e.line(), e.col()), (3, 1));
+
+    let e = parse_ddd("1\n6 0 256 0\n").unwrap_err();
+    assert_eq!((e.line(), e.col(), e.kind()), (2, 5, &AsmErrorKind::OutOfRange));
+
+    let e = parse_c_array("{ 0x6, 0, 0 },").unwrap_err();
+    assert_eq!((e.line(), e.col()), (1, 1));
+}
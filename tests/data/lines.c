@@ -0,0 +1,12 @@
+static int classify(unsigned int len)
+{
+	if (len > 64)
+		return 1;
+	return 2;
+}
+
+int filter(unsigned int *ctx)
+{
+	unsigned int len = *ctx;
+	return classify(len);
+}
@@ -0,0 +1,42 @@
+; Built from lines.c (by hand, as there is no clang here) with
+; `llc -march=bpfel -filetype=obj lines.ll -o lines.bpfel.o`
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+define internal i32 @classify(i32 %len) noinline nounwind !dbg !10 {
+  %c = icmp ugt i32 %len, 64, !dbg !20
+  br i1 %c, label %big, label %small, !dbg !20
+big:
+  ret i32 1, !dbg !21
+small:
+  ret i32 2, !dbg !22
+}
+
+define dso_local i32 @filter(ptr %ctx) nounwind section "socket" !dbg !30 {
+  %len = load i32, ptr %ctx, align 4, !dbg !40
+  %r = call i32 @classify(i32 %len), !dbg !41
+  ret i32 %r, !dbg !41
+}
+
+!llvm.dbg.cu = !{!2}
+!llvm.module.flags = !{!5, !6}
+
+!2 = distinct !DICompileUnit(language: DW_LANG_C99, file: !3, producer: "hand written", isOptimized: true, runtimeVersion: 0, emissionKind: FullDebug)
+!3 = !DIFile(filename: "lines.c", directory: ".")
+!5 = !{i32 7, !"Dwarf Version", i32 5}
+!6 = !{i32 2, !"Debug Info Version", i32 3}
+!7 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!8 = !DIBasicType(name: "unsigned int", size: 32, encoding: DW_ATE_unsigned)
+!9 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !8, size: 64)
+!10 = distinct !DISubprogram(name: "classify", scope: !3, file: !3, line: 1, type: !11, scopeLine: 2, flags: DIFlagPrototyped, spFlags: DISPFlagLocalToUnit | DISPFlagDefinition | DISPFlagOptimized, unit: !2)
+!11 = !DISubroutineType(types: !12)
+!12 = !{!7, !8}
+!20 = !DILocation(line: 3, column: 10, scope: !10)
+!21 = !DILocation(line: 4, column: 3, scope: !10)
+!22 = !DILocation(line: 5, column: 2, scope: !10)
+!30 = distinct !DISubprogram(name: "filter", scope: !3, file: !3, line: 8, type: !31, scopeLine: 9, flags: DIFlagPrototyped, spFlags: DISPFlagDefinition | DISPFlagOptimized, unit: !2)
+!31 = !DISubroutineType(types: !32)
+!32 = !{!7, !9}
+!40 = !DILocation(line: 10, column: 21, scope: !30)
+!41 = !DILocation(line: 11, column: 9, scope: !30)
@@ -0,0 +1,23 @@
+struct bpf_map_def {
+	unsigned int type, key_size, value_size, max_entries, map_flags;
+};
+
+struct bpf_map_def counts __attribute__((section("maps"))) = { 1, 4, 8, 16, 0 };
+
+struct {
+	int (*type)[2];
+	int (*max_entries)[64];
+	unsigned int *key;
+	unsigned long long *value;
+	int (*map_flags)[1];
+} hits __attribute__((section(".maps")));
+
+int count(void *ctx)
+{
+	unsigned int key = 0;
+	unsigned long long *v = lookup(&hits, &key);
+	if (v)
+		return *v != 0;
+	delete(&counts, &key);
+	return 0;
+}
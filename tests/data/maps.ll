@@ -0,0 +1,77 @@
+; Built from maps.c (by hand, as there is no clang here) with
+; `llc -march=bpfel -filetype=obj maps.ll -o maps.bpfel.o`, and likewise for bpfeb
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+%struct.bpf_map_def = type { i32, i32, i32, i32, i32 }
+%struct.anon = type { ptr, ptr, ptr, ptr, ptr }
+
+@counts = dso_local global %struct.bpf_map_def { i32 1, i32 4, i32 8, i32 16, i32 0 }, section "maps", align 4, !dbg !0
+@hits = dso_local global %struct.anon zeroinitializer, section ".maps", align 8, !dbg !5
+
+define dso_local i32 @count(ptr %ctx) nounwind section "socket" !dbg !50 {
+  %key = alloca i32, align 4
+  store i32 0, ptr %key, align 4, !dbg !60
+  %fn = inttoptr i64 1 to ptr
+  %v = call ptr %fn(ptr @hits, ptr %key), !dbg !61
+  %isnull = icmp eq ptr %v, null, !dbg !62
+  br i1 %isnull, label %miss, label %hit, !dbg !62
+hit:
+  %x = load i64, ptr %v, align 8, !dbg !63
+  %nz = icmp ne i64 %x, 0, !dbg !63
+  %t = zext i1 %nz to i32, !dbg !63
+  ret i32 %t, !dbg !63
+miss:
+  %del = inttoptr i64 3 to ptr
+  %w = call i64 %del(ptr @counts, ptr %key), !dbg !64
+  ret i32 0, !dbg !65
+}
+
+!llvm.dbg.cu = !{!2}
+!llvm.module.flags = !{!40, !41}
+
+!0 = !DIGlobalVariableExpression(var: !1, expr: !DIExpression())
+!1 = distinct !DIGlobalVariable(name: "counts", scope: !2, file: !3, line: 5, type: !10, isLocal: false, isDefinition: true)
+!2 = distinct !DICompileUnit(language: DW_LANG_C99, file: !3, producer: "hand written", isOptimized: true, runtimeVersion: 0, emissionKind: FullDebug, globals: !4)
+!3 = !DIFile(filename: "maps.c", directory: ".")
+!4 = !{!0, !5}
+!5 = !DIGlobalVariableExpression(var: !6, expr: !DIExpression())
+!6 = distinct !DIGlobalVariable(name: "hits", scope: !2, file: !3, line: 13, type: !20, isLocal: false, isDefinition: true)
+!10 = distinct !DICompositeType(tag: DW_TAG_structure_type, name: "bpf_map_def", file: !3, line: 1, size: 160, elements: !11)
+!11 = !{!12, !13, !14, !15, !16}
+!12 = !DIDerivedType(tag: DW_TAG_member, name: "type", scope: !10, file: !3, line: 2, baseType: !17, size: 32)
+!13 = !DIDerivedType(tag: DW_TAG_member, name: "key_size", scope: !10, file: !3, line: 2, baseType: !17, size: 32, offset: 32)
+!14 = !DIDerivedType(tag: DW_TAG_member, name: "value_size", scope: !10, file: !3, line: 2, baseType: !17, size: 32, offset: 64)
+!15 = !DIDerivedType(tag: DW_TAG_member, name: "max_entries", scope: !10, file: !3, line: 2, baseType: !17, size: 32, offset: 96)
+!16 = !DIDerivedType(tag: DW_TAG_member, name: "map_flags", scope: !10, file: !3, line: 2, baseType: !17, size: 32, offset: 128)
+!17 = !DIBasicType(name: "unsigned int", size: 32, encoding: DW_ATE_unsigned)
+!18 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!19 = !DIBasicType(name: "unsigned long long", size: 64, encoding: DW_ATE_unsigned)
+!20 = distinct !DICompositeType(tag: DW_TAG_structure_type, file: !3, line: 7, size: 320, elements: !21)
+!21 = !{!22, !23, !24, !25, !26}
+!22 = !DIDerivedType(tag: DW_TAG_member, name: "type", scope: !20, file: !3, line: 8, baseType: !30, size: 64)
+!23 = !DIDerivedType(tag: DW_TAG_member, name: "max_entries", scope: !20, file: !3, line: 9, baseType: !32, size: 64, offset: 64)
+!24 = !DIDerivedType(tag: DW_TAG_member, name: "key", scope: !20, file: !3, line: 10, baseType: !36, size: 64, offset: 128)
+!25 = !DIDerivedType(tag: DW_TAG_member, name: "value", scope: !20, file: !3, line: 11, baseType: !37, size: 64, offset: 192)
+!26 = !DIDerivedType(tag: DW_TAG_member, name: "map_flags", scope: !20, file: !3, line: 12, baseType: !38, size: 64, offset: 256)
+!30 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !31, size: 64)
+!31 = !DICompositeType(tag: DW_TAG_array_type, baseType: !18, size: 64, elements: !{!DISubrange(count: 2)})
+!32 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !33, size: 64)
+!33 = !DICompositeType(tag: DW_TAG_array_type, baseType: !18, size: 2048, elements: !{!DISubrange(count: 64)})
+!36 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !17, size: 64)
+!37 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !19, size: 64)
+!38 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !39, size: 64)
+!39 = !DICompositeType(tag: DW_TAG_array_type, baseType: !18, size: 32, elements: !{!DISubrange(count: 1)})
+!40 = !{i32 7, !"Dwarf Version", i32 5}
+!41 = !{i32 2, !"Debug Info Version", i32 3}
+!50 = distinct !DISubprogram(name: "count", scope: !3, file: !3, line: 15, type: !51, scopeLine: 16, flags: DIFlagPrototyped, spFlags: DISPFlagDefinition | DISPFlagOptimized, unit: !2)
+!51 = !DISubroutineType(types: !52)
+!52 = !{!18, !53}
+!53 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: null, size: 64)
+!60 = !DILocation(line: 17, column: 15, scope: !50)
+!61 = !DILocation(line: 18, column: 30, scope: !50)
+!62 = !DILocation(line: 19, column: 6, scope: !50)
+!63 = !DILocation(line: 20, column: 10, scope: !50)
+!64 = !DILocation(line: 21, column: 2, scope: !50)
+!65 = !DILocation(line: 22, column: 2, scope: !50)
@@ -0,0 +1,47 @@
+; Built with `llc -march=bpfel -filetype=obj prog.ll -o prog.bpfel.o`, and likewise for bpfeb
+
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpf"
+
+%struct.bpf_map_def = type { i32, i32, i32, i32, i32 }
+
+@counts = dso_local global %struct.bpf_map_def { i32 2, i32 4, i32 8, i32 16, i32 0 }, section "maps", align 4
+@_license = dso_local global [4 x i8] c"GPL\00", section "license", align 1
+
+define internal i32 @classify(i32 %x) noinline nounwind {
+  %c = icmp ugt i32 %x, 3
+  %r = select i1 %c, i32 1, i32 2
+  ret i32 %r
+}
+
+define dso_local i32 @unused(i32 %x) noinline nounwind {
+  %r = add i32 %x, 7
+  ret i32 %r
+}
+
+define internal i32 @twice(i32 %x) noinline nounwind {
+  %a = call i32 @classify(i32 %x)
+  %b = shl i32 %a, 1
+  ret i32 %b
+}
+
+define dso_local i32 @filter(ptr %ctx) nounwind section "socket" {
+  %l = load i32, ptr %ctx, align 4
+  ret i32 %l
+}
+
+define dso_local i32 @prog(ptr %ctx) nounwind section "xdp" {
+  %key = alloca i32, align 4
+  store i32 0, ptr %key, align 4
+  %fn = inttoptr i64 1 to ptr
+  %v = call ptr %fn(ptr @counts, ptr %key)
+  %l = load i32, ptr %ctx, align 4
+  %r = call i32 @classify(i32 %l)
+  ret i32 %r
+}
+
+define dso_local i32 @ingress(ptr %ctx) nounwind section "classifier/ingress" {
+  %l = load i32, ptr %ctx, align 4
+  %r = call i32 @twice(i32 %l)
+  ret i32 %r
+}
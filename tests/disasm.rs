@@ -0,0 +1,110 @@
+extern crate cbpf;
+
+use cbpf::asm::assemble;
+use cbpf::disasm::Disasm;
+use cbpf::Inst;
+
+const SRC: &str = "
+    r0 = *(u16 *)skb[12]
+    if r0 == 0x800 goto L4
+L2:
+    r0 = 0
+    exit
+L4:
+    r1 = 0x123456789 ll
+    r1 &= 0xff00
+    *(u32 *)(r10 - 4) = r1
+    r2 = *(u32 *)(r10 - 4)
+    lock *(u64 *)(r10 - 8) += r2
+    w3 = -w3
+    r2 = be16 r2
+    if r1 s> -5 goto L2
+    call 1
+    r0 = -1
+    exit
+";
+
+#[test]
+fn listing() {
+    let p = assemble(SRC).unwrap();
+    assert_eq!(format!("{}", Disasm::new(&p)), "    r0 = *(u16 *)skb[12]
+    if r0 == 0x800 goto L4
+L2:
+    r0 = 0
+    exit
+L4:
+    r1 = 0x123456789 ll
+    r1 &= 0xff00
+    *(u32 *)(r10 - 4) = r1
+    r2 = *(u32 *)(r10 - 4)
+    lock *(u64 *)(r10 - 8) += r2
+    w3 = -w3
+    r2 = be16 r2
+    if r1 s> -5 goto L2
+    call 1
+    r0 = -1
+    exit
+");
+    assert_eq!(format!("{}", Disasm::new(&p).at(1)), "if r0 == 0x800 goto L4");
+}
+
+#[test]
+fn round_trip() {
+    let p = assemble(SRC).unwrap();
+    let text = format!("{}", Disasm::new(&p));
+    assert_eq!(assemble(&text).unwrap(), p);
+}
+
+#[test]
+fn calls() {
+    // calls to BPF functions have labels like jumps, or an offset when outside the program
+    let p = assemble("call +1\nexit\ncall 5\ncall -5\nexit").unwrap();
+    assert_eq!(p[0], 0x8510000000000001);
+    assert_eq!(p[3], 0x85100000fffffffb);
+    let text = format!("{}", Disasm::new(&p));
+    assert_eq!(text, "    call L2\n    exit\nL2:\n    call 5\n    call -5\n    exit\n");
+    assert_eq!(assemble(&text).unwrap(), p);
+    assert_eq!(format!("{}", Inst::from_u64(p[0]).unwrap()), "call +1");
+}
+
+#[test]
+fn maps() {
+    let p = assemble("r1 = map[3] ll\nr2 = map_value[0] + 16 ll\nexit").unwrap();
+    assert_eq!(p, [0x1811000000000003, 0, 0x1822000000000000, 0x0000000000000010, 0x9500000000000000]);
+    let text = format!("{}", Disasm::new(&p));
+    assert_eq!(text, "    r1 = map[3] ll\n    r2 = map_value[0] + 16 ll\n    exit\n");
+    assert_eq!(assemble(&text).unwrap(), p);
+    assert_eq!(format!("{}", Inst::from_u64(p[2]).unwrap()), "r2 = map_value[0] + ? ll");
+
+    assert!(assemble("w1 = map[0] ll").is_err());
+    assert!(assemble("r1 = map[0]").is_err());
+    assert!(assemble("r1 = map_value[0] ll").is_err());
+}
+
+#[test]
+fn single_inst() {
+    let show = |v: u64| format!("{}", Inst::from_u64(v).unwrap());
+    assert_eq!(show(0x0500fffc00000000), "goto -4");
+    assert_eq!(show(0x2501000200000010), "if r1 > 16 goto +2");
+    assert_eq!(show(0x00000000deadbeef), "ldw r0, 0xdeadbeef");
+    assert_eq!(show(0x1800000023456789), "r0 = 0x????????23456789 ll");
+    assert_eq!(show(0x5010000000000003), "r0 = *(u8 *)skb[r1 + 3]");
+    assert_eq!(show(0x3001000000000003), "r1 = *(u8 *)skb[3]");
+}
+
+#[test]
+fn invalid() {
+    let show = |v: u64| format!("{}", Inst::from_u64(v).unwrap());
+    // dst register out of range
+    assert!(show(0xb70b000000000001).starts_with("<invalid 0xb70b000000000001: "));
+    // exit with a non-zero immediate
+    assert!(show(0x9500000000000001).starts_with("<invalid "));
+
+    // ld_imm64 missing its second half
+    let p = [0x1800000000000001];
+    assert!(format!("{}", Disasm::new(&p)).starts_with("    <invalid 0x1800000000000001: "));
+
+    // jump out of the program is shown as an offset
+    let p = [0x0500000500000000, 0x9500000000000000];
+    assert_eq!(format!("{}", Disasm::new(&p)), "    goto +5\n    exit\n");
+}
@@ -0,0 +1,136 @@
+extern crate cbpf;
+
+use cbpf::elf::{ElfError, Object};
+use cbpf::profile::Profile;
+use cbpf::verifier::{MapDef, PrgmVerifyErrorKind};
+use cbpf::{Endian, Inst, Invoke};
+
+// built from tests/data/prog.ll
+static BPFEL: &[u8] = include_bytes!("data/prog.bpfel.o");
+static BPFEB: &[u8] = include_bytes!("data/prog.bpfeb.o");
+static MAPS_BPFEL: &[u8] = include_bytes!("data/maps.bpfel.o");
+static MAPS_BPFEB: &[u8] = include_bytes!("data/maps.bpfeb.o");
+
+fn inst(raw: u64) -> Inst {
+    Inst::from_u64(raw).unwrap()
+}
+
+#[test]
+fn programs() {
+    for &(data, endian) in &[(BPFEL, Endian::Little), (BPFEB, Endian::Big)] {
+        let obj = Object::parse(data).unwrap();
+        assert_eq!(obj.endian(), endian);
+
+        let names: Vec<_> = obj.programs().iter().map(|p| (p.section(), p.name())).collect();
+        assert_eq!(names, [("socket", "filter"), ("xdp", "prog"), ("classifier/ingress", "ingress")]);
+        assert_eq!(obj.maps().iter().map(|m| &m.name[..]).collect::<Vec<_>>(), ["counts"]);
+
+        // the `ld_imm64` of `counts` becomes a load of map 0, and `classify` is appended
+        let xdp = obj.program("xdp").unwrap();
+        assert_eq!(xdp.insts().len(), 11 + 6);
+        let ld = inst(xdp.insts()[5]);
+        assert_eq!((ld.src(), ld.dst(), ld.imm32()), (1, 1, 0));
+        let call = inst(xdp.insts()[9]);
+        assert_eq!((call.src(), call.imm32() as i32), (1, 1));
+        assert_eq!(xdp.insts()[11..], obj.program("ingress").unwrap().insts()[6..]);
+
+        // `ingress` calls `twice`, which calls `classify` without a relocation. `unused` is left out.
+        let ingress = obj.program("classifier/ingress").unwrap();
+        assert_eq!(ingress.insts().len(), 3 + 3 + 6);
+        assert_eq!(inst(ingress.insts()[1]).imm32() as i32, 1);
+        assert_eq!(inst(ingress.insts()[3]).imm32() as i32, 2);
+        assert_eq!(ingress.profile(), None);
+    }
+
+    let le = Object::parse(BPFEL).unwrap();
+    let be = Object::parse(BPFEB).unwrap();
+    assert_eq!(le.programs(), be.programs());
+}
+
+#[test]
+fn verify() {
+    let obj = Object::parse(BPFEL).unwrap();
+
+    let filter = obj.program("filter").unwrap();
+    let v = obj.env(filter).verify(filter.insts()).unwrap();
+    assert_eq!(v.insts().len(), 2);
+
+    // calls to functions are linked and followed. `prog` passes `classify` the packet pointer
+    // xdp's context starts with, which it can't shift.
+    let xdp = obj.program("prog").unwrap();
+    let e = obj.env(xdp).verify(xdp.insts()).unwrap_err();
+    assert_eq!(e.inst_idx(), 11);
+    assert_eq!(*e.kind(), PrgmVerifyErrorKind::InvalidPointerArithmetic("only add and sub may be used on pointers"));
+
+    // `classifier` has no profile, but reads `len` as a socket filter would
+    let ingress = obj.program("ingress").unwrap();
+    let v = obj.env(ingress).profile(Profile::socket_filter()).verify(ingress.insts()).unwrap();
+    assert_eq!(v.max_call_depth(), 2);
+}
+
+#[test]
+fn errors() {
+    assert_eq!(Object::parse(b"\x7fELF"), Err(ElfError::NotBpf));
+    assert_eq!(Object::parse(&BPFEL[..0x30]), Err(ElfError::Truncated));
+
+    // section headers at the end of the address space
+    let mut data = BPFEL[..64].to_vec();
+    data[0x28..0x30].copy_from_slice(&(u64::MAX - 10).to_le_bytes());
+    data[0x3e..0x40].copy_from_slice(&[0, 0]);
+    assert_eq!(Object::parse(&data), Err(ElfError::Truncated));
+
+    // a non-BPF machine
+    let mut data = BPFEL.to_vec();
+    data[0x12] = 62;
+    assert_eq!(Object::parse(&data), Err(ElfError::NotBpf));
+
+    // the call relocation of `xdp` against a symbol outside `.text`
+    let mut data = BPFEL.to_vec();
+    let rel = 0x258 + 16 + 8 + 4;
+    data[rel] = 9;
+    match Object::parse(&data) {
+        Err(ElfError::Relocation { ref section, offset: 0x48, .. }) if section == "xdp" => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn maps() {
+    let counts = MapDef { map_type: 1, key_size: 4, value_size: 8, max_entries: 16, flags: 0 };
+    let hits = MapDef { map_type: 2, key_size: 4, value_size: 8, max_entries: 64, flags: 1 };
+
+    for data in &[MAPS_BPFEL, MAPS_BPFEB] {
+        let obj = Object::parse(data).unwrap();
+        let maps: Vec<_> = obj.maps().iter().map(|m| (&m.name[..], m.def)).collect();
+        assert_eq!(maps, [("counts", counts), ("hits", hits)]);
+
+        // `hits` is looked up, and `counts` deleted from, by index
+        let p = obj.program("count").unwrap();
+        assert_eq!((inst(p.insts()[4]).src(), inst(p.insts()[4]).imm32()), (1, 1));
+        assert_eq!((inst(p.insts()[15]).src(), inst(p.insts()[15]).imm32()), (1, 0));
+
+        let v = obj.env(p).verify(p.insts()).unwrap();
+        assert_eq!(v.helpers(), [1, 3]);
+
+        // and run with maps made from their definitions
+        let mut created = obj.create_maps().unwrap();
+        assert_eq!(created[1].def(), &hits);
+        assert_eq!(Invoke::new(v.clone()).maps(&mut created[..]).run(), Ok(0));
+        assert_eq!(created[1].update(&[0; 4], &[1, 0, 0, 0, 0, 0, 0, 0], 0), 0);
+        assert_eq!(Invoke::new(v).maps(&mut created[..]).run(), Ok(1));
+    }
+
+    // the legacy definition in `prog.ll`
+    let obj = Object::parse(BPFEL).unwrap();
+    assert_eq!(obj.maps()[0].def, MapDef { map_type: 2, key_size: 4, value_size: 8, max_entries: 16, flags: 0 });
+    assert!(obj.btf().is_none());
+
+    // `.maps` can't be read without BTF
+    let mut data = MAPS_BPFEL.to_vec();
+    let at = data.windows(5).position(|w| w == b".BTF\0").unwrap();
+    data[at + 1] = b'X';
+    assert_eq!(Object::parse(&data), Err(ElfError::Map {
+        name: "hits".to_string(),
+        reason: "no BTF describing .maps",
+    }));
+}
@@ -0,0 +1,77 @@
+extern crate cbpf;
+
+use cbpf::asm::assemble;
+use cbpf::cfg::Cfg;
+use cbpf::liveness::{Liveness, RegSet, ARGS};
+use cbpf::profile::helper;
+
+fn liveness(src: &str) -> Liveness {
+    let p = assemble(src).unwrap();
+    Liveness::new(&Cfg::new(&p).unwrap(), &p, |id| helper::proto(id).map_or(5, |p| p.args.len()))
+}
+
+fn regs(rs: &[u8]) -> RegSet {
+    rs.iter().fold(0, |s, &r| s | 1 << r)
+}
+
+#[test]
+fn branches() {
+    let l = liveness("
+        r0 = r1
+        r2 = 0
+        if r3 > 5 goto join
+        r2 = r4
+        r2 = 1
+    join:
+        r0 += r2
+        exit
+    ");
+    let live: Vec<RegSet> = (0..7).map(|pc| l.live_in(pc)).collect();
+    assert_eq!(live, vec![
+        regs(&[1, 3, 4]),
+        regs(&[0, 3, 4]),
+        regs(&[0, 2, 3, 4]),
+        regs(&[0, 4]),
+        regs(&[0]),
+        regs(&[0, 2]),
+        regs(&[0]),
+    ]);
+    assert_eq!(l.args(), regs(&[1, 3, 4]));
+    assert_eq!(l.dead_stores(), &[3]);
+}
+
+#[test]
+fn loops() {
+    let l = liveness("
+        r0 = 0
+        r2 = r1
+    top:
+        r0 += r2
+        r2 -= 1
+        if r2 != 0 goto top
+        exit
+    ");
+    assert_eq!(l.live_in(4), regs(&[0, 2]));
+    assert_eq!(l.live_in(2), regs(&[0, 2]));
+    assert_eq!(l.live_in(0), regs(&[1]));
+    assert_eq!(l.args(), regs(&[1]));
+    assert!(l.dead_stores().is_empty());
+
+    // the second half of a `ld_imm64` is not an instruction
+    let l = liveness("r3 = 0x100000000 ll\nr0 = r3\nr3 = 0 ll\nexit");
+    assert_eq!((l.live_in(0), l.live_in(1), l.live_in(2)), (0, 0, regs(&[3])));
+    assert_eq!(l.dead_stores(), &[3]);
+    assert_eq!(l.args(), 0);
+}
+
+#[test]
+fn calls() {
+    // a call reads only the arguments its helper takes
+    assert_eq!(liveness("call 5\nexit").args(), 0);
+    let l = liveness("r2 = r10\nr2 += -8\ncall 1\nexit");
+    assert_eq!(l.args(), regs(&[1]));
+    assert_eq!(l.live_in(2), regs(&[1, 2]));
+
+    // and any of them if it is unknown
+    assert_eq!(liveness("call 1000\nexit").args(), ARGS);
+}
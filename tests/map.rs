@@ -0,0 +1,106 @@
+extern crate cbpf;
+
+use cbpf::asm::assemble;
+use cbpf::map::{self, Map};
+use cbpf::profile::Profile;
+use cbpf::verifier::{Env, MapDef};
+use cbpf::Invoke;
+
+fn def(map_type: u32, value_size: u32, max_entries: u32) -> MapDef {
+    MapDef { map_type, key_size: 4, value_size, max_entries, flags: 0 }
+}
+
+#[test]
+fn hash() {
+    let mut m = Map::new(def(map::HASH, 2, 2)).unwrap();
+    assert_eq!(m.get(&[1, 0, 0, 0]), None);
+    assert_eq!(m.update(&[1, 0, 0, 0], &[5, 6], 2), -2);
+    assert_eq!(m.update(&[1, 0, 0, 0], &[5, 6], 1), 0);
+    assert_eq!(m.update(&[1, 0, 0, 0], &[7, 8], 1), -17);
+    assert_eq!(m.update(&[1, 0, 0, 0], &[7, 8], 2), 0);
+    assert_eq!(m.get(&[1, 0, 0, 0]), Some(&[7, 8][..]));
+
+    // full
+    assert_eq!(m.update(&[2, 0, 0, 0], &[0, 0], 0), 0);
+    assert_eq!(m.update(&[3, 0, 0, 0], &[0, 0], 0), -7);
+    assert_eq!(m.delete(&[1, 0, 0, 0]), 0);
+    assert_eq!(m.delete(&[1, 0, 0, 0]), -2);
+    assert_eq!(m.update(&[3, 0, 0, 0], &[9, 9], 0), 0);
+    assert_eq!(m.get(&[3, 0, 0, 0]), Some(&[9, 9][..]));
+
+    assert_eq!(m.update(&[1, 0], &[0, 0], 0), -22);
+    assert_eq!(m.update(&[1, 0, 0, 0], &[0], 0), -22);
+    assert_eq!(m.update(&[1, 0, 0, 0], &[0, 0], 3), -22);
+}
+
+#[test]
+fn array() {
+    let mut m = Map::new(def(map::ARRAY, 1, 4)).unwrap();
+    assert_eq!(m.get(&[3, 0, 0, 0]), Some(&[0][..]));
+    assert_eq!(m.get(&[4, 0, 0, 0]), None);
+    assert_eq!(m.update(&[3, 0, 0, 0], &[1], 2), 0);
+    assert_eq!(m.get(&[3, 0, 0, 0]), Some(&[1][..]));
+    assert_eq!(m.update(&[3, 0, 0, 0], &[1], 1), -17);
+    assert_eq!(m.update(&[4, 0, 0, 0], &[1], 0), -7);
+    assert_eq!(m.delete(&[3, 0, 0, 0]), -22);
+
+    assert_eq!(Map::new(MapDef { key_size: 8, ..def(map::ARRAY, 1, 4) }), Err("array map key size is not 4"));
+    assert_eq!(Map::new(def(map::HASH, 0, 4)), Err("key size, value size, or max entries is 0"));
+    assert_eq!(Map::new(def(27, 8, 4)), Err("unsupported map type"));
+
+    // sizes from an object are bounded before anything is allocated
+    let too_big = Err("key or value size is larger than a stack frame");
+    assert_eq!(Map::new(def(map::HASH, 513, 1)), too_big);
+    assert_eq!(Map::new(MapDef { key_size: 513, ..def(map::HASH, 8, 1) }), too_big);
+    assert!(Map::new(def(map::ARRAY, 512, 8192)).is_ok());
+    assert_eq!(Map::new(def(map::ARRAY, 512, 8193)), Err("array map is larger than MAX_ARRAY_BYTES"));
+    assert_eq!(Map::new(def(map::ARRAY, 8, u32::MAX)), Err("array map is larger than MAX_ARRAY_BYTES"));
+    assert!(Map::new(def(map::HASH, 512, u32::MAX)).is_ok());
+}
+
+#[test]
+fn invoke() {
+    // count runs in a hash map, and store the count at 4 bytes into index 0 of an array
+    let p = assemble("
+        *(u32 *)(r10 - 4) = 7
+        r1 = map[0] ll
+        r2 = r10
+        r2 += -4
+        call 1
+        if r0 != 0 goto found
+        *(u64 *)(r10 - 16) = 1
+        r1 = map[0] ll
+        r2 = r10
+        r2 += -4
+        r3 = r10
+        r3 += -16
+        ldw r4, 1
+        call 2
+        ldw r0, 1
+        goto out
+    found:
+        r1 = 1
+        lock *(u64 *)(r0 + 0) += r1
+        r0 = *(u64 *)(r0 + 0)
+    out:
+        r1 = map_value[1] + 4 ll
+        *(u32 *)(r1 + 0) = r0
+        exit
+    ").unwrap();
+    let defs = [def(map::HASH, 8, 16), def(map::ARRAY, 8, 1)];
+    let mut maps: Vec<Map> = defs.iter().map(|&d| Map::new(d).unwrap()).collect();
+
+    let env = || defs.iter().fold(Env::default().profile(Profile::tracing()), |env, &d| env.map(d));
+    for n in 1..4 {
+        let v = env().verify(&p).unwrap();
+        assert_eq!(Invoke::new(v).maps(&mut maps[..]).run(), Ok(n));
+    }
+    assert_eq!(maps[0].get(&[7, 0, 0, 0]), Some(&[3, 0, 0, 0, 0, 0, 0, 0][..]));
+    assert_eq!(maps[1].get(&[0, 0, 0, 0]), Some(&[0, 0, 0, 0, 3, 0, 0, 0][..]));
+
+    // without the maps, or without one of them
+    let v = env().verify(&p).unwrap();
+    assert_eq!(Invoke::new(v).run(), Err(()));
+    let v = env().verify(&p).unwrap();
+    assert_eq!(Invoke::new(v).maps(&mut maps[..1]).run(), Err(()));
+}
@@ -0,0 +1,202 @@
+#![allow(clippy::unusual_byte_groupings)]
+extern crate cbpf;
+
+use cbpf::pcap::{Capture, Format, Interface, PcapError, Writer};
+use cbpf::replay::{Replay, Summary};
+
+// accept IPv4 (ethertype 0x0800), reject everything else
+const IPV4: [u64; 6] = [
+    // ldh [12]
+    0x28_00_00_00__00_00_00_0c,
+    // jeq r0, #0x800, +2
+    0x15_00_00_02__00_00_08_00,
+    // ld r0, 0x0u32
+    0x00_00_00_00__00_00_00_00,
+    // exit
+    0x95_00_00_00__00_00_00_00,
+    // ld r0, 0x40u32
+    0x00_00_00_00__00_00_00_40,
+    // exit
+    0x95_00_00_00__00_00_00_00,
+];
+
+fn eth(ethertype: u16, len: usize) -> Vec<u8> {
+    let mut p = vec![0u8; len];
+    p[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    p
+}
+
+fn pcap_le(pkts: &[Vec<u8>]) -> Vec<u8> {
+    let mut f = Vec::new();
+    f.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    f.extend_from_slice(&2u16.to_le_bytes());
+    f.extend_from_slice(&4u16.to_le_bytes());
+    f.extend_from_slice(&[0; 8]);
+    f.extend_from_slice(&65535u32.to_le_bytes());
+    f.extend_from_slice(&1u32.to_le_bytes());
+    for (i, p) in pkts.iter().enumerate() {
+        f.extend_from_slice(&(i as u32).to_le_bytes());
+        f.extend_from_slice(&500u32.to_le_bytes());
+        f.extend_from_slice(&(p.len() as u32).to_le_bytes());
+        f.extend_from_slice(&(p.len() as u32).to_le_bytes());
+        f.extend_from_slice(p);
+    }
+    f
+}
+
+fn pcapng_block(ty: u32, body: &[u8]) -> Vec<u8> {
+    let len = 12 + ((body.len() + 3) & !3);
+    let mut b = Vec::new();
+    b.extend_from_slice(&ty.to_be_bytes());
+    b.extend_from_slice(&(len as u32).to_be_bytes());
+    b.extend_from_slice(body);
+    b.resize(len - 4, 0);
+    b.extend_from_slice(&(len as u32).to_be_bytes());
+    b
+}
+
+// big endian, with one enhanced & one simple packet block
+fn pcapng_be(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut shb = Vec::new();
+    shb.extend_from_slice(&0x1a2b3c4du32.to_be_bytes());
+    shb.extend_from_slice(&1u16.to_be_bytes());
+    shb.extend_from_slice(&0u16.to_be_bytes());
+    shb.extend_from_slice(&(-1i64).to_be_bytes());
+
+    let mut idb = Vec::new();
+    idb.extend_from_slice(&1u16.to_be_bytes());
+    idb.extend_from_slice(&0u16.to_be_bytes());
+    idb.extend_from_slice(&0u32.to_be_bytes());
+    // if_tsresol = 10^-9
+    idb.extend_from_slice(&9u16.to_be_bytes());
+    idb.extend_from_slice(&1u16.to_be_bytes());
+    idb.extend_from_slice(&[9, 0, 0, 0]);
+    idb.extend_from_slice(&[0; 4]);
+
+    let mut epb = Vec::new();
+    epb.extend_from_slice(&0u32.to_be_bytes());
+    epb.extend_from_slice(&0u32.to_be_bytes());
+    epb.extend_from_slice(&1234u32.to_be_bytes());
+    epb.extend_from_slice(&(a.len() as u32).to_be_bytes());
+    epb.extend_from_slice(&(a.len() as u32).to_be_bytes());
+    epb.extend_from_slice(a);
+
+    let mut spb = Vec::new();
+    spb.extend_from_slice(&(b.len() as u32).to_be_bytes());
+    spb.extend_from_slice(b);
+
+    let mut f = pcapng_block(0x0a0d0d0a, &shb);
+    f.extend(pcapng_block(1, &idb));
+    f.extend(pcapng_block(6, &epb));
+    f.extend(pcapng_block(3, &spb));
+    f
+}
+
+fn summarize(prgm: &[u64], capture: &[u8]) -> Summary {
+    let p = unsafe { cbpf::Program::from_raw(prgm) };
+    let cap = Capture::new(capture).unwrap();
+    let mut r = Replay::new(p, cap.packets());
+    for o in &mut r {
+        o.unwrap();
+    }
+    r.summary().clone()
+}
+
+#[test]
+fn pcap_replay() {
+    let f = pcap_le(&[eth(0x0800, 60), eth(0x0806, 42), eth(0x0800, 20)]);
+    let cap = Capture::new(&f).unwrap();
+    assert_eq!(cap.format(), Format::Pcap);
+
+    let p = unsafe { cbpf::Program::from_raw(&IPV4) };
+    let lens: Vec<_> = Replay::new(p, cap.packets()).map(|o| {
+        let o = o.unwrap();
+        (o.is_accept(), o.len())
+    }).collect();
+    assert_eq!(lens, vec![(true, 60), (false, 0), (true, 20)]);
+}
+
+#[test]
+fn pcapng_replay() {
+    let f = pcapng_be(&eth(0x0806, 42), &eth(0x0800, 100));
+    let cap = Capture::new(&f).unwrap();
+    assert_eq!(cap.format(), Format::PcapNg);
+
+    let pkts: Vec<_> = cap.packets().map(|p| p.unwrap()).collect();
+    assert_eq!(pkts.len(), 2);
+    assert_eq!(pkts[0].timestamp_ns, 1234);
+    assert_eq!(pkts[0].link_type, 1);
+    assert_eq!(pkts[1].data.len(), 100);
+
+    assert_eq!(summarize(&IPV4, &f), Summary {
+        packets: 2,
+        accepted: 1,
+        rejected: 1,
+        errors: 0,
+    });
+}
+
+#[test]
+fn interface() {
+    // from the file header, even with no packets
+    let f = pcap_le(&[]);
+    assert_eq!(Capture::new(&f).unwrap().interface(), Ok(Interface { link_type: 1, snap_len: 65535 }));
+
+    let f = pcapng_be(&eth(0x0806, 42), &eth(0x0800, 100));
+    assert_eq!(Capture::new(&f).unwrap().interface(), Ok(Interface { link_type: 1, snap_len: 0 }));
+
+    // an interface description block too short for its fields
+    let shb = &f[..28];
+    let mut short = shb.to_vec();
+    short.extend(pcapng_block(1, &1u16.to_be_bytes()));
+    short.extend_from_slice(&f[28 + 32..]);
+    let cap = Capture::new(&short).unwrap();
+    assert_eq!(cap.interface(), Err(PcapError::Malformed("interface description block is too short")));
+    let r: Vec<_> = cap.packets().collect();
+    assert_eq!(r, vec![Err(PcapError::Malformed("interface description block is too short"))]);
+}
+
+#[test]
+fn short_packet_is_error() {
+    let f = pcap_le(&[eth(0x0800, 60), vec![0; 10]]);
+    assert_eq!(summarize(&IPV4, &f), Summary {
+        packets: 2,
+        accepted: 1,
+        rejected: 0,
+        errors: 1,
+    });
+}
+
+#[test]
+fn truncated() {
+    let mut f = pcap_le(&[eth(0x0800, 60)]);
+    f.pop();
+    let cap = Capture::new(&f).unwrap();
+    let r: Vec<_> = cap.packets().collect();
+    assert_eq!(r, vec![Err(PcapError::Truncated)]);
+
+    assert_eq!(Capture::new(&[0; 24]).unwrap_err(), PcapError::UnknownFormat);
+}
+
+#[test]
+fn write_accepted() {
+    let f = pcap_le(&[eth(0x0800, 100), eth(0x0806, 42)]);
+    let cap = Capture::new(&f).unwrap();
+    let p = unsafe { cbpf::Program::from_raw(&IPV4) };
+
+    let mut w = Writer::new(Vec::new(), 1, 65535).unwrap();
+    for o in Replay::new(p, cap.packets()) {
+        let o = o.unwrap();
+        if o.is_accept() {
+            w.write_packet(&o.kept()).unwrap();
+        }
+    }
+
+    let out = w.into_inner();
+    let cap = Capture::new(&out).unwrap();
+    let pkts: Vec<_> = cap.packets().map(|p| p.unwrap()).collect();
+    assert_eq!(pkts.len(), 1);
+    assert_eq!(pkts[0].data, &eth(0x0800, 100)[..0x40]);
+    assert_eq!(pkts[0].orig_len, 100);
+    assert_eq!(pkts[0].timestamp_ns, 500_000);
+}
@@ -0,0 +1,178 @@
+extern crate cbpf;
+
+use cbpf::asm::assemble;
+use cbpf::map::{self, Map};
+use cbpf::sanitize::SanitizeErrorKind;
+use cbpf::verifier::{Env, MapDef};
+use cbpf::{Invoke, Program};
+
+fn run(src: &str) -> Result<u64, (usize, SanitizeErrorKind)> {
+    let p = assemble(src).unwrap();
+    let mut c = Invoke::new(unsafe { Program::from_raw(&p) });
+    c.arg_raw(1, 5);
+    c.run_sanitized().map_err(|e| (e.pc(), *e.kind()))
+}
+
+#[test]
+fn stack() {
+    let src = "
+        r2 = r10
+        r2 += -16
+        *(u64 *)(r10 - 8) = r2
+        *(u32 *)(r10 - 16) = 3
+        r3 = *(u64 *)(r10 - 8)
+        ldw r4, 5
+        lock *(u32 *)(r3 + 0) += r4
+        r0 = *(u32 *)(r10 - 16)
+        r0 += 1
+        exit
+    ";
+    assert_eq!(run(src), Ok(9));
+
+    // the same program runs unsanitized, and passes the verifier
+    let p = assemble(src).unwrap();
+    assert_eq!(Invoke::new(Env::default().verify(&p).unwrap()).run(), Ok(9));
+}
+
+#[test]
+fn errors() {
+    use SanitizeErrorKind::*;
+
+    assert_eq!(run("r0 = r2\nexit"), Err((0, UninitializedRegister { reg: 2 })));
+    assert_eq!(run("exit"), Err((0, UninitializedRegister { reg: 0 })));
+    assert_eq!(run("r0 = r1\nexit"), Ok(5));
+
+    assert_eq!(run("r0 = *(u64 *)(r10 + 0)\nexit"), Err((0, OutOfBounds { off: 0, size: 8 })));
+    assert_eq!(run("r0 = *(u64 *)(r10 - 520)\nexit"), Err((0, OutOfBounds { off: -520, size: 8 })));
+    assert_eq!(run("r0 = *(u32 *)(r10 - 6)\nexit"), Err((0, Misaligned { off: -6, size: 4 })));
+    assert_eq!(run("r0 = *(u32 *)(r10 - 4)\nexit"), Err((0, UninitializedStack { off: -4 })));
+    assert_eq!(run("*(u16 *)(r10 - 4) = 1\nr0 = *(u32 *)(r10 - 4)\nexit"),
+        Err((1, UninitializedStack { off: -2 })));
+    assert_eq!(run("r0 = *(u64 *)(r1 + 0)\nexit"), Err((0, InvalidMemoryAccess { reg: 1 })));
+
+    // pointers
+    assert_eq!(run("r0 = r10\nexit"), Err((1, PointerLeak { reg: 0 })));
+    assert_eq!(run("r0 = r10\nr0 -= r10\nexit"), Ok(0));
+    assert_eq!(run("r0 = r10\nr0 *= 2\nexit"),
+        Err((1, InvalidPointerArithmetic("only add and sub may be used on pointers"))));
+    assert_eq!(run("w0 = w10\nexit"), Err((0, InvalidPointerArithmetic("32-bit operation on a pointer"))));
+    assert_eq!(run("*(u64 *)(r10 - 8) = r10\nr0 = *(u32 *)(r10 - 8)\nexit"),
+        Err((1, PartialPointer { off: -8, size: 4 })));
+    assert_eq!(run("*(u32 *)(r10 - 8) = r10\nexit"), Err((0, PartialPointer { off: -8, size: 4 })));
+
+    // control flow
+    assert_eq!(run("r0 = 0"), Err((0, OutOfProgram)));
+    assert_eq!(run("call 1\nexit"), Err((0, InvalidHelperArg { reg: 1 })));
+    assert_eq!(run("call 5\nexit"), Err((0, Unsupported("helper other than a map lookup, update or delete"))));
+    assert_eq!(run("r0 = *(u8 *)skb[0]\nexit"), Err((0, LoadFailed)));
+}
+
+#[test]
+fn calls() {
+    use SanitizeErrorKind::*;
+
+    // `f` adds 1 to what its argument points to in the caller's stack
+    let src = "
+        r1 = r10
+        r1 += -8
+        *(u64 *)(r10 - 8) = 3
+        r6 = 7
+        call f
+        r0 = *(u64 *)(r10 - 8)
+        r0 += r6
+        exit
+    f:
+        r0 = *(u64 *)(r1 + 0)
+        r0 += 1
+        *(u64 *)(r1 + 0) = r0
+        *(u64 *)(r10 - 8) = r1
+        r0 = 0
+        exit
+    ";
+    assert_eq!(run(src), Ok(11));
+    let p = assemble(src).unwrap();
+    assert_eq!(Invoke::new(Env::default().verify(&p).unwrap()).run(), Ok(11));
+
+    // the callee has `r1` to `r5` and a stack of its own, and the caller gets back only `r0`
+    assert_eq!(run("r6 = 1\ncall f\nr0 = r6\nexit\nf: r0 = r6\nexit"), Err((4, UninitializedRegister { reg: 6 })));
+    assert_eq!(run("*(u64 *)(r10 - 8) = 1\ncall f\nexit\nf: r0 = *(u64 *)(r10 - 8)\nexit"),
+        Err((3, UninitializedStack { off: -8 })));
+    assert_eq!(run("call f\nr0 = r1\nexit\nf: r0 = 0\nexit"), Err((1, UninitializedRegister { reg: 1 })));
+
+    // no pointer may outlive the stack it points to
+    assert_eq!(run("call f\nexit\nf: r0 = r10\nexit"), Err((3, PointerLeak { reg: 0 })));
+    assert_eq!(run("r1 = r10\ncall f\nr0 = 0\nexit\nf: *(u64 *)(r1 - 8) = r10\nexit"), Err((4, PointerLeak { reg: 10 })));
+
+    assert_eq!(run("r0 = 0\ncall -1\nexit"), Err((1, CallDepthExceeded { limit: 8 })));
+}
+
+#[test]
+fn maps() {
+    use SanitizeErrorKind::*;
+
+    let def = |map_type, max_entries| MapDef { map_type, key_size: 4, value_size: 8, max_entries, flags: 0 };
+    let mut maps = [Map::new(def(map::HASH, 4)).unwrap(), Map::new(def(map::ARRAY, 1)).unwrap()];
+    let mut run = |src: &str| {
+        let p = assemble(src).unwrap();
+        let c = Invoke::new(unsafe { Program::from_raw(&p) }).maps(&mut maps[..]);
+        c.run_sanitized().map_err(|e| (e.pc(), *e.kind()))
+    };
+
+    // store 5 under the key 7, and look it up
+    let lookup = "
+        *(u32 *)(r10 - 4) = 7
+        r1 = map[0] ll
+        r2 = r10
+        r2 += -4
+        call 1
+        if r0 == 0 goto out
+        r0 = *(u64 *)(r0 + 0)
+    out:
+        exit
+    ";
+    assert_eq!(run(lookup), Ok(0));
+    assert_eq!(run("
+        *(u32 *)(r10 - 4) = 7
+        *(u64 *)(r10 - 16) = 5
+        r1 = map[0] ll
+        r2 = r10
+        r2 += -4
+        r3 = r10
+        r3 += -16
+        r4 = 0
+        call 2
+        exit
+    "), Ok(0));
+    assert_eq!(run(lookup), Ok(5));
+    assert_eq!(run("*(u32 *)(r10 - 4) = 8\nr1 = map[0] ll\nr2 = r10\nr2 += -4\ncall 1\nr0 = *(u64 *)(r0 + 0)\nexit"),
+        Err((6, InvalidMemoryAccess { reg: 0 })));
+
+    // keys and values must be written numbers
+    assert_eq!(run("r1 = map[0] ll\nr2 = r10\nr2 += -4\ncall 1\nexit"), Err((4, UninitializedStack { off: -4 })));
+    assert_eq!(run("r1 = map[0] ll\nr2 = 0\ncall 1\nexit"), Err((3, InvalidHelperArg { reg: 2 })));
+
+    assert_eq!(run("r1 = map_value[1] + 4 ll\nr0 = *(u32 *)(r1 + 0)\nexit"), Ok(0));
+    assert_eq!(run("r1 = map_value[1] + 4 ll\nr0 = *(u64 *)(r1 + 0)\nexit"),
+        Err((2, MapValueOutOfBounds { off: 4, size: 8 })));
+    assert_eq!(run("r1 = map_value[1] + 0 ll\n*(u64 *)(r1 + 0) = r10\nr0 = 0\nexit"), Err((2, PointerLeak { reg: 10 })));
+    assert_eq!(run("r1 = map_value[0] + 0 ll\nr0 = 0\nexit"), Err((0, Unsupported("map without a direct value"))));
+    assert_eq!(run("r1 = map[2] ll\nr0 = 0\nexit"), Err((0, UnknownMap { index: 2 })));
+    assert_eq!(run("r1 = map[0] ll\nr1 += 8\nr0 = 0\nexit"),
+        Err((2, InvalidPointerArithmetic("arithmetic on a pointer that can't be moved"))));
+}
+
+#[test]
+fn reserved_fields() {
+    // `exit` with a non-zero offset
+    let p = [0x9500_0001_0000_0000];
+    let e = Invoke::new(unsafe { Program::from_raw(&p) }).run_sanitized().unwrap_err();
+    assert_eq!(e.pc(), 0);
+    match *e.kind() {
+        SanitizeErrorKind::InvalidInst(_) => {},
+        ref k => panic!("unexpected {:?}", k),
+    }
+
+    let p = assemble("r0 = r2\nexit").unwrap();
+    let e = Invoke::new(unsafe { Program::from_raw(&p) }).run_sanitized().unwrap_err();
+    assert_eq!(format!("{}", e), "instruction 0: r2 is read before it is written");
+}
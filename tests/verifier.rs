@@ -0,0 +1,774 @@
+extern crate cbpf;
+
+use cbpf::asm::assemble;
+use cbpf::cfg::CfgErrorKind;
+use cbpf::profile::{ArgKind, CtxField, HelperProto, Profile, RetKind, RetValues};
+use cbpf::verifier::{Env, LogLevel, MapDef, PrgmVerifyErrorKind, PtrKind, Stats};
+use cbpf::{InstDecodeError, Invoke};
+
+fn verify(src: &str) -> Result<(), (usize, PrgmVerifyErrorKind)> {
+    let p = assemble(src).unwrap();
+    Env::default().verify(&p).map(|_| ()).map_err(|e| (e.inst_idx(), e.kind().clone()))
+}
+
+#[test]
+fn forward_jumps() {
+    let p = assemble("
+        ldw r0, 1
+        ldw r1, 2
+        if r1 > 1 goto big
+        ldw r0, 3
+        goto done
+    big:
+        ldw r0, 4
+    done:
+        exit
+    ").unwrap();
+    let prgm = Env::default().verify(&p).unwrap();
+    assert_eq!(Invoke::new(prgm).run(), Ok(4));
+
+    assert_eq!(verify("ldw r0, 1\ngoto +0\nexit"), Ok(()));
+}
+
+#[test]
+fn loops() {
+    let sum = "
+        ldw r0, 0
+        ldw r1, 10
+    top:
+        r0 += r1
+        w1 -= 1
+        if w1 != 0 goto top
+        exit
+    ";
+    let p = assemble(sum).unwrap();
+    let prgm = Env::default().verify(&p).unwrap();
+    assert_eq!(Invoke::new(prgm).run(), Ok(55));
+    assert_eq!(Env::default().complexity_limit(20).verify(&p).unwrap_err().kind(),
+        &PrgmVerifyErrorKind::ComplexityLimitExceeded { limit: 20 });
+
+    let limited = |src: &str| {
+        let e = Env::default().complexity_limit(100).verify(&assemble(src).unwrap()).unwrap_err();
+        (e.inst_idx(), e.kind().clone())
+    };
+    let exceeded = PrgmVerifyErrorKind::ComplexityLimitExceeded { limit: 100 };
+
+    // ends, but not soon enough
+    assert_eq!(limited("ldw r0, 1\nldw r1, 0\nw1 += 1\nif w1 != 0 goto -2\nexit"), (2, exceeded));
+
+    // never ends, which is seen as soon as the loop comes round in the same state
+    assert_eq!(limited("ldw r0, 1\nldw r1, 1\nif r1 != 0 goto -1\nexit"), (2, PrgmVerifyErrorKind::InfiniteLoop));
+    assert_eq!(verify("r0 = 0\nl: r0 += 0\nif r0 == 0 goto l\nexit"), Err((1, PrgmVerifyErrorKind::InfiniteLoop)));
+
+    // may not end, depending on the packet
+    assert_eq!(limited("
+        r0 = *(u8 *)skb[0]
+    top:
+        if r0 == 0 goto done
+        goto top
+    done:
+        exit
+    "), (1, PrgmVerifyErrorKind::InfiniteLoop));
+
+    // jumping backwards is fine as long as it does not close a cycle
+    assert_eq!(verify("
+        goto second
+    first:
+        ldw r0, 1
+        exit
+    second:
+        goto first
+    "), Ok(()));
+}
+
+#[test]
+fn unreachable() {
+    assert_eq!(verify("ldw r0, 1\nexit\nldw r0, 2\nexit"),
+        Err((2, PrgmVerifyErrorKind::Unreachable)));
+    assert_eq!(verify("goto +2\nldw r0, 1\nldw r0, 2\nldw r0, 3\nexit"),
+        Err((1, PrgmVerifyErrorKind::Unreachable)));
+}
+
+#[test]
+fn malformed() {
+    assert_eq!(verify("ldw r0, 1\ngoto +3\nexit"),
+        Err((1, PrgmVerifyErrorKind::Cfg(CfgErrorKind::JumpOutOfRange))));
+    assert_eq!(verify("ldw r0, 1\nif r0 > 1 goto -1"),
+        Err((1, PrgmVerifyErrorKind::Cfg(CfgErrorKind::FallsOffEnd))));
+    assert_eq!(Env::default().verify(&[0xb70b000000000001, 0x9500000000000000]).unwrap_err().kind(),
+        &PrgmVerifyErrorKind::InstDecode(InstDecodeError::InvalidEncoding("register out of range")));
+    assert_eq!(verify("call 1\nexit"), Err((0, PrgmVerifyErrorKind::UnknownHelper { id: 1 })));
+}
+
+#[test]
+fn uninitialized() {
+    let uninit = |reg| PrgmVerifyErrorKind::UninitializedRegister { reg };
+    assert_eq!(verify("exit"), Err((0, uninit(0))));
+    assert_eq!(verify("r0 = r2\nexit"), Err((0, uninit(2))));
+    assert_eq!(verify("ldw r0, 0\nr0 += r3\nexit"), Err((1, uninit(3))));
+    assert_eq!(verify("ldw r0, 0\nif r0 > r4 goto +0\nexit"), Err((1, uninit(4))));
+
+    // r1 (the context) and r10 (the frame pointer) start initialized
+    assert_eq!(verify("r0 = r1\nr0 -= r1\nr2 = r10\nr2 -= r10\nr0 += r2\nexit"), Ok(()));
+
+    // only initialized on one path
+    assert_eq!(verify("
+        r2 = r1
+        if r2 == 0 goto +1
+        ldw r0, 1
+        exit
+    "), Err((3, uninit(0))));
+}
+
+#[test]
+fn ranges() {
+    // a path which can't be taken isn't explored, even when it would never end
+    let forever = |cond: &str| format!("
+        r0 = *(u8 *)skb[0]
+        {}
+        exit
+    spin:
+        goto spin
+    ", cond);
+    let limited = |src: &str| Env::default().complexity_limit(100)
+        .verify(&assemble(src).unwrap()).map(|_| ());
+
+    assert_eq!(limited(&forever("if r0 > 255 goto spin")), Ok(()));
+    assert!(limited(&forever("if r0 > 254 goto spin")).is_err());
+    assert_eq!(limited(&forever("r0 &= 0xf0\nif r0 & 0x0f goto spin")), Ok(()));
+    assert_eq!(limited(&forever("r0 += 10\nif r0 s< 10 goto spin")), Ok(()));
+    assert_eq!(limited(&forever("r0 <<= 4\nif w0 == 0x1001 goto spin")), Ok(()));
+    assert_eq!(limited(&forever("r0 |= 0x100\nr0 s>>= 8\nif r0 == 0 goto spin")), Ok(()));
+
+    // narrowed by an earlier comparison
+    assert_eq!(limited(&forever("
+        if r0 < 100 goto +1
+        ldw r0, 5
+        if r0 >= 100 goto spin
+    ")), Ok(()));
+
+    // counting down from a packet byte ends once the counter reaches zero
+    assert_eq!(verify("
+        r1 = *(u8 *)skb[0]
+        ldw r0, 0
+    top:
+        if r1 == 0 goto done
+        r1 -= 1
+        r0 += 1
+        goto top
+    done:
+        exit
+    "), Ok(()));
+}
+
+#[test]
+fn pointer_arithmetic() {
+    let err = |pc, msg| Err((pc, PrgmVerifyErrorKind::InvalidPointerArithmetic(msg)));
+    assert_eq!(verify("r0 = r1\nr0 += r10\nexit"), err(1, "adding two pointers"));
+    assert_eq!(verify("r0 = r10\nr0 -= r1\nexit"), err(1, "subtracting pointers of different kinds"));
+    assert_eq!(verify("ldw r0, 0\nr0 -= r10\nexit"), err(1, "subtracting a pointer from a number"));
+    assert_eq!(verify("r0 = r10\nr0 *= 2\nexit"), err(1, "only add and sub may be used on pointers"));
+    assert_eq!(verify("w0 = w10\nexit"), err(0, "32-bit operation on a pointer"));
+    assert_eq!(verify("r0 = r10\nr0 += 0x20000001\nexit"), err(1, "pointer offset out of range"));
+    assert_eq!(verify("r0 = *(u8 *)skb[r10]\nexit"), err(0, "pointer used as a number"));
+
+    // the distance between two pointers is a number
+    assert_eq!(verify("r0 = r10\nr0 -= r10\nexit"), Ok(()));
+    assert_eq!(verify("ldw r0, 8\nr0 += r10\nexit"),
+        Err((2, PrgmVerifyErrorKind::PointerLeak { reg: 0 })));
+    assert_eq!(verify("ldw r2, 0\nr0 = *(u8 *)(r2 + 0)\nexit"),
+        Err((1, PrgmVerifyErrorKind::InvalidMemoryAccess { reg: 2, kind: None })));
+}
+
+#[test]
+fn stack() {
+    let oob = |off, size| PrgmVerifyErrorKind::OutOfBounds { region: PtrKind::Stack, off, size };
+    assert_eq!(verify("
+        *(u32 *)(r10 - 512) = 7
+        r0 = *(u16 *)(r10 - 510)
+        exit
+    "), Ok(()));
+    assert_eq!(verify("*(u64 *)(r10 - 4) = 0\nldw r0, 0\nexit"), Err((0, oob(-4, 8))));
+    assert_eq!(verify("r2 = r10\nr2 -= 512\nr0 = *(u8 *)(r2 - 1)\nexit"), Err((2, oob(-513, 1))));
+    assert_eq!(verify("*(u32 *)(r10 - 6) = 0\nldw r0, 0\nexit"),
+        Err((0, PrgmVerifyErrorKind::Misaligned { region: PtrKind::Stack, off: -6, size: 4 })));
+
+    // the frame pointer may only be moved by a known amount
+    assert_eq!(verify("
+        r2 = *(u8 *)skb[0]
+        r3 = r10
+        r3 -= r2
+        ldw r0, 0
+        exit
+    "), Err((2, PrgmVerifyErrorKind::InvalidPointerArithmetic("variable offset into the stack or context"))));
+}
+
+#[test]
+fn spills() {
+    let uninit = |off| PrgmVerifyErrorKind::UninitializedStack { off };
+    assert_eq!(verify("r0 = *(u8 *)(r10 - 1)\nexit"), Err((0, uninit(-1))));
+    assert_eq!(verify("*(u16 *)(r10 - 8) = 1\nr0 = *(u32 *)(r10 - 8)\nexit"), Err((1, uninit(-6))));
+
+    // a spilled pointer is a pointer again when filled
+    assert_eq!(verify("
+        *(u64 *)(r10 - 8) = r10
+        r2 = *(u64 *)(r10 - 8)
+        *(u8 *)(r2 - 16) = 1
+        r0 = *(u8 *)(r2 - 16)
+        exit
+    "), Ok(()));
+    assert_eq!(verify("*(u64 *)(r10 - 8) = r1\nr0 = *(u32 *)(r10 - 8)\nexit"),
+        Err((1, PrgmVerifyErrorKind::PartialPointer { region: PtrKind::Stack, off: -8, size: 4 })));
+    assert_eq!(verify("*(u32 *)(r10 - 8) = r1\nldw r0, 0\nexit"),
+        Err((0, PrgmVerifyErrorKind::PartialPointer { region: PtrKind::Stack, off: -8, size: 4 })));
+
+    // as is a number, including what is known of it
+    assert_eq!(Env::default().complexity_limit(100).verify(&assemble("
+        r2 = *(u8 *)skb[0]
+        *(u64 *)(r10 - 8) = r2
+        r0 = *(u64 *)(r10 - 8)
+        if r0 > 255 goto spin
+        exit
+    spin:
+        goto spin
+    ").unwrap()).map(|_| ()).map_err(|e| e.inst_idx()), Ok(()));
+
+    // overwriting part of a spilled number leaves plain data
+    assert_eq!(verify("
+        ldw r2, 1
+        *(u64 *)(r10 - 8) = r2
+        *(u8 *)(r10 - 8) = 0
+        r0 = *(u64 *)(r10 - 8)
+        exit
+    "), Ok(()));
+    // but part of a spilled pointer can't be, or the rest could be read back
+    let partial = |off, size| PrgmVerifyErrorKind::PartialPointer { region: PtrKind::Stack, off, size };
+    assert_eq!(verify("
+        *(u64 *)(r10 - 8) = r10
+        *(u8 *)(r10 - 8) = 0
+        r0 = *(u8 *)(r10 - 7)
+        exit
+    "), Err((1, partial(-8, 1))));
+    assert_eq!(Env::default().profile(Profile::tracing()).verify(&assemble("
+        *(u64 *)(r10 - 8) = r10
+        r1 = r10
+        r1 += -8
+        ldw r2, 8
+        call 16
+        r0 = *(u8 *)(r10 - 7)
+        exit
+    ").unwrap()).map(|_| ()).map_err(|e| (e.inst_idx(), e.kind().clone())), Err((4, partial(-8, 1))));
+}
+
+#[test]
+fn packet() {
+    let profile = Profile {
+        ctx: vec![
+            CtxField::ptr(0, 8, PtrKind::Packet),
+            CtxField::ptr(8, 8, PtrKind::PacketEnd),
+            CtxField::read(16, 4),
+        ],
+        ..Profile::default()
+    };
+    let check = |src: &str| Env::default().profile(profile.clone())
+        .verify(&assemble(src).unwrap()).map(|_| ()).map_err(|e| (e.inst_idx(), e.kind().clone()));
+    let oob = |region, off, size| PrgmVerifyErrorKind::OutOfBounds { region, off, size };
+
+    let bounds_checked = |load: &str| format!("
+        r2 = *(u64 *)(r1 + 0)
+        r3 = *(u64 *)(r1 + 8)
+        r4 = r2
+        r4 += 4
+        ldw r0, 0
+        if r4 > r3 goto done
+        {}
+    done:
+        exit
+    ", load);
+    assert_eq!(check(&bounds_checked("r0 = *(u32 *)(r2 + 0)")), Ok(()));
+    assert_eq!(check(&bounds_checked("r0 = *(u32 *)(r2 + 1)")), Err((6, oob(PtrKind::Packet, 1, 4))));
+    assert_eq!(check("r2 = *(u64 *)(r1 + 0)\nr0 = *(u8 *)(r2 + 0)\nexit"),
+        Err((1, oob(PtrKind::Packet, 0, 1))));
+
+    // the packet end may be on either side, and a strict comparison covers one more byte
+    assert_eq!(check("
+        r2 = *(u64 *)(r1 + 0)
+        r3 = *(u64 *)(r1 + 8)
+        r4 = r2
+        r4 += 1
+        ldw r0, 0
+        if r3 <= r4 goto done
+        r0 = *(u16 *)(r2 + 0)
+    done:
+        exit
+    "), Ok(()));
+
+    // a variable offset, once checked
+    let variable = |mask: &str| format!("
+        r2 = *(u64 *)(r1 + 0)
+        r3 = *(u64 *)(r1 + 8)
+        r4 = *(u32 *)(r1 + 16)
+        {}
+        r2 += r4
+        r5 = r2
+        r5 += 2
+        ldw r0, 0
+        if r5 > r3 goto done
+        r0 = *(u16 *)(r2 + 0)
+    done:
+        exit
+    ", mask);
+    assert_eq!(check(&variable("r4 &= 0xff")), Ok(()));
+    assert_eq!(check(&variable("ldw r0, 0")),
+        Err((4, PrgmVerifyErrorKind::InvalidPointerArithmetic("pointer offset is not bounded"))));
+
+    // the rest of the context
+    assert_eq!(check("r0 = *(u32 *)(r1 + 16)\nexit"), Ok(()));
+    assert_eq!(check("r0 = *(u32 *)(r1 + 20)\nexit"), Err((0, oob(PtrKind::Ctx, 20, 4))));
+    assert_eq!(check("r0 = *(u32 *)(r1 + 4)\nexit"),
+        Err((0, PrgmVerifyErrorKind::PartialPointer { region: PtrKind::Ctx, off: 4, size: 4 })));
+    assert_eq!(check("*(u32 *)(r1 + 16) = 0\nldw r0, 0\nexit"),
+        Err((0, PrgmVerifyErrorKind::InvalidMemoryAccess { reg: 1, kind: Some(PtrKind::Ctx) })));
+
+    // the end of the packet is only for comparisons
+    assert_eq!(check("r3 = *(u64 *)(r1 + 8)\nr0 = *(u8 *)(r3 + 0)\nexit"),
+        Err((1, PrgmVerifyErrorKind::InvalidMemoryAccess { reg: 3, kind: Some(PtrKind::PacketEnd) })));
+    assert_eq!(check("r3 = *(u64 *)(r1 + 8)\nr3 += 1\nldw r0, 0\nexit"),
+        Err((1, PrgmVerifyErrorKind::InvalidPointerArithmetic("arithmetic on a pointer that can't be moved"))));
+}
+
+#[test]
+fn map_values() {
+    // `ld_imm64` with a src_reg of 1 loads a map, and of 2 a pointer into the map's value
+    let check = |src_reg: u64, src: &str| {
+        let mut p = assemble(src).unwrap();
+        p[0] |= src_reg << 52;
+        Env::default().map(MapDef { value_size: 8, ..MapDef::default() })
+            .verify(&p).map(|_| ()).map_err(|e| (e.inst_idx(), e.kind().clone()))
+    };
+    let oob = |off, size| PrgmVerifyErrorKind::OutOfBounds { region: PtrKind::MapValue, off, size };
+
+    assert_eq!(check(2, "r2 = 0 ll\nr0 = *(u64 *)(r2 + 0)\nexit"), Ok(()));
+    // the upper half of the immediate is an offset into the value
+    assert_eq!(check(2, "r2 = 0x400000000 ll\nr0 = *(u32 *)(r2 + 0)\nexit"), Ok(()));
+    assert_eq!(check(2, "r2 = 0x400000000 ll\nr0 = *(u64 *)(r2 + 0)\nexit"), Err((2, oob(4, 8))));
+
+    let variable = |load: &str| format!("
+        r2 = 0 ll
+        r3 = *(u8 *)(r2 + 0)
+        r3 &= 7
+        r2 += r3
+        {}
+        exit
+    ", load);
+    assert_eq!(check(2, &variable("r0 = *(u8 *)(r2 + 0)")), Ok(()));
+    assert_eq!(check(2, &variable("r0 = *(u16 *)(r2 + 0)")), Err((5, oob(0, 2))));
+    assert_eq!(check(2, "r2 = 0 ll\n*(u64 *)(r2 + 0) = r10\nldw r0, 0\nexit"),
+        Err((2, PrgmVerifyErrorKind::PointerLeak { reg: 10 })));
+
+    assert_eq!(check(1, "r2 = 0 ll\nr0 = *(u32 *)(r2 + 0)\nexit"),
+        Err((2, PrgmVerifyErrorKind::InvalidMemoryAccess { reg: 2, kind: Some(PtrKind::Map) })));
+    assert_eq!(check(1, "r2 = 1 ll\nldw r0, 0\nexit"), Err((0, PrgmVerifyErrorKind::UnknownMap { index: 1 })));
+}
+
+#[test]
+fn pruning() {
+    // each branch doubles the paths, but they all rejoin in a state the first one covers
+    let mut src = String::from("ldw r0, 0\n");
+    for _ in 0..20 {
+        src.push_str("r5 = *(u8 *)skb[0]\nif r5 > 100 goto +1\nr5 = *(u8 *)skb[1]\n");
+    }
+    src.push_str("exit\n");
+
+    let mut env = Env::default();
+    env.verify(&assemble(&src).unwrap()).unwrap();
+    assert_eq!(env.stats(), Stats {
+        insns_processed: 62,
+        states_explored: 41,
+        states_pruned: 20,
+        peak_states: 61,
+    });
+
+    // a register which is written before it is read again does not matter
+    let mut src = String::from("ldw r0, 0\nr5 = *(u8 *)skb[0]\n");
+    for _ in 0..20 {
+        src.push_str("if r5 > 100 goto +1\nr5 += 1\nr5 = *(u8 *)skb[0]\n");
+    }
+    src.push_str("exit\n");
+    env.verify(&assemble(&src).unwrap()).unwrap();
+    assert_eq!(env.stats().states_pruned, 20);
+    assert_eq!(env.liveness().dead_stores(), (0..20).map(|n| 3 + 3 * n).collect::<Vec<_>>().as_slice());
+    assert_eq!(env.liveness().args(), 0);
+
+    // a state which only might end is not used to cut a loop short
+    let mut env = Env::default().complexity_limit(100);
+    assert_eq!(env.verify(&assemble("ldw r1, 1\nif r1 != 0 goto -1\nldw r0, 0\nexit").unwrap())
+        .unwrap_err().kind(), &PrgmVerifyErrorKind::InfiniteLoop);
+    assert_eq!(env.stats().states_pruned, 0);
+}
+
+#[test]
+fn profiles() {
+    let check = |profile: Profile, src: &str| Env::default().profile(profile)
+        .verify(&assemble(src).unwrap()).map(|_| ()).map_err(|e| (e.inst_idx(), e.kind().clone()));
+    let between = |off, size| Err((0, PrgmVerifyErrorKind::InvalidContextAccess { off, size }));
+
+    // `data` and `data_end` are 32-bit fields, loaded as pointers
+    assert_eq!(check(Profile::xdp(), "
+        r2 = *(u32 *)(r1 + 0)
+        r3 = *(u32 *)(r1 + 4)
+        r4 = r2
+        r4 += 14
+        ldw r0, 1
+        if r4 > r3 goto done
+        r0 = *(u16 *)(r2 + 12)
+        r0 &= 3
+    done:
+        exit
+    "), Ok(()));
+    assert_eq!(check(Profile::xdp(), "r0 = *(u32 *)(r1 + 8)\nexit"), between(8, 4));
+    assert_eq!(check(Profile::xdp(), "r0 = *(u8 *)skb[0]\nexit"),
+        Err((0, PrgmVerifyErrorKind::InstDecode(InstDecodeError::ForbiddenInst(
+            "ld.abs and ld.ind are not allowed for this program type")))));
+
+    // only `cb` may be written
+    assert_eq!(check(Profile::socket_filter(), "*(u32 *)(r1 + 48) = 1\nr0 = *(u32 *)(r1 + 0)\nexit"), Ok(()));
+    assert_eq!(check(Profile::socket_filter(), "*(u32 *)(r1 + 0) = 1\nldw r0, 0\nexit"),
+        Err((0, PrgmVerifyErrorKind::InvalidMemoryAccess { reg: 1, kind: Some(PtrKind::Ctx) })));
+    assert_eq!(check(Profile::socket_filter(), "r0 = *(u32 *)(r1 + 72)\nexit"),
+        Err((0, PrgmVerifyErrorKind::OutOfBounds { region: PtrKind::Ctx, off: 72, size: 4 })));
+
+    // part of a field may be read, but not parts of two
+    assert_eq!(check(Profile::seccomp(), "r0 = *(u32 *)(r1 + 20)\nexit"), Ok(()));
+    assert_eq!(check(Profile::seccomp(), "r0 = *(u64 *)(r1 + 4)\nexit"), between(4, 8));
+    assert_eq!(check(Profile::tracing(), "r0 = *(u64 *)(r1 + 160)\nexit"), Ok(()));
+}
+
+#[test]
+fn calls() {
+    let check = |mut env: Env, src: &str| env.verify(&assemble(src).unwrap()).map(|_| ())
+        .map_err(|e| (e.inst_idx(), e.kind().clone()));
+    let tracing = || Env::default().profile(Profile::tracing());
+    let mismatch = |reg, expected| PrgmVerifyErrorKind::HelperArgMismatch { reg, expected };
+
+    // `bpf_get_current_comm` fills the buffer it is passed
+    let comm = |size| format!("
+        r1 = r10
+        r1 += -16
+        ldw r2, {}
+        call 16
+        r0 = *(u64 *)(r10 - 16)
+        exit
+    ", size);
+    assert_eq!(check(tracing(), &comm(16)), Ok(()));
+    assert_eq!(check(tracing(), &comm(17)),
+        Err((3, PrgmVerifyErrorKind::OutOfBounds { region: PtrKind::Stack, off: -16, size: 17 })));
+    assert_eq!(check(tracing(), &comm(0)), Err((3, mismatch(2, ArgKind::ConstSize))));
+
+    // with a size from 8 to 15, only the first 8 bytes are surely written
+    let comm = |load| format!("
+        call 5
+        r2 = r0
+        r2 &= 7
+        r2 += 8
+        r1 = r10
+        r1 += -16
+        call 16
+        r0 = *(u64 *)(r10 - {})
+        exit
+    ", load);
+    assert_eq!(check(tracing(), &comm(16)), Ok(()));
+    assert_eq!(check(tracing(), &comm(8)), Err((7, PrgmVerifyErrorKind::UninitializedStack { off: -8 })));
+
+    // arguments are consumed
+    assert_eq!(check(tracing(), "call 5\nr0 += r1\nexit"),
+        Err((1, PrgmVerifyErrorKind::UninitializedRegister { reg: 1 })));
+
+    // only helpers the profile allows, or those given a prototype
+    assert_eq!(check(Env::default().profile(Profile::seccomp()), "call 5\nexit"),
+        Err((0, PrgmVerifyErrorKind::UnknownHelper { id: 5 })));
+    let custom = || Env::default().helper(100, HelperProto::new(&[ArgKind::Scalar], RetKind::Void));
+    assert_eq!(check(custom(), "ldw r1, 1\ncall 100\nldw r0, 0\nexit"), Ok(()));
+    assert_eq!(check(custom(), "ldw r1, 1\ncall 100\nexit"),
+        Err((2, PrgmVerifyErrorKind::UninitializedRegister { reg: 0 })));
+    assert_eq!(check(custom(), "r1 = r10\ncall 100\nldw r0, 0\nexit"), Err((1, mismatch(1, ArgKind::Scalar))));
+
+    // prototypes which can't be checked are rejected when called
+    let invalid = |args: &[ArgKind]| Env::default().helper(100, HelperProto::new(args, RetKind::Scalar));
+    let proto = Err((1, PrgmVerifyErrorKind::InvalidHelperProto { id: 100 }));
+    assert_eq!(check(invalid(&[ArgKind::Scalar; 6]), "ldw r0, 0\ncall 100\nexit"), proto);
+    assert_eq!(check(invalid(&[ArgKind::Scalar, ArgKind::StackMem]), "ldw r0, 0\ncall 100\nexit"), proto);
+    assert_eq!(check(invalid(&[ArgKind::UninitStackMem, ArgKind::Scalar]), "ldw r0, 0\ncall 100\nexit"), proto);
+
+    // moving the packet invalidates pointers into it
+    assert_eq!(check(Env::default().profile(Profile::xdp()), "
+        r6 = *(u32 *)(r1 + 0)
+        r7 = *(u32 *)(r1 + 4)
+        r8 = r6
+        r8 += 1
+        if r8 > r7 goto out
+        ldw r2, 0
+        call 44
+        r0 = *(u8 *)(r6 + 0)
+    out:
+        ldw r0, 0
+        exit
+    "), Err((7, PrgmVerifyErrorKind::InvalidMemoryAccess { reg: 6, kind: None })));
+}
+
+#[test]
+fn functions() {
+    // `f` gets r1 to r5 and a stack of its own, and the caller keeps r6 to r10
+    let src = |body: &str| format!("
+        r6 = 1
+        r1 = r10
+        r1 += -8
+        *(u64 *)(r10 - 8) = r6
+        call f
+        r0 += r6
+        exit
+    f:
+        {}
+        exit
+    ", body);
+    let f = "
+        r0 = *(u64 *)(r1 + 0)
+        *(u64 *)(r10 - 16) = r0
+    ";
+    let p = assemble(&src(f)).unwrap();
+    let v = Env::default().verify(&p).unwrap();
+    assert_eq!((v.stack_depth(), v.max_call_depth(), v.helpers()), (24, 1, &[][..]));
+    assert_eq!(Invoke::new(v).run(), Ok(2));
+
+    assert_eq!(verify(&src("r0 = r6")), Err((7, PrgmVerifyErrorKind::UninitializedRegister { reg: 6 })));
+    assert_eq!(verify(&src("r0 = *(u64 *)(r10 - 8)")), Err((7, PrgmVerifyErrorKind::UninitializedStack { off: -8 })));
+    assert_eq!(verify(&src("r0 = *(u32 *)(r1 - 4)")), Err((7, PrgmVerifyErrorKind::UninitializedStack { off: -12 })));
+    assert_eq!(verify("r1 = 0
+call +2
+r0 = r1
+exit
+r0 = 0
+exit"),
+        Err((2, PrgmVerifyErrorKind::UninitializedRegister { reg: 1 })));
+
+    // no pointer to `f`'s stack may outlive it
+    assert_eq!(verify(&src("r0 = r10")), Err((8, PrgmVerifyErrorKind::PointerLeak { reg: 0 })));
+    assert_eq!(verify(&src("r2 = r10
+*(u64 *)(r1 + 0) = r2
+r0 = 0")),
+        Err((8, PrgmVerifyErrorKind::PointerLeak { reg: 2 })));
+    assert_eq!(verify(&src("r2 = r10
+*(u64 *)(r10 - 8) = r2
+r0 = *(u64 *)(r1 + 0)")), Ok(()));
+
+    // each call nests deeper; a function calling itself never ends
+    let nested = "
+        call +2
+        r0 = 0
+        exit
+        call +1
+        exit
+        r0 = 1
+        exit
+    ";
+    let p = assemble(nested).unwrap();
+    let v = Env::default().verify(&p).unwrap();
+    assert_eq!(v.max_call_depth(), 2);
+    assert_eq!(Invoke::new(v).run(), Ok(0));
+
+    let recurse = assemble("r0 = 0
+call -1
+exit").unwrap();
+    assert_eq!(Env::default().verify(&recurse).map(|_| ()).map_err(|e| (e.inst_idx(), e.kind().clone())),
+        Err((1, PrgmVerifyErrorKind::CallDepthExceeded { limit: 8 })));
+    let e = Env::default().forbid_loops().verify(&recurse).unwrap_err();
+    assert_eq!((e.inst_idx(), e.kind().clone()), (1, PrgmVerifyErrorKind::BackEdge { to: 1 }));
+}
+
+#[test]
+fn map_lookup() {
+    // `ld_imm64` of map 0, and a key on the stack
+    let check = |key: &str, after: &str| {
+        let mut p = assemble(&format!("
+            r1 = 0 ll
+            {}
+            r2 = r10
+            r2 += -4
+            call 1
+            {}
+            exit
+        ", key, after)).unwrap();
+        p[0] |= 1 << 52;
+        Env::default().profile(Profile::tracing()).map(MapDef { key_size: 4, value_size: 8, ..MapDef::default() })
+            .verify(&p).map(|_| ()).map_err(|e| (e.inst_idx(), e.kind().clone()))
+    };
+    let key = "*(u32 *)(r10 - 4) = 0";
+
+    assert_eq!(check(key, "if r0 == 0 goto out\nr0 = *(u64 *)(r0 + 0)\nexit\nout:"), Ok(()));
+    assert_eq!(check(key, "if r0 != 0 goto +1\nexit\nr0 = *(u64 *)(r0 + 0)"), Ok(()));
+
+    // the value may not exist
+    assert_eq!(check(key, "r0 = *(u64 *)(r0 + 0)"),
+        Err((6, PrgmVerifyErrorKind::InvalidMemoryAccess { reg: 0, kind: Some(PtrKind::MapValueOrNull) })));
+    assert_eq!(check(key, "if r0 == 0 goto out\nr0 = *(u64 *)(r0 + 4)\nexit\nout:"),
+        Err((7, PrgmVerifyErrorKind::OutOfBounds { region: PtrKind::MapValue, off: 4, size: 8 })));
+
+    // the key must be initialized
+    assert_eq!(check("", "ldw r0, 0"), Err((4, PrgmVerifyErrorKind::UninitializedStack { off: -4 })));
+    assert_eq!(check(key, "ldw r0, 0"), Ok(()));
+
+    // a number is not a key, even if it is 0
+    assert_eq!(check("ldw r2, 0\ncall 1", "ldw r0, 0"),
+        Err((3, PrgmVerifyErrorKind::HelperArgMismatch { reg: 2, expected: ArgKind::MapKey })));
+}
+
+#[test]
+fn log() {
+    let p = assemble("
+        r2 = r10
+        *(u32 *)(r10 - 8) = 1
+        if r1 > 3 goto +2
+        r0 = 0
+        exit
+        r2 += -8
+        r0 = *(u64 *)(r2 + 0)
+        exit
+    ").unwrap();
+
+    let mut out = String::new();
+    assert!(Env::default().verify_log(&p, LogLevel::Path, &mut out).is_err());
+    assert_eq!(out, "\
+path to the error:
+0: r2 = r10
+1: *(u32 *)(r10 - 8) = 1
+2: if r1 > 3 goto L5
+5: r2 += -8
+6: r0 = *(u64 *)(r2 + 0) ; R2=fp-8 fp-8=mmmm????
+instruction 6: stack at fp-4 is read before it is written
+");
+
+    let mut out = String::new();
+    assert!(Env::default().verify_log(&p, LogLevel::Verbose, &mut out).is_err());
+    assert!(out.starts_with("\
+0: r2 = r10 ; R1=ctx R10=fp
+1: *(u32 *)(r10 - 8) = 1 ; R1=ctx R2=fp R10=fp
+2: if r1 > 3 goto L5 ; R1=ctx R2=fp fp-8=mmmm????
+3: r0 = 0 ; fp-8=mmmm????
+4: exit ; R0=0 fp-8=mmmm????
+from 2 to 5: ; R2=fp fp-8=mmmm????
+5: r2 += -8 ; R2=fp fp-8=mmmm????
+"));
+    assert!(out.ends_with("processed 7 insns, 3 states explored, 0 pruned, peak 3 states\n"));
+
+    // nothing is written for programs which are accepted, unless verbose
+    let mut out = String::new();
+    Env::default().verify_log(&assemble("r0 = 0\nexit").unwrap(), LogLevel::Path, &mut out).unwrap();
+    assert_eq!(out, "");
+}
+
+#[test]
+fn errors() {
+    let p = assemble("ldw r0, 3\ntop:\nr0 -= 1\nif r0 != 0 goto top\nexit").unwrap();
+    assert!(Env::default().verify(&p).is_ok());
+    let e = Env::default().forbid_loops().verify(&p).unwrap_err();
+    assert_eq!((e.inst_idx(), e.kind().clone()), (2, PrgmVerifyErrorKind::BackEdge { to: 1 }));
+    assert_eq!(format!("{}", e), "instruction 2: back-edge to instruction 1");
+    // a jump backwards need not be a loop
+    let p = assemble("r0 = 0\ngoto +1\nexit\ngoto -2").unwrap();
+    assert!(Env::default().forbid_loops().verify(&p).is_ok());
+
+    assert!(Env::with_inst_limit(2).verify(&assemble("r0 = 0\nexit").unwrap()).is_ok());
+    let e = Env::with_inst_limit(2).verify(&assemble("r0 = 0\ngoto +5\nexit").unwrap()).unwrap_err();
+    assert_eq!((e.inst_idx(), e.kind().clone()), (2, PrgmVerifyErrorKind::InstLimitExceeded));
+
+    let msg = |src: &str| format!("{}", Env::default().verify(&assemble(src).unwrap()).unwrap_err());
+    assert_eq!(msg("r0 = r2\nexit"), "instruction 0: r2 is read before it is written");
+    assert_eq!(msg("r0 = *(u64 *)(r10 + 0)\nexit"),
+        "instruction 0: access of 8 bytes at offset 0 may be outside fp");
+    assert_eq!(msg("r1 *= 2\nldw r0, 0\nexit"),
+        "instruction 0: invalid pointer arithmetic: only add and sub may be used on pointers");
+    assert_eq!(msg("ldw r0, 8\nr0 += r10\nexit"), "instruction 2: r0 leaks a pointer");
+    assert_eq!(msg("r0 = 0\nexit\nexit"), "instruction 2: unreachable instruction");
+    assert_eq!(msg("r0 = 0\ngoto +5\nexit"), "instruction 1: jump out of range");
+    assert_eq!(msg("call 1\nexit"), "instruction 0: helper 1 is unknown or not allowed");
+}
+
+#[test]
+fn return_values() {
+    let check = |mut env: Env, src: &str| env.verify(&assemble(src).unwrap()).map(|_| ())
+        .map_err(|e| (e.inst_idx(), e.kind().clone()));
+    let bad = |pc, min, max| Err((pc, PrgmVerifyErrorKind::BadReturnValue { min, max }));
+
+    // an XDP action is 0 to 4, on every path
+    let xdp = || Env::default().profile(Profile::xdp());
+    assert_eq!(check(xdp(), "ldw r0, 4\nexit"), Ok(()));
+    assert_eq!(check(xdp(), "ldw r0, 5\nexit"), bad(1, 5, 5));
+    assert_eq!(check(xdp(), "r0 = *(u32 *)(r1 + 12)\nr0 &= 3\nexit"), Ok(()));
+    assert_eq!(check(xdp(), "r0 = *(u32 *)(r1 + 12)\nr0 &= 7\nexit"), bad(2, 0, 7));
+    assert_eq!(check(xdp(), "
+        r0 = *(u32 *)(r1 + 12)
+        if r0 < 5 goto +1
+        ldw r0, 2
+        exit
+    "), Ok(()));
+    assert_eq!(check(xdp(), "
+        r2 = *(u32 *)(r1 + 12)
+        ldw r0, 1
+        if r2 > 9 goto +1
+        exit
+        ldw r0, 9
+        exit
+    "), bad(5, 9, 9));
+    assert_eq!(check(xdp(), "exit"), Err((0, PrgmVerifyErrorKind::UninitializedRegister { reg: 0 })));
+
+    // the `Env` may narrow the values further, to a set
+    let set = || xdp().ret(RetValues::Set(vec![1, 2, 4]));
+    assert_eq!(check(set(), "ldw r0, 2\nexit"), Ok(()));
+    assert_eq!(check(set(), "ldw r0, 3\nexit"), bad(1, 3, 3));
+    assert_eq!(check(set(), "r0 = *(u32 *)(r1 + 12)\nr0 &= 6\nexit"), bad(2, 0, 6));
+    assert_eq!(check(set(), "r0 = *(u32 *)(r1 + 12)\nr0 &= 6\nr0 |= 2\nif r0 == 6 goto +1\nexit\nldw r0, 1\nexit"), Ok(()));
+
+    // tracing programs may return anything
+    assert_eq!(check(Env::default().profile(Profile::tracing()), "r0 = -1\nexit"), Ok(()));
+}
+
+#[test]
+fn verified_program() {
+    let mut p = assemble("
+        r1 = 0 ll
+        *(u32 *)(r10 - 4) = 0
+        r2 = r10
+        r2 += -4
+        call 1
+        call 5
+        *(u64 *)(r10 - 24) = r0
+        r1 = 0 ll
+        r2 = r10
+        r2 += -4
+        call 3
+        ldw r0, 0
+        exit
+    ").unwrap();
+    p[0] |= 1 << 52;
+    p[8] |= 1 << 52;
+    let v = Env::default().profile(Profile::tracing()).map(MapDef { key_size: 4, value_size: 8, ..MapDef::default() })
+        .verify(&p).unwrap();
+
+    assert_eq!(v.stack_depth(), 24);
+    assert_eq!(v.helpers(), &[1, 3, 5]);
+    assert_eq!(v.max_call_depth(), 0);
+    assert_eq!(v.insts().len(), p.len());
+    assert_eq!(v.insts()[4].to_u64(), p[4]);
+    assert_eq!(v.program(), unsafe { cbpf::Program::from_raw(&p) });
+    // which `Invoke` can't run, as it has no maps or helpers
+    assert_eq!(Invoke::new(v).run(), Err(()));
+
+    let v = Env::default().verify(&[0xb700000000000000, 0x9500000000000000]).unwrap();
+    assert_eq!((v.stack_depth(), v.helpers()), (0, &[][..]));
+
+    // nor a context
+    let tracing = |src: &str| Env::default().profile(Profile::tracing()).verify(&assemble(src).unwrap())
+        .map(|v| Invoke::new(v).run());
+    assert_eq!(tracing("call 5\nexit"), Ok(Err(())));
+    assert_eq!(tracing("r0 = *(u64 *)(r1 + 0)\nexit"), Ok(Err(())));
+}